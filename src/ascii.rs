@@ -1,3 +1,5 @@
+use crate::dither::DitherMatrix;
+
 pub fn angle_to_ascii(angle: f32) -> char {
     let angle_deg = angle.to_degrees();
     match angle_deg {
@@ -13,11 +15,80 @@ pub fn angle_to_ascii(angle: f32) -> char {
     }
 }
 
-pub fn brightness_to_ascii(brightness: u8, invert: bool) -> char {
-    const ASCII_CHARS: &[char] = &[' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
-    
-    // Apply gamma correction (gamma = 2.2)
-    let corrected_brightness = (brightness as f32 / 255.0).powf(1.0 / 2.2);
+// Which glyphs a cell can be drawn with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderMode {
+    // Ramp or fill glyphs by brightness everywhere, ignoring edges
+    Brightness,
+    // Line glyphs along edges and blanks everywhere else
+    Edges,
+    // Line glyphs along edges, brightness glyphs everywhere else
+    Combined,
+}
+
+impl RenderMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "brightness" => Some(RenderMode::Brightness),
+            "edges" => Some(RenderMode::Edges),
+            "combined" => Some(RenderMode::Combined),
+            _ => None,
+        }
+    }
+}
+
+// Glyphs from empty to dense used when no preset picks its own
+pub const DEFAULT_RAMP: &str = " .:-=+*#%@";
+
+// Glyph for every brightness level of a ramp, built once instead of per cell
+pub struct GlyphRamp {
+    lut: [char; 256],
+    chars: Vec<char>,
+    // Where every brightness level falls on the ramp, in steps from its first glyph
+    positions: [f32; 256],
+    // Pick between the two glyphs around each position with an ordered dither
+    // over the cell grid, instead of the nearest one
+    dither: Option<DitherMatrix>,
+}
+
+impl GlyphRamp {
+    // `ramp` runs from empty to dense; an empty ramp draws blanks. Brightness is
+    // linear and gets encoded with `display_gamma` before picking a glyph.
+    pub fn new(ramp: &str, invert: bool, display_gamma: f32) -> Self {
+        let chars: Vec<char> = ramp.chars().collect();
+        let mut lut = [' '; 256];
+        let mut positions = [0.0; 256];
+        if !chars.is_empty() {
+            for (brightness, (glyph, position)) in lut.iter_mut().zip(positions.iter_mut()).enumerate() {
+                *position = ramp_position(chars.len(), brightness as u8, invert, display_gamma);
+                *glyph = chars[position.round() as usize];
+            }
+        }
+        GlyphRamp { lut, chars, positions, dither: None }
+    }
+
+    pub fn set_dither(&mut self, dither: Option<DitherMatrix>) {
+        self.dither = dither;
+    }
+
+    pub fn glyph(&self, brightness: u8) -> char {
+        self.lut[brightness as usize]
+    }
+
+    // Glyph for the cell at (x, y). Dithered, neighbouring cells alternate between
+    // the two glyphs around the brightness so that they average to it; the pattern
+    // is fixed to the grid so it doesn't crawl from frame to frame.
+    pub fn glyph_at(&self, brightness: u8, x: usize, y: usize) -> char {
+        match self.dither {
+            Some(matrix) if !self.chars.is_empty() => self.chars[dithered_index(self.positions[brightness as usize], matrix.threshold(x, y), self.chars.len())],
+            _ => self.glyph(brightness),
+        }
+    }
+}
+
+// Position on a ramp of `len` glyphs, from 0 to len - 1
+fn ramp_position(len: usize, brightness: u8, invert: bool, display_gamma: f32) -> f32 {
+    let corrected_brightness = gamma_encode(brightness, display_gamma);
     
     // Invert if needed
     let normalized_brightness = if invert {
@@ -26,7 +97,66 @@ pub fn brightness_to_ascii(brightness: u8, invert: bool) -> char {
         corrected_brightness
     };
     
-    // Map to ASCII character
-    let index = (normalized_brightness * (ASCII_CHARS.len() - 1) as f32).round() as usize;
-    ASCII_CHARS[index]
-}
\ No newline at end of file
+    normalized_brightness * (len - 1) as f32
+}
+
+// Glyph index for `position` with the threshold added, so a position a quarter of
+// the way to the next glyph picks it in a quarter of the cells. Never more than
+// one step from the nearest glyph.
+fn dithered_index(position: f32, threshold: f32, len: usize) -> usize {
+    ((position + threshold).floor() as usize).min(len - 1)
+}
+
+// Sparse ramp for cells whose background already carries the color, so the
+// glyph adds texture without covering the fill
+pub fn brightness_to_fill_ascii(brightness: u8, display_gamma: f32) -> char {
+    const FILL_CHARS: &[char] = &[' ', ' ', '.', ':', '-', '='];
+
+    let corrected_brightness = gamma_encode(brightness, display_gamma);
+    let index = (corrected_brightness * (FILL_CHARS.len() - 1) as f32).round() as usize;
+    FILL_CHARS[index]
+}
+
+// Linear brightness to [0, 1] on a display with the given gamma
+fn gamma_encode(brightness: u8, display_gamma: f32) -> f32 {
+    (brightness as f32 / 255.0).powf(1.0 / display_gamma)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATRICES: [DitherMatrix; 4] = [DitherMatrix::Bayer2, DitherMatrix::Bayer4, DitherMatrix::Bayer8, DitherMatrix::BlueNoise];
+
+    #[test]
+    fn dithered_glyph_stays_within_a_step() {
+        for ramp_text in [" @", " .:", DEFAULT_RAMP] {
+            let plain = GlyphRamp::new(ramp_text, false, 2.2);
+            let index = |ch: char| ramp_text.chars().position(|c| c == ch).unwrap() as i32;
+            for matrix in MATRICES {
+                let mut dithered = GlyphRamp::new(ramp_text, false, 2.2);
+                dithered.set_dither(Some(matrix));
+                for brightness in 0..=255u8 {
+                    let undithered = index(plain.glyph(brightness));
+                    for (x, y) in (0..16).flat_map(|y| (0..16).map(move |x| (x * 7, y * 5))) {
+                        let step = index(dithered.glyph_at(brightness, x, y)) - undithered;
+                        assert!(step.abs() <= 1, "{:?} {} on {:?} at {} {}", ramp_text, brightness, matrix, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dithered_glyphs_average_to_the_ramp_position() {
+        let mut ramp = GlyphRamp::new(DEFAULT_RAMP, false, 2.2);
+        ramp.set_dither(Some(DitherMatrix::Bayer4));
+        for brightness in 0..=255u8 {
+            // Over one tile of the pattern, which is fixed to the cell grid
+            let sum: usize = (0..16).map(|i| DEFAULT_RAMP.chars().position(|c| c == ramp.glyph_at(brightness, i % 4, i / 4)).unwrap()).sum();
+            let position = ramp.positions[brightness as usize];
+            assert!((sum as f32 / 16.0 - position).abs() <= 1.0 / 16.0 + 1e-4, "{}: {} vs {}", brightness, sum as f32 / 16.0, position);
+            assert_eq!(ramp.glyph_at(brightness, 5, 9), ramp.glyph_at(brightness, 1, 1));
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::raymarch::RayStats;
+use std::time::Duration;
+
+// An --uncapped run: frames are drawn back to back and the run ends after a fixed
+// number of frames or a fixed time, whichever comes first
+pub struct Benchmark {
+    frames: usize,
+    duration: Option<Duration>,
+    pub stats: FrameStats,
+    // Summed over the raymarched frames
+    pub rays: RayStats,
+}
+
+impl Benchmark {
+    pub fn new(frames: usize, duration: Option<Duration>) -> Self {
+        Benchmark { frames, duration, stats: FrameStats::default(), rays: RayStats::default() }
+    }
+
+    // `elapsed` is the time since the run started
+    pub fn finished(&self, elapsed: Duration) -> bool {
+        self.stats.count >= self.frames || self.duration.is_some_and(|duration| elapsed >= duration)
+    }
+}
+
+// Running minimum, mean and maximum of frame times in milliseconds
+#[derive(Default)]
+pub struct FrameStats {
+    count: usize,
+    total: f64,
+    min: f32,
+    max: f32,
+}
+
+impl FrameStats {
+    pub fn add(&mut self, milliseconds: f32) {
+        if self.count == 0 {
+            self.min = milliseconds;
+            self.max = milliseconds;
+        } else {
+            self.min = self.min.min(milliseconds);
+            self.max = self.max.max(milliseconds);
+        }
+        self.total += milliseconds as f64;
+        self.count += 1;
+    }
+
+    // None before the first frame
+    pub fn average(&self) -> Option<f32> {
+        (self.count > 0).then(|| (self.total / self.count as f64) as f32)
+    }
+
+    pub fn summary(&self) -> String {
+        match self.average() {
+            Some(average) => format!(
+                "{} frames: min {:.2} ms, avg {:.2} ms, max {:.2} ms ({:.1} fps)",
+                self.count, self.min, average, self.max, 1000.0 / average
+            ),
+            None => "No frames drawn".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_stats_sum_up_the_durations() {
+        let mut stats = FrameStats::default();
+        assert_eq!(stats.average(), None);
+        assert_eq!(stats.summary(), "No frames drawn");
+
+        for milliseconds in [12.0, 8.0, 20.0, 10.0] {
+            stats.add(milliseconds);
+        }
+        assert_eq!((stats.count, stats.min, stats.max), (4, 8.0, 20.0));
+        assert_eq!(stats.average(), Some(12.5));
+        assert_eq!(stats.summary(), "4 frames: min 8.00 ms, avg 12.50 ms, max 20.00 ms (80.0 fps)");
+    }
+
+    #[test]
+    fn benchmark_ends_on_frames_or_time() {
+        let mut benchmark = Benchmark::new(3, Some(Duration::from_secs(2)));
+        benchmark.stats.add(1.0);
+        benchmark.stats.add(1.0);
+        assert!(!benchmark.finished(Duration::from_secs(1)));
+        assert!(benchmark.finished(Duration::from_secs(2)));
+        benchmark.stats.add(1.0);
+        assert!(benchmark.finished(Duration::ZERO));
+        assert!(!Benchmark::new(3, None).finished(Duration::from_secs(3600)));
+    }
+}
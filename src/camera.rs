@@ -0,0 +1,396 @@
+use crate::math::{concentric_disc, hash_f32, hash_u32, Vec2, Vec3};
+use std::f32::consts::PI;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Projection {
+    // Pinhole camera with a vertical field of view in radians
+    Perspective { fov: f32 },
+    // Parallel rays; `height` is the world-space height of the view
+    Orthographic { height: f32 },
+    // Equidistant fisheye with a vertical field of view in radians
+    Fisheye { fov: f32 },
+    // Full 360x180 degree panorama
+    Equirectangular,
+}
+
+impl Projection {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "perspective" => Some(Projection::Perspective { fov: 45.0f32.to_radians() }),
+            "ortho" | "orthographic" => Some(Projection::Orthographic { height: 4.0 }),
+            "fisheye" => Some(Projection::Fisheye { fov: 180.0f32.to_radians() }),
+            "equirect" | "equirectangular" => Some(Projection::Equirectangular),
+            _ => None,
+        }
+    }
+
+    // Cycle through the projections with their default parameters
+    pub fn next(&self) -> Self {
+        let name = match self {
+            Projection::Perspective { .. } => "ortho",
+            Projection::Orthographic { .. } => "fisheye",
+            Projection::Fisheye { .. } => "equirect",
+            Projection::Equirectangular => "perspective",
+        };
+        Self::from_name(name).unwrap()
+    }
+}
+
+// Normalized device coordinates of a point in a `width` x `height` viewport, both
+// axes in [-1, 1] with +y up. `x` and `y` are in pixels from the top-left corner,
+// so the center of pixel (i, j) is at (i + 0.5, j + 0.5). The viewport's aspect is
+// left to Camera::ray, which scales the axes apart.
+pub fn pixel_to_ndc(x: f32, y: f32, width: usize, height: usize) -> Vec2 {
+    Vec2::new(2.0 * x / width as f32 - 1.0, 1.0 - 2.0 * y / height as f32)
+}
+
+// Inverse of pixel_to_ndc
+pub fn ndc_to_pixel(ndc: Vec2, width: usize, height: usize) -> Vec2 {
+    Vec2::new((ndc.x + 1.0) * 0.5 * width as f32, (1.0 - ndc.y) * 0.5 * height as f32)
+}
+
+#[derive(Clone, Copy)]
+pub struct Camera {
+    pub eye: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+    pub projection: Projection,
+    // Width / height of the viewport measured in cells
+    pub aspect_ratio: f32,
+    // Height / width of a single cell (terminal fonts are roughly twice as high as wide)
+    pub cell_aspect: f32,
+    pub lens: DepthOfField,
+}
+
+impl Camera {
+    pub fn new(eye: Vec3, target: Vec3, up: Vec3, projection: Projection) -> Self {
+        Camera {
+            eye,
+            target,
+            up,
+            projection,
+            aspect_ratio: 1.0,
+            cell_aspect: 2.0,
+            lens: DepthOfField::default(),
+        }
+    }
+
+    // Right, up and forward vectors of the camera in world space
+    pub fn basis(&self) -> (Vec3, Vec3, Vec3) {
+        let f = (self.target - self.eye).normalize();
+        let s = f.cross(&self.up).normalize();
+        let u = s.cross(&f);
+        (s, u, f)
+    }
+
+    // Generate the ray through a point in normalized device coordinates, see
+    // pixel_to_ndc. Returns (origin, direction).
+    pub fn ray(&self, ndc: Vec2) -> (Vec3, Vec3) {
+        let (right, up, forward) = self.basis();
+
+        // Screen-space position with x scaled by the viewport aspect and y by the cell aspect
+        let sx = ndc.x * self.aspect_ratio;
+        let sy = ndc.y * self.cell_aspect;
+
+        match self.projection {
+            Projection::Perspective { fov } => {
+                let tan_fov = (fov * 0.5).tan();
+                let direction = right * (sx * tan_fov) + up * (sy * tan_fov) + forward;
+                (self.eye, direction.normalize())
+            }
+            Projection::Orthographic { height } => {
+                let half_height = height * 0.5 / self.cell_aspect;
+                let origin = self.eye + right * (sx * half_height) + up * (sy * half_height);
+                (origin, forward)
+            }
+            Projection::Fisheye { fov } => {
+                let radius = (sx * sx + sy * sy).sqrt() / self.cell_aspect;
+                let theta = radius * fov * 0.5;
+                let phi = sy.atan2(sx);
+                let direction = right * (theta.sin() * phi.cos())
+                    + up * (theta.sin() * phi.sin())
+                    + forward * theta.cos();
+                (self.eye, direction.normalize())
+            }
+            Projection::Equirectangular => {
+                let longitude = ndc.x * PI;
+                let latitude = ndc.y * PI * 0.5;
+                let direction = right * (longitude.sin() * latitude.cos())
+                    + up * latitude.sin()
+                    + forward * (longitude.cos() * latitude.cos());
+                (self.eye, direction.normalize())
+            }
+        }
+    }
+
+    // Inverse of ray: where `p` shows up in normalized device coordinates, and its
+    // distance along that ray. None when the projection can't see it.
+    pub fn project(&self, p: Vec3) -> Option<(Vec2, f32)> {
+        let (right, up, forward) = self.basis();
+        let offset = p - self.eye;
+        let (x, y, z) = (offset.dot(&right), offset.dot(&up), offset.dot(&forward));
+        let distance = offset.length();
+
+        let (sx, sy, depth) = match self.projection {
+            Projection::Perspective { fov } => {
+                if z <= 0.0 {
+                    return None;
+                }
+                let tan_fov = (fov * 0.5).tan();
+                (x / (z * tan_fov), y / (z * tan_fov), distance)
+            }
+            Projection::Orthographic { height } => {
+                let half_height = height * 0.5 / self.cell_aspect;
+                (x / half_height, y / half_height, z)
+            }
+            Projection::Fisheye { fov } => {
+                if distance == 0.0 {
+                    return None;
+                }
+                let theta = (z / distance).clamp(-1.0, 1.0).acos();
+                let radius = theta / (fov * 0.5) * self.cell_aspect;
+                let phi = y.atan2(x);
+                (radius * phi.cos(), radius * phi.sin(), distance)
+            }
+            Projection::Equirectangular => {
+                if distance == 0.0 {
+                    return None;
+                }
+                let longitude = x.atan2(z);
+                let latitude = (y / distance).clamp(-1.0, 1.0).asin();
+                return Some((Vec2::new(longitude / PI, latitude / (PI * 0.5)), distance));
+            }
+        };
+        Some((Vec2::new(sx / self.aspect_ratio, sy / self.cell_aspect), depth))
+    }
+
+    // Footprint of a pixel of a viewport `width` pixels wide, measured across the
+    // narrow side of the cell; expects `aspect_ratio` to be set for that viewport
+    pub fn pixel_footprint(&self, width: usize) -> PixelFootprint {
+        let width = width.max(1) as f32;
+        // A pixel spans 2 / width in ndc_x, which ray() scales by the viewport aspect
+        let screen_width = 2.0 * self.aspect_ratio / width;
+        match self.projection {
+            Projection::Perspective { fov } => PixelFootprint { base: 0.0, spread: screen_width * (fov * 0.5).tan() },
+            Projection::Orthographic { height: view_height } => {
+                PixelFootprint { base: screen_width * view_height * 0.5 / self.cell_aspect, spread: 0.0 }
+            }
+            Projection::Fisheye { fov } => PixelFootprint { base: 0.0, spread: screen_width * fov * 0.5 / self.cell_aspect },
+            Projection::Equirectangular => PixelFootprint { base: 0.0, spread: 2.0 * PI / width },
+        }
+    }
+}
+
+// Width of one pixel in world units as it travels along a ray, growing linearly
+// with distance for the projections that fan out from the eye
+#[derive(Clone, Copy, Debug)]
+pub struct PixelFootprint {
+    // Width at the ray origin
+    pub base: f32,
+    // Extra width per unit of distance
+    pub spread: f32,
+}
+
+impl PixelFootprint {
+    pub fn width_at(&self, t: f32) -> f32 {
+        self.base + self.spread * t
+    }
+}
+
+// Thin lens: rays start anywhere on a disc of `aperture` diameter and pass through
+// the same point on the focal plane, so only that plane stays sharp
+#[derive(Clone, Copy, Debug)]
+pub struct DepthOfField {
+    // Distance from the eye to the sharp plane, along the view direction; the
+    // radius of a sharp sphere for fisheye and equirectangular
+    pub focal_distance: f32,
+    // Lens diameter in world units; 0 is a pinhole
+    pub aperture: f32,
+    // Rays averaged per pixel when the aperture is open
+    pub samples: u32,
+}
+
+impl Default for DepthOfField {
+    fn default() -> Self {
+        DepthOfField {
+            focal_distance: 2.15,
+            aperture: 0.0,
+            samples: 16,
+        }
+    }
+}
+
+impl DepthOfField {
+    // Ray `sample` of a pixel's lens samples, from the pinhole ray (`origin`,
+    // `direction`). `pixel_key` seeds the lens position so it is the same every frame.
+    pub fn lens_ray(&self, camera: &Camera, origin: Vec3, direction: Vec3, sample: u32, pixel_key: u32) -> (Vec3, Vec3) {
+        if self.aperture <= 0.0 {
+            return (origin, direction);
+        }
+        let (right, up, forward) = camera.basis();
+        // The wide projections reach 90 degrees and beyond, where no point of a focal
+        // plane lies ahead, so they focus on a sphere around the eye instead
+        let along_ray = match camera.projection {
+            Projection::Perspective { .. } | Projection::Orthographic { .. } => self.focal_distance / direction.dot(&forward).max(1e-3),
+            Projection::Fisheye { .. } | Projection::Equirectangular => self.focal_distance,
+        };
+        let focus = origin + direction * along_ray;
+        let seed = hash_u32(pixel_key) ^ sample.wrapping_mul(0x9e3779b9);
+        let (x, y) = concentric_disc(hash_f32(seed), hash_f32(seed ^ 0x68e31da4));
+        let lens_origin = origin + (right * x + up * y) * (self.aperture * 0.5);
+        (lens_origin, (focus - lens_origin).normalize())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StereoMode {
+    Off,
+    // Left eye in the red channel, right eye in green and blue
+    Anaglyph,
+    // Left eye on the left half of the screen, right eye on the right half
+    SideBySide,
+}
+
+impl StereoMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(StereoMode::Off),
+            "anaglyph" => Some(StereoMode::Anaglyph),
+            "sbs" | "side-by-side" => Some(StereoMode::SideBySide),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stereo {
+    pub mode: StereoMode,
+    // Distance between the two eyes in world units
+    pub eye_separation: f32,
+    // Distance along the view direction where the two eyes converge
+    pub convergence: f32,
+}
+
+impl Default for Stereo {
+    fn default() -> Self {
+        Stereo {
+            mode: StereoMode::Off,
+            eye_separation: 0.06,
+            convergence: 2.15,
+        }
+    }
+}
+
+impl Stereo {
+    // Left and right eye cameras, offset along the camera's right vector and
+    // toed in so both look at the convergence point
+    pub fn eye_cameras(&self, camera: &Camera) -> (Camera, Camera) {
+        let (right, _, forward) = camera.basis();
+        let focus = camera.eye + forward * self.convergence;
+        let offset = right * (self.eye_separation * 0.5);
+
+        let mut left = *camera;
+        left.eye = camera.eye - offset;
+        left.target = focus;
+
+        let mut right_eye = *camera;
+        right_eye.eye = camera.eye + offset;
+        right_eye.target = focus;
+
+        (left, right_eye)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xy(v: Vec2) -> (f32, f32) {
+        (v.x, v.y)
+    }
+
+    #[test]
+    fn center_and_corners_map_to_ndc_extremes() {
+        for (width, height) in [(80, 24), (7, 3), (1, 1)] {
+            let (w, h) = (width as f32, height as f32);
+            assert_eq!(xy(pixel_to_ndc(w * 0.5, h * 0.5, width, height)), (0.0, 0.0));
+            assert_eq!(xy(pixel_to_ndc(0.0, 0.0, width, height)), (-1.0, 1.0));
+            assert_eq!(xy(pixel_to_ndc(w, 0.0, width, height)), (1.0, 1.0));
+            assert_eq!(xy(pixel_to_ndc(0.0, h, width, height)), (-1.0, -1.0));
+            assert_eq!(xy(pixel_to_ndc(w, h, width, height)), (1.0, -1.0));
+            let back = ndc_to_pixel(pixel_to_ndc(1.5, 0.5, width, height), width, height);
+            assert!((back.x - 1.5).abs() < 1e-5 && (back.y - 0.5).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn rays_through_the_corners_spread_from_the_view_direction() {
+        let mut camera = Camera::new(Vec3::new(0.0, 1.0, -3.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Projection::from_name("perspective").unwrap());
+        camera.aspect_ratio = 2.0;
+        let (right, up, forward) = camera.basis();
+        let (origin, center) = camera.ray(Vec2::new(0.0, 0.0));
+        assert_eq!((origin.x, origin.y, origin.z), (0.0, 1.0, -3.0));
+        assert!(center.dot(&forward) > 0.9999);
+
+        for (x, y) in [(-1.0, 1.0), (1.0, 1.0), (-1.0, -1.0), (1.0, -1.0)] {
+            let (_, direction) = camera.ray(Vec2::new(x, y));
+            assert!(direction.dot(&right) * x > 0.0 && direction.dot(&up) * y > 0.0, "corner {} {}", x, y);
+            assert!((direction.length() - 1.0).abs() < 1e-5);
+            // Mirrored corners lean out by the same amount
+            let (_, mirrored) = camera.ray(Vec2::new(-x, -y));
+            assert!((direction.dot(&forward) - mirrored.dot(&forward)).abs() < 1e-5);
+        }
+    }
+
+    // How far `point` is from the ray (`origin`, unit `direction`)
+    fn miss_distance(origin: Vec3, direction: Vec3, point: Vec3) -> f32 {
+        let to_point = point - origin;
+        (to_point - direction * to_point.dot(&direction)).length()
+    }
+
+    #[test]
+    fn a_closed_lens_is_the_pinhole() {
+        for name in ["perspective", "ortho", "fisheye", "equirect"] {
+            let mut camera = Camera::new(Vec3::new(0.3, 1.0, -3.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Projection::from_name(name).unwrap());
+            camera.lens = DepthOfField { focal_distance: 2.0, aperture: 0.0, samples: 8 };
+            for (x, y) in [(0.0, 0.0), (-0.9, 0.7), (0.99, -1.0), (0.37, 0.2)] {
+                let (origin, direction) = camera.ray(Vec2::new(x, y));
+                for sample in 0..4 {
+                    let (lens_origin, lens_direction) = camera.lens.lens_ray(&camera, origin, direction, sample, 1234);
+                    let bits = |v: Vec3| [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()];
+                    assert_eq!((bits(lens_origin), bits(lens_direction)), (bits(origin), bits(direction)), "{} at {} {}", name, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lens_rays_meet_at_the_focal_distance() {
+        let lens = DepthOfField { focal_distance: 2.0, aperture: 0.4, samples: 8 };
+        for name in ["perspective", "fisheye", "equirect"] {
+            let mut camera = Camera::new(Vec3::new(0.0, 1.0, -3.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Projection::from_name(name).unwrap());
+            camera.lens = lens;
+            let (_, _, forward) = camera.basis();
+            // Straight ahead, off to the side, and for the wide projections past 90
+            // degrees and nearly behind
+            for (x, y) in [(0.0, 0.0), (0.5, 0.3), (0.8, 0.0), (0.95, -0.2)] {
+                let (origin, direction) = camera.ray(Vec2::new(x, y));
+                let focus = match camera.projection {
+                    Projection::Perspective { .. } => origin + direction * (lens.focal_distance / direction.dot(&forward)),
+                    _ => origin + direction * lens.focal_distance,
+                };
+                let mut origins = Vec::new();
+                for sample in 0..8 {
+                    let (lens_origin, lens_direction) = lens.lens_ray(&camera, origin, direction, sample, 99);
+                    assert!(miss_distance(lens_origin, lens_direction, focus) < 1e-4, "{} at {} {}", name, x, y);
+                    assert!((lens_origin - origin).length() <= lens.aperture * 0.5 + 1e-6);
+                    origins.push(lens_origin);
+                }
+                assert!(origins.windows(2).any(|pair| (pair[0] - pair[1]).length() > 1e-3));
+            }
+        }
+        // The wide projections do reach behind the eye
+        let camera = Camera::new(Vec3::new(0.0, 1.0, -3.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Projection::Equirectangular);
+        assert!(camera.ray(Vec2::new(0.95, -0.2)).1.dot(&camera.basis().2) < 0.0);
+    }
+}
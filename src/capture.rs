@@ -0,0 +1,307 @@
+use crate::framebuffer::Framebuffer;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Delay given to the last frame of a capture, which has no successor to time it by
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(16);
+
+// Destination for an animation captured from the color buffer, one file per capture.
+// Frames stream in as they are produced; the sink decides how to encode them.
+pub trait FrameSink {
+    // Append a frame that stays on screen for `delay`
+    fn write_frame(&mut self, fb: &Framebuffer, delay: Duration) -> io::Result<()>;
+
+    // Complete the file after the last frame
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptureFormat {
+    Apng,
+}
+
+impl CaptureFormat {
+    fn create_sink(self, path: &Path, width: usize, height: usize) -> io::Result<Box<dyn FrameSink>> {
+        match self {
+            CaptureFormat::Apng => Ok(Box::new(ApngWriter::create(path, width, height)?)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureSettings {
+    pub format: CaptureFormat,
+    pub path: PathBuf,
+    // Stop by itself after this many frames
+    pub max_frames: Option<u32>,
+}
+
+// Starts and stops captures, times the frames and keeps every frame of a capture the
+// same size, so the sinks only ever see a clean sequence
+pub struct Capture {
+    settings: CaptureSettings,
+    // Captures started so far, later ones are numbered to not overwrite the first
+    count: u32,
+    active: Option<ActiveCapture>,
+}
+
+struct ActiveCapture {
+    path: PathBuf,
+    // Created from the size of the first frame
+    sink: Option<Box<dyn FrameSink>>,
+    size: (usize, usize),
+    // Held back one frame, since a frame's delay is only known once the next arrives
+    pending: Option<(Framebuffer, f32)>,
+    last_delay: Duration,
+    frames: u32,
+}
+
+impl Capture {
+    pub fn new(settings: CaptureSettings) -> Self {
+        Capture { settings, count: 0, active: None }
+    }
+
+    // Start a capture, or stop the running one. Returns a status line.
+    pub fn toggle(&mut self) -> String {
+        if self.active.is_some() {
+            return self.stop("stopped");
+        }
+        self.count += 1;
+        let path = numbered_path(&self.settings.path, self.count);
+        let text = format!("Capturing to {}", path.display());
+        self.active = Some(ActiveCapture {
+            path,
+            sink: None,
+            size: (0, 0),
+            pending: None,
+            last_delay: DEFAULT_FRAME_DELAY,
+            frames: 0,
+        });
+        text
+    }
+
+    // Add a frame shown at `time` seconds. Returns a status line when this ends the
+    // capture, because the frame limit was reached, the frame size changed or a
+    // write failed.
+    pub fn add_frame(&mut self, fb: &Framebuffer, time: f32) -> Option<String> {
+        let active = self.active.as_mut()?;
+        if active.sink.is_none() {
+            active.size = (fb.width, fb.height);
+            match self.settings.format.create_sink(&active.path, fb.width, fb.height) {
+                Ok(sink) => active.sink = Some(sink),
+                Err(e) => {
+                    self.active = None;
+                    return Some(format!("Capture failed: {}", e));
+                }
+            }
+        }
+        if active.size != (fb.width, fb.height) {
+            return Some(self.stop("aborted by resize"));
+        }
+
+        if let Some((previous, previous_time)) = active.pending.take() {
+            active.last_delay = Duration::from_secs_f32((time - previous_time).max(0.0));
+            if let Err(e) = active.sink.as_mut()?.write_frame(&previous, active.last_delay) {
+                self.active = None;
+                return Some(format!("Capture failed: {}", e));
+            }
+        }
+        active.pending = Some((fb.clone(), time));
+        active.frames += 1;
+
+        if self.settings.max_frames.is_some_and(|max| active.frames >= max) {
+            return Some(self.stop("finished"));
+        }
+        None
+    }
+
+    // Stop a running capture, e.g. on exit
+    pub fn finish(&mut self) -> Option<String> {
+        self.active.is_some().then(|| self.stop("stopped"))
+    }
+
+    fn stop(&mut self, reason: &str) -> String {
+        let Some(mut active) = self.active.take() else {
+            return String::new();
+        };
+        let Some(mut sink) = active.sink.take() else {
+            return format!("Capture {} before the first frame", reason);
+        };
+        let result = match active.pending.take() {
+            Some((last, _)) => sink.write_frame(&last, active.last_delay),
+            None => Ok(()),
+        };
+        match result.and_then(|_| sink.finish()) {
+            Ok(()) => format!("Capture {}, {} frames written to {}", reason, active.frames, active.path.display()),
+            Err(e) => format!("Capture failed: {}", e),
+        }
+    }
+}
+
+// `path` for the first capture, `name-N.ext` for capture N after that
+fn numbered_path(path: &Path, count: u32) -> PathBuf {
+    if count <= 1 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, count, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, count),
+    };
+    path.with_file_name(name)
+}
+
+// Animated PNG with full 24-bit color. The frame count in the acTL chunk isn't known
+// until the capture stops, so it is patched in by finish().
+pub struct ApngWriter {
+    file: BufWriter<File>,
+    width: usize,
+    height: usize,
+    // File offset of the acTL chunk
+    actl_offset: u64,
+    frames: u32,
+    // fcTL and fdAT chunks share one sequence counter
+    sequence: u32,
+}
+
+impl ApngWriter {
+    pub fn create(path: &Path, width: usize, height: usize) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8-bit RGB, deflate, adaptive filtering, no interlace
+        write_chunk(&mut file, b"IHDR", &ihdr)?;
+
+        let actl_offset = file.stream_position()?;
+        write_chunk(&mut file, b"acTL", &actl(0))?;
+        Ok(ApngWriter { file, width, height, actl_offset, frames: 0, sequence: 0 })
+    }
+}
+
+impl FrameSink for ApngWriter {
+    fn write_frame(&mut self, fb: &Framebuffer, delay: Duration) -> io::Result<()> {
+        // Millisecond delays, capped to what the 16-bit numerator holds
+        let delay_ms = (delay.as_secs_f64() * 1000.0).round().min(u16::MAX as f64) as u16;
+        let mut fctl = Vec::with_capacity(26);
+        fctl.extend_from_slice(&self.sequence.to_be_bytes());
+        fctl.extend_from_slice(&(self.width as u32).to_be_bytes());
+        fctl.extend_from_slice(&(self.height as u32).to_be_bytes());
+        fctl.extend_from_slice(&[0; 8]); // x and y offset
+        fctl.extend_from_slice(&delay_ms.to_be_bytes());
+        fctl.extend_from_slice(&1000u16.to_be_bytes());
+        fctl.extend_from_slice(&[0, 0]); // Dispose none, blend source
+        write_chunk(&mut self.file, b"fcTL", &fctl)?;
+        self.sequence += 1;
+
+        let data = compress_rows(fb)?;
+        if self.frames == 0 {
+            // The first frame doubles as the still image for viewers without APNG support
+            write_chunk(&mut self.file, b"IDAT", &data)?;
+        } else {
+            let mut fdat = Vec::with_capacity(data.len() + 4);
+            fdat.extend_from_slice(&self.sequence.to_be_bytes());
+            fdat.extend_from_slice(&data);
+            write_chunk(&mut self.file, b"fdAT", &fdat)?;
+            self.sequence += 1;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        write_chunk(&mut self.file, b"IEND", &[])?;
+        self.file.seek(SeekFrom::Start(self.actl_offset))?;
+        write_chunk(&mut self.file, b"acTL", &actl(self.frames))?;
+        self.file.flush()
+    }
+}
+
+// Frame count, then the number of plays with 0 looping forever
+fn actl(frames: u32) -> [u8; 8] {
+    let mut data = [0; 8];
+    data[..4].copy_from_slice(&frames.to_be_bytes());
+    data
+}
+
+// Zlib stream of the color buffer as RGB rows, each with the Sub filter, which
+// suits the smooth gradients of the scene
+fn compress_rows(fb: &Framebuffer) -> io::Result<Vec<u8>> {
+    let mut raw = Vec::with_capacity(fb.height * (fb.width * 3 + 1));
+    for row in fb.data.chunks(fb.width.max(1)).take(fb.height) {
+        raw.push(1);
+        let mut left = [0u8; 3];
+        for pixel in row {
+            let rgb = [pixel.r, pixel.g, pixel.b];
+            raw.extend((0..3).map(|c| rgb[c].wrapping_sub(left[c])));
+            left = rgb;
+        }
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(&raw)?;
+    encoder.finish()
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc.finalize().to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pixel::Pixel;
+
+    // A frame with a different color at every pixel, varying with `frame`
+    fn frame(width: usize, height: usize, frame: u8) -> Framebuffer {
+        let mut fb = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let (r, g, b) = ((x * 40) as u8, (y * 60) as u8, frame.wrapping_mul(90).wrapping_add((x * y) as u8));
+                fb.set_pixel(x, y, Pixel { r, g, b, a: 255 });
+            }
+        }
+        fb
+    }
+
+    fn rgb(fb: &Framebuffer) -> Vec<u8> {
+        fb.data.iter().flat_map(|pixel| [pixel.r, pixel.g, pixel.b]).collect()
+    }
+
+    #[test]
+    fn apng_frames_decode_to_the_captured_framebuffers() {
+        let path = std::env::temp_dir().join(format!("ascii_sobel-capture-{}.png", std::process::id()));
+        let frames: Vec<Framebuffer> = (0..3).map(|i| frame(5, 3, i)).collect();
+        let mut writer = ApngWriter::create(&path, 5, 3).unwrap();
+        for (fb, delay) in frames.iter().zip([20, 40, 16]) {
+            writer.write_frame(fb, Duration::from_millis(delay)).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().animation_control.map(|actl| actl.num_frames), Some(3));
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        for (fb, delay) in frames.iter().zip([20, 40]) {
+            let info = reader.next_frame(&mut buffer).unwrap();
+            assert_eq!((info.width, info.height, info.color_type), (5, 3, png::ColorType::Rgb));
+            assert_eq!(buffer[..info.buffer_size()], rgb(fb)[..]);
+            let control = reader.info().frame_control.unwrap();
+            assert_eq!((control.delay_num, control.delay_den), (delay, 1000));
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::preset::{Palette, Preset};
+use crate::raymarch::{explain_scene, Scene};
+use crate::shadertoy::ShaderScene;
+
+// What --list-scenes, --list-presets, --list-palettes and --explain print. Lines
+// are tab separated with the name first, so completion scripts can `cut -f1`, and
+// their layout stays the same from version to version.
+
+// Rough price of a scene per pixel, from the steps its rays take
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cost {
+    // No marching, one function evaluation per pixel
+    Low,
+    Medium,
+    // Long marches, as across a heightfield to the horizon
+    High,
+}
+
+impl Cost {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cost::Low => "low",
+            Cost::Medium => "medium",
+            Cost::High => "high",
+        }
+    }
+}
+
+// A scene's entry in the registry
+#[derive(Clone, Copy, Debug)]
+pub struct SceneInfo {
+    // Its --scene name
+    pub name: &'static str,
+    pub description: &'static str,
+    pub cost: Cost,
+}
+
+// A setting a scene or preset comes with, as it is at startup
+#[derive(Clone, Debug)]
+pub struct Parameter {
+    pub name: &'static str,
+    pub value: String,
+    // Flags and keys that change it, empty when nothing does
+    pub overridden_by: &'static str,
+}
+
+impl Parameter {
+    pub fn new(name: &'static str, value: impl ToString, overridden_by: &'static str) -> Self {
+        Parameter { name, value: value.to_string(), overridden_by }
+    }
+}
+
+// Every built-in scene, raymarched ones first
+pub fn scenes() -> Vec<SceneInfo> {
+    Scene::INFO.iter().chain(ShaderScene::INFO.iter()).copied().collect()
+}
+
+pub fn scene_names() -> Vec<&'static str> {
+    scenes().iter().map(|scene| scene.name).collect()
+}
+
+// One scene per line: name, cost and description
+pub fn list_scenes() -> String {
+    scenes().iter().map(|scene| format!("{}\t{}\t{}\n", scene.name, scene.cost.name(), scene.description)).collect()
+}
+
+// One preset per line: name and description
+pub fn list_presets() -> String {
+    Preset::all().iter().map(|preset| format!("{}\t{}\n", preset.name, preset.description)).collect()
+}
+
+// One palette per line: name, color count and description
+pub fn list_palettes() -> String {
+    Palette::all().iter().map(|palette| format!("{}\t{}\t{}\n", palette.name, palette.colors.len(), palette.description)).collect()
+}
+
+// A scene or preset by name: what it is on the first line, then one line per
+// setting with its value at startup and what overrides it. None for an unknown
+// name. Scene names are looked up first; no preset shares one. `seed` is the
+// --seed given, if any.
+pub fn explain(name: &str, seed: Option<u32>) -> Option<String> {
+    if let Some(scene) = scenes().into_iter().find(|scene| scene.name == name) {
+        let mut parameters = Scene::from_name(name, 0).map_or_else(Vec::new, explain_scene);
+        let seed = seed.map_or_else(|| "random".to_string(), |seed| seed.to_string());
+        parameters.push(Parameter::new("seed", seed, "--seed, key n"));
+        let header = format!("scene\t{}\t{}\t{}\n", scene.name, scene.cost.name(), scene.description);
+        return Some(header + &format_parameters(&parameters));
+    }
+    let preset = Preset::from_name(name)?;
+    let header = format!("preset\t{}\t{}\n", preset.name, preset.description);
+    Some(header + &format_parameters(&preset.parameters()))
+}
+
+fn format_parameters(parameters: &[Parameter]) -> String {
+    parameters.iter().map(|parameter| format!("{}\t{}\t{}\n", parameter.name, parameter.value, parameter.overridden_by)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postprocess::PostProcessConfig;
+    use std::collections::HashMap;
+
+    // Name to value for the setting lines of an --explain
+    fn settings(explained: &str) -> HashMap<String, String> {
+        explained.lines().skip(1).map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            assert_eq!(fields.len(), 3, "{:?}", line);
+            (fields[0].to_string(), fields[1].to_string())
+        }).collect()
+    }
+
+    #[test]
+    fn listings_cover_everything_built_in() {
+        let scenes = list_scenes();
+        let listed: Vec<&str> = scenes.lines().map(|line| line.split('\t').next().unwrap()).collect();
+        for name in ["cubes", "terrain", "repetition", "plasma"] {
+            assert!(listed.contains(&name), "{} missing from {:?}", name, listed);
+        }
+        // And every name listed is one --scene takes
+        for line in scenes.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            assert_eq!(fields.len(), 3, "{:?}", line);
+            assert!(Scene::from_name(fields[0], 0).is_some() || ShaderScene::from_name(fields[0]).is_some(), "{}", fields[0]);
+            assert!(["low", "medium", "high"].contains(&fields[1]));
+        }
+        assert_eq!(listed, scene_names());
+
+        let presets = list_presets();
+        let presets: Vec<&str> = presets.lines().map(|line| line.split('\t').next().unwrap()).collect();
+        assert_eq!(presets, Preset::names());
+        for (line, palette) in list_palettes().lines().zip(Palette::all()) {
+            assert_eq!(line, format!("{}\t{}\t{}", palette.name, palette.colors.len(), palette.description));
+        }
+        assert_eq!(list_palettes().lines().count(), Palette::all().len());
+    }
+
+    #[test]
+    fn scenes_explain_the_defaults_they_start_with() {
+        let explained = explain("cubes", Some(7)).unwrap();
+        assert!(explained.starts_with("scene\tcubes\tmedium\t"));
+        let values = settings(&explained);
+        let shadows = crate::raymarch::ShadowSettings::default();
+        let epsilon = crate::raymarch::HitEpsilon::default();
+        assert_eq!(values["light-radius"], shadows.light_radius.to_string());
+        assert_eq!(values["shadows"], shadows.quality.name());
+        assert_eq!(values["shadow-steps"], shadows.max_steps.to_string());
+        assert_eq!(values["hit-epsilon"], epsilon.base.to_string());
+        assert_eq!(values["epsilon-growth"], epsilon.growth.to_string());
+        assert_eq!(values["floor-blend"], "0.25");
+        assert_eq!(values["layout"], "classic");
+        assert_eq!(values["envmap"], "none");
+        assert_eq!(values["seed"], "7");
+        assert_eq!(settings(&explain("terrain", None).unwrap())["seed"], "random");
+        // Only the cubes have a layout to explain
+        assert!(!settings(&explain("terrain", None).unwrap()).contains_key("layout"));
+        assert!(explain("plasma", None).unwrap().starts_with("scene\tplasma\tlow\t"));
+        assert_eq!(explain("no-such-scene", None), None);
+    }
+
+    #[test]
+    fn presets_explain_what_applying_them_sets() {
+        for preset in Preset::all() {
+            let explained = explain(preset.name, None).unwrap();
+            assert_eq!(explained.lines().next().unwrap(), format!("preset\t{}\t{}", preset.name, preset.description));
+            let values = settings(&explained);
+            let mut config = PostProcessConfig::default();
+            preset.apply(&mut config);
+            assert_eq!(values["posterize-levels"], config.posterize_levels.unwrap().to_string(), "{}", preset.name);
+            assert_eq!(values["contrast"], config.contrast.to_string(), "{}", preset.name);
+            assert_eq!(values["scanlines"], config.scanlines.to_string(), "{}", preset.name);
+            assert_eq!(values["vignette"], config.vignette.to_string(), "{}", preset.name);
+            assert_eq!(values["palette"], preset.palette.map_or("terminal", |palette| palette.name));
+            assert_eq!(values["ramp"], format!("\"{}\"", preset.ramp));
+        }
+    }
+}
@@ -0,0 +1,1583 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use ncurses::*;
+use rayon::prelude::*;
+
+use crate::capture::{Capture, CaptureSettings};
+use crate::camera::{ndc_to_pixel, pixel_to_ndc, Camera, DepthOfField, Projection, Stereo, StereoMode};
+use crate::catalog::scene_names;
+use crate::debugwindow::DebugWindow;
+use crate::dither::DitherMatrix;
+use crate::dump::FrameDump;
+use crate::export::FrameExport;
+use crate::error::RenderError;
+use crate::ascii::{GlyphRamp, RenderMode};
+use crate::framebuffer::{ColorPalette, Framebuffer, Reprojection, MAX_DITHER_STRENGTH};
+use crate::geometry::{OutputGeometry, PixelFormat};
+use crate::font::GLYPH_HEIGHT;
+use crate::imageview::fit_image;
+use crate::math::{entropy_seed, Vec2, Vec3};
+use crate::pacing::PresentPacer;
+use crate::panorama::PanoramaExport;
+use crate::pick::{describe, overlay, PickCursor};
+use crate::picker::{PickerAction, ScenePicker, THUMBNAIL_HEIGHT, THUMBNAIL_TIME, THUMBNAIL_WIDTH};
+use crate::pixel::Pixel;
+use crate::plot::{draw_plot, draw_sparkline, PlotStyle};
+use crate::postprocess::{apply_screen_effects, AutoExposure, ColorPipeline, GlitchEffect, GlowTrails, PostProcessConfig, PosterizeOrder, SharpenTarget, TemporalSmoothing};
+use crate::raymarch::{average_samples, prepare_frame, prepare_scene_frame, ray_march, ray_march_blurred, ray_march_hit, set_feedback_texture, set_scene, set_seed, update_globals, Hit, MarchResult, MotionBlur, ObjectId, RayStats, Scene, SceneFrame};
+use crate::shader::{Shader, ShaderSettings};
+use crate::shadertoy::ShaderScene;
+use crate::sobel::{write_gradient_angle_pgm, write_gradient_magnitude_pgm, GradientBuffer};
+use crate::preset::Preset;
+use crate::timings::{FrameTiming, Stage, StageTimer};
+use crate::timestep::{FixedTimestep, SceneClock, UPDATE_RATE};
+use crate::tonecurve::{CustomCurve, ToneCurve};
+use crate::terminal::{active_palette, active_theme, capture_cells, draw_colored_frame, effective_colors, frame_background, lock_terminal, set_preset_palette};
+use crate::theme::Theme;
+use crate::timeline::{EventAction, Timeline};
+use crate::terminalbuffer::TerminalBuffer;
+use crate::transition::{Transition, TransitionSettings};
+use crate::watchdog::{Interruption, Watchdog};
+
+const CHUNK_SIZE: usize = 8; 
+const EXPOSURE_STEP: f32 = 0.25; // Stops per key press
+const EYE_SEPARATION_STEP: f32 = 0.01;
+const DITHER_STRENGTH_STEP: f32 = 0.05;
+// Brightness factor of the frame a timeline flash lands on
+const FLASH_BRIGHTNESS: f32 = 2.0;
+const TIME_SCRUB_STEP: f32 = 0.25; // Seconds per key press
+// Frame around the selected scene picker tile
+const PICKER_HIGHLIGHT: Pixel = Pixel { r: 255, g: 200, b: 0, a: 255 };
+// Frames of render time kept for the HUD sparkline
+const HUD_HISTORY: usize = 32;
+const HUD_HEIGHT: usize = 6;
+// Height of the tone curve graph under the HUD figures
+const TONE_PLOT_HEIGHT: usize = 12;
+// How long a notice stays on screen
+const NOTICE_SECONDS: f32 = 3.0;
+// Largest copy of the previous frame kept for the scene to sample
+const FEEDBACK_MAX_WIDTH: usize = 256;
+const FEEDBACK_MAX_HEIGHT: usize = 128;
+// Coarsest resolution a long frame can push the renderer down to, in pixels per
+// raymarched sample along each axis
+const MAX_PIXEL_STEP: usize = 4;
+// Distance standing in for infinity when reprojecting the sky
+const SKY_DISTANCE: f32 = 1.0e4;
+
+// Options picked on the command line. The ones with a key binding change at runtime.
+pub struct RenderSettings {
+    pub debug_mode: bool,
+    pub title: Option<String>,
+    // Shown in place of the raymarched scene
+    pub image: Option<Framebuffer>,
+    // What the framebuffer clears to: the bars beside a letterboxed image and the
+    // space around the picker's tiles
+    pub clear_color: Pixel,
+    pub shader_scene: Option<ShaderScene>,
+    // Drives every scene's per-run variation; reseeded with 'n'
+    pub seed: u32,
+    // Open the scene picker on the first frame
+    pub picker: bool,
+    pub feedback: bool,
+    // Which glyphs the cells are drawn with
+    pub mode: RenderMode,
+    // Cells added on each side of an edge line
+    pub edge_width: usize,
+    // Width of a panorama exported with 'P'
+    pub panorama_width: usize,
+    pub fill: bool,
+    pub stereo: Stereo,
+    pub projection: Projection,
+    pub shader: ShaderSettings,
+    pub transition: TransitionSettings,
+    pub motion: MotionBlur,
+    // Post-process blur along the motion vectors, see apply_motion_blur
+    pub vector_blur_samples: u32,
+    pub dof: DepthOfField,
+    // None picks the default for the pixel format
+    pub sharpen_target: Option<SharpenTarget>,
+    pub sharpen_overshoot: u8,
+    pub color_blur: usize,
+    pub posterize_order: PosterizeOrder,
+    pub color_pipeline: ColorPipeline,
+    pub tone_curve: ToneCurve,
+    pub display_gamma: f32,
+    pub dither_strength: f32,
+    pub dither_matrix: DitherMatrix,
+    // White balance, -1 cool to 1 warm
+    pub temperature: f32,
+    pub auto_exposure: bool,
+    pub ramp_dither: bool,
+    pub ramp_dither_matrix: DitherMatrix,
+    // Starts with glow trails on at this decay
+    pub trail_decay: Option<f32>,
+    pub dump_frame: Option<u32>,
+    // Where frame dumps go, None for the default directory
+    pub dump_dir: Option<PathBuf>,
+    // Saved as text and HTML when this frame is drawn
+    pub export_frame: Option<u32>,
+    // Where F9 captures the color buffer to
+    pub capture: Option<CaptureSettings>,
+    pub preset: Preset,
+    // Frames running longer are cut short and the resolution drops; None never cuts
+    pub frame_budget: Option<Duration>,
+    // Longest a terminal write may take before frames start being dropped from
+    // the output, None to write every frame
+    pub present_budget: Option<Duration>,
+    // Events fired as the scene clock passes them
+    pub timeline: Timeline,
+}
+
+// Everything the render loop keeps from one frame to the next
+pub struct RenderContext {
+    settings: RenderSettings,
+    geometry: OutputGeometry,
+    framebuffer: Arc<Mutex<Framebuffer>>,
+    // The last raymarched frame before post-processing, which chunks of a frame cut
+    // short keep showing
+    last_render: Framebuffer,
+    // Render target for the look being switched away from during a transition
+    outgoing_framebuffer: Arc<Mutex<Framebuffer>>,
+    outgoing: Option<Outgoing>,
+    window: Option<DebugWindow>,
+    terminal_buffer: TerminalBuffer,
+    scratch: RenderScratch,
+    post_config: PostProcessConfig,
+    glitch: GlitchEffect,
+    trails: GlowTrails,
+    temporal: TemporalSmoothing,
+    auto_exposure: AutoExposure,
+    // How the last raymarched frame was drawn, for reprojecting into it
+    previous_view: Option<FrameView>,
+    paused: bool,
+    // Set when a frame has to be drawn even though nothing animates, e.g. after a
+    // key press or scrubbing while paused
+    redraw: bool,
+    exposure: f32, // Exposure in stops applied before tone mapping
+    // Scene time, stepped at a fixed rate and drawn between its last two steps
+    clock: SceneClock,
+    timestep: FixedTimestep,
+    frame_index: u32,
+    // Whether the framebuffer holds a finished frame at the current size
+    frame_complete: bool,
+    show_hud: bool,
+    // Second HUD page: the marcher's work instead of the render times
+    hud_ray_stats: bool,
+    frame_times: Vec<f32>, // Milliseconds
+    // Stage breakdown of the last draw, None before the first
+    last_timing: Option<FrameTiming>,
+    // Counted for the last raymarched frame while the HUD page is up or a benchmark
+    // wants them, None otherwise
+    ray_stats: Option<RayStats>,
+    always_count_rays: bool,
+    notice: Option<(String, Instant)>,
+    capture: Option<Capture>,
+    // Colors the frame is quantized to and the glyph ramp, both from the preset
+    palette: ColorPalette,
+    ramp: GlyphRamp,
+    // Switched to at the start of the next frame, so a frame never mixes two presets
+    pending_preset: Option<Preset>,
+    picker: ScenePicker,
+    // Cell whose ray is inspected, toggled with 'O'; None while not picking
+    pick: Option<PickCursor>,
+    // Which frames are written to the terminal
+    pacer: PresentPacer,
+    // Panorama rendering in the background, and the percentage last shown of it
+    panorama: Option<PanoramaExport>,
+    panorama_shown: u32,
+    // The curve loaded with --tone-curve, kept for cycling back to it
+    custom_tone_curve: Option<Arc<CustomCurve>>,
+    watchdog: Option<Watchdog>,
+    timeline: Timeline,
+    // Set by a timeline flash, drawn brighter for the next presented frame
+    flash: bool,
+}
+
+impl RenderContext {
+    // Opens the debug window if asked for, carrying on without it if it can't be opened
+    pub fn new(mut settings: RenderSettings, pixel_format: PixelFormat, geometry: OutputGeometry, messages: &mut Vec<String>) -> Self {
+        let window = if settings.debug_mode {
+            let (width, height) = geometry.framebuffer_size();
+            match DebugWindow::new(width, height) {
+                Ok(window) => Some(window),
+                Err(e) => {
+                    messages.push(format!("Warning: {}, continuing in terminal-only mode", e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let post_config = PostProcessConfig {
+            sharpen_target: settings.sharpen_target.unwrap_or(SharpenTarget::default_for(pixel_format)),
+            color_sharpen_overshoot: settings.sharpen_overshoot,
+            color_blur: settings.color_blur,
+            trails: settings.trail_decay.is_some(),
+            trail_decay: settings.trail_decay.unwrap_or(PostProcessConfig::default().trail_decay),
+            posterize_order: settings.posterize_order,
+            color_pipeline: settings.color_pipeline,
+            tone_curve: settings.tone_curve.clone(),
+            display_gamma: settings.display_gamma,
+            dither_strength: settings.dither_strength,
+            temperature: settings.temperature,
+            auto_exposure: settings.auto_exposure,
+            dither_matrix: settings.dither_matrix,
+            ramp_dither: settings.ramp_dither,
+            ramp_dither_matrix: settings.ramp_dither_matrix,
+            vector_blur_samples: settings.vector_blur_samples,
+            ..PostProcessConfig::default()
+        };
+
+        let scratch = RenderScratch::default();
+        let watchdog = settings.frame_budget.map(|budget| Watchdog::new(budget, scratch.cancel.clone()));
+
+        RenderContext {
+            framebuffer: Arc::new(Mutex::new(create_framebuffer(&geometry, settings.clear_color))),
+            last_render: create_framebuffer(&geometry, settings.clear_color),
+            outgoing_framebuffer: Arc::new(Mutex::new(create_framebuffer(&geometry, settings.clear_color))),
+            outgoing: None,
+            window,
+            terminal_buffer: TerminalBuffer::new(geometry.cells_w, geometry.cells_h),
+            scratch,
+            post_config,
+            glitch: GlitchEffect::new(),
+            trails: GlowTrails::new(),
+            temporal: TemporalSmoothing::new(),
+            auto_exposure: AutoExposure::new(),
+            previous_view: None,
+            paused: false,
+            redraw: true,
+            exposure: 0.0,
+            clock: SceneClock::new(),
+            timestep: FixedTimestep::new(UPDATE_RATE),
+            frame_index: 0,
+            frame_complete: false,
+            show_hud: false,
+            hud_ray_stats: false,
+            frame_times: Vec::with_capacity(HUD_HISTORY + 1),
+            last_timing: None,
+            ray_stats: None,
+            always_count_rays: false,
+            notice: None,
+            capture: settings.capture.clone().map(Capture::new),
+            palette: ColorPalette::new(),
+            ramp: GlyphRamp::new("", false, settings.display_gamma),
+            pending_preset: Some(settings.preset),
+            pacer: PresentPacer::new(settings.present_budget),
+            panorama: None,
+            panorama_shown: 0,
+            picker: {
+                let mut picker = ScenePicker::new(scene_names());
+                if settings.picker {
+                    picker.open();
+                }
+                picker
+            },
+            pick: None,
+            custom_tone_curve: match &settings.tone_curve {
+                ToneCurve::Custom(curve) => Some(curve.clone()),
+                _ => None,
+            },
+            watchdog,
+            timeline: std::mem::take(&mut settings.timeline),
+            flash: false,
+            settings,
+            geometry,
+        }
+    }
+
+    pub fn geometry(&self) -> &OutputGeometry {
+        &self.geometry
+    }
+
+    // Per-tick bookkeeping, run whether or not a frame gets drawn
+    pub fn update(&mut self, delta_time: f32) {
+        // Scene time stands still while paused or picking the next scene
+        let running = !(self.paused || self.picker.is_open());
+        for _ in 0..self.timestep.advance(delta_time) {
+            self.clock.tick(self.timestep.step(), running);
+        }
+        if self.outgoing.as_ref().is_some_and(|outgoing| outgoing.transition.finished()) {
+            self.outgoing = None;
+        }
+        if self.notice.as_ref().is_some_and(|(_, shown)| shown.elapsed().as_secs_f32() > NOTICE_SECONDS) {
+            self.notice = None;
+            self.redraw = true;
+        }
+        // Redraw for the status line as the panorama moves on, even while paused
+        if let Some(panorama) = &self.panorama {
+            let percent = (panorama.progress() * 100.0) as u32;
+            if percent != self.panorama_shown || panorama.is_finished() {
+                self.panorama_shown = percent;
+                self.redraw = true;
+            }
+        }
+    }
+
+    // Everything but ESC, which the caller handles unless the scene picker is open
+    pub fn handle_key(&mut self, key: i32, messages: &mut Vec<String>) {
+        // Any key may change what is shown
+        self.redraw = true;
+        // The picker takes every key while it is open
+        if self.picker.is_open() {
+            if let PickerAction::Start(name) = self.picker.handle_key(key) {
+                self.start_scene(name);
+                self.announce(format!("Scene {}", name), messages);
+            }
+            return;
+        }
+        // While picking, the arrows move the cursor rather than scrub
+        if let Some(cursor) = self.pick.and_then(|cursor| cursor.moved(key, self.geometry.cells_w, self.geometry.cells_h)) {
+            self.pick = Some(cursor);
+            return;
+        }
+        let settings = &mut self.settings;
+        match key {
+            32 => self.paused = !self.paused,  // Spacebar is ASCII 32
+            c if c == ',' as i32 || c == KEY_LEFT => {
+                self.clock.scrub(-TIME_SCRUB_STEP);
+                self.redraw = true;
+            }
+            c if c == '.' as i32 || c == KEY_RIGHT => {
+                self.clock.scrub(TIME_SCRUB_STEP);
+                self.redraw = true;
+            }
+            c if c == 'r' as i32 => {
+                self.clock.restart();
+                self.redraw = true;
+            }
+            c if c == '-' as i32 => self.exposure -= EXPOSURE_STEP,
+            c if c == '=' as i32 || c == '+' as i32 => self.exposure += EXPOSURE_STEP,
+            c if c == 'v' as i32 => self.post_config.vignette = !self.post_config.vignette,
+            c if c == 's' as i32 => self.post_config.scanlines = !self.post_config.scanlines,
+            c if c == 'c' as i32 => self.post_config.crt = !self.post_config.crt,
+            c if c == 'o' as i32 => self.post_config.outline = !self.post_config.outline,
+            c if c == 'O' as i32 => {
+                self.pick = match self.pick {
+                    Some(_) => None,
+                    None => Some(PickCursor::centered(self.geometry.cells_w, self.geometry.cells_h)),
+                };
+            }
+            c if c == 'a' as i32 => self.post_config.chromatic_aberration = !self.post_config.chromatic_aberration,
+            c if c == 't' as i32 => self.post_config.temporal_dither = !self.post_config.temporal_dither,
+            c if c == 'j' as i32 => {
+                self.post_config.ramp_dither = !self.post_config.ramp_dither;
+                self.announce(format!("Glyph dither {}", if self.post_config.ramp_dither { "on" } else { "off" }), messages);
+            }
+            c if c == 'd' as i32 || c == 'D' as i32 => {
+                let step = if c == 'D' as i32 { DITHER_STRENGTH_STEP } else { -DITHER_STRENGTH_STEP };
+                self.post_config.dither_strength = (self.post_config.dither_strength + step).clamp(0.0, MAX_DITHER_STRENGTH);
+                self.announce(format!("Dither strength {:.2}", self.post_config.dither_strength), messages);
+            }
+            c if c == 'e' as i32 => {
+                self.post_config.auto_exposure = !self.post_config.auto_exposure;
+                self.announce(format!("Auto exposure {}", if self.post_config.auto_exposure { "on" } else { "off" }), messages);
+            }
+            c if c == 'u' as i32 => {
+                self.post_config.tone_curve = self.post_config.tone_curve.next(self.custom_tone_curve.as_ref());
+                self.announce(format!("Tone curve {}", self.post_config.tone_curve.name()), messages);
+            }
+            c if c == 'w' as i32 => self.picker.open(),
+            c if c == 'n' as i32 => {
+                self.settings.seed = entropy_seed();
+                set_seed(self.settings.seed);
+                self.picker.forget_thumbnails();
+                self.announce(format!("Seed {}", self.settings.seed), messages);
+            }
+            c if c == 'z' as i32 => {
+                self.post_config.dither_matrix = self.post_config.dither_matrix.next();
+                self.announce(format!("Dither matrix {}", self.post_config.dither_matrix.name()), messages);
+            }
+            c if c == 'g' as i32 => self.glitch.trigger(),
+            c if c == 'm' as i32 => self.post_config.trails = !self.post_config.trails,
+            c if c == 'b' as i32 => self.post_config.temporal_smoothing = !self.post_config.temporal_smoothing,
+            c if c == 'k' as i32 => {
+                let preset = self.pending_preset.unwrap_or(settings.preset).next();
+                self.pending_preset = Some(preset);
+                self.announce(format!("Preset {}", preset.name), messages);
+            }
+            c if c == 'y' as i32 => {
+                self.post_config.color_pipeline = self.post_config.color_pipeline.next();
+                self.announce(format!("Color pipeline {}", self.post_config.color_pipeline.name()), messages);
+            }
+            c if c == 'f' as i32 => settings.fill = !settings.fill,
+            c if c == 'h' as i32 => self.show_hud = !self.show_hud,
+            c if c == 'i' as i32 => {
+                // Flip pages on a visible HUD, bring up the stats page on a hidden one
+                self.hud_ray_stats = !self.show_hud || !self.hud_ray_stats;
+                self.show_hud = true;
+            }
+            c if c == 'q' as i32 => {
+                if let Some(win) = self.window.as_mut() {
+                    win.show_terminal_colors = !win.show_terminal_colors;
+                }
+            }
+            c if c == 'p' as i32 => {
+                self.outgoing = Outgoing::start(settings);
+                settings.projection = settings.projection.next();
+            }
+            c if c == 'l' as i32 => {
+                self.outgoing = Outgoing::start(settings);
+                settings.shader.kind = settings.shader.kind.next();
+            }
+            c if c == '[' as i32 => settings.stereo.eye_separation = (settings.stereo.eye_separation - EYE_SEPARATION_STEP).max(0.0),
+            c if c == ']' as i32 => settings.stereo.eye_separation += EYE_SEPARATION_STEP,
+            c if c == KEY_F(12) => settings.dump_frame = Some(self.frame_index),
+            c if c == 'x' as i32 => settings.export_frame = Some(self.frame_index),
+            c if c == 'P' as i32 => {
+                let text = self.start_panorama(self.clock.now());
+                self.announce(text, messages);
+            }
+            c if c == KEY_F(9) => {
+                let text = match self.capture.as_mut() {
+                    Some(capture) => capture.toggle(),
+                    None => "No capture file, pass --apng to enable capturing".to_string(),
+                };
+                self.announce(text, messages);
+            }
+            _ => {}
+        }
+    }
+
+    // Resize the per-size buffers after the terminal changed size. The screen is
+    // cleared along with the next frame rather than right away.
+    pub fn handle_resize(&mut self, geometry: OutputGeometry) -> Result<(), RenderError> {
+        self.geometry = geometry;
+        self.terminal_buffer.resize(geometry.cells_w, geometry.cells_h);
+        let (width, height) = geometry.framebuffer_size();
+        self.framebuffer.lock()?.resize(width, height);
+        self.outgoing_framebuffer.lock()?.resize(width, height);
+        self.last_render.resize(width, height);
+        self.frame_complete = false;
+        self.redraw = true;
+        // The new size may well be cheaper, so start over at full resolution
+        self.scratch.pixel_step = 1;
+        Ok(())
+    }
+
+    // A paused or static frame is only redrawn after input, a resize, or while a
+    // transition runs
+    pub fn needs_frame(&self) -> bool {
+        // The picker only changes on a key press
+        self.redraw || (!self.picker.is_open() && (self.outgoing.is_some() || (!self.paused && self.is_animated())))
+    }
+
+    // Whether ESC goes to the scene picker rather than ending the program
+    pub fn picker_open(&self) -> bool {
+        self.picker.is_open()
+    }
+
+    // Whether frames change with time alone. An image is static unless an effect
+    // varies from frame to frame or timeline events are still to come.
+    fn is_animated(&self) -> bool {
+        self.settings.image.is_none()
+            || self.glitch.is_active()
+            || self.post_config.trails
+            || self.post_config.temporal_smoothing
+            || self.post_config.temporal_dither
+            || !self.timeline.is_finished()
+    }
+
+    // Keep the debug window responsive while no frames are drawn
+    pub fn update_window(&mut self) {
+        if let Some(win) = self.window.as_mut() {
+            win.update();
+        }
+    }
+
+    pub fn has_window(&self) -> bool {
+        self.window.is_some()
+    }
+
+    // Count the marcher's work on every frame, not just while the HUD shows it
+    pub fn count_rays(&mut self) {
+        self.always_count_rays = true;
+    }
+
+    // The marcher's work on the last frame, None if it wasn't counted or nothing
+    // was raymarched
+    pub fn ray_stats(&self) -> Option<&RayStats> {
+        self.ray_stats.as_ref()
+    }
+
+    // How long the last draw took in milliseconds, None before the first
+    pub fn last_frame_time(&self) -> Option<f32> {
+        self.frame_times.last().copied()
+    }
+
+    // Where the last draw spent its time. Its timestamp is the scene's wall time and
+    // it is never marked late; the caller knows the clock and the frame rate.
+    pub fn last_frame_timing(&self) -> Option<FrameTiming> {
+        self.last_timing
+    }
+
+    // Render, post-process and present one frame. `timer` gets a lap for each stage.
+    pub fn draw(&mut self, wall_time: f32, timer: &mut StageTimer, messages: &mut Vec<String>) -> Result<(), RenderError> {
+        let render_start = Instant::now();
+        self.redraw = false;
+        if let Some(preset) = self.pending_preset.take() {
+            self.apply_preset(preset);
+        }
+        let scene_time = self.clock.at(self.timestep.alpha());
+        for action in self.timeline.advance(scene_time) {
+            self.fire(action);
+        }
+        self.poll_panorama(messages);
+        timer.lap(Stage::Simulate);
+        // Resolution the frame is rendered at, before the watchdog changes it
+        let pixel_step = self.scratch.pixel_step;
+        let dropped = match self.watchdog.clone() {
+            Some(watchdog) => {
+                let (result, interruption) = watchdog.run(|| self.render_scene(scene_time));
+                result?;
+                self.adapt_resolution(interruption, render_start.elapsed(), messages);
+                interruption.is_some()
+            }
+            None => {
+                self.render_scene(scene_time)?;
+                false
+            }
+        };
+        self.update_pick();
+        timer.lap(Stage::Raymarch);
+
+        let mut dump = None;
+        if self.settings.dump_frame == Some(self.frame_index) {
+            self.settings.dump_frame = None;
+            let created = match &self.settings.dump_dir {
+                Some(dir) => FrameDump::create_in(dir, self.frame_index),
+                None => FrameDump::create(self.frame_index),
+            };
+            match created {
+                Ok(frame_dump) => dump = Some(frame_dump),
+                Err(e) => self.notice = Some((format!("Frame dump failed: {}", e), Instant::now())),
+            }
+        }
+        let mut export = None;
+        if self.settings.export_frame == Some(self.frame_index) {
+            self.settings.export_frame = None;
+            export = Some(FrameExport::new(self.geometry.cells_w, self.geometry.cells_h, frame_background(), active_palette()));
+        }
+        if let Some(text) = self.present(dump.as_mut(), export.as_mut(), wall_time, timer)? {
+            self.announce(text, messages);
+        }
+
+        if let Some(export) = export {
+            let text = match export.write(self.frame_index) {
+                Ok(path) => format!("Frame {} exported to {}", self.frame_index, path.display()),
+                Err(e) => format!("Frame export failed: {}", e),
+            };
+            self.announce(text, messages);
+        }
+
+        if let Some(dump) = dump {
+            let text = match dump.finish() {
+                Ok(dir) => format!("Frame {} dumped to {}", self.frame_index, dir.display()),
+                Err(e) => format!("Frame dump failed: {}", e),
+            };
+            self.announce(text, messages);
+        }
+        timer.lap(Stage::Present);
+        self.last_timing = Some(FrameTiming {
+            timestamp: wall_time,
+            frame_index: self.frame_index,
+            stages: timer.times,
+            resolution_scale: 1.0 / pixel_step as f32,
+            dropped,
+            late: false,
+        });
+        self.frame_index = self.frame_index.wrapping_add(1);
+        self.frame_complete = true;
+
+        self.frame_times.push(render_start.elapsed().as_secs_f32() * 1000.0);
+        if self.frame_times.len() > HUD_HISTORY {
+            self.frame_times.remove(0);
+        }
+        Ok(())
+    }
+
+    // Stop a capture and a panorama still running at exit
+    pub fn finish(&mut self, messages: &mut Vec<String>) {
+        if let Some(text) = self.capture.as_mut().and_then(Capture::finish) {
+            messages.push(text);
+        }
+        if let Some(panorama) = self.panorama.take() {
+            panorama.cancel();
+            messages.push("Panorama export cancelled".to_string());
+        }
+    }
+
+    // Start rendering a panorama of the raymarched scene at `scene_time` from the
+    // scene camera, returning the status line
+    fn start_panorama(&mut self, scene_time: f32) -> String {
+        if self.panorama.is_some() {
+            return "A panorama is already being exported".to_string();
+        }
+        if self.settings.image.is_some() || self.settings.shader_scene.is_some() {
+            return "Panorama export needs a raymarched scene".to_string();
+        }
+        let camera = scene_camera(self.settings.projection);
+        let panorama = PanoramaExport::start(prepare_frame(scene_time), camera, self.settings.shader, self.settings.panorama_width, self.frame_index);
+        let (width, height) = panorama.size();
+        self.panorama = Some(panorama);
+        self.panorama_shown = 0;
+        format!("Panorama {}x{} started", width, height)
+    }
+
+    // Announce a finished panorama, or show how far along it is
+    fn poll_panorama(&mut self, messages: &mut Vec<String>) {
+        let Some(panorama) = self.panorama.take_if(|panorama| panorama.is_finished()) else {
+            if let Some(panorama) = &self.panorama {
+                self.notice = Some((format!("Panorama {}%", (panorama.progress() * 100.0) as u32), Instant::now()));
+            }
+            return;
+        };
+        let text = match panorama.finish() {
+            Ok(path) => format!("Panorama written to {}", path.display()),
+            Err(e) => format!("Panorama export failed: {}", e),
+        };
+        self.announce(text, messages);
+    }
+
+    // Drop a notch of resolution after a frame ran over budget, and climb back once a
+    // frame would still fit with four times the rays
+    fn adapt_resolution(&mut self, interruption: Option<Interruption>, render_time: Duration, messages: &mut Vec<String>) {
+        let Some(budget) = self.settings.frame_budget else {
+            return;
+        };
+        let step = self.scratch.pixel_step;
+        if interruption == Some(Interruption::OverBudget) && step < MAX_PIXEL_STEP {
+            self.scratch.pixel_step = step * 2;
+            let text = format!("Frame over the {} ms budget, rendering at 1/{} resolution", budget.as_millis(), step * 2);
+            self.announce(text, messages);
+        } else if interruption.is_none() && step > 1 && render_time * 8 < budget {
+            self.scratch.pixel_step = step / 2;
+        }
+    }
+
+    // Swap in every part of a preset together: post-process settings, the quantizer
+    // palette, the glyph ramp and the terminal color pairs
+    fn apply_preset(&mut self, preset: Preset) {
+        self.settings.preset = preset;
+        preset.apply(&mut self.post_config);
+        set_preset_palette(preset.palette.map(|palette| palette.colors), preset.background);
+        // Whatever the terminal ended up able to show, so dithering mixes colors
+        // that actually appear
+        self.palette = effective_colors()
+            .and_then(|colors| ColorPalette::from_colors(colors).ok())
+            .unwrap_or_else(ColorPalette::new);
+        self.ramp = GlyphRamp::new(preset.ramp, self.theme().inverts_ramp(), self.post_config.display_gamma);
+    }
+
+    // The preset's background decides over the terminal's when it has one
+    fn theme(&self) -> Theme {
+        self.settings.preset.background.map_or_else(active_theme, Theme::for_background)
+    }
+
+    // Carry out a timeline or metronome event
+    fn fire(&mut self, action: EventAction) {
+        match action {
+            EventAction::Flash => self.flash = true,
+            EventAction::Bell => {
+                let _terminal = lock_terminal();
+                beep();
+            }
+            EventAction::Blink => {
+                let _terminal = lock_terminal();
+                flash();
+            }
+            EventAction::Glitch => self.glitch.trigger(),
+            EventAction::SwitchScene(scene) => set_scene(scene),
+        }
+    }
+
+    // Switch to a scene by its --scene name, leaving image mode
+    fn start_scene(&mut self, name: &str) {
+        self.settings.image = None;
+        match Scene::from_name(name, self.settings.seed) {
+            Some(scene) => {
+                self.settings.shader_scene = None;
+                set_scene(scene);
+            }
+            None => self.settings.shader_scene = ShaderScene::from_name(name),
+        }
+    }
+
+    // The picker's grid in place of the scene, rendering the thumbnails it doesn't
+    // have yet first
+    fn draw_picker(&mut self) -> Result<(), RenderError> {
+        let pixel_aspect = self.geometry.pixel_aspect();
+        let (width, height) = self.geometry.framebuffer_size();
+        update_globals(Vec2::new(width as f32, height as f32), THUMBNAIL_TIME, self.exposure, self.post_config.color_pipeline, &self.post_config.tone_curve);
+        for (index, name) in self.picker.missing_thumbnails() {
+            let mut thumbnail = Framebuffer::new(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT);
+            match Scene::from_name(name, self.settings.seed) {
+                Some(scene) => draw_thumbnail(&mut thumbnail, scene, self.settings.projection, self.settings.shader.shader().as_ref(), pixel_aspect),
+                None => {
+                    if let Some(scene) = ShaderScene::from_name(name) {
+                        scene.render(&mut thumbnail, THUMBNAIL_TIME, pixel_aspect, self.post_config.color_pipeline, &self.post_config.tone_curve, self.settings.seed);
+                    }
+                }
+            }
+            self.picker.set_thumbnail(index, thumbnail);
+        }
+        let (text_color, _) = self.theme().overlay_colors();
+        let mut fb = self.framebuffer.lock()?;
+        self.picker.draw(&mut fb, text_color, PICKER_HIGHLIGHT);
+        Ok(())
+    }
+
+    // Show a status line on screen and keep it for after exit
+    fn announce(&mut self, text: String, messages: &mut Vec<String>) {
+        messages.push(text.clone());
+        self.notice = Some((text, Instant::now()));
+    }
+
+    // Cast the picked cell's ray again for this frame, and lay a crosshair and what
+    // the ray hit over the terminal output
+    fn update_pick(&mut self) {
+        let (cells_w, cells_h) = (self.geometry.cells_w, self.geometry.cells_h);
+        let Some(cursor) = self.pick.map(|cursor| cursor.clamped(cells_w, cells_h)).filter(|_| !self.picker.is_open()) else {
+            self.terminal_buffer.set_overlay(Vec::new());
+            return;
+        };
+        self.pick = Some(cursor);
+        let raymarched = self.settings.image.is_none() && self.settings.shader_scene.is_none();
+        let lines = match self.previous_view.as_ref().filter(|_| raymarched) {
+            Some(view) => {
+                let (x, y) = self.geometry.cell_origin(cursor.x, cursor.y);
+                match view.pick(x, y, self.settings.shader.shader().as_ref()) {
+                    Some((result, hit)) => describe(hit.as_ref(), result.color),
+                    None => vec!["nothing drawn here".to_string()],
+                }
+            }
+            None => vec!["picking needs a raymarched scene".to_string()],
+        };
+        self.terminal_buffer.set_overlay(overlay(cursor, &lines, cells_w));
+    }
+
+    // Fill the framebuffer with the scene, or the image in image mode
+    fn render_scene(&mut self, scene_time: f32) -> Result<(), RenderError> {
+        self.ray_stats = None;
+        {
+            let mut fb = self.framebuffer.lock()?;
+            if self.settings.feedback {
+                // Hand the finished frame to the scene before it is cleared
+                set_feedback_texture(self.frame_complete.then(|| Arc::new(feedback_texture(&fb))));
+            }
+            // Start from the last render, so chunks a frame cut short never got to
+            // keep their pixels
+            fb.clone_from(&self.last_render);
+        }
+
+        if self.picker.is_open() {
+            return self.draw_picker();
+        }
+
+        let settings = &self.settings;
+        if let Some(image) = &settings.image {
+            // Image mode feeds the picture straight into the post-process pipeline
+            let mut fb = self.framebuffer.lock()?;
+            fb.clear();
+            fit_image(&mut fb, image, self.geometry.pixel_aspect());
+            return Ok(());
+        }
+        if let Some(scene) = &settings.shader_scene {
+            let mut fb = self.framebuffer.lock()?;
+            scene.render(&mut fb, scene_time, self.geometry.pixel_aspect(), self.post_config.color_pipeline, &self.post_config.tone_curve, settings.seed);
+            return Ok(());
+        }
+
+        let (width, height) = self.geometry.framebuffer_size();
+        update_globals(Vec2::new(width as f32, height as f32), scene_time, self.exposure, self.post_config.color_pipeline, &self.post_config.tone_curve);
+        let pixel_aspect = self.geometry.pixel_aspect();
+        let count_rays = self.always_count_rays || (self.show_hud && self.hud_ray_stats);
+        self.scratch.ray_stats = count_rays.then(|| RayStats { frames: 1, ..RayStats::default() });
+        let view = draw_test_scene(&self.framebuffer, scene_time, settings.projection, settings.shader.shader().as_ref(), &settings.motion, &settings.dof, &settings.stereo, pixel_aspect, self.previous_view.as_ref(), &mut self.scratch)?;
+        self.previous_view = Some(view);
+        self.last_render.clone_from(&*self.framebuffer.lock()?);
+        if let Some(outgoing) = &self.outgoing {
+            self.outgoing_framebuffer.lock()?.clear();
+            draw_test_scene(&self.outgoing_framebuffer, scene_time, outgoing.projection, outgoing.shader.shader().as_ref(), &settings.motion, &settings.dof, &settings.stereo, pixel_aspect, None, &mut self.scratch)?;
+            let progress = outgoing.transition.progress();
+            let outgoing_fb = self.outgoing_framebuffer.lock()?;
+            let mut fb = self.framebuffer.lock()?;
+            let width = fb.width as f32;
+            fb.blend_with(&outgoing_fb, |x, _| outgoing.transition.outgoing_weight(progress, x, width));
+        }
+        self.ray_stats = self.scratch.ray_stats.take();
+        Ok(())
+    }
+
+    // Post-process the framebuffer, convert it to characters and show it. Returns a
+    // status line when this frame ended a capture.
+    fn present(&mut self, mut dump: Option<&mut FrameDump>, export: Option<&mut FrameExport>, wall_time: f32, timer: &mut StageTimer) -> Result<Option<String>, RenderError> {
+        let mut fb = self.framebuffer.lock()?;
+
+        // Shading models with an ink outline force the normal outline pass on
+        let mut post_config = self.post_config.clone();
+        post_config.outline |= self.settings.shader.kind.outlined();
+
+        if let Some(dump) = dump.as_mut() {
+            dump.write("raymarch", "ppm", |path| fb.write_color_ppm(path));
+            dump.write("normals", "ppm", |path| fb.write_normal_ppm(path));
+            dump.write("depth", "pgm", |path| fb.write_depth_pgm(path));
+        }
+    
+        // The picker's grid is no frame of the scene, so it stays out of the passes
+        // that carry state over to the next frame. The scene comes back as it was.
+        let scene_frame = !self.picker.is_open();
+
+        // Screen-space effects on the tone-mapped color buffer, each dumped when it ran
+        if scene_frame {
+            self.temporal.apply(&mut fb, &post_config);
+            if post_config.temporal_smoothing {
+                dump_color(&mut dump, "temporal", &fb);
+            }
+            self.trails.apply(&mut fb, &post_config);
+            if post_config.trails {
+                dump_color(&mut dump, "trails", &fb);
+            }
+        }
+        apply_screen_effects(&mut fb, &post_config, |pass, fb| dump_color(&mut dump, pass, fb));
+        if scene_frame && self.glitch.is_active() {
+            self.glitch.apply(&mut fb, &post_config);
+            dump_color(&mut dump, "glitch", &fb);
+        }
+        // Captured in full color, before the overlays and palette quantization
+        let capture_status = self.capture.as_mut().and_then(|capture| capture.add_frame(&fb, wall_time));
+        let overlays = Overlays {
+            theme: self.theme(),
+            title: self.settings.title.as_deref(),
+            frame_times: if self.show_hud && !self.hud_ray_stats { Some(self.frame_times.as_slice()) } else { None },
+            ray_stats: if self.show_hud && self.hud_ray_stats { self.ray_stats.as_ref() } else { None },
+            dither_strength: self.post_config.dither_strength,
+            tone_curve: &self.post_config.tone_curve,
+            seed: self.settings.seed,
+            rates: self.pacer.rates(Instant::now()),
+            notice: self.notice.as_ref().map(|(text, _)| text.as_str()),
+        };
+        if overlays.title.is_some() || overlays.frame_times.is_some() || overlays.ray_stats.is_some() || overlays.notice.is_some() {
+            draw_overlays(&mut fb, &overlays);
+            dump_color(&mut dump, "overlays", &fb);
+        }
+
+        // Compute brightness buffer and gradients
+        if post_config.color_blur > 0 {
+            fb.blur_color(post_config.color_blur);
+            dump_color(&mut dump, "color-blurred", &fb);
+        }
+        if post_config.sharpen_target.color() {
+            fb.sharpen_color(post_config.color_sharpening, post_config.color_sharpen_overshoot);
+            dump_color(&mut dump, "color-sharpened", &fb);
+        }
+        let flash = if std::mem::take(&mut self.flash) { FLASH_BRIGHTNESS } else { 1.0 };
+        let exposure = if scene_frame { self.auto_exposure.gain(&fb, &post_config, wall_time) } else { 1.0 };
+        let brightness = flash * exposure;
+        fb.compute_adjusted_brightness(post_config.posterize_levels, post_config.posterize_order, post_config.color_pipeline, brightness, post_config.contrast);
+        dump_brightness(&mut dump, "brightness", &fb);
+        if post_config.sharpen_target.brightness() {
+            fb.apply_sharpening(post_config.sharpening);
+            dump_brightness(&mut dump, "sharpened", &fb);
+        }
+        // The export keeps the colors from before palette quantization
+        let unquantized = export.is_some().then(|| fb.clone());
+        let frame_parity = if post_config.temporal_dither { Some(self.frame_index) } else { None };
+        fb.apply_ordered_dithering(&self.palette, post_config.dither_matrix, post_config.dither_strength, frame_parity);
+        dump_color(&mut dump, "dithered", &fb);
+        self.ramp.set_dither(post_config.ramp_dither.then_some(post_config.ramp_dither_matrix));
+        timer.lap(Stage::Post);
+        let gradients = self.scratch.gradients.compute(&fb, self.geometry.pixel_aspect());
+        if let Some(dump) = dump.as_mut() {
+            dump.write("gradient-magnitude", "pgm", |path| write_gradient_magnitude_pgm(gradients, fb.width, fb.height, path));
+            dump.write("gradient-angle", "pgm", |path| write_gradient_angle_pgm(gradients, fb.width, fb.height, path));
+        }
+        timer.lap(Stage::Edges);
+
+        if let (Some(export), Some(unquantized)) = (export, unquantized) {
+            capture_cells(&unquantized, gradients, &self.geometry, self.settings.mode, self.settings.edge_width, self.settings.fill, &self.ramp, post_config.display_gamma, export);
+            // The screenshot shows what the terminal does, from the same frame
+            capture_cells(&fb, gradients, &self.geometry, self.settings.mode, self.settings.edge_width, self.settings.fill, &self.ramp, post_config.display_gamma, export.screen_mut());
+        }
+
+        // Render to terminal using ncurses, unless it is still taking in earlier
+        // frames. A frame being dumped always goes out, so the dump shows it.
+        let present_start = Instant::now();
+        if self.pacer.should_present(present_start) || dump.is_some() {
+            draw_colored_frame(&fb, gradients, &self.geometry, self.settings.mode, self.settings.edge_width, self.settings.fill, &self.ramp, post_config.display_gamma, &mut self.terminal_buffer);
+            let present_end = Instant::now();
+            self.pacer.presented(present_end - present_start, present_end);
+        } else {
+            // The newest frame still has to reach the screen, paused or not
+            self.redraw = true;
+        }
+        if let Some(dump) = dump.as_mut() {
+            dump.write("characters", "txt", |path| self.terminal_buffer.write_characters(path));
+            dump.write("color-pairs", "txt", |path| self.terminal_buffer.write_color_pairs(path));
+        }
+
+        // If in debug mode, render to minifb window as well
+        if let Some(ref mut win) = self.window {
+            win.present(&fb)?;
+        }
+        Ok(capture_status)
+    }
+}
+
+// The cameras a frame was drawn with, one per region in drawing order, and the
+// scene as it was drawn
+pub struct FrameView {
+    cameras: Vec<Camera>,
+    // Where each camera drew, as x, y, width and height in the framebuffer
+    regions: Vec<(usize, usize, usize, usize)>,
+    frame: SceneFrame,
+    pixel_step: usize,
+}
+
+impl FrameView {
+    // The ray framebuffer pixel (x, y) was drawn with, marched again. Without motion
+    // blur and depth of field it reproduces the pixel exactly; with them it is the
+    // ray at the middle of the shutter and lens. Regions that overlap, as for
+    // anaglyph stereo, pick with the first one's camera. None outside every region.
+    fn pick(&self, x: usize, y: usize, shader: &dyn Shader) -> Option<(MarchResult, Option<Hit>)> {
+        let (camera, &(region_x, region_y, width, height)) = self
+            .cameras
+            .iter()
+            .zip(&self.regions)
+            .find(|(_, &(region_x, region_y, width, height))| (region_x..region_x + width).contains(&x) && (region_y..region_y + height).contains(&y))?;
+        let (origin, direction) = camera.ray(sample_ndc(x - region_x, y - region_y, width, height, self.pixel_step));
+        let footprint = camera.pixel_footprint(width.div_ceil(self.pixel_step));
+        Some(ray_march_hit(origin, direction, &self.frame, shader, footprint, None))
+    }
+}
+
+// Color buffer after a post-process pass, when this frame is being dumped
+fn dump_color(dump: &mut Option<&mut FrameDump>, pass: &str, fb: &Framebuffer) {
+    if let Some(dump) = dump.as_mut() {
+        dump.write(pass, "ppm", |path| fb.write_color_ppm(path));
+    }
+}
+
+// Brightness buffer after a pass that works on it, when this frame is being dumped
+fn dump_brightness(dump: &mut Option<&mut FrameDump>, pass: &str, fb: &Framebuffer) {
+    if let Some(dump) = dump.as_mut() {
+        dump.write(pass, "pgm", |path| fb.write_brightness_pgm(path));
+    }
+}
+
+// `previous` is the view returned for the last frame, used to work out where each
+// pixel's surface was in it. A frame of another scene is no use for that.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_test_scene(framebuffer: &Arc<Mutex<Framebuffer>>, total_time: f32, projection: Projection, shader: &dyn Shader, motion: &MotionBlur, dof: &DepthOfField, stereo: &Stereo, pixel_aspect: f32, previous: Option<&FrameView>, scratch: &mut RenderScratch) -> Result<FrameView, RenderError> {
+    let mut fb = framebuffer.lock()?;
+    let width = fb.width;
+    let height = fb.height;
+
+    let mut camera = scene_camera(projection);
+    camera.cell_aspect = pixel_aspect;
+    camera.lens = *dof;
+
+    let frame = prepare_frame(total_time);
+    let previous = previous.filter(|view| view.frame.scene() == frame.scene());
+    let previous_region = |region: usize| {
+        let view = previous?;
+        Some((view.cameras.get(region)?, &view.frame))
+    };
+
+    let left_width = width / 2;
+    let regions = match stereo.mode {
+        StereoMode::Off => vec![(0, 0, width, height)],
+        StereoMode::Anaglyph => vec![(0, 0, width, height); 2],
+        StereoMode::SideBySide => vec![(0, 0, left_width, height), (left_width, 0, width - left_width, height)],
+    };
+    let cameras = match stereo.mode {
+        StereoMode::Off => {
+            vec![render_region(&mut fb, 0, 0, width, height, camera, &frame, shader, motion, previous_region(0), scratch)]
+        }
+        StereoMode::Anaglyph => {
+            let (left_camera, right_camera) = stereo.eye_cameras(&camera);
+            let mut left = Framebuffer::new(width, height);
+            let mut right = Framebuffer::new(width, height);
+            let left_camera = render_region(&mut left, 0, 0, width, height, left_camera, &frame, shader, motion, previous_region(0), scratch);
+            let right_camera = render_region(&mut right, 0, 0, width, height, right_camera, &frame, shader, motion, previous_region(1), scratch);
+            *fb = Framebuffer::combine_anaglyph(&left, &right);
+            vec![left_camera, right_camera]
+        }
+        StereoMode::SideBySide => {
+            // Each half of the terminal gets a full frustum of its own
+            let (left_camera, right_camera) = stereo.eye_cameras(&camera);
+            vec![
+                render_region(&mut fb, 0, 0, left_width, height, left_camera, &frame, shader, motion, previous_region(0), scratch),
+                render_region(&mut fb, left_width, 0, width - left_width, height, right_camera, &frame, shader, motion, previous_region(1), scratch),
+            ]
+        }
+    };
+    Ok(FrameView { cameras, regions, frame, pixel_step: scratch.pixel_step.max(1) })
+}
+
+// The camera every raymarched view starts from
+fn scene_camera(projection: Projection) -> Camera {
+    let eye = Vec3::new(0.0, 1.25, -1.75); // Positioned at (0, 5, 5)
+    let target = Vec3::new(0.0, 0.0, 0.0); // Looking directly at the origin
+    let up = Vec3::new(0.0, 1.0, 0.0);    
+
+    // let camera_radius = 8.0;
+    // let camera_height = 3.0 + (total_time * 0.2).sin();
+    // let camera_angle = total_time * 0.5;
+    // let eye = Vec3::new(
+    //     camera_radius * camera_angle.cos(),
+    //     camera_height,
+    //     camera_radius * camera_angle.sin()
+    // );
+    // let target = Vec3::new(0.0, 1.0, 0.0); // Look at the center of the scene, slightly above the ground
+    // let up = Vec3::new(0.0, 1.0, 0.0);
+
+    Camera::new(eye, target, up, projection)
+}
+
+// A still of `scene` at the thumbnail time, one plain ray per pixel
+fn draw_thumbnail(fb: &mut Framebuffer, scene: Scene, projection: Projection, shader: &dyn Shader, pixel_aspect: f32) {
+    let (width, height) = (fb.width, fb.height);
+    let mut camera = scene_camera(projection);
+    camera.cell_aspect = pixel_aspect;
+    camera.aspect_ratio = width as f32 / height as f32;
+    let footprint = camera.pixel_footprint(width);
+    let frame = prepare_scene_frame(scene, THUMBNAIL_TIME);
+    fb.data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let (origin, direction) = camera.ray(pixel_to_ndc(x as f32 + 0.5, y as f32 + 0.5, width, height));
+            *pixel = ray_march(origin, direction, &frame, shader, footprint, None).color;
+        }
+    });
+}
+
+// Raymarch the camera's view into a rectangular region of the framebuffer, and
+// return the camera as set up for the region. `previous` is the camera this region
+// had last frame and that frame's scene, for filling the reprojection buffer.
+#[allow(clippy::too_many_arguments)]
+fn render_region(fb: &mut Framebuffer, region_x: usize, region_y: usize, width: usize, height: usize, mut camera: Camera, frame: &SceneFrame, shader: &dyn Shader, motion: &MotionBlur, previous: Option<(&Camera, &SceneFrame)>, scratch: &mut RenderScratch) -> Camera {
+    if width == 0 || height == 0 {
+        return camera;
+    }
+    camera.aspect_ratio = width as f32 / height as f32;
+    let step = scratch.pixel_step.max(1);
+    let footprint = camera.pixel_footprint(width.div_ceil(step));
+    let fb_width = fb.width;
+    let cancel = &scratch.cancel;
+
+    scratch.tiles.clear();
+    scratch.tiles.extend((0..height).step_by(CHUNK_SIZE).flat_map(|y| {
+        (0..width).step_by(CHUNK_SIZE).map(move |x| (x, y))
+    }));
+    scratch.tile_results.resize_with(scratch.tiles.len(), Vec::new);
+    scratch.tile_stats.resize(scratch.tiles.len(), RayStats::default());
+    let count_rays = scratch.ray_stats.is_some();
+
+    let tiles = scratch.tile_results.par_iter_mut().zip(scratch.tile_stats.par_iter_mut()).zip(scratch.tiles.par_iter());
+    tiles.for_each(|((chunk_pixels, chunk_stats), &(start_x, start_y))| {
+        chunk_pixels.clear();
+        *chunk_stats = RayStats::default();
+        // Left empty when the frame is cut short, so the tile keeps its old pixels
+        if cancel.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut stats = count_rays.then_some(chunk_stats);
+        for y in (start_y..std::cmp::min(start_y + CHUNK_SIZE, height)).step_by(step) {
+            for x in (start_x..std::cmp::min(start_x + CHUNK_SIZE, width)).step_by(step) {
+                let (ray_origin, ray_dir) = camera.ray(sample_ndc(x, y, width, height, step));
+
+                // Stable per-pixel seed for the motion blur jitter
+                let pixel_key = ((region_y + y) * fb_width + region_x + x) as u32;
+                let result = if camera.lens.aperture > 0.0 {
+                    // Depth of field: rays from across the lens, the first one giving
+                    // the normal and depth
+                    let mut lens_sample = |i: u32| {
+                        let (origin, direction) = camera.lens.lens_ray(&camera, ray_origin, ray_dir, i, pixel_key);
+                        ray_march_blurred(origin, direction, frame, shader, motion, pixel_key, footprint, stats.as_deref_mut())
+                    };
+                    average_samples(lens_sample(0), (1..camera.lens.samples).map(lens_sample))
+                } else {
+                    ray_march_blurred(ray_origin, ray_dir, frame, shader, motion, pixel_key, footprint, stats.as_deref_mut())
+                };
+                let reprojection = previous.and_then(|(previous_camera, previous_frame)| {
+                    reproject(ray_origin, ray_dir, &result, frame, previous_camera, previous_frame, width, height)
+                });
+                chunk_pixels.push((result, reprojection));
+            }
+        }
+    });
+
+    if let Some(stats) = scratch.ray_stats.as_mut() {
+        scratch.tile_stats.iter().for_each(|tile| stats.merge(tile));
+    }
+
+    for (&(start_x, start_y), chunk_pixels) in scratch.tiles.iter().zip(scratch.tile_results.iter()) {
+        if chunk_pixels.is_empty() {
+            continue;
+        }
+        let (end_x, end_y) = (std::cmp::min(start_x + CHUNK_SIZE, width), std::cmp::min(start_y + CHUNK_SIZE, height));
+        let mut pixel_index = 0;
+        for y in (start_y..end_y).step_by(step) {
+            for x in (start_x..end_x).step_by(step) {
+                let (result, reprojection) = &chunk_pixels[pixel_index];
+                let center = 0.5 * step as f32;
+                for block_y in y..std::cmp::min(y + step, end_y) {
+                    for block_x in x..std::cmp::min(x + step, end_x) {
+                        fb.set_pixel(region_x + block_x, region_y + block_y, result.color);
+                        fb.set_normal(region_x + block_x, region_y + block_y, result.normal);
+                        fb.set_depth(region_x + block_x, region_y + block_y, result.depth);
+                        // Pixels of a block keep their offset from the sampled center
+                        let reprojection = reprojection.map(|r| Reprojection {
+                            x: r.x + region_x as f32 + (block_x - x) as f32 + 0.5 - center,
+                            y: r.y + region_y as f32 + (block_y - y) as f32 + 0.5 - center,
+                            depth: r.depth,
+                        });
+                        fb.set_reprojection(region_x + block_x, region_y + block_y, reprojection);
+                    }
+                }
+                pixel_index += 1;
+            }
+        }
+    }
+    camera
+}
+
+// Where the ray drawing pixel (x, y) of a `width` x `height` region passes: the
+// middle of the step x step block holding the pixel, blocks laid out from the
+// corner of each chunk. Picking goes through here too, so a picked ray is the one
+// the pixel shows.
+fn sample_ndc(x: usize, y: usize, width: usize, height: usize, step: usize) -> Vec2 {
+    let block = |v: usize| {
+        let chunk = v - v % CHUNK_SIZE;
+        chunk + (v - chunk) / step * step
+    };
+    let center = 0.5 * step as f32;
+    pixel_to_ndc(block(x) as f32 + center, block(y) as f32 + center, width, height)
+}
+
+// Where the surface a primary ray hit was on screen last frame, in pixels of the
+// region. The sky only depends on the direction, so it is followed as a point far
+// along the ray.
+#[allow(clippy::too_many_arguments)]
+fn reproject(origin: Vec3, direction: Vec3, result: &MarchResult, frame: &SceneFrame, previous_camera: &Camera, previous_frame: &SceneFrame, width: usize, height: usize) -> Option<Reprojection> {
+    let (ndc, depth) = match result.object {
+        ObjectId::Sky => {
+            let (ndc, _) = previous_camera.project(origin + direction * SKY_DISTANCE)?;
+            (ndc, f32::INFINITY)
+        }
+        object => {
+            let position = frame.previous_position(origin + direction * result.depth, object, previous_frame)?;
+            previous_camera.project(position)?
+        }
+    };
+    let pixel = ndc_to_pixel(ndc, width, height);
+    Some(Reprojection { x: pixel.x, y: pixel.y, depth })
+}
+
+// Per-frame working buffers, kept across frames so rendering doesn't allocate once
+// the terminal size settles, and the knobs the frame watchdog turns
+pub struct RenderScratch {
+    // Top-left corner of each tile of the region being raymarched
+    tiles: Vec<(usize, usize)>,
+    // Raymarch results per tile, row-major within the tile
+    tile_results: Vec<Vec<(MarchResult, Option<Reprojection>)>>,
+    // The marcher's work per tile, summed into `ray_stats` after the tiles are done
+    tile_stats: Vec<RayStats>,
+    // Some to count the marcher's work, which every region rendered adds to
+    ray_stats: Option<RayStats>,
+    gradients: GradientBuffer,
+    // Raised by the watchdog; tiles not started yet are skipped
+    cancel: Arc<AtomicBool>,
+    // Pixels per raymarched sample along each axis, 1 for full resolution
+    pixel_step: usize,
+}
+
+impl Default for RenderScratch {
+    fn default() -> Self {
+        RenderScratch {
+            tiles: Vec::new(),
+            tile_results: Vec::new(),
+            tile_stats: Vec::new(),
+            ray_stats: None,
+            gradients: GradientBuffer::default(),
+            cancel: Arc::new(AtomicBool::new(false)),
+            pixel_step: 1,
+        }
+    }
+}
+
+// The look being switched away from, rendered alongside the new one until the
+// transition is over
+struct Outgoing {
+    transition: Transition,
+    projection: Projection,
+    shader: ShaderSettings,
+}
+
+impl Outgoing {
+    // The current look, about to be replaced; None when switches are hard cuts
+    fn start(settings: &RenderSettings) -> Option<Self> {
+        Transition::start(&settings.transition).map(|transition| Outgoing {
+            transition,
+            projection: settings.projection,
+            shader: settings.shader,
+        })
+    }
+}
+
+// Text and graphs composited onto the frame before ASCII conversion
+struct Overlays<'a> {
+    theme: Theme,
+    title: Option<&'a str>,
+    // Render times for the HUD sparkline, None while the HUD is hidden
+    frame_times: Option<&'a [f32]>,
+    // The HUD's second page, None while it isn't showing
+    ray_stats: Option<&'a RayStats>,
+    // Listed in the HUD under the render time
+    dither_strength: f32,
+    // Graphed in the HUD under the figures
+    tone_curve: &'a ToneCurve,
+    // Listed in the HUD under the dither strength
+    seed: u32,
+    // Frames rendered and frames written to the terminal per second, listed under
+    // the seed
+    rates: (f32, f32),
+    // Transient status message along the bottom edge
+    notice: Option<&'a str>,
+}
+
+fn draw_overlays(fb: &mut Framebuffer, overlays: &Overlays) {
+    // Flipped on light backgrounds so the glyph ramp still draws text as ink
+    let (text_color, backdrop) = overlays.theme.overlay_colors();
+
+    // Title banner on a translucent backdrop
+    if let Some(title) = overlays.title {
+        let (text_width, text_height) = Framebuffer::text_size(title);
+        fb.blit_rect(0, 0, text_width + 2, text_height + 2, backdrop);
+        fb.draw_text(1, 1, title, text_color);
+    }
+
+    // Render time sparkline in the top right corner, latest value underneath
+    if let Some(frame_times) = overlays.frame_times {
+        let width = HUD_HISTORY.min(fb.width);
+        let x = fb.width - width;
+        let style = PlotStyle { line_color: text_color, background: backdrop, ..PlotStyle::sparkline() };
+        draw_sparkline(fb, x, 0, width, HUD_HEIGHT, frame_times, &style);
+        if let Some(latest) = frame_times.last() {
+            let label = format!("{:.1}MS", latest);
+            let (label_width, _) = Framebuffer::text_size(&label);
+            fb.draw_text(fb.width.saturating_sub(label_width + 1), HUD_HEIGHT + 1, &label, text_color);
+        }
+        let label = format!("DITHER {:.2}", overlays.dither_strength);
+        let (label_width, label_height) = Framebuffer::text_size(&label);
+        fb.draw_text(fb.width.saturating_sub(label_width + 1), HUD_HEIGHT + label_height + 2, &label, text_color);
+        let label = format!("SEED {}", overlays.seed);
+        let (label_width, _) = Framebuffer::text_size(&label);
+        fb.draw_text(fb.width.saturating_sub(label_width + 1), HUD_HEIGHT + 2 * label_height + 3, &label, text_color);
+        let (rendered, presented) = overlays.rates;
+        let label = format!("FPS {:.0} OUT {:.0}", rendered, presented);
+        let (label_width, _) = Framebuffer::text_size(&label);
+        fb.draw_text(fb.width.saturating_sub(label_width + 1), HUD_HEIGHT + 3 * label_height + 4, &label, text_color);
+
+        // The tone curve's shape, so tuning it can be done by eye
+        let top = HUD_HEIGHT + 4 * label_height + 6;
+        let style = PlotStyle { line_color: text_color, background: backdrop, fill_color: None, ..PlotStyle::default() };
+        draw_plot(fb, x, top, width, TONE_PLOT_HEIGHT, &overlays.tone_curve.samples(width), &style);
+        let label = format!("TONE {}", overlays.tone_curve.name().to_uppercase());
+        let (label_width, _) = Framebuffer::text_size(&label);
+        fb.draw_text(fb.width.saturating_sub(label_width + 1), top + TONE_PLOT_HEIGHT + 1, &label, text_color);
+    }
+
+    // Ray counts in the same corner, one figure per line
+    if let Some(stats) = overlays.ray_stats {
+        let lines: Vec<String> = stats.figures().iter().map(|(label, value)| format!("{} {}", label, value)).collect();
+        let width = lines.iter().map(|line| Framebuffer::text_size(line).0).max().unwrap_or(0);
+        let line_height = GLYPH_HEIGHT + 1;
+        let x = fb.width.saturating_sub(width + 2);
+        fb.blit_rect(x, 0, width + 2, lines.len() * line_height + 1, backdrop);
+        for (row, line) in lines.iter().enumerate() {
+            let (line_width, _) = Framebuffer::text_size(line);
+            fb.draw_text(fb.width.saturating_sub(line_width + 1), 1 + row * line_height, line, text_color);
+        }
+    }
+
+    if let Some(notice) = overlays.notice {
+        let (text_width, text_height) = Framebuffer::text_size(notice);
+        let y = fb.height.saturating_sub(text_height + 2);
+        fb.blit_rect(0, y, text_width + 2, text_height + 2, backdrop);
+        fb.draw_text(1, y + 1, notice, text_color);
+    }
+}
+
+// Copy of a finished frame for the scene to sample, shrunk to fit the feedback size
+// limit so memory and sampling cost stay fixed
+fn feedback_texture(fb: &Framebuffer) -> Framebuffer {
+    let scale = (FEEDBACK_MAX_WIDTH as f32 / fb.width as f32)
+        .min(FEEDBACK_MAX_HEIGHT as f32 / fb.height as f32)
+        .min(1.0);
+    let width = ((fb.width as f32 * scale).round() as usize).max(1);
+    let height = ((fb.height as f32 * scale).round() as usize).max(1);
+    fb.resample_bilinear(width, height)
+}
+
+// Function to create the framebuffer
+fn create_framebuffer(geometry: &OutputGeometry, clear_color: Pixel) -> Framebuffer {
+    let (width, height) = geometry.framebuffer_size();
+    let mut fb = Framebuffer::new(width, height);
+    fb.set_clear_color(clear_color);
+    fb.clear();
+    fb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::DEFAULT_CELL_ASPECT;
+    use crate::inputlog::{InputRecorder, InputReplay};
+    use crate::panorama::DEFAULT_PANORAMA_WIDTH;
+    use crate::postprocess::DEFAULT_DISPLAY_GAMMA;
+    use crate::shader::PhongShader;
+    use std::sync::MutexGuard;
+
+    const FPS: f32 = 60.0;
+
+    // The scene and its settings live in the raymarcher's globals, so tests that
+    // draw through a context take turns
+    static SCENE_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_scene() -> MutexGuard<'static, ()> {
+        SCENE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // The settings with no command line options, drawing the cubes from `seed`.
+    // Curses is never started, so frames go to the terminal buffer and no further.
+    // Expects the scene lock to be held.
+    fn context(seed: u32) -> RenderContext {
+        set_seed(seed);
+        set_scene(Scene::Cubes);
+        let settings = RenderSettings {
+            debug_mode: false,
+            title: None,
+            image: None,
+            clear_color: Pixel { r: 0, g: 0, b: 0, a: 255 },
+            shader_scene: None,
+            seed,
+            picker: false,
+            feedback: false,
+            mode: RenderMode::Combined,
+            edge_width: 0,
+            panorama_width: DEFAULT_PANORAMA_WIDTH,
+            fill: false,
+            stereo: Stereo::default(),
+            projection: Projection::from_name("perspective").unwrap(),
+            shader: ShaderSettings::default(),
+            transition: TransitionSettings::default(),
+            motion: MotionBlur::default(),
+            vector_blur_samples: 1,
+            dof: DepthOfField::default(),
+            sharpen_target: None,
+            sharpen_overshoot: PostProcessConfig::default().color_sharpen_overshoot,
+            color_blur: 0,
+            posterize_order: PosterizeOrder::BeforeAdjust,
+            color_pipeline: ColorPipeline::Linear,
+            tone_curve: ToneCurve::Linear,
+            display_gamma: DEFAULT_DISPLAY_GAMMA,
+            dither_strength: PostProcessConfig::default().dither_strength,
+            dither_matrix: PostProcessConfig::default().dither_matrix,
+            temperature: PostProcessConfig::default().temperature,
+            auto_exposure: false,
+            ramp_dither: false,
+            ramp_dither_matrix: PostProcessConfig::default().ramp_dither_matrix,
+            trail_decay: None,
+            dump_frame: None,
+            dump_dir: None,
+            export_frame: None,
+            capture: None,
+            preset: Preset::default(),
+            frame_budget: None,
+            present_budget: None,
+            timeline: Timeline::default(),
+        };
+        let geometry = OutputGeometry::new(40, 16, PixelFormat::Ascii, DEFAULT_CELL_ASPECT);
+        RenderContext::new(settings, PixelFormat::Ascii, geometry, &mut Vec::new())
+    }
+
+    // `frames` turns of the render loop on the fixed clock a replay runs on, taking
+    // the keys `keys` hands out for each frame's time. Returns the last frame drawn.
+    fn play(context: &mut RenderContext, frames: u32, mut keys: impl FnMut(f32) -> Vec<i32>) -> Vec<(u8, u8, u8)> {
+        let mut messages = Vec::new();
+        for frame in 1..=frames {
+            let wall_time = frame as f32 / FPS;
+            context.update(1.0 / FPS);
+            for key in keys(wall_time) {
+                context.handle_key(key, &mut messages);
+            }
+            if context.needs_frame() {
+                context.draw(wall_time, &mut StageTimer::new(), &mut messages).unwrap();
+            }
+        }
+        let fb = context.framebuffer.lock().unwrap();
+        fb.data.iter().map(|pixel| pixel.to_rgb()).collect()
+    }
+
+    // FNV-1a over the pixels, for comparing a frame against a stored one
+    fn checksum(pixels: &[(u8, u8, u8)]) -> u64 {
+        pixels.iter().flat_map(|&(r, g, b)| [r, g, b]).fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+    }
+
+    // Scrubbing, exposure, a few effects and a pause, none of which put up a notice
+    // that would depend on how fast the test runs
+    const SESSION: [(f32, char); 8] = [(0.1, '.'), (0.2, '.'), (0.25, '='), (0.3, 'v'), (0.4, 'o'), (0.45, ','), (0.5, 's'), (0.6, ' ')];
+    const SESSION_FRAMES: u32 = 48;
+
+    // Seed 7 after SESSION, as it looked when the scene or the pipeline was last
+    // changed on purpose. Update it along with a change meant to alter the frame.
+    const SESSION_CHECKSUM: u64 = 6855778132405535394;
+
+    #[test]
+    fn replayed_session_reproduces_the_final_frame() {
+        let _scene = lock_scene();
+        let path = std::env::temp_dir().join(format!("ascii_sobel-session-{}.json", std::process::id()));
+
+        // Played live, every key recorded as it is handled
+        let mut live = context(7);
+        let mut recorder = InputRecorder::new(&path);
+        let mut pending = SESSION.iter().peekable();
+        let recorded = play(&mut live, SESSION_FRAMES, |time| {
+            let keys: Vec<i32> = std::iter::from_fn(|| pending.next_if(|&&(at, _)| at <= time)).map(|&(_, key)| key as i32).collect();
+            for &key in &keys {
+                recorder.record(time, key);
+            }
+            keys
+        });
+        recorder.save().unwrap();
+
+        let mut replayed = context(7);
+        let mut replay = InputReplay::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let played_back = play(&mut replayed, SESSION_FRAMES, |time| std::iter::from_fn(|| replay.next_due(time)).collect());
+
+        assert!(recorded == played_back, "the replay drew a different frame");
+        assert_eq!(checksum(&played_back), SESSION_CHECKSUM);
+
+        // The keys made a difference, so the replay really did feed them in
+        assert!(play(&mut context(7), SESSION_FRAMES, |_| Vec::new()) != played_back);
+    }
+
+    #[test]
+    fn one_frame_through_a_new_context() {
+        let _scene = lock_scene();
+        let mut context = context(3);
+        assert!(context.needs_frame());
+        assert_eq!(context.last_frame_time(), None);
+
+        let mut messages = Vec::new();
+        context.update(1.0 / FPS);
+        context.draw(1.0 / FPS, &mut StageTimer::new(), &mut messages).unwrap();
+        assert_eq!(context.frame_index, 1);
+        assert!(context.last_frame_time().is_some());
+        assert_eq!(context.last_frame_timing().map(|timing| timing.frame_index), Some(0));
+        assert!(context.framebuffer.lock().unwrap().data.iter().any(|pixel| pixel.to_rgb() != (0, 0, 0)));
+        assert!(messages.is_empty());
+
+        // The scene moves on its own until paused, then waits for the next key
+        assert!(context.needs_frame());
+        context.handle_key(' ' as i32, &mut messages);
+        context.draw(2.0 / FPS, &mut StageTimer::new(), &mut messages).unwrap();
+        assert!(!context.needs_frame());
+    }
+
+    // Motion vectors of the frame drawn at `time` following one drawn at `previous_time`
+    fn motion_vectors(previous_time: f32, time: f32) -> Vec<Option<(f32, f32)>> {
+        set_seed(1);
+        set_scene(Scene::Cubes);
+        let framebuffer = Arc::new(Mutex::new(Framebuffer::new(32, 16)));
+        let projection = Projection::from_name("perspective").unwrap();
+        let mut scratch = RenderScratch::default();
+        let mut draw = |time: f32, previous: Option<&FrameView>| {
+            draw_test_scene(&framebuffer, time, projection, &PhongShader, &MotionBlur::default(), &DepthOfField::default(), &Stereo::default(), 2.0, previous, &mut scratch).unwrap()
+        };
+        let previous = draw(previous_time, None);
+        draw(time, Some(&previous));
+        let fb = framebuffer.lock().unwrap();
+        (0..fb.width * fb.height).map(|i| fb.motion_vector(i % fb.width, i / fb.width)).collect()
+    }
+
+    #[test]
+    fn only_moving_surfaces_have_motion_vectors() {
+        let _scene = lock_scene();
+        let length = |vector: &Option<(f32, f32)>| vector.map(|(dx, dy)| (dx * dx + dy * dy).sqrt());
+
+        // The same moment twice: nothing moved
+        let still = motion_vectors(1.0, 1.0);
+        assert!(still.iter().all(|vector| vector.is_some()));
+        assert!(still.iter().all(|vector| length(vector).unwrap() < 1e-3), "{:?}", still);
+
+        // Half a second on, the spinning cubes moved and the floor and sky did not
+        let moving = motion_vectors(1.0, 1.5);
+        let moved = moving.iter().filter(|vector| length(vector).is_some_and(|length| length > 0.5)).count();
+        assert!(moved > 0 && moved < moving.len() / 2, "{} of {} moved", moved, moving.len());
+    }
+
+    #[test]
+    fn the_picker_leaves_the_scene_as_it_was() {
+        let _scene = lock_scene();
+        // Glow trails and auto exposure both carry over from frame to frame
+        let paused = || {
+            let mut context = context(5);
+            context.post_config.trails = true;
+            context.post_config.auto_exposure = true;
+            context.handle_key(' ' as i32, &mut Vec::new());
+            context
+        };
+        let draw = |context: &mut RenderContext, frame: u32| {
+            context.draw(frame as f32 / FPS, &mut StageTimer::new(), &mut Vec::new()).unwrap();
+            context.framebuffer.lock().unwrap().data.iter().map(|pixel| pixel.to_rgb()).collect::<Vec<_>>()
+        };
+
+        // One with the picker open over a stretch of the frames, one without
+        let (mut with_picker, mut without) = (paused(), paused());
+        for frame in 1..=10 {
+            draw(&mut with_picker, frame);
+            draw(&mut without, frame);
+        }
+        with_picker.handle_key('w' as i32, &mut Vec::new());
+        let grid = draw(&mut with_picker, 11);
+        for frame in 12..=20 {
+            draw(&mut with_picker, frame);
+        }
+        with_picker.handle_key(27, &mut Vec::new());
+        assert!(!with_picker.picker_open());
+
+        let after = draw(&mut with_picker, 21);
+        assert!(after != grid);
+        assert!(after == draw(&mut without, 21), "the picker's frames changed the scene's");
+    }
+
+    #[test]
+    fn picking_marches_the_ray_each_pixel_shows() {
+        let _scene = lock_scene();
+        set_seed(3);
+        set_scene(Scene::Cubes);
+        let projection = Projection::from_name("perspective").unwrap();
+        for (mode, pixel_step) in [(StereoMode::Off, 1), (StereoMode::SideBySide, 1), (StereoMode::Off, 3), (StereoMode::SideBySide, 2)] {
+            // Odd sizes, so the right half and the last blocks come out uneven
+            let framebuffer = Arc::new(Mutex::new(Framebuffer::new(41, 19)));
+            let mut scratch = RenderScratch { pixel_step, ..RenderScratch::default() };
+            let stereo = Stereo { mode, ..Stereo::default() };
+            let view = draw_test_scene(&framebuffer, 1.25, projection, &PhongShader, &MotionBlur::default(), &DepthOfField::default(), &stereo, 2.0, None, &mut scratch).unwrap();
+            let fb = framebuffer.lock().unwrap();
+            for y in 0..fb.height {
+                for x in 0..fb.width {
+                    let (result, _) = view.pick(x, y, &PhongShader).unwrap();
+                    assert_eq!(result.color.to_rgb(), fb.get_pixel(x, y).to_rgb(), "{:?} step {} at {} {}", mode, pixel_step, x, y);
+                }
+            }
+            assert!(view.pick(fb.width, 0, &PhongShader).is_none());
+            assert!(view.pick(0, fb.height, &PhongShader).is_none());
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use minifb::{Window, WindowOptions};
+use crate::error::RenderError;
+use crate::framebuffer::Framebuffer;
+use crate::terminal::{active_palette, quantize_framebuffer};
+
+// Graphical minifb view of the color framebuffer, used in debug mode
+pub struct DebugWindow {
+    window: Window,
+    buffer: Vec<u32>,
+    // Show the colors the terminal palette actually displays instead of the framebuffer's
+    pub show_terminal_colors: bool,
+}
+
+impl DebugWindow {
+    pub fn new(width: usize, height: usize) -> Result<Self, RenderError> {
+        if width == 0 || height == 0 {
+            return Err(RenderError::EmptyFramebuffer { width, height });
+        }
+        let window = Window::new(
+            "Debug Framebuffer - ESC to exit",
+            width,
+            height,
+            WindowOptions::default(),
+        )?;
+
+        Ok(DebugWindow {
+            window,
+            buffer: vec![0; width * height],
+            show_terminal_colors: false,
+        })
+    }
+
+    pub fn present(&mut self, fb: &Framebuffer) -> Result<(), RenderError> {
+        // Nothing to show while the terminal is collapsed; keep the last frame
+        if fb.width == 0 || fb.height == 0 {
+            return Ok(());
+        }
+        let quantized;
+        let fb = if self.show_terminal_colors {
+            quantized = quantize_framebuffer(fb, active_palette());
+            &quantized
+        } else {
+            fb
+        };
+
+        // The framebuffer may have been resized since the last frame
+        self.buffer.resize(fb.width * fb.height, 0);
+        for (i, pixel) in fb.data.iter().enumerate() {
+            self.buffer[i] = ((pixel.r as u32) << 16) | ((pixel.g as u32) << 8) | (pixel.b as u32);
+        }
+        self.window.update_with_buffer(&self.buffer, fb.width, fb.height)?;
+        Ok(())
+    }
+
+    // Handle window events and show the last frame again
+    pub fn update(&mut self) {
+        self.window.update();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_window_is_an_error() {
+        for (width, height) in [(0, 0), (0, 4), (4, 0)] {
+            let result = DebugWindow::new(width, height);
+            assert!(matches!(result, Err(RenderError::EmptyFramebuffer { width: w, height: h }) if (w, h) == (width, height)));
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use crate::math::hash_u32;
+use std::sync::LazyLock;
+
+// Side of the blue-noise texture; it tiles, so any framebuffer size wraps into it
+const BLUE_NOISE_SIZE: usize = 64;
+// Width of the Gaussian the void-and-cluster generator measures clustering with
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+// Share of pixels set in the generator's initial pattern
+const BLUE_NOISE_INITIAL_FILL: u32 = 10;
+// Per-frame shift of the blue-noise thresholds, the golden ratio's fractional part,
+// so each pixel cycles through well-spread thresholds over consecutive frames
+const BLUE_NOISE_FRAME_STEP: f32 = 0.618_034;
+
+static BLUE_NOISE: LazyLock<Vec<f32>> = LazyLock::new(|| generate_blue_noise(BLUE_NOISE_SIZE, BLUE_NOISE_SIGMA));
+
+// Where the ordered dithers get their per-pixel thresholds from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DitherMatrix {
+    Bayer2,
+    Bayer4,
+    Bayer8,
+    // 64x64 void-and-cluster texture: no crosshatch, at the cost of a grainier look
+    BlueNoise,
+}
+
+impl DitherMatrix {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bayer2" => Some(DitherMatrix::Bayer2),
+            "bayer4" => Some(DitherMatrix::Bayer4),
+            "bayer8" => Some(DitherMatrix::Bayer8),
+            "blue-noise" => Some(DitherMatrix::BlueNoise),
+            _ => None,
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            DitherMatrix::Bayer2 => DitherMatrix::Bayer4,
+            DitherMatrix::Bayer4 => DitherMatrix::Bayer8,
+            DitherMatrix::Bayer8 => DitherMatrix::BlueNoise,
+            DitherMatrix::BlueNoise => DitherMatrix::Bayer2,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DitherMatrix::Bayer2 => "bayer2",
+            DitherMatrix::Bayer4 => "bayer4",
+            DitherMatrix::Bayer8 => "bayer8",
+            DitherMatrix::BlueNoise => "blue-noise",
+        }
+    }
+
+    // Threshold for pixel (x, y), from 0 up to but not including 1. The pattern
+    // repeats, so any position is fine.
+    pub fn threshold(&self, x: usize, y: usize) -> f32 {
+        match self {
+            DitherMatrix::Bayer2 => bayer(x, y, 1),
+            DitherMatrix::Bayer4 => bayer(x, y, 2),
+            DitherMatrix::Bayer8 => bayer(x, y, 3),
+            DitherMatrix::BlueNoise => BLUE_NOISE[(y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE],
+        }
+    }
+
+    // The threshold shifted for the given frame, for dithers that also spread
+    // over time. The Bayer patterns flip by half a step every other frame.
+    pub fn animated_threshold(&self, x: usize, y: usize, frame: u32) -> f32 {
+        let offset = match self {
+            DitherMatrix::BlueNoise => (frame as f32 * BLUE_NOISE_FRAME_STEP).fract(),
+            _ => (frame % 2) as f32 * 0.5,
+        };
+        (self.threshold(x, y) + offset).fract()
+    }
+}
+
+// Bayer matrix of side 2^levels at (x, y), built up from the 2x2 one: each bit
+// level of the coordinates picks a quadrant, the lowest bits weighing the most
+fn bayer(x: usize, y: usize, levels: u32) -> f32 {
+    const BASE: [[usize; 2]; 2] = [[0, 2], [3, 1]];
+    let mut value = 0;
+    for level in 0..levels {
+        let quadrant = BASE[(y >> level) & 1][(x >> level) & 1];
+        value += quadrant << (2 * (levels - 1 - level));
+    }
+    value as f32 / (1 << (2 * levels)) as f32
+}
+
+// Thresholds of a size x size tiling blue-noise texture by Ulichney's
+// void-and-cluster method: every pixel gets the rank at which it is switched on
+// in a sequence of ever denser binary patterns, each one spreading its set pixels
+// as evenly as possible. Deterministic, so every run gets the same texture.
+fn generate_blue_noise(size: usize, sigma: f32) -> Vec<f32> {
+    let count = size * size;
+    // Gaussian of the wrapped distance for every offset, so the texture tiles
+    let kernel: Vec<f32> = (0..count)
+        .map(|index| {
+            let wrap = |d: usize| d.min(size - d) as f32;
+            let (dx, dy) = (wrap(index % size), wrap(index / size));
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+
+    // Each pixel's summed kernel over the set pixels: high in clusters, low in voids
+    let toggle = |energy: &mut [f32], pattern: &mut [bool], pixel: usize, on: bool| {
+        pattern[pixel] = on;
+        let sign = if on { 1.0 } else { -1.0 };
+        let (px, py) = (pixel % size, pixel / size);
+        for (index, e) in energy.iter_mut().enumerate() {
+            let dx = (index % size + size - px) % size;
+            let dy = (index / size + size - py) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // Set pixel with the most energy, or unset pixel with the least
+    let tightest_cluster = |energy: &[f32], pattern: &[bool]| {
+        (0..count).filter(|&i| pattern[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+    };
+    let largest_void = |energy: &[f32], pattern: &[bool]| {
+        (0..count).filter(|&i| !pattern[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+    };
+
+    // Random initial pattern, relaxed by moving the tightest cluster's pixel into
+    // the largest void until that stops changing anything. Capped, in case ties
+    // keep two pixels swapping places.
+    let mut pattern = vec![false; count];
+    let mut energy = vec![0.0; count];
+    for pixel in 0..count {
+        if hash_u32(pixel as u32).is_multiple_of(BLUE_NOISE_INITIAL_FILL) {
+            toggle(&mut energy, &mut pattern, pixel, true);
+        }
+    }
+    for _ in 0..count {
+        let Some(cluster) = tightest_cluster(&energy, &pattern) else {
+            break;
+        };
+        toggle(&mut energy, &mut pattern, cluster, false);
+        // Never None, the cluster's pixel was just unset
+        let void = largest_void(&energy, &pattern).unwrap_or(cluster);
+        toggle(&mut energy, &mut pattern, void, true);
+        if void == cluster {
+            break;
+        }
+    }
+    let initial_ones = pattern.iter().filter(|&&on| on).count();
+
+    let mut ranks = vec![0; count];
+    // Ranks below the initial pattern's: take away the tightest clusters
+    let (mut thinned, mut thinned_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..initial_ones).rev() {
+        let Some(cluster) = tightest_cluster(&thinned_energy, &thinned) else {
+            break;
+        };
+        toggle(&mut thinned_energy, &mut thinned, cluster, false);
+        ranks[cluster] = rank;
+    }
+    // And above it: fill the largest voids, up to every pixel
+    for rank in initial_ones..count {
+        let Some(void) = largest_void(&energy, &pattern) else {
+            break;
+        };
+        toggle(&mut energy, &mut pattern, void, true);
+        ranks[void] = rank;
+    }
+    ranks.into_iter().map(|rank| rank as f32 / count as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATRICES: [(DitherMatrix, usize); 4] = [(DitherMatrix::Bayer2, 2), (DitherMatrix::Bayer4, 4), (DitherMatrix::Bayer8, 8), (DitherMatrix::BlueNoise, BLUE_NOISE_SIZE)];
+
+    #[test]
+    fn every_tile_holds_each_threshold_once() {
+        for (matrix, side) in MATRICES {
+            let mut ranks: Vec<usize> = (0..side * side).map(|i| (matrix.threshold(i % side, i / side) * (side * side) as f32).round() as usize).collect();
+            ranks.sort_unstable();
+            assert!(ranks.iter().copied().eq(0..side * side), "{:?}", matrix);
+        }
+    }
+
+    #[test]
+    fn thresholds_wrap_for_any_position_and_frame() {
+        for (matrix, side) in MATRICES {
+            for (x, y) in [(0, 0), (3, 17), (63, 1), (250, 999)] {
+                let threshold = matrix.threshold(x, y);
+                assert_eq!(threshold, matrix.threshold(x + side * 3, y + side * 7), "{:?}", matrix);
+                for frame in [0, 1, 2, 17, u32::MAX] {
+                    assert!((0.0..1.0).contains(&matrix.animated_threshold(x, y, frame)), "{:?}", matrix);
+                }
+            }
+        }
+    }
+
+    // Power of the texture's 2D DFT at every frequency, the mean taken out
+    fn power_spectrum(texture: &[f32], size: usize) -> Vec<f32> {
+        let mean = texture.iter().sum::<f32>() / texture.len() as f32;
+        let dft = |input: &[(f32, f32)], stride: usize, offset: usize| -> Vec<(f32, f32)> {
+            (0..size)
+                .map(|k| {
+                    (0..size).fold((0.0, 0.0), |(re, im), n| {
+                        let (a, b) = input[offset + n * stride];
+                        let angle = -std::f32::consts::TAU * (k * n % size) as f32 / size as f32;
+                        (re + a * angle.cos() - b * angle.sin(), im + a * angle.sin() + b * angle.cos())
+                    })
+                })
+                .collect()
+        };
+        let centered: Vec<(f32, f32)> = texture.iter().map(|&t| (t - mean, 0.0)).collect();
+        let rows: Vec<(f32, f32)> = (0..size).flat_map(|y| dft(&centered, 1, y * size)).collect();
+        let mut power = vec![0.0; size * size];
+        for x in 0..size {
+            for (y, (re, im)) in dft(&rows, size, x).into_iter().enumerate() {
+                power[y * size + x] = re * re + im * im;
+            }
+        }
+        power
+    }
+
+    #[test]
+    fn blue_noise_has_little_low_frequency_energy() {
+        let size = BLUE_NOISE_SIZE;
+        let power = power_spectrum(&BLUE_NOISE, size);
+        let radius = |i: usize| {
+            let wrap = |f: usize| f.min(size - f) as f32;
+            wrap(i % size).hypot(wrap(i / size))
+        };
+        let band_mean = |low: f32, high: f32| {
+            let band: Vec<f32> = (0..size * size).filter(|&i| (low..high).contains(&radius(i))).map(|i| power[i]).collect();
+            band.iter().sum::<f32>() / band.len() as f32
+        };
+        // White noise spreads its energy evenly; blue noise keeps it out of the lows
+        let (low, high) = (band_mean(0.5, size as f32 / 8.0), band_mean(size as f32 / 4.0, size as f32));
+        assert!(low < high * 0.1, "low {} high {}", low, high);
+    }
+
+    #[test]
+    fn blue_noise_generator_is_deterministic() {
+        let texture = generate_blue_noise(16, BLUE_NOISE_SIGMA);
+        assert_eq!(texture, generate_blue_noise(16, BLUE_NOISE_SIGMA));
+        let mut ranks: Vec<usize> = texture.iter().map(|&t| (t * 256.0).round() as usize).collect();
+        ranks.sort_unstable();
+        assert!(ranks.into_iter().eq(0..256));
+    }
+}
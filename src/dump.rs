@@ -0,0 +1,67 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Writes the intermediate buffers of one frame to numbered files so they sort in
+// pipeline order. The first failed write is kept and later stages are skipped.
+pub struct FrameDump {
+    dir: PathBuf,
+    stage: usize,
+    error: Option<io::Error>,
+}
+
+const DUMP_ROOT: &str = "frame-dumps";
+
+impl FrameDump {
+    pub fn create(frame_index: u32) -> io::Result<Self> {
+        FrameDump::create_in(Path::new(DUMP_ROOT), frame_index)
+    }
+
+    // Dump into a directory of `root` instead of the default one
+    pub fn create_in(root: &Path, frame_index: u32) -> io::Result<Self> {
+        let dir = root.join(format!("frame-{:06}", frame_index));
+        fs::create_dir_all(&dir)?;
+        Ok(FrameDump { dir, stage: 0, error: None })
+    }
+
+    // Write the next stage as `NN-name.extension` inside the dump directory
+    pub fn write<F>(&mut self, name: &str, extension: &str, write: F)
+    where
+        F: FnOnce(&Path) -> io::Result<()>,
+    {
+        if self.error.is_some() {
+            return;
+        }
+        let path = self.dir.join(format!("{:02}-{}.{}", self.stage, name, extension));
+        self.stage += 1;
+        if let Err(e) = write(&path) {
+            self.error = Some(e);
+        }
+    }
+
+    // Directory holding the dump, or the first error hit while writing it
+    pub fn finish(self) -> io::Result<PathBuf> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.dir),
+        }
+    }
+}
+
+// Binary PGM (P5) from one byte per pixel
+pub fn write_pgm(path: &Path, width: usize, height: usize, values: impl Iterator<Item = u8>) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P5\n{} {}\n255\n", width, height)?;
+    let bytes: Vec<u8> = values.collect();
+    file.write_all(&bytes)?;
+    file.flush()
+}
+
+// Binary PPM (P6) from one RGB triple per pixel
+pub fn write_ppm(path: &Path, width: usize, height: usize, values: impl Iterator<Item = (u8, u8, u8)>) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", width, height)?;
+    let bytes: Vec<u8> = values.flat_map(|(r, g, b)| [r, g, b]).collect();
+    file.write_all(&bytes)?;
+    file.flush()
+}
@@ -0,0 +1,329 @@
+use crate::imageview::load_png;
+use crate::math::Vec3;
+use std::f32::consts::{PI, TAU};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Size of the blurred copy the ambient light is looked up in. Irradiance varies
+// slowly with the normal, so a coarse grid holds it without visible steps.
+const IRRADIANCE_WIDTH: usize = 32;
+const IRRADIANCE_HEIGHT: usize = 16;
+
+// Where `direction` falls on an equirectangular map, u and v in [0, 1]. The
+// middle column looks along +z with +x to its left and v = 0 is straight up, the
+// layout panorama export writes, so an exported panorama loads back in place.
+pub fn direction_to_uv(direction: Vec3) -> (f32, f32) {
+    let direction = direction.normalize();
+    let longitude = (-direction.x).atan2(direction.z);
+    let latitude = direction.y.clamp(-1.0, 1.0).asin();
+    (0.5 + longitude / TAU, 0.5 - latitude / PI)
+}
+
+// Inverse of direction_to_uv
+pub fn uv_to_direction(u: f32, v: f32) -> Vec3 {
+    let longitude = (u - 0.5) * TAU;
+    let latitude = (0.5 - v) * PI;
+    Vec3::new(-longitude.sin() * latitude.cos(), latitude.sin(), longitude.cos() * latitude.cos())
+}
+
+// Linear radiance all around the scene from an equirectangular image, twice as
+// wide as it is high. Shown where rays leave the scene, and blurred into the
+// light a surface receives from every direction for the ambient term.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    texels: Vec<Vec3>,
+    // Cosine weighted mean radiance around each normal, on a coarse grid
+    irradiance: Vec<Vec3>,
+    // Mean luminance of `irradiance` over the sphere
+    irradiance_luminance: f32,
+}
+
+impl EnvironmentMap {
+    // `texels` are linear radiance, rows top to bottom
+    pub fn new(width: usize, height: usize, texels: Vec<Vec3>) -> io::Result<Self> {
+        if width == 0 || width != 2 * height {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("an environment map has to be twice as wide as it is high, not {}x{}", width, height)));
+        }
+        let mut map = EnvironmentMap { width, height, texels, irradiance: Vec::new(), irradiance_luminance: 0.0 };
+        map.irradiance = map.convolve_irradiance();
+        // Each grid row covers a band of the sphere in proportion to its cosine
+        let (weighted, total) = map.irradiance.iter().enumerate().fold((0.0, 0.0), |(weighted, total), (index, &e)| {
+            let weight = texel_solid_angle(index / IRRADIANCE_WIDTH, IRRADIANCE_WIDTH, IRRADIANCE_HEIGHT);
+            (weighted + luminance(e) * weight, total + weight)
+        });
+        map.irradiance_luminance = weighted / total;
+        Ok(map)
+    }
+
+    // Radiance HDR (.hdr) by its extension, anything else as PNG, which is taken
+    // to be sRGB encoded
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+        let is_hdr = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"));
+        let (width, height, texels) = if is_hdr {
+            read_radiance_hdr(&fs::read(path).map_err(with_path)?).map_err(with_path)?
+        } else {
+            let image = load_png(path).map_err(with_path)?;
+            let texels = image.data.iter().map(|pixel| Vec3::new(pixel.r as f32, pixel.g as f32, pixel.b as f32) * (1.0 / 255.0)).map(|c| c.srgb_to_linear()).collect();
+            (image.width, image.height, texels)
+        };
+        EnvironmentMap::new(width, height, texels).map_err(with_path)
+    }
+
+    // Radiance along `direction`, filtered between the four nearest texels
+    pub fn sample(&self, direction: Vec3) -> Vec3 {
+        let (u, v) = direction_to_uv(direction);
+        sample_wrapped(&self.texels, self.width, self.height, u, v)
+    }
+
+    // Light falling on a surface facing `normal`, as the cosine weighted mean of
+    // the radiance over its hemisphere
+    pub fn irradiance(&self, normal: Vec3) -> Vec3 {
+        let (u, v) = direction_to_uv(normal);
+        sample_wrapped(&self.irradiance, IRRADIANCE_WIDTH, IRRADIANCE_HEIGHT, u, v)
+    }
+
+    // Luminance of irradiance() averaged over all normals, for scaling it
+    pub fn mean_irradiance_luminance(&self) -> f32 {
+        self.irradiance_luminance
+    }
+
+    // Box filter the map down to the irradiance grid, then integrate the cosine
+    // lobe around every grid texel's direction over all the others
+    fn convolve_irradiance(&self) -> Vec<Vec3> {
+        let (width, height) = (IRRADIANCE_WIDTH, IRRADIANCE_HEIGHT);
+        let mut sums = vec![Vec3::zero(); width * height];
+        let mut counts = vec![0u32; width * height];
+        for (index, &texel) in self.texels.iter().enumerate() {
+            let (x, y) = (index % self.width, index / self.width);
+            let cell = y * height / self.height * width + x * width / self.width;
+            sums[cell] = sums[cell] + texel;
+            counts[cell] += 1;
+        }
+        // A map smaller than the grid leaves gaps, filled from the full map
+        let radiance: Vec<Vec3> = (0..width * height)
+            .map(|cell| match counts[cell] {
+                0 => self.sample(texel_direction(cell, width, height)),
+                count => sums[cell] * (1.0 / count as f32),
+            })
+            .collect();
+        let directions: Vec<Vec3> = (0..width * height).map(|cell| texel_direction(cell, width, height)).collect();
+        let solid_angles: Vec<f32> = (0..height).map(|y| texel_solid_angle(y, width, height)).collect();
+
+        directions
+            .iter()
+            .map(|&normal| {
+                let (sum, weight) = directions.iter().zip(&radiance).enumerate().fold((Vec3::zero(), 0.0), |(sum, weight), (cell, (&direction, &light))| {
+                    let w = normal.dot(&direction).max(0.0) * solid_angles[cell / width];
+                    (sum + light * w, weight + w)
+                });
+                sum * (1.0 / weight)
+            })
+            .collect()
+    }
+}
+
+// Center direction of texel `index` of a `width` x `height` map
+fn texel_direction(index: usize, width: usize, height: usize) -> Vec3 {
+    uv_to_direction(((index % width) as f32 + 0.5) / width as f32, ((index / width) as f32 + 0.5) / height as f32)
+}
+
+// Solid angle covered by a texel in row `y`, shrinking toward the poles
+fn texel_solid_angle(y: usize, width: usize, height: usize) -> f32 {
+    let latitude = (0.5 - (y as f32 + 0.5) / height as f32) * PI;
+    (TAU / width as f32) * (PI / height as f32) * latitude.cos()
+}
+
+// Bilinear lookup between texel centers, wrapping around horizontally and clamped
+// at the poles
+fn sample_wrapped(texels: &[Vec3], width: usize, height: usize, u: f32, v: f32) -> Vec3 {
+    let x = u * width as f32 - 0.5;
+    let y = (v * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let column = |offset: i64| (x0 as i64 + offset).rem_euclid(width as i64) as usize;
+    let row = |offset: usize| (y0 as usize + offset).min(height - 1);
+    let texel = |x: usize, y: usize| texels[y * width + x];
+    let top = texel(column(0), row(0)).lerp(texel(column(1), row(0)), tx);
+    let bottom = texel(column(0), row(1)).lerp(texel(column(1), row(1)), tx);
+    top.lerp(bottom, ty)
+}
+
+fn luminance(color: Vec3) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+// Radiance's RGBE format: a text header, a "-Y height +X width" resolution line,
+// then scanlines top to bottom, flat or run-length encoded per channel
+fn read_radiance_hdr(bytes: &[u8]) -> io::Result<(usize, usize, Vec<Vec3>)> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut position = 0;
+    let mut next_line = || {
+        let start = position;
+        let end = bytes[start..].iter().position(|&b| b == b'\n').map(|offset| start + offset)?;
+        position = end + 1;
+        Some(String::from_utf8_lossy(&bytes[start..end]).into_owned())
+    };
+
+    let magic = next_line().ok_or_else(|| invalid("empty file"))?;
+    if !magic.starts_with("#?") {
+        return Err(invalid("not a Radiance HDR file"));
+    }
+    loop {
+        let line = next_line().ok_or_else(|| invalid("header never ends"))?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format.trim() != "32-bit_rle_rgbe" {
+                return Err(invalid("only RGBE pixels are supported, not XYZE"));
+            }
+        }
+    }
+    let resolution = next_line().ok_or_else(|| invalid("missing resolution"))?;
+    let (height, width) = match resolution.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["-Y", height, "+X", width] => (height.parse::<usize>().ok(), width.parse::<usize>().ok()),
+        _ => return Err(invalid("only top-to-bottom, left-to-right images (-Y H +X W) are supported")),
+    };
+    let (Some(height), Some(width)) = (height, width) else {
+        return Err(invalid("bad resolution"));
+    };
+
+    let mut data = &bytes[position..];
+    let mut texels = Vec::with_capacity(width * height);
+    // Red, green, blue and the shared exponent of a scanline, channel by channel
+    let mut channels = vec![vec![0u8; width]; 4];
+    for _ in 0..height {
+        let run_length_encoded = (8..0x8000).contains(&width) && data.len() >= 4 && data[0] == 2 && data[1] == 2 && data[2] & 0x80 == 0;
+        if run_length_encoded {
+            if ((data[2] as usize) << 8 | data[3] as usize) != width {
+                return Err(invalid("scanline width doesn't match the image"));
+            }
+            data = &data[4..];
+            for channel in channels.iter_mut() {
+                let mut x = 0;
+                while x < width {
+                    let (&count, rest) = data.split_first().ok_or_else(|| invalid("truncated scanline"))?;
+                    let (run, literal) = if count > 128 { ((count - 128) as usize, false) } else { (count as usize, true) };
+                    if run == 0 || x + run > width {
+                        return Err(invalid("bad run length"));
+                    }
+                    let needed = if literal { run } else { 1 };
+                    if rest.len() < needed {
+                        return Err(invalid("truncated scanline"));
+                    }
+                    if literal {
+                        channel[x..x + run].copy_from_slice(&rest[..run]);
+                    } else {
+                        channel[x..x + run].fill(rest[0]);
+                    }
+                    data = &rest[needed..];
+                    x += run;
+                }
+            }
+        } else {
+            if data.len() < width * 4 {
+                return Err(invalid("truncated scanline"));
+            }
+            for (x, rgbe) in data.chunks_exact(4).take(width).enumerate() {
+                for (channel, &value) in channels.iter_mut().zip(rgbe) {
+                    channel[x] = value;
+                }
+            }
+            data = &data[width * 4..];
+        }
+        texels.extend((0..width).map(|x| {
+            let [r, g, b, e] = [0, 1, 2, 3].map(|channel| channels[channel][x]);
+            if e == 0 {
+                Vec3::zero()
+            } else {
+                let scale = 2f32.powi(e as i32 - 136);
+                Vec3::new(r as f32, g as f32, b as f32) * scale
+            }
+        }));
+    }
+    Ok((width, height, texels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    // A distinct color in each texel of a 4x2 map
+    fn tiny_map() -> (Vec<Vec3>, EnvironmentMap) {
+        let texels: Vec<Vec3> = (0..8).map(|i| Vec3::new((i % 4) as f32, (i / 4) as f32, i as f32 * 0.5 + 1.0)).collect();
+        (texels.clone(), EnvironmentMap::new(4, 2, texels).unwrap())
+    }
+
+    #[test]
+    fn directions_find_their_texels() {
+        let (texels, map) = tiny_map();
+        // Straight at a texel's center is that texel alone
+        for (index, &texel) in texels.iter().enumerate() {
+            let direction = texel_direction(index, 4, 2);
+            let (u, v) = direction_to_uv(direction);
+            assert!((u - ((index % 4) as f32 + 0.5) / 4.0).abs() < 1e-5 && (v - ((index / 4) as f32 + 0.5) / 2.0).abs() < 1e-5);
+            assert!(near(map.sample(direction), texel), "texel {}", index);
+        }
+        // +z is the middle of the map, evenly between the four texels around it
+        let middle = (texels[1] + texels[2] + texels[5] + texels[6]) * 0.25;
+        assert!(near(map.sample(Vec3::new(0.0, 0.0, 1.0)), middle));
+        // +x is left of the middle, a quarter turn round
+        let (u, v) = direction_to_uv(Vec3::new(1.0, 0.0, 0.0));
+        assert!((u - 0.25).abs() < 1e-6 && (v - 0.5).abs() < 1e-6);
+        // Straight back is on the seam, between the first and last columns
+        let seam = (texels[0] + texels[3] + texels[4] + texels[7]) * 0.25;
+        assert!(near(map.sample(Vec3::new(0.0, 0.0, -1.0)), seam));
+        // Straight up only sees the top row
+        let (top, bottom) = (map.sample(Vec3::new(0.0, 1.0, 0.0)), map.sample(Vec3::new(0.0, -1.0, 0.0)));
+        assert!(top.y.abs() < 1e-5 && (bottom.y - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn uv_and_direction_round_trip() {
+        for i in 0..64 {
+            let (u, v) = ((i % 8) as f32 / 8.0 + 0.03, (i / 8) as f32 / 8.0 + 0.05);
+            let (u2, v2) = direction_to_uv(uv_to_direction(u, v));
+            assert!((u - u2).abs() < 1e-5 && (v - v2).abs() < 1e-5, "{} {}", u, v);
+        }
+    }
+
+    #[test]
+    fn irradiance_is_the_cosine_weighted_mean() {
+        // A uniform sky lights every normal the same
+        let gray = Vec3::new(0.5, 0.5, 0.5);
+        let uniform = EnvironmentMap::new(8, 4, vec![gray; 32]).unwrap();
+        for direction in [Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.3, -0.8, 0.5)] {
+            assert!(near(uniform.irradiance(direction), gray));
+        }
+        assert!((uniform.mean_irradiance_luminance() - 0.5).abs() < 1e-4);
+
+        // A bright upper half lights faces turned up more than ones turned down,
+        // and blurs: neither sees only one half
+        let texels = (0..32).map(|i| if i < 16 { Vec3::new(1.0, 1.0, 1.0) } else { Vec3::zero() }).collect();
+        let sky = EnvironmentMap::new(8, 4, texels).unwrap();
+        let (up, side, down) = (sky.irradiance(Vec3::new(0.0, 1.0, 0.0)).x, sky.irradiance(Vec3::new(0.0, 0.0, 1.0)).x, sky.irradiance(Vec3::new(0.0, -1.0, 0.0)).x);
+        assert!(up > side && side > down, "{} {} {}", up, side, down);
+        assert!(up < 1.0 && down > 0.0);
+        assert!((side - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn bad_maps_are_refused() {
+        for (width, height) in [(3, 2), (4, 4), (0, 0)] {
+            let error = EnvironmentMap::new(width, height, vec![Vec3::zero(); width * height]).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains(&format!("{}x{}", width, height)), "{}", error);
+        }
+        let missing = Path::new("no-such-dir/missing.hdr");
+        let error = EnvironmentMap::load(missing).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().starts_with("no-such-dir/missing.hdr: "), "{}", error);
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::sync::PoisonError;
+
+// Everything that can go wrong in the render loop
+#[derive(Debug)]
+pub enum RenderError {
+    // The minifb debug window could not be opened or updated
+    Window(minifb::Error),
+    // A color palette was built without any entries
+    EmptyPalette,
+    // The framebuffer has no pixels to present
+    EmptyFramebuffer { width: usize, height: usize },
+    // A thread panicked while holding a lock on shared render state
+    Poisoned,
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Window(e) => write!(f, "debug window error: {}", e),
+            RenderError::EmptyPalette => write!(f, "color palette has no entries"),
+            RenderError::EmptyFramebuffer { width, height } => {
+                write!(f, "framebuffer is empty ({}x{})", width, height)
+            }
+            RenderError::Poisoned => write!(f, "render state was poisoned by a panicking thread"),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RenderError::Window(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<minifb::Error> for RenderError {
+    fn from(e: minifb::Error) -> Self {
+        RenderError::Window(e)
+    }
+}
+
+impl<T> From<PoisonError<T>> for RenderError {
+    fn from(_: PoisonError<T>) -> Self {
+        RenderError::Poisoned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poisoned_lock_becomes_an_error() {
+        let lock = std::sync::Arc::new(std::sync::Mutex::new(()));
+        let holder = lock.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = holder.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        let error: RenderError = lock.lock().unwrap_err().into();
+        assert!(matches!(error, RenderError::Poisoned));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::screenshot::TerminalScreenshot;
+use crate::terminal::{Cell, CellSink, PaletteKind};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const EXPORT_ROOT: &str = "frame-exports";
+
+// One frame's character grid as plain text and as colored HTML, filled through
+// capture_cells so the glyphs match the terminal exactly. Fed with the colors from
+// before palette quantization, the HTML shows the frame in full color. Alongside
+// goes a PNG screenshot of the frame as the terminal shows it.
+pub struct FrameExport {
+    width: usize,
+    height: usize,
+    cells: Vec<Option<Cell>>,
+    // Page color, the one the frame was drawn over
+    background: (u8, u8, u8),
+    screen: TerminalScreenshot,
+}
+
+impl CellSink for FrameExport {
+    fn clear(&mut self) {
+        self.cells.fill(None);
+    }
+
+    fn set_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        if x < self.width && y < self.height {
+            self.cells[y * self.width + x] = Some(cell);
+        }
+    }
+}
+
+impl FrameExport {
+    // `palette` is the terminal's, which the screenshot is quantized to
+    pub fn new(width: usize, height: usize, background: (u8, u8, u8), palette: PaletteKind) -> Self {
+        FrameExport {
+            width,
+            height,
+            cells: vec![None; width * height],
+            background,
+            screen: TerminalScreenshot::new(width, height, palette),
+        }
+    }
+
+    // Where the final, quantized cells go for the screenshot
+    pub fn screen_mut(&mut self) -> &mut TerminalScreenshot {
+        &mut self.screen
+    }
+
+    // Every row at full width, trailing spaces included, so columns stay aligned
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity((self.width + 1) * self.height);
+        for row in self.cells.chunks(self.width.max(1)).take(self.height) {
+            text.extend(row.iter().map(|cell| cell.map_or(' ', |cell| cell.ch)));
+            text.push('\n');
+        }
+        text
+    }
+
+    // A standalone page: one span per run of cells with the same colors inside a
+    // <pre> that never wraps
+    pub fn html(&self) -> String {
+        let (r, g, b) = self.background;
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>ascii_sobel frame</title>\n<style>\n");
+        let _ = writeln!(html, "body {{ margin: 0; padding: 1em; background: #{:02x}{:02x}{:02x}; }}", r, g, b);
+        html.push_str("pre { margin: 0; font-family: \"DejaVu Sans Mono\", Menlo, Consolas, monospace; font-size: 12px; line-height: 1.2; white-space: pre; }\n");
+        html.push_str("</style>\n</head>\n<body>\n<pre>");
+
+        for row in self.cells.chunks(self.width.max(1)).take(self.height) {
+            let mut run: Option<(String, String)> = None;
+            for cell in row {
+                let style = cell.map_or_else(String::new, |cell| cell_style(&cell));
+                let ch = cell.map_or(' ', |cell| cell.ch);
+                match run.as_mut() {
+                    Some((run_style, text)) if *run_style == style => escape_into(text, ch),
+                    _ => {
+                        if let Some((run_style, text)) = run.take() {
+                            push_span(&mut html, &run_style, &text);
+                        }
+                        let mut text = String::new();
+                        escape_into(&mut text, ch);
+                        run = Some((style, text));
+                    }
+                }
+            }
+            if let Some((run_style, text)) = run {
+                push_span(&mut html, &run_style, &text);
+            }
+            html.push('\n');
+        }
+        html.push_str("</pre>\n</body>\n</html>\n");
+        html
+    }
+
+    // Write `frame-NNNNNN.txt`, `.html` and `.png` into the export directory,
+    // returning the path of the HTML file
+    pub fn write(&self, frame_index: u32) -> io::Result<PathBuf> {
+        fs::create_dir_all(EXPORT_ROOT)?;
+        let base = Path::new(EXPORT_ROOT).join(format!("frame-{:06}", frame_index));
+        fs::write(base.with_extension("txt"), self.text())?;
+        self.screen.write_png(&base.with_extension("png"))?;
+        let html_path = base.with_extension("html");
+        fs::write(&html_path, self.html())?;
+        Ok(html_path)
+    }
+}
+
+// Inline CSS for a cell: its color as the glyph color, or as the background under
+// a contrasting glyph in fill mode
+fn cell_style(cell: &Cell) -> String {
+    let (r, g, b) = cell.color;
+    if cell.fill {
+        let (fr, fg, fb) = contrast_color(cell.color);
+        format!("color:#{:02x}{:02x}{:02x};background:#{:02x}{:02x}{:02x}", fr, fg, fb, r, g, b)
+    } else {
+        format!("color:#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+// The same shift the terminal's fill pairs use: three of the six cube levels away
+// from the background, darker on bright colors and lighter on dark ones
+fn contrast_color((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+    let luminance = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+    let shift = |c: u8| if luminance >= 128.0 { c.saturating_sub(153) } else { c.saturating_add(153) };
+    (shift(r), shift(g), shift(b))
+}
+
+fn push_span(html: &mut String, style: &str, text: &str) {
+    if style.is_empty() {
+        html.push_str(text);
+    } else {
+        let _ = write!(html, "<span style=\"{}\">{}</span>", style, text);
+    }
+}
+
+fn escape_into(text: &mut String, ch: char) {
+    match ch {
+        '&' => text.push_str("&amp;"),
+        '<' => text.push_str("&lt;"),
+        '>' => text.push_str("&gt;"),
+        _ => text.push(ch),
+    }
+}
@@ -1,6 +1,23 @@
+use crate::dither::DitherMatrix;
+use crate::dump::{write_pgm, write_ppm};
+use crate::error::RenderError;
+use crate::font::{glyph, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::math::{hash_f32, hash_u32, srgb_to_linear, Smoothstep, Vec3};
 use crate::pixel::Pixel;
+use crate::postprocess::{ColorPipeline, PosterizeOrder};
 
-use lazy_static::lazy_static;
+use rayon::prelude::*;
+use std::io;
+use std::path::Path;
+use std::sync::LazyLock;
+
+// Luma weights: Rec. 601 as the legacy pipeline applies them straight to the
+// stored bytes, Rec. 709 for the luminance of linear values
+const REC601_WEIGHTS: [f32; 3] = [0.299, 0.587, 0.114];
+const REC709_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+// Linear light of every sRGB byte, scaled to [0, 255]
+static SRGB_TO_LINEAR: LazyLock<[f32; 256]> = LazyLock::new(|| std::array::from_fn(|v| srgb_to_linear(v as f32 / 255.0) * 255.0));
 
 pub struct ColorPalette {
     colors: Vec<(u8, u8, u8)>,
@@ -17,24 +34,73 @@ impl ColorPalette {
                 (r as u8, g as u8, b as u8)
             })
             .collect();
-        ColorPalette { colors }
+        Self::from_colors(colors).expect("the 6x6x6 cube has 216 entries")
+    }
+
+    // Palette from an arbitrary list of colors; lookups need at least one entry
+    pub fn from_colors(colors: Vec<(u8, u8, u8)>) -> Result<Self, RenderError> {
+        if colors.is_empty() {
+            return Err(RenderError::EmptyPalette);
+        }
+        Ok(ColorPalette { colors })
     }
 
     pub fn closest_color(&self, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
-        *self.colors
+        // from_colors guarantees at least one entry, the fallback is never taken
+        self.colors
             .iter()
-            .min_by_key(|&&(cr, cg, cb)| {
-                let dr = (r as i32 - cr as i32).abs();
-                let dg = (g as i32 - cg as i32).abs();
-                let db = (b as i32 - cb as i32).abs();
-                dr * dr + dg * dg + db * db
-            })
-            .unwrap()
+            .copied()
+            .min_by_key(|&color| Self::distance_squared((r, g, b), color))
+            .unwrap_or((r, g, b))
+    }
+
+    // The two nearest palette entries plus a blend weight in [0, 0.5] giving how far
+    // the input sits from the nearest entry towards the second nearest
+    pub fn two_closest(&self, r: u8, g: u8, b: u8) -> ((u8, u8, u8), (u8, u8, u8), f32) {
+        let mut best = (self.colors[0], i32::MAX);
+        let mut second = (self.colors[0], i32::MAX);
+        for &color in &self.colors {
+            let distance = Self::distance_squared((r, g, b), color);
+            if distance < best.1 {
+                second = best;
+                best = (color, distance);
+            } else if distance < second.1 {
+                second = (color, distance);
+            }
+        }
+
+        let d1 = (best.1 as f32).sqrt();
+        let d2 = (second.1 as f32).sqrt();
+        let weight = if d1 + d2 > 0.0 { d1 / (d1 + d2) } else { 0.0 };
+        (best.0, second.0, weight)
+    }
+
+    fn distance_squared(a: (u8, u8, u8), b: (u8, u8, u8)) -> i32 {
+        let dr = a.0 as i32 - b.0 as i32;
+        let dg = a.1 as i32 - b.1 as i32;
+        let db = a.2 as i32 - b.2 as i32;
+        dr * dr + dg * dg + db * db
     }
 }
 
-lazy_static! {
-    static ref TERMINAL_COLORS: ColorPalette = ColorPalette::new();
+// Colors closer than this to their nearest palette entry are left alone by the
+// temporal dither, so only cells near a palette boundary alternate between frames
+const TEMPORAL_DITHER_MIN_WEIGHT: f32 = 0.15;
+// Ordered dither strength at which the Bayer offsets span the full channel range
+pub const MAX_DITHER_STRENGTH: f32 = 1.0;
+// White balance temperatures run from -1 (coolest) to 1 (warmest), where red and
+// blue are scaled up and down by this much
+pub const MAX_TEMPERATURE: f32 = 1.0;
+const WHITE_BALANCE_RANGE: f32 = 0.3;
+
+// Where a pixel's surface point was on screen in the previous frame
+#[derive(Clone, Copy, Debug)]
+pub struct Reprojection {
+    // Framebuffer position in pixels, (0.5, 0.5) being the center of the top-left pixel
+    pub x: f32,
+    pub y: f32,
+    // Distance along the previous frame's ray
+    pub depth: f32,
 }
 
 #[derive(Clone, Debug)]
@@ -42,25 +108,56 @@ pub struct Framebuffer {
     pub width: usize, 
     pub height: usize,
     pub data: Vec<Pixel>,
+    // Distance along the primary ray, infinite where it escaped
     z_buffer: Vec<f32>,
     brightness_buffer: Vec<u8>,
+    normal_buffer: Vec<Vec3>,
+    // None where the renderer didn't say, e.g. image mode
+    reprojection_buffer: Vec<Option<Reprojection>>,
+    // What clear() fills the color buffer with
+    clear_color: Pixel,
 }
 
 impl Framebuffer {
     pub fn new(width: usize, height: usize) -> Self {
-        let initial_pixel = Pixel { r: 0, g: 0, b: 0, a: 255 };
+        let clear_color = Pixel { r: 0, g: 0, b: 0, a: 255 };
         Framebuffer {
             width,
             height,
-            data: vec![initial_pixel; width * height],
-            z_buffer: vec![0.0; width * height],
+            data: vec![clear_color; width * height],
+            z_buffer: vec![f32::INFINITY; width * height],
             brightness_buffer: vec![0; width * height],
+            normal_buffer: vec![Vec3::zero(); width * height],
+            reprojection_buffer: vec![None; width * height],
+            clear_color,
         }
     }
 
+    // Change the size in place, reusing the buffers' allocations. Leaves the buffers
+    // cleared, the clear color stays.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let size = width * height;
+        self.width = width;
+        self.height = height;
+        self.data.resize(size, self.clear_color);
+        self.z_buffer.resize(size, f32::INFINITY);
+        self.brightness_buffer.resize(size, 0);
+        self.normal_buffer.resize(size, Vec3::zero());
+        self.reprojection_buffer.resize(size, None);
+        self.clear();
+        self.brightness_buffer.fill(0);
+    }
+
+    // Takes effect on the next clear()
+    pub fn set_clear_color(&mut self, color: Pixel) {
+        self.clear_color = color;
+    }
+
     pub fn clear(&mut self) {
-        let default_pixel = Pixel { r: 0, g: 0, b: 0, a: 255 };
-        self.data.fill(default_pixel);
+        self.data.fill(self.clear_color);
+        self.normal_buffer.fill(Vec3::zero());
+        self.reprojection_buffer.fill(None);
+        self.z_buffer.fill(f32::INFINITY);
     }
 
     pub fn get_pixel(&self, x: usize, y: usize) -> &Pixel {
@@ -71,104 +168,1448 @@ impl Framebuffer {
         self.data[y * self.width + x] = pixel;
     }
 
-    pub fn apply_bayer_dithering(&mut self) {
-        const BAYER_MATRIX: [[f32; 2]; 2] = [
-            [0.0 / 4.0, 2.0 / 4.0],
-            [3.0 / 4.0, 1.0 / 4.0],
-        ];
+    // Bounds-checked variant for callers that can't guarantee valid coordinates;
+    // the internal passes keep using the unchecked version above
+    pub fn try_get_pixel(&self, x: usize, y: usize) -> Option<&Pixel> {
+        if x < self.width && y < self.height {
+            Some(&self.data[y * self.width + x])
+        } else {
+            None
+        }
+    }
+
+    // Fill a rectangle, alpha blending `color` over the existing pixels.
+    // Parts of the rectangle outside the framebuffer are clipped.
+    pub fn blit_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: Pixel) {
+        let x_end = x.saturating_add(w).min(self.width);
+        let y_end = y.saturating_add(h).min(self.height);
+        for py in y..y_end {
+            for px in x..x_end {
+                self.blend_pixel(px, py, color);
+            }
+        }
+    }
+
+    // Copy the color of `src` with its top-left corner at (x, y), clipped to the
+    // framebuffer. The other buffers are left alone.
+    pub fn blit(&mut self, src: &Framebuffer, x: usize, y: usize) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let width = src.width.min(self.width.saturating_sub(x));
+        for row in 0..src.height.min(self.height.saturating_sub(y)) {
+            let start = (y + row) * self.width + x;
+            self.data[start..start + width].copy_from_slice(&src.data[row * src.width..row * src.width + width]);
+        }
+    }
+
+    // Bresenham line between two points, blended with `color`. Coordinates may lie
+    // outside the framebuffer, only the visible part is drawn.
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Pixel) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            if let (Ok(px), Ok(py)) = (usize::try_from(x), usize::try_from(y)) {
+                self.blend_clipped(px, py, color);
+            }
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    // Draw text with the built-in 3x5 bitmap font, one framebuffer pixel per font pixel.
+    // Characters without a glyph are skipped but still advance the cursor.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, color: Pixel) {
+        let mut cursor_x = x;
+        for ch in text.chars() {
+            if let Some(rows) = glyph(ch) {
+                for (row, bits) in rows.iter().enumerate() {
+                    for col in 0..GLYPH_WIDTH {
+                        let (px, py) = (cursor_x + col, y + row);
+                        if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                            self.blend_clipped(px, py, color);
+                        }
+                    }
+                }
+            }
+            cursor_x += GLYPH_WIDTH + 1;
+        }
+    }
+
+    pub fn text_size(text: &str) -> (usize, usize) {
+        let count = text.chars().count();
+        ((count * (GLYPH_WIDTH + 1)).saturating_sub(1), GLYPH_HEIGHT)
+    }
+
+    // Alpha-composite `src` over the existing pixel using `src.a`
+    pub fn blend_pixel(&mut self, x: usize, y: usize, src: Pixel) {
+        let dst = *self.get_pixel(x, y);
+        self.set_pixel(x, y, Self::blend_over(dst, src));
+    }
+
+    // blend_pixel for drawing that runs past the edges, which leaves out the pixels
+    // outside the framebuffer
+    fn blend_clipped(&mut self, x: usize, y: usize, src: Pixel) {
+        if let Some(&dst) = self.try_get_pixel(x, y) {
+            self.set_pixel(x, y, Self::blend_over(dst, src));
+        }
+    }
+
+    // Porter-Duff "over" with straight (non-premultiplied) alpha
+    fn blend_over(dst: Pixel, src: Pixel) -> Pixel {
+        let alpha = src.a as f32 / 255.0;
+        let mix = |d: u8, s: u8| -> u8 { (s as f32 * alpha + d as f32 * (1.0 - alpha)).round() as u8 };
+        Pixel {
+            r: mix(dst.r, src.r),
+            g: mix(dst.g, src.g),
+            b: mix(dst.b, src.b),
+            a: (src.a as f32 + dst.a as f32 * (1.0 - alpha)).round() as u8,
+        }
+    }
+
+    pub fn get_normal(&self, x: usize, y: usize) -> Vec3 {
+        self.normal_buffer[y * self.width + x]
+    }
+
+    pub fn set_normal(&mut self, x: usize, y: usize, normal: Vec3) {
+        self.normal_buffer[y * self.width + x] = normal;
+    }
+
+    pub fn get_depth(&self, x: usize, y: usize) -> f32 {
+        self.z_buffer[y * self.width + x]
+    }
+
+    pub fn set_depth(&mut self, x: usize, y: usize, depth: f32) {
+        self.z_buffer[y * self.width + x] = depth;
+    }
+
+    pub fn get_reprojection(&self, x: usize, y: usize) -> Option<Reprojection> {
+        self.reprojection_buffer[y * self.width + x]
+    }
+
+    pub fn set_reprojection(&mut self, x: usize, y: usize, reprojection: Option<Reprojection>) {
+        self.reprojection_buffer[y * self.width + x] = reprojection;
+    }
+
+    // How far the pixel's surface point moved on screen since the previous frame, in
+    // pixels. Read off the reprojection buffer, so None wherever that is.
+    pub fn motion_vector(&self, x: usize, y: usize) -> Option<(f32, f32)> {
+        self.get_reprojection(x, y).map(|r| (x as f32 + 0.5 - r.x, y as f32 + 0.5 - r.y))
+    }
+
+    // Debug dumps of the individual buffers as PPM/PGM images
+
+    pub fn write_color_ppm(&self, path: &Path) -> io::Result<()> {
+        write_ppm(path, self.width, self.height, self.data.iter().map(|pixel| pixel.to_rgb()))
+    }
+
+    pub fn write_brightness_pgm(&self, path: &Path) -> io::Result<()> {
+        write_pgm(path, self.width, self.height, self.brightness_buffer.iter().copied())
+    }
+
+    // Normals mapped from [-1, 1] to [0, 255] per axis, misses stay mid gray
+    pub fn write_normal_ppm(&self, path: &Path) -> io::Result<()> {
+        let channel = |v: f32| ((v * 0.5 + 0.5).clamp(0.0, 1.0) * 255.0) as u8;
+        write_ppm(path, self.width, self.height, self.normal_buffer.iter().map(|n| (channel(n.x), channel(n.y), channel(n.z))))
+    }
+
+    // Depth scaled over the finite range, near is white and misses are black
+    pub fn write_depth_pgm(&self, path: &Path) -> io::Result<()> {
+        let finite = self.z_buffer.iter().copied().filter(|d| d.is_finite());
+        let (near, far) = finite.fold((f32::INFINITY, 0.0f32), |(near, far), d| (near.min(d), far.max(d)));
+        let range = (far - near).max(f32::EPSILON);
+        write_pgm(path, self.width, self.height, self.z_buffer.iter().map(|&d| {
+            if d.is_finite() { (255.0 - (d - near) / range * 223.0) as u8 } else { 0 }
+        }))
+    }
+
+    // Divergence between the normals of two adjacent rays: 0 for parallel normals,
+    // up to 2 for opposing ones, and 1 where only one of the rays hit a surface
+    fn normal_divergence(a: Vec3, b: Vec3) -> f32 {
+        let a_hit = a.length() > 0.0;
+        let b_hit = b.length() > 0.0;
+        match (a_hit, b_hit) {
+            (true, true) => 1.0 - a.dot(&b),
+            (false, false) => 0.0,
+            _ => 1.0,
+        }
+    }
+
+    pub fn normal_outline_response(&self, x: usize, y: usize) -> f32 {
+        let center = self.get_normal(x, y);
+        let mut divergence: f32 = 0.0;
+        if x + 1 < self.width {
+            divergence = divergence.max(Self::normal_divergence(center, self.get_normal(x + 1, y)));
+        }
+        if y + 1 < self.height {
+            divergence = divergence.max(Self::normal_divergence(center, self.get_normal(x, y + 1)));
+        }
+        if x > 0 {
+            divergence = divergence.max(Self::normal_divergence(center, self.get_normal(x - 1, y)));
+        }
+        if y > 0 {
+            divergence = divergence.max(Self::normal_divergence(center, self.get_normal(x, y - 1)));
+        }
+        divergence
+    }
+
+    pub fn apply_normal_outline(&mut self, threshold: f32, strength: f32) {
+        let width = self.width;
+        if width == 0 {
+            return;
+        }
+
+        // Evaluate the responses against the normal buffer first, then darken in parallel
+        let responses: Vec<f32> = (0..self.data.len())
+            .into_par_iter()
+            .map(|i| self.normal_outline_response(i % width, i / width))
+            .collect();
+
+        self.data.par_iter_mut().zip(responses.par_iter()).for_each(|(pixel, &response)| {
+            // Soft ramp just above the threshold to avoid jaggy on/off outlines
+            let edge = response.smoothstep(threshold, threshold * 1.5);
+            if edge > 0.0 {
+                *pixel = Self::scale_pixel(*pixel, 1.0 - strength * edge);
+            }
+        });
+    }
+
+    // Quantize to `palette`, with thresholds from `matrix`. With `frame_parity` set,
+    // colors near a palette boundary alternate between their two nearest entries
+    // across frames and across the pattern, in proportion to where they sit between
+    // them. `strength` scales the ordered dither offsets, clamped to
+    // [0, MAX_DITHER_STRENGTH]; 0 is plain nearest-color quantization. The temporal
+    // dither doesn't use it.
+    pub fn apply_ordered_dithering(&mut self, palette: &ColorPalette, matrix: DitherMatrix, strength: f32, frame_parity: Option<u32>) {
+        if self.width == 0 {
+            return;
+        }
+        let strength = strength.clamp(0.0, MAX_DITHER_STRENGTH);
+
+        // Rows are processed in parallel; the row index keeps the pattern aligned
+        self.data.par_chunks_mut(self.width).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                if let Some(frame) = frame_parity {
+                    let (nearest, second, weight) = palette.two_closest(pixel.r, pixel.g, pixel.b);
+                    let threshold = matrix.animated_threshold(x, y, frame);
+                    let chosen = if weight >= TEMPORAL_DITHER_MIN_WEIGHT && threshold < weight {
+                        second
+                    } else {
+                        nearest
+                    };
+                    *pixel = Pixel { r: chosen.0, g: chosen.1, b: chosen.2, a: 255 };
+                    continue;
+                }
 
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let pixel = self.get_pixel(x, y);
                 let (r, g, b) = (pixel.r as f32, pixel.g as f32, pixel.b as f32);
 
-                // Apply Bayer matrix threshold
-                let threshold = BAYER_MATRIX[y % 2][x % 2] * 255.0;
+                // Apply the matrix threshold
+                let threshold = matrix.threshold(x, y) * 255.0;
                 
-                // Apply dithering with reduced intensity
-                let dither_factor = 0.1; // Adjust this value to control dithering intensity
-                let r_dithered = (r + (threshold - 128.0) * dither_factor).clamp(0.0, 255.0) as u8;
-                let g_dithered = (g + (threshold - 128.0) * dither_factor).clamp(0.0, 255.0) as u8;
-                let b_dithered = (b + (threshold - 128.0) * dither_factor).clamp(0.0, 255.0) as u8;
+                let r_dithered = (r + (threshold - 128.0) * strength).clamp(0.0, 255.0) as u8;
+                let g_dithered = (g + (threshold - 128.0) * strength).clamp(0.0, 255.0) as u8;
+                let b_dithered = (b + (threshold - 128.0) * strength).clamp(0.0, 255.0) as u8;
 
                 // Find the closest terminal color
-                let closest_color = TERMINAL_COLORS.closest_color(r_dithered, g_dithered, b_dithered);
+                let closest_color = palette.closest_color(r_dithered, g_dithered, b_dithered);
 
                 // Set the pixel to the closest terminal color
-                self.set_pixel(x, y, Pixel {
+                *pixel = Pixel {
                     r: closest_color.0,
                     g: closest_color.1,
                     b: closest_color.2,
                     a: 255,
-                });
+                };
+            }
+        });
+    }
+
+    // The luminance pass on its own, unfused and without adjustments, which tests
+    // fill the brightness buffer with. None skips posterization.
+    #[cfg(test)]
+    pub fn compute_brightness_buffer(&mut self, posterize_levels: Option<u8>, pipeline: ColorPipeline) {
+        self.brightness_buffer
+            .par_iter_mut()
+            .zip(self.data.par_iter())
+            .for_each(|(brightness, pixel)| {
+                let value = Self::luminance(pixel, pipeline);
+                *brightness = posterize_levels.map_or(value, |levels| Self::posterize_brightness(value, levels));
+            });
+    }
+
+    // Luminance, posterize, brightness and contrast in one pass (posterize last for
+    // PosterizeOrder::AfterAdjust). Walks the buffers only once and does the math on
+    // planar blocks of f32 lanes that the compiler can vectorize.
+    pub fn compute_adjusted_brightness(&mut self, posterize_levels: Option<u8>, order: PosterizeOrder, pipeline: ColorPipeline, brightness_factor: f32, contrast_factor: f32) {
+        let adjust = BrightnessAdjust::new(posterize_levels, order, pipeline, brightness_factor, contrast_factor);
+        self.brightness_buffer
+            .par_chunks_mut(BRIGHTNESS_CHUNK)
+            .zip(self.data.par_chunks(BRIGHTNESS_CHUNK))
+            .for_each(|(brightness, pixels)| {
+                for (out, block) in brightness.chunks_mut(LANES).zip(pixels.chunks(LANES)) {
+                    adjust.apply(block, out);
+                }
+            });
+    }
+
+    // How many pixels have each luminance, as compute_brightness_buffer measures it
+    pub fn luminance_histogram(&self, pipeline: ColorPipeline) -> [u32; 256] {
+        self.data
+            .par_chunks(BRIGHTNESS_CHUNK)
+            .map(|pixels| {
+                let mut histogram = [0; 256];
+                for pixel in pixels {
+                    histogram[Self::luminance(pixel, pipeline) as usize] += 1;
+                }
+                histogram
+            })
+            .reduce(|| [0; 256], |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                a
+            })
+    }
+
+    // Luminance in [0, 255]; linear light for ColorPipeline::Linear
+    fn luminance(pixel: &Pixel, pipeline: ColorPipeline) -> u8 {
+        let ([r, g, b], [wr, wg, wb]) = match pipeline {
+            ColorPipeline::Legacy => ([pixel.r as f32, pixel.g as f32, pixel.b as f32], REC601_WEIGHTS),
+            ColorPipeline::Linear => {
+                let decode = &*SRGB_TO_LINEAR;
+                ([decode[pixel.r as usize], decode[pixel.g as usize], decode[pixel.b as usize]], REC709_WEIGHTS)
+            }
+        };
+        (wr * r + wg * g + wb * b) as u8
+    }
+
+    // Unsharp mask against the average of the four direct neighbors
+    pub fn apply_sharpening(&mut self, sharpening_factor: f32) {
+        let edge = -sharpening_factor / 4.0;
+        let kernel = [
+            0.0, edge, 0.0,
+            edge, 1.0 + sharpening_factor, edge,
+            0.0, edge, 0.0,
+        ];
+        self.convolve(&kernel, 3, false);
+    }
+
+    // Convolve the brightness buffer with a row-major `size` x `size` kernel, `size`
+    // odd. Samples past the edges repeat the nearest edge pixel. With `normalize` the
+    // kernel is divided by its sum first, unless the weights sum to zero as in edge
+    // detection kernels.
+    pub fn convolve(&mut self, kernel: &[f32], size: usize, normalize: bool) {
+        assert!(size % 2 == 1 && kernel.len() == size * size, "convolution kernel must be size x size with an odd size");
+        let width = self.width;
+        let height = self.height;
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let sum: f32 = kernel.iter().sum();
+        let scale = if normalize && sum != 0.0 { 1.0 / sum } else { 1.0 };
+        let radius = (size / 2) as isize;
+        // Read neighbors from an immutable snapshot while rows are written in parallel
+        let source = self.brightness_buffer.clone();
+
+        self.brightness_buffer.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for (x, out) in row.iter_mut().enumerate() {
+                let mut total = 0.0;
+                for (ky, weights) in kernel.chunks(size).enumerate() {
+                    let sy = (y as isize + ky as isize - radius).clamp(0, height as isize - 1) as usize;
+                    let source_row = &source[sy * width..(sy + 1) * width];
+                    for (kx, weight) in weights.iter().enumerate() {
+                        let sx = (x as isize + kx as isize - radius).clamp(0, width as isize - 1) as usize;
+                        total += weight * source_row[sx] as f32;
+                    }
+                }
+                *out = (total * scale).clamp(0.0, 255.0) as u8;
             }
+        });
+    }
+
+    // Unsharp mask on the RGB channels against a 3x3 box blur. No channel moves more
+    // than `overshoot` from its value, which bounds the halo along edges.
+    pub fn sharpen_color(&mut self, amount: f32, overshoot: u8) {
+        if self.width < 3 || self.height < 3 || amount == 0.0 {
+            return;
         }
+        let blurred = Self::box_blurred(&self.data, self.width, self.height, 1);
+        let overshoot = overshoot as f32;
+        self.data.par_iter_mut().zip(blurred.par_iter()).for_each(|(pixel, blur)| {
+            let sharpen = |c: u8, b: u8| {
+                let change = (amount * (c as f32 - b as f32)).clamp(-overshoot, overshoot);
+                (c as f32 + change).round().clamp(0.0, 255.0) as u8
+            };
+            *pixel = Pixel {
+                r: sharpen(pixel.r, blur.r),
+                g: sharpen(pixel.g, blur.g),
+                b: sharpen(pixel.b, blur.b),
+                a: pixel.a,
+            };
+        });
     }
 
-    pub fn compute_brightness_buffer(&mut self, posterize_levels: u8) {
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let pixel = self.get_pixel(x, y);
-                let brightness = (0.299 * pixel.r as f32 + 0.587 * pixel.g as f32 + 0.114 * pixel.b as f32) as u8;
-                let posterized_brightness = Self::posterize_brightness(brightness, posterize_levels);
-                self.brightness_buffer[y * self.width + x] = posterized_brightness;
+    // Box blur of the RGB channels over a (2 * radius + 1)^2 window, edges clamped
+    pub fn blur_color(&mut self, radius: usize) {
+        if radius == 0 || self.width == 0 || self.height == 0 {
+            return;
+        }
+        self.data = Self::box_blurred(&self.data, self.width, self.height, radius);
+    }
+
+    // Separable box blur: a horizontal pass then a vertical pass, rows in parallel.
+    // Alpha is passed through.
+    fn box_blurred(data: &[Pixel], width: usize, height: usize, radius: usize) -> Vec<Pixel> {
+        let average = |samples: &mut dyn Iterator<Item = &Pixel>, center: &Pixel| {
+            let (mut r, mut g, mut b, mut count) = (0u32, 0u32, 0u32, 0u32);
+            for sample in samples {
+                r += sample.r as u32;
+                g += sample.g as u32;
+                b += sample.b as u32;
+                count += 1;
+            }
+            let mean = |sum: u32| ((sum + count / 2) / count) as u8;
+            Pixel { r: mean(r), g: mean(g), b: mean(b), a: center.a }
+        };
+
+        let mut horizontal = data.to_vec();
+        horizontal.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            let source = &data[y * width..(y + 1) * width];
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let window = x.saturating_sub(radius)..=(x + radius).min(width - 1);
+                *pixel = average(&mut source[window].iter(), &source[x]);
+            }
+        });
+
+        let mut vertical = horizontal.clone();
+        vertical.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            let rows = y.saturating_sub(radius)..=(y + radius).min(height - 1);
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let mut column = rows.clone().map(|yy| &horizontal[yy * width + x]);
+                *pixel = average(&mut column, &horizontal[y * width + x]);
             }
+        });
+        vertical
+    }
+
+    // Snap to the nearest of `levels` evenly spaced values from 0 to 255. A single
+    // level leaves nothing to tell apart, so everything maps to black. The scalar
+    // form of what compute_adjusted_brightness does to a block of lanes.
+    #[cfg(test)]
+    pub fn posterize_brightness(brightness: u8, levels: u8) -> u8 {
+        if levels <= 1 {
+            return 0;
+        }
+        // Round to a level index and back, rather than to multiples of a float step
+        // that can land a hair under 255 or over it
+        let intervals = (levels - 1) as f32;
+        let level = (brightness as f32 * intervals / 255.0).round();
+        (level * 255.0 / intervals).round().min(255.0) as u8
+    }
+    
+    pub fn get_brightness(&self, x: usize, y: usize) -> u8 {
+        self.brightness_buffer[y * self.width + x]
+    }
+
+    pub fn sample_bilinear(&self, fx: f32, fy: f32) -> Pixel {
+        if self.width == 0 || self.height == 0 {
+            return Pixel { r: 0, g: 0, b: 0, a: 255 };
+        }
+
+        // Clamp to the buffer so samples outside the source repeat the edge pixels
+        let fx = fx.clamp(0.0, (self.width - 1) as f32);
+        let fy = fy.clamp(0.0, (self.height - 1) as f32);
+
+        let x0 = fx.floor() as usize;
+        let y0 = fy.floor() as usize;
+        let x1 = (x0 + 1).min(self.width - 1);
+        let y1 = (y0 + 1).min(self.height - 1);
+        let tx = fx - x0 as f32;
+        let ty = fy - y0 as f32;
+
+        let p00 = self.get_pixel(x0, y0);
+        let p10 = self.get_pixel(x1, y0);
+        let p01 = self.get_pixel(x0, y1);
+        let p11 = self.get_pixel(x1, y1);
+
+        let lerp_channel = |c00: u8, c10: u8, c01: u8, c11: u8| -> u8 {
+            let top = c00 as f32 + (c10 as f32 - c00 as f32) * tx;
+            let bottom = c01 as f32 + (c11 as f32 - c01 as f32) * tx;
+            (top + (bottom - top) * ty).round().clamp(0.0, 255.0) as u8
+        };
+
+        Pixel {
+            r: lerp_channel(p00.r, p10.r, p01.r, p11.r),
+            g: lerp_channel(p00.g, p10.g, p01.g, p11.g),
+            b: lerp_channel(p00.b, p10.b, p01.b, p11.b),
+            a: lerp_channel(p00.a, p10.a, p01.a, p11.a),
         }
     }
 
-    pub fn increase_brightness(&mut self, brightness_factor: f32) {
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let brightness = self.get_brightness(x, y) as f32;
-                
-                // Apply brightness adjustment
-                let brightened = (brightness * brightness_factor).clamp(0.0, 255.0);
+    // Color at normalized coordinates, (0, 0) top-left to (1, 1) bottom-right, as [0, 1]
+    // channels, for sampling a frame as a texture
+    pub fn sample_texture(&self, u: f32, v: f32) -> Vec3 {
+        let pixel = self.sample_bilinear(u * self.width as f32 - 0.5, v * self.height as f32 - 0.5);
+        Vec3::new(pixel.r as f32 / 255.0, pixel.g as f32 / 255.0, pixel.b as f32 / 255.0)
+    }
+
+    // Scaled copy with bilinear interpolation between pixel centers. Samples past the
+    // border are clamped to the edge pixels.
+    pub fn resample_bilinear(&self, new_width: usize, new_height: usize) -> Framebuffer {
+        let mut resampled = Framebuffer::new(new_width, new_height);
+        if new_width == 0 || new_height == 0 {
+            return resampled;
+        }
+        let scale_x = self.width as f32 / new_width as f32;
+        let scale_y = self.height as f32 / new_height as f32;
+
+        resampled.data.par_chunks_mut(new_width).enumerate().for_each(|(y, row)| {
+            let src_y = (y as f32 + 0.5) * scale_y - 0.5;
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let src_x = (x as f32 + 0.5) * scale_x - 0.5;
+                *pixel = self.sample_bilinear(src_x, src_y);
+            }
+        });
+        resampled
+    }
+
+    // Darken toward the edges. Pixels within `radius` (normalized distance, 1.0 at the
+    // corners) are untouched, beyond it the darkening ramps up to `strength` at the corners.
+    pub fn apply_vignette(&mut self, strength: f32, radius: f32, falloff: f32) {
+        if self.width == 0 {
+            return;
+        }
+        let center_x = (self.width as f32 - 1.0) * 0.5;
+        let center_y = (self.height as f32 - 1.0) * 0.5;
 
-                // Store the result back in the brightness buffer
-                self.brightness_buffer[y * self.width + x] = brightened as u8;
+        self.data.par_chunks_mut(self.width).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                // Normalized distance from the center, 1.0 at the corners
+                let dx = if center_x > 0.0 { (x as f32 - center_x) / center_x } else { 0.0 };
+                let dy = if center_y > 0.0 { (y as f32 - center_y) / center_y } else { 0.0 };
+                let distance = (dx * dx + dy * dy).sqrt() / std::f32::consts::SQRT_2;
+                let distance = if radius < 1.0 { ((distance - radius) / (1.0 - radius)).max(0.0) } else { 0.0 };
+
+                let factor = (1.0 - strength * distance.powf(falloff)).clamp(0.0, 1.0);
+                *pixel = Self::scale_pixel(*pixel, factor);
             }
+        });
+    }
+
+    // Tint toward orange for a positive `temperature` and toward blue for a negative
+    // one, by scaling red and blue in opposite directions. 0 leaves colors alone.
+    pub fn apply_white_balance(&mut self, temperature: f32) {
+        let shift = WHITE_BALANCE_RANGE * temperature.clamp(-MAX_TEMPERATURE, MAX_TEMPERATURE) / MAX_TEMPERATURE;
+        if shift == 0.0 {
+            return;
         }
+        let scale = |channel: u8, factor: f32| (channel as f32 * factor).round().min(255.0) as u8;
+
+        self.data.par_iter_mut().for_each(|pixel| {
+            pixel.r = scale(pixel.r, 1.0 + shift);
+            pixel.b = scale(pixel.b, 1.0 - shift);
+        });
     }
 
-    pub fn increase_contrast(&mut self, contrast_factor: f32) {
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let brightness = self.get_brightness(x, y) as f32 / 255.0;
+    pub fn apply_scanlines(&mut self, factor: f32) {
+        if self.width == 0 {
+            return;
+        }
+        let factor = factor.clamp(0.0, 1.0);
+
+        // Darken every other row
+        self.data.par_chunks_mut(self.width).skip(1).step_by(2).for_each(|row| {
+            for pixel in row.iter_mut() {
+                *pixel = Self::scale_pixel(*pixel, factor);
+            }
+        });
+    }
+
+    pub fn apply_barrel_distortion(&mut self, strength: f32) {
+        if self.width == 0 {
+            return;
+        }
+        let source = self.clone();
+        let center_x = (self.width as f32 - 1.0) * 0.5;
+        let center_y = (self.height as f32 - 1.0) * 0.5;
+
+        self.data.par_chunks_mut(self.width).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let u = if center_x > 0.0 { (x as f32 - center_x) / center_x } else { 0.0 };
+                let v = if center_y > 0.0 { (y as f32 - center_y) / center_y } else { 0.0 };
 
-                // Apply contrast transformation
-                let contrasted = ((brightness - 0.5) * contrast_factor + 0.5).clamp(0.0, 1.0);
+                // Inverse distortion: find where this output pixel comes from in the source
+                let scale = 1.0 + strength * (u * u + v * v);
+                let src_x = center_x + u * scale * center_x;
+                let src_y = center_y + v * scale * center_y;
 
-                // Convert back to u8 and store in the brightness buffer
-                self.brightness_buffer[y * self.width + x] = (contrasted * 255.0) as u8;
+                *pixel = source.sample_bilinear(src_x, src_y);
             }
+        });
+    }
+
+    // Red/cyan anaglyph: red from the left eye, green and blue from the right eye
+    pub fn combine_anaglyph(left: &Framebuffer, right: &Framebuffer) -> Framebuffer {
+        let mut combined = left.clone();
+        for (pixel, right_pixel) in combined.data.iter_mut().zip(right.data.iter()) {
+            pixel.g = right_pixel.g;
+            pixel.b = right_pixel.b;
         }
+        combined
     }
 
-    pub fn apply_sharpening(&mut self, sharpening_factor: f32) {
-        let temp_buffer = self.brightness_buffer.clone();
+    // Lerp the color toward `other` by `weight(x, y)` in [0, 1]. Normals, depth and
+    // reprojection are taken from whichever frame dominates, so the outline pass still
+    // lines up.
+    pub fn blend_with(&mut self, other: &Framebuffer, weight: impl Fn(usize, usize) -> f32 + Sync) {
+        if self.width == 0 || other.width != self.width || other.height != self.height {
+            return;
+        }
+        let width = self.width;
+
+        self.data.par_chunks_mut(width)
+            .zip(self.normal_buffer.par_chunks_mut(width))
+            .zip(self.z_buffer.par_chunks_mut(width))
+            .zip(self.reprojection_buffer.par_chunks_mut(width))
+            .enumerate()
+            .for_each(|(y, (((row, normals), depths), reprojections))| {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let t = weight(x, y).clamp(0.0, 1.0);
+                    let index = y * width + x;
+                    let src = other.data[index];
+                    let mix = |d: u8, s: u8| -> u8 { (d as f32 + (s as f32 - d as f32) * t).round() as u8 };
+                    *pixel = Pixel { r: mix(pixel.r, src.r), g: mix(pixel.g, src.g), b: mix(pixel.b, src.b), a: pixel.a };
+                    if t > 0.5 {
+                        normals[x] = other.normal_buffer[index];
+                        depths[x] = other.z_buffer[index];
+                        reprojections[x] = other.reprojection_buffer[index];
+                    }
+                }
+            });
+    }
+
+    pub fn apply_chromatic_aberration(&mut self, strength: f32) {
+        if self.width < 2 || self.height < 2 || strength == 0.0 {
+            return;
+        }
+
+        // Sample from an untouched copy so shifted channels don't feed into each other
+        let source = self.clone();
+        let width = self.width;
+        let center_x = (self.width as f32 - 1.0) * 0.5;
+        let center_y = (self.height as f32 - 1.0) * 0.5;
+        let max_radius = (center_x * center_x + center_y * center_y).sqrt();
+
+        // Offset in pixels grows linearly with the distance from the center,
+        // reaching `strength` pixels in the corners
+        let scale = strength / max_radius;
 
-        for y in 1..self.height - 1 {
-            for x in 1..self.width - 1 {
-                let current = temp_buffer[y * self.width + x] as f32;
-                let neighbors = [
-                    temp_buffer[(y - 1) * self.width + x] as f32,
-                    temp_buffer[(y + 1) * self.width + x] as f32,
-                    temp_buffer[y * self.width + (x - 1)] as f32,
-                    temp_buffer[y * self.width + (x + 1)] as f32,
-                ];
-                let blur = neighbors.iter().sum::<f32>() / 4.0;
-                let sharpened = current + sharpening_factor * (current - blur);
-                self.brightness_buffer[y * self.width + x] = sharpened.clamp(0.0, 255.0) as u8;
+        self.data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let dx = (x as f32 - center_x) * scale;
+                let dy = (y as f32 - center_y) * scale;
+                let red = source.sample_bilinear(x as f32 - dx, y as f32 - dy);
+                let blue = source.sample_bilinear(x as f32 + dx, y as f32 + dy);
+                pixel.r = red.r;
+                pixel.b = blue.b;
             }
+        });
+    }
+
+    // Smear every pixel along its motion vector: the average of `samples` points
+    // spread evenly over the path the surface took during one frame, centered on
+    // the pixel. Pixels without a motion vector are left alone.
+    pub fn apply_motion_blur(&mut self, samples: u32) {
+        if self.width == 0 || samples <= 1 {
+            return;
         }
+
+        let source = self.clone();
+        let width = self.width;
+        self.data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let Some((dx, dy)) = source.motion_vector(x, y) else {
+                    continue;
+                };
+                let mut sum = [0.0f32; 3];
+                for i in 0..samples {
+                    let t = i as f32 / (samples - 1) as f32 - 0.5;
+                    let sample = source.sample_bilinear(x as f32 + dx * t, y as f32 + dy * t);
+                    sum[0] += sample.r as f32;
+                    sum[1] += sample.g as f32;
+                    sum[2] += sample.b as f32;
+                }
+                let average = |channel: f32| (channel / samples as f32).round() as u8;
+                *pixel = Pixel { r: average(sum[0]), g: average(sum[1]), b: average(sum[2]), a: pixel.a };
+            }
+        });
     }
 
-    pub fn posterize_brightness(brightness: u8, levels: u8) -> u8 {
-        let step = 255.0 / (levels - 1) as f32;
-        ((brightness as f32 / step).round() * step) as u8
+    pub fn apply_glitch(&mut self, seed: u32, intensity: f32) {
+        if self.width == 0 || self.height == 0 {
+            return;
+        }
+
+        let source = self.data.clone();
+        let width = self.width;
+        let height = self.height;
+        let slice_count = ((height as f32 * 0.25 * intensity) as usize).max(1);
+        let max_shift = (width as f32 * 0.15 * intensity).max(1.0);
+
+        // Each slice gets a random row band, horizontal shift and channel rotation
+        let slices: Vec<(usize, usize, isize, u32)> = (0..slice_count as u32)
+            .map(|i| {
+                let key = seed.wrapping_mul(0x9e3779b9) ^ i.wrapping_mul(0x85ebca6b);
+                let start = (hash_f32(key) * height as f32) as usize;
+                let length = 1 + (hash_f32(key ^ 0x1) * 3.0) as usize;
+                let shift = ((hash_f32(key ^ 0x2) * 2.0 - 1.0) * max_shift) as isize;
+                let rotation = hash_u32(key ^ 0x3) % 3;
+                (start, (start + length).min(height), shift, rotation)
+            })
+            .collect();
+
+        self.data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for &(start, end, shift, rotation) in &slices {
+                if y < start || y >= end {
+                    continue;
+                }
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let src_x = (x as isize - shift).rem_euclid(width as isize) as usize;
+                    let src = source[y * width + src_x];
+                    *pixel = match rotation {
+                        1 => Pixel { r: src.g, g: src.b, b: src.r, a: src.a },
+                        2 => Pixel { r: src.b, g: src.r, b: src.g, a: src.a },
+                        _ => src,
+                    };
+                }
+            }
+        });
     }
-    
-    pub fn get_brightness(&self, x: usize, y: usize) -> u8 {
-        self.brightness_buffer[y * self.width + x]
+
+    fn scale_pixel(pixel: Pixel, factor: f32) -> Pixel {
+        Pixel {
+            r: (pixel.r as f32 * factor) as u8,
+            g: (pixel.g as f32 * factor) as u8,
+            b: (pixel.b as f32 * factor) as u8,
+            a: pixel.a,
+        }
+    }
+}
+
+// Pixels per block of the planar brightness pass, a multiple of the SIMD width
+const LANES: usize = 8;
+// Pixels per parallel work item of the planar brightness pass
+const BRIGHTNESS_CHUNK: usize = 1024;
+
+// The brightness math of compute_adjusted_brightness, applied to one block of
+// pixels at a time. Every step repeats the scalar helpers' f32 operations in the
+// same order, with the float to byte truncations done as truncations in f32, so
+// the result is bit-identical to the per-pixel path.
+struct BrightnessAdjust {
+    pipeline: ColorPipeline,
+    posterize: Levels,
+    posterize_after: bool,
+    brightness_factor: f32,
+    contrast_factor: f32,
+}
+
+// Posterization as the planar pass applies it
+#[derive(Clone, Copy)]
+enum Levels {
+    Off,
+    // A single level, everything goes black
+    Single,
+    // Number of intervals between the levels
+    Intervals(f32),
+}
+
+impl Levels {
+    fn apply(self, value: &mut [f32; LANES]) {
+        match self {
+            Levels::Off => {}
+            Levels::Single => value.fill(0.0),
+            Levels::Intervals(intervals) => {
+                for v in value.iter_mut() {
+                    let level = round_half_up(*v * intervals / 255.0);
+                    *v = round_half_up(level * 255.0 / intervals).min(255.0);
+                }
+            }
+        }
+    }
+}
+
+impl BrightnessAdjust {
+    fn new(posterize_levels: Option<u8>, order: PosterizeOrder, pipeline: ColorPipeline, brightness_factor: f32, contrast_factor: f32) -> Self {
+        let posterize = match posterize_levels {
+            None => Levels::Off,
+            Some(0 | 1) => Levels::Single,
+            Some(levels) => Levels::Intervals((levels - 1) as f32),
+        };
+        BrightnessAdjust {
+            pipeline,
+            posterize,
+            posterize_after: order == PosterizeOrder::AfterAdjust,
+            brightness_factor,
+            contrast_factor,
+        }
+    }
+
+    // `pixels` and `out` are the same length, at most LANES
+    fn apply(&self, pixels: &[Pixel], out: &mut [u8]) {
+        // Split the block into channel planes, padding a short tail block with black
+        let mut r = [0.0f32; LANES];
+        let mut g = [0.0f32; LANES];
+        let mut b = [0.0f32; LANES];
+        let [wr, wg, wb] = match self.pipeline {
+            ColorPipeline::Legacy => {
+                for (i, pixel) in pixels.iter().enumerate() {
+                    r[i] = pixel.r as f32;
+                    g[i] = pixel.g as f32;
+                    b[i] = pixel.b as f32;
+                }
+                REC601_WEIGHTS
+            }
+            ColorPipeline::Linear => {
+                let decode = &*SRGB_TO_LINEAR;
+                for (i, pixel) in pixels.iter().enumerate() {
+                    r[i] = decode[pixel.r as usize];
+                    g[i] = decode[pixel.g as usize];
+                    b[i] = decode[pixel.b as usize];
+                }
+                REC709_WEIGHTS
+            }
+        };
+
+        let mut value = [0.0f32; LANES];
+        for i in 0..LANES {
+            value[i] = truncate(wr * r[i] + wg * g[i] + wb * b[i]);
+        }
+        if !self.posterize_after {
+            self.posterize.apply(&mut value);
+        }
+        for v in value.iter_mut() {
+            *v = truncate((*v * self.brightness_factor).clamp(0.0, 255.0));
+        }
+        for v in value.iter_mut() {
+            let normalized = *v / 255.0;
+            let contrasted = ((normalized - 0.5) * self.contrast_factor + 0.5).clamp(0.0, 1.0);
+            *v = truncate(contrasted * 255.0);
+        }
+        if self.posterize_after {
+            self.posterize.apply(&mut value);
+        }
+
+        for (out, v) in out.iter_mut().zip(value) {
+            *out = v as u8;
+        }
+    }
+}
+
+// Same as `as u8` for values in [0, 255]; `as i32` is a single vector instruction
+// where the saturating byte cast is not
+fn truncate(value: f32) -> f32 {
+    value as i32 as f32
+}
+
+// f32::round for the non-negative values the brightness pass sees. Adding 0.5
+// and truncating would round 0.49999997 up, so compare the fraction instead.
+fn round_half_up(value: f32) -> f32 {
+    let whole = truncate(value);
+    if value - whole >= 0.5 { whole + 1.0 } else { whole }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Pixel = Pixel { r: 255, g: 0, b: 0, a: 255 };
+
+    // A framebuffer with every pixel set to `color`
+    fn filled(width: usize, height: usize, color: Pixel) -> Framebuffer {
+        let mut fb = Framebuffer::new(width, height);
+        fb.data.fill(color);
+        fb
+    }
+
+    #[test]
+    fn blit_clips_to_the_framebuffer() {
+        let mut fb = Framebuffer::new(4, 3);
+        fb.blit(&filled(3, 3, RED), 2, 1);
+        assert_eq!(fb.get_pixel(2, 1).to_rgb(), (255, 0, 0));
+        assert_eq!(fb.get_pixel(3, 2).to_rgb(), (255, 0, 0));
+        assert_eq!(fb.get_pixel(1, 1).to_rgb(), (0, 0, 0));
+        assert_eq!(fb.get_pixel(3, 0).to_rgb(), (0, 0, 0));
+    }
+
+    #[test]
+    fn blit_past_the_edge_draws_nothing() {
+        let mut fb = Framebuffer::new(4, 3);
+        let src = filled(2, 2, RED);
+        for (x, y) in [(4, 0), (9, 2), (0, 3), (7, 8)] {
+            fb.blit(&src, x, y);
+        }
+        assert!(fb.data.iter().all(|pixel| pixel.to_rgb() == (0, 0, 0)));
+    }
+
+    #[test]
+    fn chromatic_aberration_pulls_red_toward_the_center() {
+        // Red rising across and down, green rising down, blue flat
+        let (width, height) = (9, 5);
+        let mut fb = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                fb.set_pixel(x, y, Pixel { r: (20 * x + 10 * y) as u8, g: (30 * y) as u8, b: 90, a: 255 });
+            }
+        }
+        let original = fb.clone();
+        let strength = 2.0;
+        fb.apply_chromatic_aberration(strength);
+
+        assert_eq!(fb.get_pixel(4, 2).to_rgb(), original.get_pixel(4, 2).to_rgb());
+
+        // A corner is the farthest from the center, so its red comes from `strength`
+        // pixels along the diagonal toward it
+        let (dx, dy) = (4.0f32, 2.0f32);
+        let length = (dx * dx + dy * dy).sqrt();
+        let (source_x, source_y) = (8.0 - dx / length * strength, 4.0 - dy / length * strength);
+        let expected = (20.0 * source_x + 10.0 * source_y).round() as u8;
+        let corner = fb.get_pixel(8, 4);
+        assert_eq!(corner.r, expected);
+        assert_eq!(corner.g, original.get_pixel(8, 4).g);
+        assert_eq!(corner.b, 90);
+    }
+
+    #[test]
+    fn every_pass_survives_degenerate_sizes() {
+        for (width, height) in [(0, 0), (1, 1), (2, 2), (3, 1)] {
+            let mut fb = filled(width, height, RED);
+            let other = filled(width, height, RED);
+            fb.compute_brightness_buffer(Some(4), ColorPipeline::Legacy);
+            fb.compute_adjusted_brightness(Some(4), PosterizeOrder::AfterAdjust, ColorPipeline::Linear, 1.2, 1.5);
+            fb.luminance_histogram(ColorPipeline::Linear);
+            fb.apply_sharpening(1.0);
+            fb.convolve(&[0.0, -1.0, 0.0, -1.0, 5.0, -1.0, 0.0, -1.0, 0.0], 3, true);
+            fb.sharpen_color(1.0, 32);
+            fb.blur_color(2);
+            fb.apply_normal_outline(0.5, 1.0);
+            fb.apply_ordered_dithering(&ColorPalette::new(), DitherMatrix::Bayer4, 1.0, Some(1));
+            fb.apply_vignette(0.5, 0.5, 0.5);
+            fb.apply_white_balance(0.5);
+            fb.apply_scanlines(0.5);
+            fb.apply_barrel_distortion(0.5);
+            fb.apply_chromatic_aberration(2.0);
+            fb.apply_motion_blur(4);
+            fb.apply_glitch(7, 1.0);
+            fb.blend_with(&other, |_, _| 0.5);
+            let resampled = fb.resample_bilinear(width * 2, height * 2);
+            assert_eq!((resampled.width, resampled.height), (width * 2, height * 2));
+            let anaglyph = Framebuffer::combine_anaglyph(&fb, &other);
+            assert_eq!((anaglyph.width, anaglyph.height), (width, height));
+        }
+    }
+
+    #[test]
+    fn posterizing_to_one_level_is_black() {
+        for levels in [0, 1] {
+            assert_eq!(Framebuffer::posterize_brightness(200, levels), 0);
+        }
+        assert_eq!(Framebuffer::posterize_brightness(200, 2), 255);
+    }
+
+    #[test]
+    fn flat_surfaces_have_no_outline_and_silhouettes_do() {
+        let (width, height) = (15, 15);
+        let mut fb = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                fb.set_normal(x, y, Vec3::new(0.0, 1.0, 0.0));
+            }
+        }
+        assert!((0..width * height).all(|i| fb.normal_outline_response(i % width, i / width) == 0.0));
+
+        // A sphere of radius 5 facing the camera, nothing behind it
+        let mut fb = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let (dx, dy) = ((x as f32 - 7.0) / 5.5, (y as f32 - 7.0) / 5.5);
+                let z2 = 1.0 - dx * dx - dy * dy;
+                if z2 > 0.0 {
+                    fb.set_normal(x, y, Vec3::new(dx, -dy, -z2.sqrt()));
+                }
+            }
+        }
+        assert!(fb.normal_outline_response(7, 7) < 0.1);
+        assert!(fb.normal_outline_response(2, 7) >= 1.0);
+        assert!(fb.normal_outline_response(7, 12) >= 1.0);
+        assert_eq!(fb.normal_outline_response(0, 0), 0.0);
+    }
+
+    #[test]
+    fn checked_access_outside_the_framebuffer_fails() {
+        let mut fb = Framebuffer::new(4, 3);
+        assert!(fb.try_get_pixel(3, 2).is_some());
+        for (x, y) in [(4, 0), (0, 3), (usize::MAX, usize::MAX)] {
+            assert!(fb.try_get_pixel(x, y).is_none());
+        }
+        fb.set_pixel(3, 2, RED);
+        assert_eq!(fb.try_get_pixel(3, 2).map(|pixel| pixel.to_rgb()), Some((255, 0, 0)));
+        assert!(Framebuffer::new(0, 0).try_get_pixel(0, 0).is_none());
+
+        // Lines and text hanging off every edge draw only what is on screen
+        let mut fb = Framebuffer::new(4, 3);
+        fb.draw_line(-5, -2, 9, 7, RED);
+        fb.draw_line(2, -10, 2, 10, RED);
+        fb.draw_text(2, 1, "WIDE TEXT", RED);
+        assert_eq!(fb.get_pixel(2, 0).to_rgb(), (255, 0, 0));
+        assert_eq!(fb.get_pixel(2, 2).to_rgb(), (255, 0, 0));
+    }
+
+    #[test]
+    fn opaque_rects_overwrite_and_translucent_ones_blend() {
+        let mut fb = Framebuffer::new(6, 4);
+        fb.blit_rect(1, 1, 2, 2, RED);
+        assert_eq!(fb.get_pixel(1, 1).to_rgb(), (255, 0, 0));
+        assert_eq!(fb.get_pixel(2, 2).to_rgb(), (255, 0, 0));
+        assert_eq!(fb.get_pixel(3, 1).to_rgb(), (0, 0, 0));
+
+        // Half alpha white over red and over the black background, clipped at the edge
+        fb.blit_rect(2, 1, 10, 1, Pixel { r: 255, g: 255, b: 255, a: 128 });
+        assert_eq!(fb.get_pixel(2, 1).to_rgb(), (255, 128, 128));
+        assert_eq!(fb.get_pixel(5, 1).to_rgb(), (128, 128, 128));
+        assert_eq!(fb.get_pixel(1, 1).to_rgb(), (255, 0, 0));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn blending_follows_the_source_alpha() {
+        let mut fb = Framebuffer::new(2, 1);
+        fb.blend_pixel(0, 0, Pixel { r: 255, g: 255, b: 255, a: 128 });
+        assert_eq!(fb.get_pixel(0, 0).to_rgb(), (128, 128, 128));
+        assert_eq!(fb.get_pixel(0, 0).a, 255);
+        fb.blend_pixel(1, 0, Pixel { r: 10, g: 20, b: 30, a: 255 });
+        assert_eq!(fb.get_pixel(1, 0).to_rgb(), (10, 20, 30));
+
+        // Over a transparent pixel the coverage adds up rather than taking the larger
+        let mut fb = filled(1, 1, Pixel { r: 0, g: 0, b: 0, a: 0 });
+        fb.blend_pixel(0, 0, Pixel { r: 255, g: 255, b: 255, a: 128 });
+        fb.blend_pixel(0, 0, Pixel { r: 255, g: 255, b: 255, a: 128 });
+        assert_eq!(fb.get_pixel(0, 0).a, 192);
+    }
+
+    // Colors and normals that differ from pixel to pixel
+    fn noisy(width: usize, height: usize) -> Framebuffer {
+        let mut fb = Framebuffer::new(width, height);
+        for i in 0..width * height {
+            let [r, g, b, _] = hash_u32(i as u32).to_le_bytes();
+            fb.set_pixel(i % width, i / width, Pixel { r, g, b, a: 255 });
+            let angle = hash_f32(i as u32 + 7919) * 2.0;
+            fb.set_normal(i % width, i / width, Vec3::new(angle.cos(), angle.sin(), 0.0));
+        }
+        fb
+    }
+
+    // Plain single threaded loops doing what the parallel passes do, pixel by pixel
+    // in reading order, to check those against
+
+    fn serial_brightness(fb: &Framebuffer, pipeline: ColorPipeline, brightness: f32, contrast: f32) -> Vec<u8> {
+        let mut out = Vec::with_capacity(fb.data.len());
+        for pixel in &fb.data {
+            let ([r, g, b], [wr, wg, wb]) = match pipeline {
+                ColorPipeline::Legacy => ([pixel.r as f32, pixel.g as f32, pixel.b as f32], REC601_WEIGHTS),
+                ColorPipeline::Linear => ([pixel.r, pixel.g, pixel.b].map(|c| SRGB_TO_LINEAR[c as usize]), REC709_WEIGHTS),
+            };
+            out.push(serial_adjust((wr * r + wg * g + wb * b) as u8, brightness, contrast));
+        }
+        out
+    }
+
+    fn serial_adjust(value: u8, brightness: f32, contrast: f32) -> u8 {
+        let brightened = (value as f32 * brightness).clamp(0.0, 255.0) as u8;
+        let contrasted = ((brightened as f32 / 255.0 - 0.5) * contrast + 0.5).clamp(0.0, 1.0);
+        (contrasted * 255.0) as u8
+    }
+
+    // The sharpening kernel's terms summed in the same order as the convolution
+    fn serial_sharpen(source: &[u8], width: usize, height: usize, factor: f32) -> Vec<u8> {
+        let edge = -factor / 4.0;
+        let mut out = vec![0; source.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let at = |dx: isize, dy: isize| {
+                    let sx = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+                    let sy = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+                    source[sy * width + sx] as f32
+                };
+                let mut total = 0.0;
+                for (weight, dx, dy) in [(edge, 0, -1), (edge, -1, 0), (1.0 + factor, 0, 0), (edge, 1, 0), (edge, 0, 1)] {
+                    total += weight * at(dx, dy);
+                }
+                out[y * width + x] = total.clamp(0.0, 255.0) as u8;
+            }
+        }
+        out
+    }
+
+    fn serial_dither(fb: &Framebuffer, palette: &ColorPalette, matrix: DitherMatrix, strength: f32, frame_parity: Option<u32>) -> Vec<(u8, u8, u8)> {
+        let mut out = Vec::with_capacity(fb.data.len());
+        for y in 0..fb.height {
+            for x in 0..fb.width {
+                let pixel = fb.get_pixel(x, y);
+                let color = match frame_parity {
+                    Some(frame) => {
+                        let (nearest, second, weight) = palette.two_closest(pixel.r, pixel.g, pixel.b);
+                        if weight >= TEMPORAL_DITHER_MIN_WEIGHT && matrix.animated_threshold(x, y, frame) < weight { second } else { nearest }
+                    }
+                    None => {
+                        let offset = (matrix.threshold(x, y) * 255.0 - 128.0) * strength;
+                        let dither = |c: u8| (c as f32 + offset).clamp(0.0, 255.0) as u8;
+                        palette.closest_color(dither(pixel.r), dither(pixel.g), dither(pixel.b))
+                    }
+                };
+                out.push(color);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn parallel_passes_match_a_single_thread() {
+        let pass = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let mut fb = noisy(37, 23);
+                fb.apply_normal_outline(0.3, 0.8);
+                fb.apply_vignette(0.7, 0.2, 1.5);
+                fb.apply_scanlines(0.6);
+                fb.apply_barrel_distortion(0.3);
+                fb.data.iter().map(|pixel| pixel.to_rgb()).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(pass(1), pass(4));
+
+        // The brightness, contrast, sharpening and dither passes against their serial
+        // versions, on as many threads as rayon likes and on several
+        let palette = ColorPalette::new();
+        let fb = noisy(37, 23);
+        for threads in [1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                for pipeline in [ColorPipeline::Legacy, ColorPipeline::Linear] {
+                    for (brightness, contrast) in [(1.0, 1.0), (1.3, 1.1), (0.7, 1.8)] {
+                        let mut parallel = fb.clone();
+                        parallel.compute_adjusted_brightness(None, PosterizeOrder::BeforeAdjust, pipeline, brightness, contrast);
+                        let serial = serial_brightness(&fb, pipeline, brightness, contrast);
+                        assert!(parallel.brightness_buffer == serial, "{:?} {} {} on {}", pipeline, brightness, contrast, threads);
+
+                        for factor in [0.5, 1.25, 3.0] {
+                            let mut sharpened = parallel.clone();
+                            sharpened.apply_sharpening(factor);
+                            assert!(sharpened.brightness_buffer == serial_sharpen(&serial, fb.width, fb.height, factor), "sharpen {} on {}", factor, threads);
+                        }
+                    }
+                }
+                for matrix in [DitherMatrix::Bayer2, DitherMatrix::Bayer8, DitherMatrix::BlueNoise] {
+                    for (strength, frame_parity) in [(0.1, None), (0.6, None), (0.3, Some(5))] {
+                        let mut parallel = fb.clone();
+                        parallel.apply_ordered_dithering(&palette, matrix, strength, frame_parity);
+                        let dithered: Vec<_> = parallel.data.iter().map(|pixel| pixel.to_rgb()).collect();
+                        assert!(dithered == serial_dither(&fb, &palette, matrix, strength, frame_parity), "{:?} {} {:?} on {}", matrix, strength, frame_parity, threads);
+                    }
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn palette_without_colors_is_an_error() {
+        assert!(matches!(ColorPalette::from_colors(Vec::new()), Err(RenderError::EmptyPalette)));
+        let palette = ColorPalette::from_colors(vec![(10, 20, 30)]).unwrap();
+        assert_eq!(palette.closest_color(255, 255, 255), (10, 20, 30));
+    }
+
+    #[test]
+    fn vignette_darkens_corners_by_the_strength() {
+        let gray = Pixel { r: 200, g: 200, b: 200, a: 255 };
+        for (strength, corner) in [(0.0, 200), (0.25, 150), (0.5, 100), (1.0, 0)] {
+            let mut fb = filled(9, 5, gray);
+            fb.apply_vignette(strength, 0.0, 1.0);
+            assert_eq!(fb.get_pixel(4, 2).to_rgb(), (200, 200, 200));
+            for (x, y) in [(0, 0), (8, 0), (0, 4), (8, 4)] {
+                assert_eq!(fb.get_pixel(x, y).to_rgb(), (corner, corner, corner), "strength {}", strength);
+            }
+        }
+    }
+
+    // Vertical step edge: `low` left of column 4, `high` from it on
+    fn step_edge(low: u8, high: u8) -> Framebuffer {
+        let mut fb = Framebuffer::new(8, 5);
+        for y in 0..5 {
+            for x in 0..8 {
+                let level = if x < 4 { low } else { high };
+                fb.set_pixel(x, y, Pixel { r: level, g: level, b: level, a: 255 });
+            }
+        }
+        fb
+    }
+
+    #[test]
+    fn color_sharpening_overshoots_within_the_clamp() {
+        let row = |fb: &Framebuffer| (0..8).map(|x| fb.get_pixel(x, 2).r).collect::<Vec<_>>();
+
+        let mut fb = step_edge(60, 180);
+        fb.sharpen_color(0.75, u8::MAX);
+        let sharpened = row(&fb);
+        // Flat areas stay, the two columns at the edge overshoot outward
+        assert_eq!(&sharpened[..3], [60, 60, 60]);
+        assert_eq!(&sharpened[5..], [180, 180, 180]);
+        assert_eq!(sharpened[3], 30);
+        assert_eq!(sharpened[4], 210);
+
+        // Strong enough to overshoot past black and white, it stops at them instead of wrapping
+        let mut fb = step_edge(20, 235);
+        fb.sharpen_color(4.0, u8::MAX);
+        assert_eq!(row(&fb), [20, 20, 20, 0, 255, 235, 235, 235]);
+
+        // The configured overshoot caps the halo on either side, whatever the amount
+        for overshoot in [0, 10, 25] {
+            for amount in [0.75, 4.0] {
+                let mut fb = step_edge(60, 180);
+                fb.sharpen_color(amount, overshoot);
+                assert_eq!(row(&fb), [60, 60, 60, 60 - overshoot, 180 + overshoot, 180, 180, 180], "{} {}", overshoot, amount);
+            }
+        }
+
+        // The blur spreads the step into a ramp without leaving the two levels
+        let mut fb = step_edge(60, 180);
+        fb.blur_color(1);
+        assert_eq!(row(&fb), [60, 60, 60, 100, 140, 180, 180, 180]);
+    }
+
+    #[test]
+    fn bilinear_upscale_blends_a_checkerboard() {
+        let mut fb = Framebuffer::new(2, 2);
+        fb.set_pixel(0, 0, Pixel { r: 255, g: 255, b: 255, a: 255 });
+        fb.set_pixel(1, 1, Pixel { r: 255, g: 255, b: 255, a: 255 });
+        let resampled = fb.resample_bilinear(4, 4);
+        let gray = |x, y| resampled.get_pixel(x, y).to_rgb().0;
+
+        // Samples past the edge clamp to the source corners
+        assert_eq!((gray(0, 0), gray(3, 0), gray(0, 3), gray(3, 3)), (255, 0, 0, 255));
+        // In between, a quarter of the way from one texel to the next
+        assert_eq!((gray(1, 1), gray(2, 1), gray(1, 2), gray(2, 2)), (159, 96, 96, 159));
+        assert!(resampled.data.iter().all(|pixel| pixel.r == pixel.g && pixel.g == pixel.b));
+    }
+
+    #[test]
+    fn fused_brightness_pass_is_bit_identical_to_the_separate_passes() {
+        // Every gray level plus noise, with a tail shorter than a block of lanes
+        let mut fb = noisy(37, 29);
+        for v in 0..=255u8 {
+            fb.set_pixel(v as usize % 37, v as usize / 37, Pixel { r: v, g: v, b: v, a: 255 });
+        }
+        for pipeline in [ColorPipeline::Legacy, ColorPipeline::Linear] {
+            for order in [PosterizeOrder::BeforeAdjust, PosterizeOrder::AfterAdjust] {
+                for levels in [None, Some(1), Some(2), Some(5), Some(16)] {
+                    for (brightness, contrast) in [(1.0, 1.0), (1.3, 1.1), (0.7, 1.8), (2.5, 0.4)] {
+                        let mut scalar = fb.clone();
+                        let before = if order == PosterizeOrder::BeforeAdjust { levels } else { None };
+                        scalar.compute_brightness_buffer(before, pipeline);
+                        for value in scalar.brightness_buffer.iter_mut() {
+                            *value = serial_adjust(*value, brightness, contrast);
+                            if let (PosterizeOrder::AfterAdjust, Some(levels)) = (order, levels) {
+                                *value = Framebuffer::posterize_brightness(*value, levels);
+                            }
+                        }
+
+                        let mut fused = fb.clone();
+                        fused.compute_adjusted_brightness(levels, order, pipeline, brightness, contrast);
+                        assert!(
+                            fused.brightness_buffer == scalar.brightness_buffer,
+                            "{:?} {:?} {:?} {} {}",
+                            pipeline,
+                            order,
+                            levels,
+                            brightness,
+                            contrast
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // A width x height framebuffer with the given brightness, row by row
+    fn with_brightness(width: usize, height: usize, brightness: &[u8]) -> Framebuffer {
+        let mut fb = Framebuffer::new(width, height);
+        fb.brightness_buffer.copy_from_slice(brightness);
+        fb
+    }
+
+    #[test]
+    fn identity_kernel_leaves_the_brightness_unchanged() {
+        let brightness: Vec<u8> = (0..20).map(|i| (i * 37 % 256) as u8).collect();
+        let mut fb = with_brightness(5, 4, &brightness);
+        fb.convolve(&[0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0], 3, false);
+        assert_eq!(fb.brightness_buffer, brightness);
+        fb.convolve(&[0.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 0.0], 3, true);
+        assert_eq!(fb.brightness_buffer, brightness);
+    }
+
+    #[test]
+    fn box_kernel_averages_with_clamped_edges() {
+        let mut fb = with_brightness(3, 3, &[0, 10, 20, 30, 40, 50, 60, 70, 80]);
+        fb.convolve(&[1.0; 9], 3, true);
+        // The corner counts itself four times, its edge neighbors twice: 120 / 9.
+        // The top middle sees the top row twice and the middle row once: 180 / 9.
+        assert_eq!(fb.brightness_buffer, [13, 20, 26, 33, 40, 46, 53, 60, 66]);
+    }
+
+    #[test]
+    fn posterize_is_a_monotonic_quantization_for_every_level_count() {
+        for levels in 1..=255u8 {
+            let outputs: Vec<u8> = (0..=255).map(|v| Framebuffer::posterize_brightness(v, levels)).collect();
+            assert!(outputs.windows(2).all(|pair| pair[0] <= pair[1]), "levels {}", levels);
+            let mut distinct = outputs.clone();
+            distinct.dedup();
+            if levels == 1 {
+                assert_eq!(distinct, [0]);
+                continue;
+            }
+            // Exactly `levels` outputs, evenly spread from black to white
+            assert_eq!(distinct.len(), levels as usize, "levels {}", levels);
+            assert_eq!((distinct[0], distinct[distinct.len() - 1]), (0, 255), "levels {}", levels);
+            // Each within half a unit of i * 255 / (levels - 1), in integers
+            let intervals = levels as i32 - 1;
+            for (i, &level) in distinct.iter().enumerate() {
+                assert!((level as i32 * intervals - i as i32 * 255).abs() * 2 <= intervals, "levels {}: {}", levels, level);
+            }
+            // Idempotent: a level posterizes to itself
+            assert!(distinct.iter().all(|&level| Framebuffer::posterize_brightness(level, levels) == level), "levels {}", levels);
+        }
+    }
+
+    #[test]
+    fn dither_strength_zero_is_nearest_color() {
+        let palette = ColorPalette::from_colors(vec![(0, 0, 0), (255, 255, 255), (255, 0, 0)]).unwrap();
+        let source = noisy(16, 12);
+        let mut fb = source.clone();
+        fb.apply_ordered_dithering(&palette, DitherMatrix::Bayer4, 0.0, None);
+        for (dithered, pixel) in fb.data.iter().zip(&source.data) {
+            assert_eq!(dithered.to_rgb(), palette.closest_color(pixel.r, pixel.g, pixel.b));
+        }
+    }
+
+    #[test]
+    fn dither_strength_perturbs_before_quantizing() {
+        let palette = ColorPalette::from_colors(vec![(0, 0, 0), (255, 255, 255)]).unwrap();
+        let white_share = |strength: f32| {
+            let mut fb = filled(8, 8, Pixel { r: 100, g: 100, b: 100, a: 255 });
+            fb.apply_ordered_dithering(&palette, DitherMatrix::Bayer4, strength, None);
+            fb.data.iter().filter(|pixel| pixel.to_rgb() == (255, 255, 255)).count() as f32 / 64.0
+        };
+        // Gray 100 is nearer black, but at full strength the pattern lifts part of it to white
+        assert_eq!(white_share(0.0), 0.0);
+        assert!(white_share(0.1) < white_share(1.0));
+        assert!((white_share(1.0) - 100.0 / 255.0).abs() < 0.1, "{}", white_share(1.0));
+        // Past the maximum the strength is clamped
+        assert_eq!(white_share(5.0), white_share(MAX_DITHER_STRENGTH));
+    }
+
+    #[test]
+    fn resizing_keeps_every_buffer_in_step() {
+        let mut fb = noisy(8, 6);
+        let allocation = fb.data.as_ptr();
+        for (width, height) in [(3, 2), (8, 6), (10, 1), (0, 0), (1, 7), (5, 5)] {
+            fb.resize(width, height);
+            let size = width * height;
+            assert_eq!((fb.data.len(), fb.z_buffer.len(), fb.brightness_buffer.len()), (size, size, size));
+            assert_eq!((fb.normal_buffer.len(), fb.reprojection_buffer.len()), (size, size));
+            if size > 0 {
+                fb.set_pixel(width - 1, height - 1, RED);
+                fb.compute_brightness_buffer(None, ColorPipeline::Legacy);
+                assert!(fb.get_brightness(width - 1, height - 1) > 0);
+            }
+            // Cleared, apart from what was just drawn
+            assert_eq!(fb.data.iter().filter(|pixel| pixel.to_rgb() != (0, 0, 0)).count(), size.min(1));
+        }
+        // Never larger than the first size, so the color buffer was never reallocated
+        assert_eq!(fb.data.as_ptr(), allocation);
+    }
+
+    #[test]
+    fn white_balance_warms_and_cools() {
+        let gray = Pixel { r: 100, g: 100, b: 100, a: 200 };
+        let balanced = |temperature: f32| {
+            let mut fb = filled(3, 2, gray);
+            fb.set_pixel(1, 1, Pixel { r: 250, g: 30, b: 240, a: 255 });
+            fb.apply_white_balance(temperature);
+            fb
+        };
+
+        // Neutral leaves every pixel as it was
+        let neutral = balanced(0.0);
+        assert!(neutral.data.iter().zip(&balanced(0.0).data).all(|(a, b)| a.to_rgb() == b.to_rgb()));
+        assert_eq!(neutral.get_pixel(0, 0).to_rgb(), (100, 100, 100));
+
+        // Warm is more red and less blue, cool the other way round, green and alpha untouched
+        let warm = *balanced(0.5).get_pixel(0, 0);
+        assert_eq!((warm.r, warm.g, warm.b, warm.a), (115, 100, 85, 200));
+        let cool = *balanced(-0.5).get_pixel(0, 0);
+        assert_eq!((cool.r, cool.g, cool.b, cool.a), (85, 100, 115, 200));
+        // Brighter channels clip rather than wrap, and anything past 1 is 1
+        assert_eq!(balanced(1.0).get_pixel(1, 1).to_rgb(), (255, 30, 168));
+        assert_eq!(balanced(4.0).get_pixel(0, 0).to_rgb(), balanced(1.0).get_pixel(0, 0).to_rgb());
+        // And a stronger temperature tints further
+        let reds: Vec<u8> = [0.0, 0.25, 0.5, 1.0].iter().map(|&t| balanced(t).get_pixel(0, 0).r).collect();
+        assert!(reds.windows(2).all(|pair| pair[1] > pair[0]), "{:?}", reds);
+    }
+
+    #[test]
+    fn clear_fills_with_the_clear_color() {
+        let mut fb = filled(5, 3, RED);
+        // Black until set
+        fb.clear();
+        assert!(fb.data.iter().all(|pixel| (pixel.to_rgb(), pixel.a) == ((0, 0, 0), 255)));
+
+        let teal = Pixel { r: 0, g: 128, b: 128, a: 200 };
+        fb.set_clear_color(teal);
+        fb.set_pixel(2, 1, RED);
+        fb.clear();
+        assert!(fb.data.iter().all(|pixel| (pixel.to_rgb(), pixel.a) == ((0, 128, 128), 200)));
+        // And it holds through a resize
+        fb.resize(7, 4);
+        assert_eq!(fb.data.len(), 28);
+        assert!(fb.data.iter().all(|pixel| (pixel.to_rgb(), pixel.a) == ((0, 128, 128), 200)));
+    }
+
+    // Brightness of a few reference colors in each pipeline. Legacy weighs the stored
+    // values with Rec. 601; linear decodes them first, so mid gray drops to 55 and it
+    // takes sRGB 188 to reach half, and weighs green even more over blue.
+    #[test]
+    fn color_pipelines_golden_brightness() {
+        let colors = [(0, 0, 0), (255, 255, 255), (128, 128, 128), (255, 0, 0), (0, 255, 0), (0, 0, 255), (188, 188, 188)];
+        let mut fb = Framebuffer::new(colors.len(), 1);
+        for (x, &(r, g, b)) in colors.iter().enumerate() {
+            fb.set_pixel(x, 0, Pixel { r, g, b, a: 255 });
+        }
+        let brightness = |pipeline| {
+            let mut fb = fb.clone();
+            fb.compute_brightness_buffer(None, pipeline);
+            fb.brightness_buffer.clone()
+        };
+        assert_eq!(brightness(ColorPipeline::Legacy), [0, 255, 128, 76, 149, 29, 188]);
+        assert_eq!(brightness(ColorPipeline::Linear), [0, 255, 55, 54, 182, 18, 128]);
+    }
+}
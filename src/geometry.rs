@@ -0,0 +1,105 @@
+// How framebuffer pixels map onto terminal cells
+
+pub const DEFAULT_CELL_ASPECT: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)] // Only Ascii has a renderer so far
+pub enum PixelFormat {
+    // One pixel per cell
+    Ascii,
+    // Two vertically stacked pixels per cell (upper/lower half block)
+    HalfBlock,
+    // 2x4 dot matrix per cell
+    Braille,
+    // One pixel per font pixel
+    Sixel { font_width: usize, font_height: usize },
+}
+
+impl PixelFormat {
+    // Framebuffer pixels per cell horizontally and vertically
+    pub fn subpixels(&self) -> (usize, usize) {
+        match *self {
+            PixelFormat::Ascii => (1, 1),
+            PixelFormat::HalfBlock => (1, 2),
+            PixelFormat::Braille => (2, 4),
+            PixelFormat::Sixel { font_width, font_height } => (font_width.max(1), font_height.max(1)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputGeometry {
+    pub cells_w: usize,
+    pub cells_h: usize,
+    pub subpixels_x: usize,
+    pub subpixels_y: usize,
+    // Height / width of a terminal cell
+    pub cell_aspect: f32,
+}
+
+impl OutputGeometry {
+    pub fn new(cells_w: usize, cells_h: usize, format: PixelFormat, cell_aspect: f32) -> Self {
+        let (subpixels_x, subpixels_y) = format.subpixels();
+        OutputGeometry {
+            cells_w,
+            cells_h,
+            subpixels_x,
+            subpixels_y,
+            cell_aspect,
+        }
+    }
+
+    // Never smaller than 1x1, so a collapsed terminal still gets a valid framebuffer
+    pub fn framebuffer_size(&self) -> (usize, usize) {
+        ((self.cells_w * self.subpixels_x).max(1), (self.cells_h * self.subpixels_y).max(1))
+    }
+
+    // Height / width of a single framebuffer pixel on screen
+    pub fn pixel_aspect(&self) -> f32 {
+        self.cell_aspect * self.subpixels_x as f32 / self.subpixels_y as f32
+    }
+
+    // Top-left framebuffer pixel covered by a cell
+    pub fn cell_origin(&self, cell_x: usize, cell_y: usize) -> (usize, usize) {
+        (cell_x * self.subpixels_x, cell_y * self.subpixels_y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framebuffer_is_never_empty() {
+        for format in [PixelFormat::Ascii, PixelFormat::HalfBlock, PixelFormat::Braille] {
+            let geometry = OutputGeometry::new(0, 0, format, DEFAULT_CELL_ASPECT);
+            assert_eq!(geometry.framebuffer_size(), (1, 1));
+        }
+        assert_eq!(OutputGeometry::new(3, 1, PixelFormat::Braille, DEFAULT_CELL_ASPECT).framebuffer_size(), (6, 4));
+        assert_eq!(OutputGeometry::new(2, 0, PixelFormat::HalfBlock, DEFAULT_CELL_ASPECT).framebuffer_size(), (2, 1));
+    }
+
+    #[test]
+    fn framebuffer_size_per_format() {
+        let sixel = PixelFormat::Sixel { font_width: 8, font_height: 16 };
+        for (format, expected) in [
+            (PixelFormat::Ascii, (80, 24)),
+            (PixelFormat::HalfBlock, (80, 48)),
+            (PixelFormat::Braille, (160, 96)),
+            (sixel, (640, 384)),
+        ] {
+            assert_eq!(OutputGeometry::new(80, 24, format, DEFAULT_CELL_ASPECT).framebuffer_size(), expected, "{:?}", format);
+        }
+        assert_eq!(PixelFormat::Sixel { font_width: 0, font_height: 0 }.subpixels(), (1, 1));
+    }
+
+    #[test]
+    fn pixels_keep_the_cell_aspect() {
+        let aspect = |format| OutputGeometry::new(80, 24, format, DEFAULT_CELL_ASPECT).pixel_aspect();
+        assert_eq!(aspect(PixelFormat::Ascii), 2.0);
+        assert_eq!(aspect(PixelFormat::HalfBlock), 1.0);
+        assert_eq!(aspect(PixelFormat::Braille), 1.0);
+        let braille = OutputGeometry::new(80, 24, PixelFormat::Braille, DEFAULT_CELL_ASPECT);
+        assert_eq!(braille.cell_origin(3, 2), (6, 8));
+    }
+}
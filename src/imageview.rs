@@ -0,0 +1,151 @@
+use crate::framebuffer::Framebuffer;
+use crate::pixel::Pixel;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// Decodes a PNG into a framebuffer at its native size. Transparent pixels are
+// composited over black, since the terminal has no alpha.
+pub fn load_png(path: &Path) -> io::Result<Framebuffer> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let bytes = &buffer[..info.buffer_size()];
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        // Expanded to RGB by the normalize transformation
+        png::ColorType::Indexed => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpanded indexed PNG"));
+        }
+    };
+
+    let width = info.width as usize;
+    let height = info.height as usize;
+    let mut image = Framebuffer::new(width, height);
+    for (index, texel) in bytes.chunks_exact(channels).take(width * height).enumerate() {
+        let (r, g, b, a) = match *texel {
+            [l] => (l, l, l, 255),
+            [l, a] => (l, l, l, a),
+            [r, g, b] => (r, g, b, 255),
+            [r, g, b, a] => (r, g, b, a),
+            _ => unreachable!(),
+        };
+        let over_black = |c: u8| ((c as u32 * a as u32 + 127) / 255) as u8;
+        image.set_pixel(index % width, index / width, Pixel { r: over_black(r), g: over_black(g), b: over_black(b), a: 255 });
+    }
+    Ok(image)
+}
+
+// Scale `image` to the largest size that fits `fb` without distorting it on screen,
+// centered with black bars. `pixel_aspect` is the height / width of an `fb` pixel.
+pub fn fit_image(fb: &mut Framebuffer, image: &Framebuffer, pixel_aspect: f32) {
+    if fb.width == 0 || fb.height == 0 || image.width == 0 || image.height == 0 {
+        return;
+    }
+
+    // Framebuffer columns per image pixel; rows get 1 / pixel_aspect as many
+    let scale = (fb.width as f32 / image.width as f32).min(fb.height as f32 * pixel_aspect / image.height as f32);
+    let scale_y = scale / pixel_aspect;
+    let fit_w = image.width as f32 * scale;
+    let fit_h = image.height as f32 * scale_y;
+    let left = (fb.width as f32 - fit_w) * 0.5;
+    let top = (fb.height as f32 - fit_h) * 0.5;
+
+    let background = Pixel { r: 0, g: 0, b: 0, a: 255 };
+    if scale >= 1.0 && scale_y >= 1.0 {
+        // Magnifying: interpolate between texel centers
+        let scaled = image.resample_bilinear(fit_w.round() as usize, fit_h.round() as usize);
+        let left = (fb.width - scaled.width) / 2;
+        let top = (fb.height - scaled.height) / 2;
+        let width = fb.width;
+        fb.data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            row.fill(background);
+            if (top..top + scaled.height).contains(&y) {
+                let source = &scaled.data[(y - top) * scaled.width..(y - top + 1) * scaled.width];
+                row[left..left + scaled.width].copy_from_slice(source);
+            }
+        });
+        return;
+    }
+
+    let width = fb.width;
+    fb.data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            // Footprint of this framebuffer pixel in image coordinates
+            let x0 = (x as f32 - left) / scale;
+            let y0 = (y as f32 - top) / scale_y;
+            let x1 = x0 + 1.0 / scale;
+            let y1 = y0 + 1.0 / scale_y;
+            *pixel = if x1 <= 0.0 || y1 <= 0.0 || x0 >= image.width as f32 || y0 >= image.height as f32 {
+                background
+            } else {
+                average_area(image, x0, y0, x1, y1)
+            };
+        }
+    });
+}
+
+// Box-filtered average of the texels overlapping [x0, x1) x [y0, y1), so shrinking a
+// large image doesn't alias
+fn average_area(image: &Framebuffer, x0: f32, y0: f32, x1: f32, y1: f32) -> Pixel {
+    let start_x = x0.max(0.0) as usize;
+    let start_y = y0.max(0.0) as usize;
+    let end_x = (x1.ceil() as usize).clamp(start_x + 1, image.width);
+    let end_y = (y1.ceil() as usize).clamp(start_y + 1, image.height);
+
+    let mut sum = [0u32; 3];
+    for sy in start_y..end_y {
+        for sx in start_x..end_x {
+            let texel = image.get_pixel(sx, sy);
+            sum[0] += texel.r as u32;
+            sum[1] += texel.g as u32;
+            sum[2] += texel.b as u32;
+        }
+    }
+    let count = ((end_x - start_x) * (end_y - start_y)) as u32;
+    let average = |total: u32| ((total + count / 2) / count) as u8;
+    Pixel { r: average(sum[0]), g: average(sum[1]), b: average(sum[2]), a: 255 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::postprocess::ColorPipeline;
+    use std::io::BufWriter;
+
+    // A grayscale PNG of `width` x `height` in the temp directory
+    fn write_gray_png(name: &str, width: u32, height: u32, texels: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ascii_sobel-{}-{}.png", name, std::process::id()));
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(&path).unwrap()), width, height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header().unwrap().write_image_data(texels).unwrap();
+        path
+    }
+
+    #[test]
+    fn loaded_image_scales_to_the_expected_brightness() {
+        // Brightness rising by 50 a column and 10 a row
+        let texels: Vec<u8> = (0..16).map(|i| (i % 4 * 50 + i / 4 * 10) as u8).collect();
+        let path = write_gray_png("image", 4, 4, &texels);
+        let image = load_png(&path);
+        let _ = std::fs::remove_file(&path);
+        let image = image.unwrap();
+        assert_eq!((image.width, image.height), (4, 4));
+
+        // Cells twice as tall as wide: each one averages two rows of a column
+        let mut fb = Framebuffer::new(4, 2);
+        fit_image(&mut fb, &image, 2.0);
+        fb.compute_brightness_buffer(None, ColorPipeline::Legacy);
+        let brightness: Vec<u8> = (0..8).map(|i| fb.get_brightness(i % 4, i / 4)).collect();
+        assert_eq!(brightness, [5, 55, 105, 155, 25, 75, 125, 175]);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::terminal::lock_terminal;
+use ncurses::{getch, ERR};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::cell::Cell;
+use std::time::Duration;
+use std::thread::{self, JoinHandle};
+
+// Longest the thread waits for a key before checking whether it should stop
+const POLL_TIMEOUT_MS: i32 = 20;
+
+// ESC presses read but not yet drained by the render loop
+static QUIT_QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+// ESC is ASCII 27
+pub const QUIT_KEY: i32 = 27;
+
+// Reads keys on its own thread, so presses between slow frames are neither delayed
+// nor dropped. The render loop drains everything that arrived since the last frame.
+pub struct InputThread {
+    receiver: Receiver<i32>,
+    // A key `wait` took off the channel, handed out by the next `drain`
+    waited: Cell<Option<i32>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl InputThread {
+    // Expects ncurses to be set up with nodelay, so getch never blocks while it
+    // holds the terminal
+    pub fn spawn() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                let key = {
+                    let _terminal = lock_terminal();
+                    getch()
+                };
+                if key == ERR {
+                    wait_for_stdin(POLL_TIMEOUT_MS);
+                    continue;
+                }
+                count_quit(key);
+                if sender.send(key).is_err() {
+                    break;
+                }
+            }
+        });
+        InputThread { receiver, waited: Cell::new(None), stop, handle: Some(handle) }
+    }
+
+    // Every key pressed since the last call, oldest first
+    pub fn drain(&self) -> Vec<i32> {
+        let mut keys: Vec<i32> = self.waited.take().into_iter().collect();
+        keys.extend(drain_events(&self.receiver));
+        QUIT_QUEUED.fetch_sub(keys.iter().filter(|&&key| key == QUIT_KEY).count(), Ordering::Relaxed);
+        keys
+    }
+
+    // Block until a key arrives or the timeout passes, leaving the key for `drain`
+    pub fn wait(&self, timeout: Duration) {
+        let waited = self.waited.take();
+        self.waited.set(waited.or_else(|| self.receiver.recv_timeout(timeout).ok()));
+    }
+}
+
+impl Drop for InputThread {
+    // Stops the thread before ncurses is torn down
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Whether a quit key is waiting for the render loop
+pub fn quit_queued() -> bool {
+    QUIT_QUEUED.load(Ordering::Relaxed) > 0
+}
+
+fn count_quit(key: i32) {
+    if key == QUIT_KEY {
+        QUIT_QUEUED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn drain_events(receiver: &Receiver<i32>) -> Vec<i32> {
+    receiver.try_iter().collect()
+}
+
+// Sleep until stdin is readable or the timeout passes
+fn wait_for_stdin(timeout_ms: i32) {
+    let mut poll_fd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An input thread fed by the test instead of the terminal
+    fn fed_by_test() -> (InputThread, mpsc::Sender<i32>) {
+        let (sender, receiver) = mpsc::channel();
+        let input = InputThread { receiver, waited: Cell::new(None), stop: Arc::new(AtomicBool::new(false)), handle: None };
+        (input, sender)
+    }
+
+    fn send(sender: &mpsc::Sender<i32>, keys: &[i32]) {
+        for &key in keys {
+            count_quit(key);
+            sender.send(key).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_burst_of_keys_drains_in_order() {
+        let (input, sender) = fed_by_test();
+        let burst: Vec<i32> = (0..200).map(|i| 'a' as i32 + i % 26).collect();
+        send(&sender, &burst);
+        assert_eq!(input.drain(), burst);
+        assert!(input.drain().is_empty());
+
+        // A key taken by wait comes out first, ahead of the ones behind it
+        send(&sender, &[1, 2, 3]);
+        input.wait(Duration::from_millis(10));
+        send(&sender, &[4]);
+        assert_eq!(input.drain(), [1, 2, 3, 4]);
+
+        // Waiting with nothing queued times out and leaves nothing behind
+        input.wait(Duration::from_millis(1));
+        assert!(input.drain().is_empty());
+    }
+
+    #[test]
+    fn only_a_quit_key_counts_as_a_quit() {
+        let (input, sender) = fed_by_test();
+        send(&sender, &['a' as i32, 'q' as i32, 32]);
+        assert!(!quit_queued());
+        send(&sender, &[QUIT_KEY]);
+        assert!(quit_queued());
+        assert_eq!(input.drain(), ['a' as i32, 'q' as i32, 32, QUIT_KEY]);
+        assert!(!quit_queued());
+    }
+}
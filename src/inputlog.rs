@@ -0,0 +1,66 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Key presses as (seconds since start, keycode) pairs, stored as a JSON array of
+// two-element arrays so the file stays readable and easy to edit by hand
+
+pub struct InputRecorder {
+    path: PathBuf,
+    events: Vec<(f32, i32)>,
+}
+
+impl InputRecorder {
+    pub fn new(path: &Path) -> Self {
+        InputRecorder { path: path.to_path_buf(), events: Vec::new() }
+    }
+
+    pub fn record(&mut self, time: f32, key: i32) {
+        self.events.push((time, key));
+    }
+
+    pub fn save(&self) -> io::Result<&Path> {
+        let lines: Vec<String> = self.events.iter().map(|(time, key)| format!("  [{}, {}]", time, key)).collect();
+        let json = if lines.is_empty() { "[]\n".to_string() } else { format!("[\n{}\n]\n", lines.join(",\n")) };
+        fs::write(&self.path, json)?;
+        Ok(&self.path)
+    }
+}
+
+pub struct InputReplay {
+    events: Vec<(f32, i32)>,
+    next: usize,
+}
+
+impl InputReplay {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{} in {}", what, path.display()));
+
+        let tokens: Vec<&str> = text
+            .split(|c: char| c == '[' || c == ']' || c == ',' || c.is_whitespace())
+            .filter(|token| !token.is_empty())
+            .collect();
+        if !tokens.len().is_multiple_of(2) {
+            return Err(invalid("odd number of values"));
+        }
+        let mut events = Vec::with_capacity(tokens.len() / 2);
+        for pair in tokens.chunks(2) {
+            let time = pair[0].parse::<f32>().map_err(|_| invalid("bad timestamp"))?;
+            let key = pair[1].parse::<i32>().map_err(|_| invalid("bad keycode"))?;
+            events.push((time, key));
+        }
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(InputReplay { events, next: 0 })
+    }
+
+    // The next key recorded at or before `time`, one per call
+    pub fn next_due(&mut self, time: f32) -> Option<i32> {
+        let &(event_time, key) = self.events.get(self.next)?;
+        if event_time > time {
+            return None;
+        }
+        self.next += 1;
+        Some(key)
+    }
+}
@@ -1,11 +1,7 @@
-use std::sync::{Arc, Mutex};
-
 use ncurses::*;
-use raymarch::{ray_march, update_globals};
+use raymarch::{set_ambient_light, set_cube_layout, set_environment_map, set_floor_blend, set_floor_texture, set_hit_epsilon, set_lighting_rig, set_scene, set_seed, set_shadow_settings, AmbientLight, CubeLayout, FloorTexture, HitEpsilon, LightingRig, MotionBlur, Scene, ShadowQuality, ShadowSettings};
 use std::env;
-use minifb::{Window, WindowOptions};
-use std::time::Instant;
-use rayon::prelude::*;
+use std::time::{Duration, Instant};
 
 mod raymarch;
 mod framebuffer;
@@ -15,113 +11,684 @@ mod ascii;
 mod pixel;
 mod terminalbuffer;
 mod math;
-
-use crate::framebuffer::Framebuffer;
-use crate::sobel::compute_gradients;
-use crate::terminal::draw_colored_frame;
-use crate::pixel::Pixel;
-use crate::terminalbuffer::TerminalBuffer;
-use crate::math::{Vec2, Vec3, Mat4};
-
-const CHUNK_SIZE: usize = 8; 
+mod postprocess;
+mod camera;
+mod catalog;
+mod font;
+mod debugwindow;
+mod geometry;
+mod error;
+mod testpattern;
+mod plot;
+mod picker;
+mod pick;
+mod pairbudget;
+mod panorama;
+mod pacing;
+mod envmap;
+mod shader;
+mod dither;
+mod dump;
+mod export;
+mod screenshot;
+mod transition;
+mod inputlog;
+mod imageview;
+mod context;
+mod theme;
+mod capture;
+mod preset;
+mod watchdog;
+mod input;
+mod shadertoy;
+mod timeline;
+mod benchmark;
+mod timings;
+mod tonecurve;
+mod timestep;
+
+use crate::terminal::{detect_cell_aspect, lock_terminal, set_theme, TerminalGuard};
+use crate::geometry::{OutputGeometry, PixelFormat, DEFAULT_CELL_ASPECT};
+use crate::camera::{DepthOfField, Projection, Stereo, StereoMode};
+use crate::framebuffer::{MAX_DITHER_STRENGTH, MAX_TEMPERATURE};
+use crate::dither::DitherMatrix;
+use crate::ascii::RenderMode;
+use crate::sobel::MAX_EDGE_WIDTH;
+use crate::envmap::EnvironmentMap;
+use crate::panorama::{DEFAULT_PANORAMA_WIDTH, MAX_PANORAMA_WIDTH};
+use crate::postprocess::{ColorPipeline, PostProcessConfig, PosterizeOrder, SharpenTarget, DEFAULT_DISPLAY_GAMMA};
+use crate::error::RenderError;
+use crate::shader::{ShaderKind, ShaderSettings};
+use crate::shadertoy::ShaderScene;
+use crate::transition::{TransitionKind, TransitionSettings};
+use crate::input::{InputThread, QUIT_KEY};
+use crate::inputlog::{InputRecorder, InputReplay};
+use crate::timeline::{EventAction, Timeline};
+use crate::imageview::load_png;
+use crate::pixel::{parse_hex_rgb, Pixel};
+use crate::context::{RenderContext, RenderSettings};
+use crate::theme::{query_background, Theme};
+use crate::capture::{CaptureFormat, CaptureSettings};
+use crate::preset::{load_presets, Preset};
+use crate::benchmark::Benchmark;
+use crate::timings::{Stage, StageTimer, TimingLog};
+use crate::tonecurve::{CustomCurve, ToneCurve};
+use crate::math::entropy_seed;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Smallest terminal the scene is rendered into
+const MIN_TERMINAL_COLS: usize = 16;
+const MIN_TERMINAL_ROWS: usize = 4;
+// How long to wait for the terminal to report its background color
+const THEME_QUERY_TIMEOUT: Duration = Duration::from_millis(200);
+// Longest a frame may take before it is cut short and the resolution drops
+const DEFAULT_FRAME_BUDGET: Duration = Duration::from_millis(500);
+// Frame rate the loop is paced to, and whose frame time a terminal write may take
+// before frames get dropped from the output
+const TARGET_FPS: f32 = 60.0;
+// How often an idle loop wakes without input, slower without the debug window to
+// keep responsive
+const IDLE_TICK: Duration = Duration::from_millis(250);
+const IDLE_TICK_DEBUG_WINDOW: Duration = Duration::from_millis(100);
+// How long the terminal size has to hold still before the buffers follow it, so
+// dragging a window edge doesn't reallocate on every step
+const RESIZE_SETTLE: Duration = Duration::from_millis(60);
+// Length of an --uncapped run without --bench-frames or --bench-seconds
+const DEFAULT_BENCH_FRAMES: usize = 300;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let test_pattern = args.get(1).map(String::as_str) == Some("test-pattern");
     let debug_mode = args.contains(&"--debug".to_string());
+    let title = arg_value(&args, "--title");
+    let mode = arg_value(&args, "--mode").map_or(RenderMode::Combined, |name| {
+        RenderMode::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown mode '{}', expected brightness, edges or combined", name);
+            std::process::exit(1);
+        })
+    });
+    let edge_width = arg_value(&args, "--edge-width").map_or(0, |value| {
+        value.parse::<usize>().ok().filter(|&width| width <= MAX_EDGE_WIDTH).unwrap_or_else(|| {
+            eprintln!("Invalid edge width '{}', expected 0 to {} cells added on each side of an edge", value, MAX_EDGE_WIDTH);
+            std::process::exit(1);
+        })
+    });
+    let panorama_width = arg_value(&args, "--panorama-width").map_or(DEFAULT_PANORAMA_WIDTH, |value| {
+        value.parse::<usize>().ok().filter(|&width| (2..=MAX_PANORAMA_WIDTH).contains(&width)).unwrap_or_else(|| {
+            eprintln!("Invalid panorama width '{}', expected 2 to {} pixels; the height is half the width", value, MAX_PANORAMA_WIDTH);
+            std::process::exit(1);
+        })
+    });
+    let fill = args.contains(&"--fill".to_string());
+    let ramp_dither = args.contains(&"--ramp-dither".to_string());
+    let auto_exposure = args.contains(&"--auto-exposure".to_string());
+    let dither_matrix = |flag: &str, default: DitherMatrix| {
+        arg_value(&args, flag).map_or(default, |name| {
+            DitherMatrix::from_name(&name).unwrap_or_else(|| {
+                eprintln!("Unknown dither matrix '{}', expected bayer2, bayer4, bayer8 or blue-noise", name);
+                std::process::exit(1);
+            })
+        })
+    };
+    let ramp_dither_matrix = dither_matrix("--ramp-dither-matrix", PostProcessConfig::default().ramp_dither_matrix);
+    let feedback = args.contains(&"--feedback".to_string());
+    let picker = args.contains(&"--picker".to_string());
+    let mut stereo = Stereo::default();
+    if let Some(name) = arg_value(&args, "--stereo") {
+        stereo.mode = StereoMode::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown stereo mode '{}', expected anaglyph or sbs", name);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--threads") {
+        let threads = parse_thread_count(&value).unwrap_or_else(|| {
+            eprintln!("Invalid thread count '{}', expected a positive integer", value);
+            std::process::exit(1);
+        });
+        if let Err(e) = configure_threads(threads) {
+            eprintln!("Failed to create a pool of {} threads: {}", threads, e);
+            std::process::exit(1);
+        }
+    }
+    let dump_frame = arg_value(&args, "--dump-frame").map(|value| {
+        value.parse::<u32>().unwrap_or_else(|_| {
+            eprintln!("Invalid frame number '{}', expected a non-negative integer", value);
+            std::process::exit(1);
+        })
+    });
+    // Every post-process pass of a frame, the first one unless --dump-frame says otherwise
+    let dump_dir = arg_value(&args, "--dump-stages").map(PathBuf::from);
+    let dump_frame = dump_frame.or(dump_dir.as_ref().map(|_| 0));
+    let export_frame = arg_value(&args, "--export-frame").map(|value| {
+        value.parse::<u32>().unwrap_or_else(|_| {
+            eprintln!("Invalid frame number '{}', expected a non-negative integer", value);
+            std::process::exit(1);
+        })
+    });
+    let cell_aspect = arg_value(&args, "--cell-aspect").map(|value| {
+        value.parse::<f32>().ok().filter(|&aspect| aspect > 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid cell aspect '{}', expected a positive number such as 2.0", value);
+            std::process::exit(1);
+        })
+    });
+    let mut motion = MotionBlur::default();
+    if let Some(value) = arg_value(&args, "--motion-samples") {
+        motion.samples = value.parse::<u32>().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+            eprintln!("Invalid motion sample count '{}', expected a positive integer", value);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--shutter") {
+        motion.shutter = value.parse::<f32>().ok().filter(|&s| s >= 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid shutter interval '{}', expected seconds such as 0.0167", value);
+            std::process::exit(1);
+        });
+    }
+    let vector_blur_samples = arg_value(&args, "--vector-blur").map_or(1, |value| {
+        value.parse::<u32>().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+            eprintln!("Invalid vector blur sample count '{}', expected a positive integer", value);
+            std::process::exit(1);
+        })
+    });
+    let mut dof = DepthOfField::default();
+    if let Some(value) = arg_value(&args, "--dof") {
+        let parsed = value.split_once(',').and_then(|(focal, aperture)| Some((focal.parse::<f32>().ok()?, aperture.parse::<f32>().ok()?)));
+        (dof.focal_distance, dof.aperture) = parsed.filter(|&(focal, aperture)| focal > 0.0 && aperture >= 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid depth of field '{}', expected focal-distance,aperture such as 2.15,0.1", value);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--dof-samples") {
+        dof.samples = value.parse::<u32>().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+            eprintln!("Invalid lens sample count '{}', expected a positive integer", value);
+            std::process::exit(1);
+        });
+    }
+    let posterize_order = arg_value(&args, "--posterize-order").map_or(PosterizeOrder::BeforeAdjust, |name| {
+        PosterizeOrder::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown posterize order '{}', expected before or after", name);
+            std::process::exit(1);
+        })
+    });
+    let color_pipeline = arg_value(&args, "--color-pipeline").map_or(ColorPipeline::Linear, |name| {
+        ColorPipeline::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown color pipeline '{}', expected linear or legacy", name);
+            std::process::exit(1);
+        })
+    });
+    // A built-in curve by name, anything else is a file of control points
+    let tone_curve = arg_value(&args, "--tone-curve").map_or(ToneCurve::Linear, |name| {
+        ToneCurve::from_name(&name).unwrap_or_else(|| match CustomCurve::load(Path::new(&name)) {
+            Ok(curve) => ToneCurve::Custom(Arc::new(curve)),
+            Err(e) => {
+                eprintln!("Failed to load tone curve '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        })
+    });
+    let display_gamma = arg_value(&args, "--gamma").map_or(DEFAULT_DISPLAY_GAMMA, |value| {
+        value.parse::<f32>().ok().filter(|&gamma| gamma > 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid display gamma '{}', expected a positive number", value);
+            std::process::exit(1);
+        })
+    });
+    let sharpen_target = arg_value(&args, "--sharpen").map(|name| {
+        SharpenTarget::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown sharpening target '{}', expected brightness, color or both", name);
+            std::process::exit(1);
+        })
+    });
+    let sharpen_overshoot = arg_value(&args, "--sharpen-overshoot").map_or(PostProcessConfig::default().color_sharpen_overshoot, |value| {
+        value.parse::<u8>().unwrap_or_else(|_| {
+            eprintln!("Invalid sharpening overshoot '{}', expected 0 to 255", value);
+            std::process::exit(1);
+        })
+    });
+    let color_blur = arg_value(&args, "--color-blur").map_or(0, |value| {
+        value.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("Invalid color blur radius '{}', expected a whole number of pixels", value);
+            std::process::exit(1);
+        })
+    });
+    let trail_decay = arg_value(&args, "--trails").map(|value| {
+        value.parse::<f32>().ok().filter(|d| (0.0..1.0).contains(d)).unwrap_or_else(|| {
+            eprintln!("Invalid trail decay '{}', expected a number from 0 up to but not including 1", value);
+            std::process::exit(1);
+        })
+    });
+    let dither_strength = arg_value(&args, "--dither-strength").map_or(PostProcessConfig::default().dither_strength, |value| {
+        value.parse::<f32>().ok().filter(|s| (0.0..=MAX_DITHER_STRENGTH).contains(s)).unwrap_or_else(|| {
+            eprintln!("Invalid dither strength '{}', expected a number from 0 to {}", value, MAX_DITHER_STRENGTH);
+            std::process::exit(1);
+        })
+    });
+    let dither_matrix = dither_matrix("--dither-matrix", PostProcessConfig::default().dither_matrix);
+    let temperature = arg_value(&args, "--temperature").map_or(PostProcessConfig::default().temperature, |value| {
+        value.parse::<f32>().ok().filter(|t| (-MAX_TEMPERATURE..=MAX_TEMPERATURE).contains(t)).unwrap_or_else(|| {
+            eprintln!("Invalid temperature '{}', expected a number from -{} (cool) to {} (warm)", value, MAX_TEMPERATURE, MAX_TEMPERATURE);
+            std::process::exit(1);
+        })
+    });
+    let mut shader = ShaderSettings::default();
+    if let Some(name) = arg_value(&args, "--shader").or_else(|| arg_value(&args, "--shade")) {
+        shader.kind = ShaderKind::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown shader '{}', expected phong or toon", name);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--toon-bands") {
+        shader.toon_bands = value.parse::<u32>().ok().filter(|&n| n >= 2).unwrap_or_else(|| {
+            eprintln!("Invalid band count '{}', expected an integer of at least 2", value);
+            std::process::exit(1);
+        });
+    }
+    let mut shadows = ShadowSettings::default();
+    if let Some(name) = arg_value(&args, "--shadows") {
+        shadows.quality = ShadowQuality::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown shadow quality '{}', expected off, hard or soft", name);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--shadow-steps") {
+        shadows.max_steps = value.parse::<u32>().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+            eprintln!("Invalid shadow step count '{}', expected a positive integer", value);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--light-radius") {
+        shadows.light_radius = value.parse::<f32>().ok().filter(|&r| r > 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid light radius '{}', expected a positive number such as 2.0", value);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--shadow-bias") {
+        shadows.bias.constant = value.parse::<f32>().ok().filter(|&b| b >= 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid shadow bias '{}', expected a distance such as 0.01", value);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--shadow-slope-bias") {
+        shadows.bias.slope = value.parse::<f32>().ok().filter(|&b| b >= 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid shadow slope bias '{}', expected a distance such as 0.01", value);
+            std::process::exit(1);
+        });
+    }
+    set_shadow_settings(shadows);
+    let mut hit_epsilon = HitEpsilon::default();
+    if let Some(value) = arg_value(&args, "--hit-epsilon") {
+        hit_epsilon.base = value.parse::<f32>().ok().filter(|&e| e > 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid hit epsilon '{}', expected a positive distance such as 0.001", value);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--epsilon-growth") {
+        hit_epsilon.growth = value.parse::<f32>().ok().filter(|&g| g >= 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid epsilon growth '{}', expected a rate per unit of distance such as 0.02", value);
+            std::process::exit(1);
+        });
+    }
+    set_hit_epsilon(hit_epsilon);
+    if let Some(name) = arg_value(&args, "--ambient") {
+        set_ambient_light(AmbientLight::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown ambient light '{}', expected flat or hemisphere", name);
+            std::process::exit(1);
+        }));
+    }
+    if let Some(name) = arg_value(&args, "--lighting") {
+        set_lighting_rig(LightingRig::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown lighting '{}', expected single or three-point", name);
+            std::process::exit(1);
+        }));
+    }
+    if let Some(path) = arg_value(&args, "--envmap") {
+        let envmap = EnvironmentMap::load(Path::new(&path)).unwrap_or_else(|e| {
+            eprintln!("Failed to load environment map: {}", e);
+            std::process::exit(1);
+        });
+        set_environment_map(Some(Arc::new(envmap)));
+    }
+    if let Some(name) = arg_value(&args, "--floor") {
+        set_floor_texture(FloorTexture::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown floor '{}', expected checker, marble or caustics", name);
+            std::process::exit(1);
+        }));
+    }
+    if let Some(value) = arg_value(&args, "--floor-blend") {
+        set_floor_blend(value.parse::<f32>().ok().filter(|&r| r >= 0.0 && r.is_finite()).unwrap_or_else(|| {
+            eprintln!("Invalid floor blend '{}', expected a radius such as 0.25, or 0 for a hard seam", value);
+            std::process::exit(1);
+        }));
+    }
+    // A fresh seed every run unless one is given; it's printed on exit so a run
+    // worth keeping can be repeated
+    let seed = arg_value(&args, "--seed").map_or_else(entropy_seed, |value| {
+        value.parse::<u32>().unwrap_or_else(|_| {
+            eprintln!("Invalid seed '{}', expected a non-negative integer", value);
+            std::process::exit(1);
+        })
+    });
+    set_seed(seed);
+    let mut shader_scene = None;
+    if let Some(name) = arg_value(&args, "--scene") {
+        if let Some(scene) = Scene::from_name(&name, seed) {
+            set_scene(scene);
+        } else {
+            shader_scene = Some(ShaderScene::from_name(&name).unwrap_or_else(|| {
+                eprintln!("Unknown scene '{}', expected one of {}", name, catalog::scene_names().join(", "));
+                std::process::exit(1);
+            }));
+        }
+    }
+    if let Some(value) = arg_value(&args, "--objects") {
+        let count = value.parse::<usize>().ok().filter(|&count| count > 0).unwrap_or_else(|| {
+            eprintln!("Invalid object count '{}', expected a positive integer", value);
+            std::process::exit(1);
+        });
+        set_cube_layout(CubeLayout::Ring { count, seed });
+    }
+    let mut timeline = arg_value(&args, "--events").map_or_else(Timeline::default, |path| {
+        Timeline::load(Path::new(&path), seed).unwrap_or_else(|e| {
+            eprintln!("Failed to load events '{}': {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    if let Some(value) = arg_value(&args, "--metronome") {
+        let bpm = value.parse::<f32>().ok().filter(|&bpm| bpm > 0.0 && bpm.is_finite()).unwrap_or_else(|| {
+            eprintln!("Invalid metronome tempo '{}', expected beats per minute such as 120", value);
+            std::process::exit(1);
+        });
+        timeline.set_metronome(60.0 / bpm, EventAction::Flash);
+    }
+    let mut transition = TransitionSettings::default();
+    if let Some(name) = arg_value(&args, "--transition") {
+        transition.kind = TransitionKind::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown transition '{}', expected cut, crossfade or wipe", name);
+            std::process::exit(1);
+        });
+    }
+    if let Some(value) = arg_value(&args, "--transition-time") {
+        transition.duration = value.parse::<f32>().ok().filter(|&s| s >= 0.0).unwrap_or_else(|| {
+            eprintln!("Invalid transition time '{}', expected seconds such as 1.0", value);
+            std::process::exit(1);
+        });
+    }
+    let recorder = arg_value(&args, "--record-input").map(|path| InputRecorder::new(Path::new(&path)));
+    let replay = arg_value(&args, "--replay-input").map(|path| {
+        InputReplay::load(Path::new(&path)).unwrap_or_else(|e| {
+            eprintln!("Failed to load input session '{}': {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    // Draw frames back to back and report frame times instead of running interactively
+    let benchmark = args.contains(&"--uncapped".to_string()).then(|| {
+        let frames = arg_value(&args, "--bench-frames").map(|value| {
+            value.parse::<usize>().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+                eprintln!("Invalid benchmark frame count '{}', expected a positive integer", value);
+                std::process::exit(1);
+            })
+        });
+        let duration = arg_value(&args, "--bench-seconds").map(|value| {
+            value.parse::<f32>().ok().filter(|&s| s > 0.0 && s.is_finite()).map(Duration::from_secs_f32).unwrap_or_else(|| {
+                eprintln!("Invalid benchmark duration '{}', expected seconds such as 10", value);
+                std::process::exit(1);
+            })
+        });
+        // Without a duration the run stops after a fixed number of frames
+        let frames = frames.unwrap_or(if duration.is_some() { usize::MAX } else { DEFAULT_BENCH_FRAMES });
+        Benchmark::new(frames, duration)
+    });
+    let timing_log = arg_value(&args, "--log-timings").map(|path| {
+        TimingLog::create(Path::new(&path)).unwrap_or_else(|e| {
+            eprintln!("Failed to open timing log '{}': {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    // A replay has to render every frame in full to play back the same way, and a
+    // benchmark should time full frames
+    let frame_budget = arg_value(&args, "--frame-budget").map_or(Some(DEFAULT_FRAME_BUDGET), |value| {
+        let millis = value.parse::<u64>().unwrap_or_else(|_| {
+            eprintln!("Invalid frame budget '{}', expected milliseconds, 0 to disable", value);
+            std::process::exit(1);
+        });
+        (millis > 0).then(|| Duration::from_millis(millis))
+    }).filter(|_| replay.is_none() && benchmark.is_none());
+    // Replays and benchmarks see every frame written out
+    let present_budget = Some(Duration::from_secs_f32(1.0 / TARGET_FPS)).filter(|_| !args.contains(&"--no-frame-skip".to_string()) && replay.is_none() && benchmark.is_none());
+    let image = arg_value(&args, "--image").map(|path| {
+        load_png(Path::new(&path)).unwrap_or_else(|e| {
+            eprintln!("Failed to load image '{}': {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    let clear_color = arg_value(&args, "--clear-color").map_or(Pixel { r: 0, g: 0, b: 0, a: 255 }, |value| {
+        let (r, g, b) = parse_hex_rgb(&value).unwrap_or_else(|| {
+            eprintln!("Invalid clear color '{}', expected RRGGBB", value);
+            std::process::exit(1);
+        });
+        Pixel { r, g, b, a: 255 }
+    });
+    let capture_frames = arg_value(&args, "--capture-frames").map(|value| {
+        value.parse::<u32>().ok().filter(|&n| n > 0).unwrap_or_else(|| {
+            eprintln!("Invalid capture frame count '{}', expected a positive integer", value);
+            std::process::exit(1);
+        })
+    });
+    let capture = arg_value(&args, "--apng").map(|path| CaptureSettings {
+        format: CaptureFormat::Apng,
+        path: Path::new(&path).to_path_buf(),
+        max_frames: capture_frames,
+    });
+    if let Some(path) = arg_value(&args, "--presets") {
+        let user = load_presets(Path::new(&path)).unwrap_or_else(|e| {
+            eprintln!("Failed to load presets from '{}':\n{}", path, e);
+            std::process::exit(1);
+        });
+        Preset::register(user);
+    }
+    let preset = match arg_value(&args, "--preset") {
+        Some(name) => Preset::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown preset '{}', expected one of {}", name, Preset::names().join(", "));
+            std::process::exit(1);
+        }),
+        None => Preset::default(),
+    };
+    let projection = match arg_value(&args, "--projection") {
+        Some(name) => Projection::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown projection '{}', expected perspective, ortho, fisheye or equirect", name);
+            std::process::exit(1);
+        }),
+        None => Projection::from_name("perspective").unwrap(),
+    };
+    let theme = arg_value(&args, "--theme").map(|name| {
+        Theme::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown theme '{}', expected dark or light", name);
+            std::process::exit(1);
+        })
+    });
+
+    // Listings go to stdout as plain text and end the run before the terminal is
+    // touched. --explain comes after the rest of the command line, so it shows
+    // the values as that leaves them.
+    if args.contains(&"--list-scenes".to_string()) {
+        print!("{}", catalog::list_scenes());
+        return;
+    }
+    if args.contains(&"--list-presets".to_string()) {
+        print!("{}", catalog::list_presets());
+        return;
+    }
+    if args.contains(&"--list-palettes".to_string()) {
+        print!("{}", catalog::list_palettes());
+        return;
+    }
+    if let Some(name) = arg_value(&args, "--explain") {
+        let given_seed = arg_value(&args, "--seed").map(|_| seed);
+        let explanation = catalog::explain(&name, given_seed).unwrap_or_else(|| {
+            eprintln!("Nothing to explain for '{}', expected a scene ({}) or a preset ({})", name, catalog::scene_names().join(", "), Preset::names().join(", "));
+            std::process::exit(1);
+        });
+        print!("{}", explanation);
+        return;
+    }
 
+    // Query the background while the terminal is still ours to talk to directly
+    let (theme, theme_source) = match theme {
+        Some(theme) => (theme, "override"),
+        None => match query_background(THEME_QUERY_TIMEOUT) {
+            Some(background) => (Theme::for_background(background), "detected"),
+            None => (Theme::Dark, "default"),
+        },
+    };
+    set_theme(theme);
+
+    setlocale(LcCategory::all, "");  // Multibyte glyphs need the locale's encoding
     initscr();  // Start the ncurses session
     noecho();   // Disable echoing of characters
     curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);  // Hide the cursor
     nodelay(stdscr(), true);  // Don't block the getch call
-
-    // Create framebuffer and window dimensions based on terminal size
-    let framebuffer = Arc::new(Mutex::new(create_framebuffer()));
-    let mut paused = false; // Track whether the animation is paused
-    let target_fps = 60.0;
-    let mut last_time = Instant::now();
-
-    // Initialize minifb window for debug mode
-    let mut window = if debug_mode {
-        Some(Window::new(
-            "Debug Framebuffer - ESC to exit",
-            framebuffer.lock().unwrap().width,
-            framebuffer.lock().unwrap().height,
-            WindowOptions::default(),
-        ).unwrap_or_else(|e| {
-            panic!("{}", e);
-        }))
+    keypad(stdscr(), true);  // Decode function keys
+    set_escdelay(25);  // Keep a lone ESC responsive with keypad enabled
+    let session = TerminalGuard::curses();
+    session.install_panic_hook();
+
+    let mut messages = vec![format!("Seed {}", seed)];
+    let result = if test_pattern {
+        testpattern::run(cell_aspect, theme_source);
+        Ok(())
     } else {
-        None
+        let settings = RenderSettings { debug_mode, title, image, clear_color, shader_scene, seed, picker, feedback, mode, edge_width, panorama_width, fill, stereo, projection, shader, transition, motion, vector_blur_samples, dof, sharpen_target, sharpen_overshoot, color_blur, posterize_order, color_pipeline, tone_curve, display_gamma, dither_strength, dither_matrix, temperature, auto_exposure, ramp_dither, ramp_dither_matrix, trail_decay, dump_frame, dump_dir, export_frame, capture, preset, frame_budget, present_budget, timeline };
+        run(settings, cell_aspect, recorder, replay, benchmark, timing_log, &mut messages)
     };
 
-    let mut terminal_buffer = {
-        let fb = framebuffer.lock().unwrap();
-        TerminalBuffer::new(fb.width, fb.height)
-    };
+    drop(session);  // End the ncurses session
 
-    // Minifb buffer for graphical rendering (used only in debug mode)
-    let mut buffer = if debug_mode {
-        let fb = framebuffer.lock().unwrap();
-        vec![0; fb.width * fb.height]
-    } else {
-        vec![]
-    };
+    for message in &messages {
+        eprintln!("{}", message);
+    }
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+// Main render loop, runs until ESC is pressed, a benchmark is over or a frame fails
+fn run(settings: RenderSettings, cell_aspect: Option<f32>, mut recorder: Option<InputRecorder>, mut replay: Option<InputReplay>, mut benchmark: Option<Benchmark>, timing_log: Option<TimingLog>, messages: &mut Vec<String>) -> Result<(), RenderError> {
+    // Create framebuffer and window dimensions based on terminal size
+    let pixel_format = PixelFormat::Ascii;
+    let geometry = terminal_geometry(pixel_format, cell_aspect);
+    let mut context = RenderContext::new(settings, pixel_format, geometry, messages);
+    if benchmark.is_some() {
+        context.count_rays();
+    }
+    let target_fps = TARGET_FPS;
+    let mut last_time = Instant::now();
+    let mut too_small_shown = false;
+    // Size the terminal changed to and when, until it settles
+    let mut pending_resize: Option<(OutputGeometry, Instant)> = None;
 
-    let total_elapsed_time = 0.0;
     let start_time = Instant::now();
-    
-    let mut prev_width;
-    let mut prev_height;
-    {
-        let fb = framebuffer.lock().unwrap();
-        prev_width = fb.width;
-        prev_height = fb.height;
-    }
+    let mut replay_steps: u32 = 0;
+    let input = InputThread::spawn();
 
-    loop {
+    'frames: loop {
         // Calculate deltaTime
         let now = Instant::now();
-        let total_elapsed_time = now.duration_since(start_time).as_secs_f32();  // Accurate elapsed time
-
-        let delta_time = now.duration_since(last_time).as_secs_f32();
+        let mut timer = StageTimer::new();
+        // A replay advances a fixed step per loop, so playback doesn't depend on render speed
+        let (wall_time, delta_time) = if replay.is_some() {
+            replay_steps += 1;
+            (replay_steps as f32 / target_fps, 1.0 / target_fps)
+        } else {
+            (now.duration_since(start_time).as_secs_f32(), now.duration_since(last_time).as_secs_f32())
+        };
         last_time = now;
+        context.update(delta_time);
         
-        // Handle user input
-        let ch = getch();
-        if ch == 32 {  // Spacebar is ASCII 32
-            paused = !paused;
+        // Handle every key since the last frame, replayed ones first
+        let mut keys = Vec::new();
+        if let Some(replay) = replay.as_mut() {
+            keys.extend(std::iter::from_fn(|| replay.next_due(wall_time)));
         }
-        if ch == 27 {  // ESC is ASCII 27
-            break;
+        keys.extend(input.drain());
+        for key in keys {
+            if let Some(recorder) = recorder.as_mut() {
+                recorder.record(wall_time, key);
+            }
+            if key == QUIT_KEY && !context.picker_open() {
+                break 'frames;
+            }
+            context.handle_key(key, messages);
         }
+        timer.lap(Stage::Simulate);
 
         // Check if terminal size has changed
-        let mut new_width = 0;
-        let mut new_height = 0;
-        getmaxyx(stdscr(), &mut new_height, &mut new_width);
-
-        // Convert i32 to usize for framebuffer comparison
-        let new_width_usize = new_width as usize;
-        let new_height_usize = new_height as usize;
+        let new_geometry = terminal_geometry(pixel_format, cell_aspect);
+        if new_geometry != *context.geometry() {
+            match pending_resize {
+                Some((geometry, since)) if geometry == new_geometry && since.elapsed() >= RESIZE_SETTLE => {
+                    // Terminal has been resized, adjust the buffers; the next frame
+                    // clears the screen
+                    context.handle_resize(new_geometry)?;
+                    pending_resize = None;
+                    too_small_shown = false;
+                }
+                _ => {
+                    // Still changing: keep the old frame up until the size holds
+                    if pending_resize.is_none_or(|(geometry, _)| geometry != new_geometry) {
+                        pending_resize = Some((new_geometry, Instant::now()));
+                    }
+                    std::thread::sleep(RESIZE_SETTLE / 4);
+                    continue;
+                }
+            }
+        } else {
+            pending_resize = None;
+        }
 
-        if new_width_usize != prev_width || new_height_usize != prev_height {
-            // Terminal has been resized, adjust framebuffer
-            terminal_buffer.resize(new_width_usize, new_height_usize);
-            let mut fb = framebuffer.lock().unwrap();
-            *fb = Framebuffer::new(new_width_usize, new_height_usize);
-            prev_width = new_width_usize;
-            prev_height = new_height_usize;
+        // Just poll input until the terminal is big enough to render into
+        if new_geometry.cells_w < MIN_TERMINAL_COLS || new_geometry.cells_h < MIN_TERMINAL_ROWS {
+            if !too_small_shown {
+                show_too_small(&new_geometry);
+                too_small_shown = true;
+            }
+            std::thread::sleep(std::time::Duration::from_secs_f32(1.0 / target_fps));
+            continue;
+        }
 
-            clear();  // Clear the screen after resizing
+        // A benchmark times every frame, static or not
+        if context.needs_frame() || benchmark.is_some() {
+            context.draw(wall_time, &mut timer, messages)?;
+            if let (Some(log), Some(mut timing)) = (timing_log.as_ref(), context.last_frame_timing()) {
+                timing.timestamp = start_time.elapsed().as_secs_f32();
+                timing.late = timing.stages.total() > 1000.0 / target_fps;
+                log.record(timing);
+            }
+            if let (Some(benchmark), Some(frame_time)) = (benchmark.as_mut(), context.last_frame_time()) {
+                benchmark.stats.add(frame_time);
+            }
+            if let (Some(benchmark), Some(rays)) = (benchmark.as_mut(), context.ray_stats()) {
+                benchmark.rays.merge(rays);
+            }
         }
 
-        if !paused {
-            {
-                let mut fb = framebuffer.lock().unwrap();
-                fb.clear();  // Clear framebuffer before drawing
+        if let Some(benchmark) = &benchmark {
+            if benchmark.finished(start_time.elapsed()) {
+                messages.push(format!("Benchmark: {}", benchmark.stats.summary()));
+                if benchmark.rays.frames > 0 {
+                    messages.push(format!("Rays {}", benchmark.rays.summary()));
+                }
+                break 'frames;
             }
-            update(delta_time, total_elapsed_time, &framebuffer);
-            draw(&framebuffer, &mut window, &mut buffer, &mut terminal_buffer, debug_mode);        
+            // Uncapped: straight on to the next frame
+            continue;
+        }
+
+        // Nothing changes until input, a resize or an unpause: wait for a key instead
+        // of polling at the frame rate. A replay feeds its keys on a fixed clock.
+        if !context.needs_frame() && replay.is_none() {
+            input.wait(if context.has_window() { IDLE_TICK_DEBUG_WINDOW } else { IDLE_TICK });
+            context.update_window();
+            continue;
         }
 
         // Sleep to maintain the target framerate
@@ -129,139 +696,111 @@ fn main() {
         let sleep_time = (1.0 / target_fps - elapsed_time).max(0.0);
         std::thread::sleep(std::time::Duration::from_secs_f32(sleep_time));
     }
+    drop(input);  // Stop reading keys before ncurses shuts down
 
-    endwin();  // End the ncurses session
+    context.finish(messages);
+    if let Some(log) = timing_log {
+        match log.finish() {
+            Ok(summary) => messages.extend(summary.lines().map(str::to_string)),
+            Err(e) => messages.push(format!("Timing log failed: {}", e)),
+        }
+    }
+    if let Some(recorder) = &recorder {
+        messages.push(match recorder.save() {
+            Ok(path) => format!("Input recorded to {}", path.display()),
+            Err(e) => format!("Input recording failed: {}", e),
+        });
+    }
+    Ok(())
 }
 
-fn update(delta_time: f32, total_time: f32, framebuffer: &Arc<Mutex<Framebuffer>>) {
-    let fb = framebuffer.lock().unwrap();
-    let width = fb.width as f32;
-    let height = fb.height as f32;
-    drop(fb); // Release the lock
-
-    update_globals(Vec2::new(width, height), total_time);
-    draw_test_scene(framebuffer, total_time);
+// Output geometry for the current terminal size. The cell aspect comes from the
+// override if given, otherwise from the terminal's pixel size when it reports one.
+fn terminal_geometry(pixel_format: PixelFormat, cell_aspect: Option<f32>) -> OutputGeometry {
+    let mut width = 0;
+    let mut height = 0;
+    {
+        let _terminal = lock_terminal();
+        getmaxyx(stdscr(), &mut height, &mut width);  // Get current terminal size, -1 without a TTY
+    }
+    let cell_aspect = cell_aspect.or_else(detect_cell_aspect).unwrap_or(DEFAULT_CELL_ASPECT);
+    OutputGeometry::new(width.max(0) as usize, height.max(0) as usize, pixel_format, cell_aspect)
 }
 
-pub fn draw_test_scene(framebuffer: &Arc<Mutex<Framebuffer>>, total_time: f32) {
-    let fb = framebuffer.lock().unwrap();
-    let width = fb.width;
-    let height = fb.height;
-    drop(fb); // Release the lock
-
-    let aspect_ratio = width as f32 / height as f32;
-
-    // Camera setup
-    let camera_radius = 8.0;
-    let camera_height = 3.0 + (total_time * 0.2).sin();
-    let camera_angle = total_time * 0.5;
-
-    let eye = Vec3::new(0.0, 1.25, -1.75); // Positioned at (0, 5, 5)
-    let target = Vec3::new(0.0, 0.0, 0.0); // Looking directly at the origin
-    let up = Vec3::new(0.0, 1.0, 0.0);    
-
-    // let eye = Vec3::new(
-    //     camera_radius * camera_angle.cos(),
-    //     camera_height,
-    //     camera_radius * camera_angle.sin()
-    // );
-    // let target = Vec3::new(0.0, 1.0, 0.0); // Look at the center of the scene, slightly above the ground
-    // let up = Vec3::new(0.0, 1.0, 0.0);
-
-    let view_matrix = look_at(eye, target, up);
-
-    let chunks: Vec<_> = (0..height)
-        .step_by(CHUNK_SIZE)
-        .flat_map(|y| {
-            (0..width).step_by(CHUNK_SIZE).map(move |x| (x, y))
-        })
-        .collect();
-
-    let chunk_results: Vec<Vec<Pixel>> = chunks.par_iter().map(|&(start_x, start_y)| {
-        let mut chunk_pixels = Vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE);
-        for y in start_y..std::cmp::min(start_y + CHUNK_SIZE, height) {
-            for x in start_x..std::cmp::min(start_x + CHUNK_SIZE, width) {
-                let ndc_x = (2.0 * x as f32 / width as f32 - 1.0) * aspect_ratio;
-                let ndc_y = 1.0 - 2.0 * y as f32 / height as f32;
-                let ray_dir = calculate_ray_direction(ndc_x, ndc_y, &view_matrix);
-
-                let color = ray_march(eye, ray_dir, total_time);
-                chunk_pixels.push(color);
-            }
-        }
-        chunk_pixels
-    }).collect();
+// Replace the screen with a notice, truncated to whatever fits
+fn show_too_small(geometry: &OutputGeometry) {
+    let _terminal = lock_terminal();
+    clear();
+    if geometry.cells_w > 0 && geometry.cells_h > 0 {
+        mvaddnstr(0, 0, "terminal too small", geometry.cells_w as i32);
+    }
+    refresh();
+}
 
-    let mut fb = framebuffer.lock().unwrap();
-    for (&(start_x, start_y), chunk_pixels) in chunks.iter().zip(chunk_results.iter()) {
-        let mut pixel_index = 0;
-        for y in start_y..std::cmp::min(start_y + CHUNK_SIZE, height) {
-            for x in start_x..std::cmp::min(start_x + CHUNK_SIZE, width) {
-                fb.set_pixel(x, y, chunk_pixels[pixel_index]);
-                pixel_index += 1;
-            }
+// Value of a `--name value` or `--name=value` command line option
+fn arg_value(args: &[String], name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == name {
+            args.get(i + 1).cloned()
+        } else {
+            arg.strip_prefix(&prefix).map(|value| value.to_string())
         }
-    }
+    })
 }
 
-fn look_at(eye: Vec3, target: Vec3, up: Vec3) -> Mat4 {
-    let f = (target - eye).normalize();
-    let s = f.cross(&up).normalize();
-    let u = s.cross(&f);
+// A --threads value: a positive thread count
+fn parse_thread_count(value: &str) -> Option<usize> {
+    value.parse::<usize>().ok().filter(|&n| n > 0)
+}
 
-    Mat4([
-        [s.x, u.x, -f.x, 0.0],
-        [s.y, u.y, -f.y, 0.0],
-        [s.z, u.z, -f.z, 0.0],
-        [-s.dot(&eye), -u.dot(&eye), f.dot(&eye), 1.0]
-    ])
+// Size the global rayon pool shared by the raymarcher and the edge detector. Only
+// the first call builds it; a later one fails unless the pool already has
+// `threads` threads.
+fn configure_threads(threads: usize) -> Result<(), rayon::ThreadPoolBuildError> {
+    match thread_pool(threads).build_global() {
+        Err(_) if rayon::current_num_threads() == threads => Ok(()),
+        result => result,
+    }
 }
 
-fn calculate_ray_direction(ndc_x: f32, ndc_y: f32, view_matrix: &Mat4) -> Vec3 {
-    let fov = 45.0f32.to_radians(); // Adjusted field of view
-    let tan_fov = (fov * 0.5).tan();
-    
-    // Adjust y scaling to account for font aspect ratio (twice as high as wide)
-    let adjusted_ndc_y = ndc_y * 2.0;
-    
-    let ray_target = Vec3::new(ndc_x * tan_fov, adjusted_ndc_y * tan_fov, -1.0);
-    
-    // Apply the inverse view matrix transformation
-    let inv_view = view_matrix.inverse();
-    let world_ray = inv_view.transform_point3(ray_target);
-    
-    (world_ray - Vec3::zero()).normalize()
+fn thread_pool(threads: usize) -> rayon::ThreadPoolBuilder {
+    rayon::ThreadPoolBuilder::new().num_threads(threads)
 }
 
-fn draw(framebuffer: &Arc<Mutex<Framebuffer>>, window: &mut Option<Window>, buffer: &mut Vec<u32>, terminal_buffer: &mut TerminalBuffer, debug_mode: bool) {
-    let mut fb = framebuffer.lock().unwrap();
-    
-    // Compute brightness buffer and gradients
-    fb.compute_brightness_buffer(32);
-//    fb.increase_brightness(1.5);
-    fb.increase_contrast(1.25);
-    fb.apply_sharpening(1.25);
-    fb.apply_bayer_dithering();
-    let gradients = compute_gradients(&fb);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Render to terminal using ncurses
-    draw_colored_frame(&fb, &gradients, terminal_buffer);
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
 
-    // If in debug mode, render to minifb window as well
-    if debug_mode {
-        if let Some(ref mut win) = window {
-            for (i, pixel) in fb.data.iter().enumerate() {
-                buffer[i] = ((pixel.r as u32) << 16) | ((pixel.g as u32) << 8) | (pixel.b as u32);
-            }
-            win.update_with_buffer(&buffer, fb.width, fb.height).unwrap();
+    #[test]
+    fn thread_count_from_either_flag_form() {
+        assert_eq!(arg_value(&args("ascii_sobel --threads 3"), "--threads").as_deref(), Some("3"));
+        assert_eq!(arg_value(&args("ascii_sobel --fill --threads=2"), "--threads").as_deref(), Some("2"));
+        assert_eq!(arg_value(&args("ascii_sobel --threads"), "--threads"), None);
+        assert_eq!(parse_thread_count("3"), Some(3));
+        assert_eq!(parse_thread_count("0"), None);
+        assert_eq!(parse_thread_count("-1"), None);
+        assert_eq!(parse_thread_count("many"), None);
+    }
+
+    #[test]
+    fn pool_has_the_requested_threads() {
+        for threads in [1, 3] {
+            let pool = thread_pool(threads).build().unwrap();
+            assert_eq!(pool.current_num_threads(), threads);
+            assert_eq!(pool.install(rayon::current_num_threads), threads);
         }
     }
-}
 
-// Function to create the framebuffer
-fn create_framebuffer() -> Framebuffer {
-    let mut width = 0;
-    let mut height = 0;
-    getmaxyx(stdscr(), &mut height, &mut width);  // Get current terminal size
-    Framebuffer::new(width as usize, height as usize)
+    #[test]
+    fn global_pool_is_sized_once() {
+        // Other tests may have started the global pool already, so take its size
+        let threads = rayon::current_num_threads();
+        assert!(configure_threads(threads).is_ok());
+        assert!(configure_threads(threads + 1).is_err());
+    }
 }
@@ -11,48 +11,170 @@ impl Smoothstep for f32 {
     }
 }
 
-pub trait Clamp {
-    fn clamp(self, min: Self, max: Self) -> Self;
+// Integer hash (lowbias32) used as a cheap deterministic random source
+pub fn hash_u32(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
 }
 
-impl Clamp for f32 {
-    fn clamp(self, min: f32, max: f32) -> f32 {
-        if self < min { min }
-        else if self > max { max }
-        else { self }
+// A seed that differs from run to run, from the clock and the process id
+pub fn entropy_seed() -> u32 {
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.subsec_nanos() ^ d.as_secs() as u32);
+    hash_u32(nanos ^ hash_u32(std::process::id()))
+}
+
+// Hash mapped to [0, 1)
+pub fn hash_f32(x: u32) -> f32 {
+    (hash_u32(x) >> 8) as f32 / (1u32 << 24) as f32
+}
+
+// Map a point of the unit square onto the unit disc, keeping evenly spread samples
+// evenly spread (Shirley and Chiu's concentric mapping)
+pub fn concentric_disc(u: f32, v: f32) -> (f32, f32) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (radius, angle) = if a.abs() > b.abs() {
+        (a, std::f32::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, std::f32::consts::FRAC_PI_2 - std::f32::consts::FRAC_PI_4 * (a / b))
+    };
+    (radius * angle.cos(), radius * angle.sin())
+}
+
+// Smooth minimum of two distances (polynomial soft min): equal to min(a, b) once
+// they are `k` or more apart, and up to k / 4 below it where they meet, which
+// rounds the crease between two surfaces into a fillet. A `k` of 0 is the hard min.
+pub fn smin(a: f32, b: f32, k: f32) -> f32 {
+    // Also keeps an infinite distance from turning the blend into NaN
+    if k <= 0.0 || (a - b).abs() >= k {
+        return a.min(b);
     }
+    let h = 0.5 + 0.5 * (b - a) / k;
+    b + (a - b) * h - k * h * (1.0 - h)
 }
 
-#[derive(Clone, Copy)]
-pub struct Vec2 {
-    pub x: f32,
-    pub y: f32,
+// Hash of an integer lattice point, mixing in one coordinate at a time
+fn lattice_hash(seed: u32, coordinates: &[i32]) -> u32 {
+    coordinates.iter().fold(hash_u32(seed), |hash, &c| hash_u32(hash ^ (c as u32).wrapping_mul(0x9e3779b9)))
 }
 
-impl Vec2 {
-    pub fn new(x: f32, y: f32) -> Self {
-        Self { x, y }
+// Quintic fade, so interpolated noise has continuous derivatives
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+// Lattice values blended across each unit cell, in [0, 1)
+pub fn value_noise_3d(p: Vec3, seed: u32) -> f32 {
+    let (cell_x, cell_y, cell_z) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (ix, iy, iz) = (cell_x as i32, cell_y as i32, cell_z as i32);
+    let (u, v, w) = (fade(p.x - cell_x), fade(p.y - cell_y), fade(p.z - cell_z));
+    let corner = |dx: i32, dy: i32, dz: i32| hash_f32(lattice_hash(seed, &[ix + dx, iy + dy, iz + dz]));
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let face = |dz: i32| {
+        lerp(lerp(corner(0, 0, dz), corner(1, 0, dz), u), lerp(corner(0, 1, dz), corner(1, 1, dz), u), v)
+    };
+    lerp(face(0), face(1), w)
+}
+
+// Shift `p` by up to `strength` along each axis, the offsets smooth noise of `p`
+// itself. Feeding the result to another pattern bends its straight lines into
+// flowing, organic ones (domain warping).
+pub fn warp(p: Vec3, strength: f32, seed: u32) -> Vec3 {
+    let offset = |axis: u32| 2.0 * value_noise_3d(p, seed.wrapping_add(axis.wrapping_mul(0x632be5ab))) - 1.0;
+    p + Vec3::new(offset(0), offset(1), offset(2)) * strength
+}
+
+// Distances from a point to the nearest and second nearest feature points of
+// cellular (Worley) noise, one feature point scattered in every unit cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Worley {
+    pub f1: f32,
+    pub f2: f32,
+}
+
+impl Worley {
+    // Zero on the borders between cells, rising toward each feature point: cell
+    // walls, cracks and caustic-like lines
+    pub fn edge(&self) -> f32 {
+        self.f2 - self.f1
     }
+}
 
-    pub fn lerp<T: Into<Vec2>>(&self, other: T, t: f32) -> Self {
-        let other = other.into();
-        Self {
-            x: self.x + (other.x - self.x) * t,
-            y: self.y + (other.y - self.y) * t,
+// Cellular noise through space. F1 is at most sqrt(3); F2 comes from the same
+// 3x3x3 block of cells and is exact in all but rare corner cases. Of the 27 cells,
+// those that can't hold a point nearer than the current F2 are skipped before
+// hashing, which leaves about half of them.
+pub fn worley_3d(p: Vec3, seed: u32) -> Worley {
+    let (cell_x, cell_y, cell_z) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let fraction = [p.x - cell_x, p.y - cell_y, p.z - cell_z];
+    // Squared distance from `p` to the nearest face of the neighbor cell at each offset
+    let gap = |axis: usize, d: i32| match d {
+        -1 => fraction[axis] * fraction[axis],
+        1 => (1.0 - fraction[axis]) * (1.0 - fraction[axis]),
+        _ => 0.0,
+    };
+    let (mut f1, mut f2) = (f32::INFINITY, f32::INFINITY);
+    // The own cell first, so F2 tightens early
+    for dz in [0, -1, 1] {
+        for dy in [0, -1, 1] {
+            for dx in [0, -1, 1] {
+                if gap(0, dx) + gap(1, dy) + gap(2, dz) >= f2 {
+                    continue;
+                }
+                let hash = lattice_hash(seed, &[cell_x as i32 + dx, cell_y as i32 + dy, cell_z as i32 + dz]);
+                let x = dx as f32 + hash_f32(hash) - fraction[0];
+                let y = dy as f32 + hash_f32(hash_u32(hash)) - fraction[1];
+                let z = dz as f32 + hash_f32(hash_u32(hash ^ 0x85ebca6b)) - fraction[2];
+                nearer(&mut f1, &mut f2, x * x + y * y + z * z);
+            }
         }
     }
+    Worley { f1: f1.sqrt(), f2: f2.sqrt() }
+}
 
-    pub fn length(&self) -> f32 {
-        (self.x * self.x + self.y * self.y).sqrt()
+// Keep the two smallest squared distances
+fn nearer(f1: &mut f32, f2: &mut f32, distance_squared: f32) {
+    if distance_squared < *f1 {
+        *f2 = *f1;
+        *f1 = distance_squared;
+    } else if distance_squared < *f2 {
+        *f2 = distance_squared;
     }
+}
 
-    pub fn normalize(&self) -> Self {
-        let len = self.length();
-        if len != 0.0 {
-            *self * (1.0 / len)
-        } else {
-            *self
-        }
+// sRGB encoding (IEC 61966-2-1) of a linear-light channel in [0, 1]
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Inverse of linear_to_srgb
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Vec2 {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Vec2 {
+    pub fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
     }
 }
 
@@ -91,7 +213,7 @@ impl From<&Vec2> for Vec2 {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct Vec3 {
     pub x: f32,
     pub y: f32,
@@ -126,7 +248,7 @@ impl Vec3 {
     }
 
     pub fn length(&self) -> f32 {
-        self.dot(&*self).sqrt()
+        self.dot(self).sqrt()
     }
 
     pub fn normalize(&self) -> Self {
@@ -138,10 +260,6 @@ impl Vec3 {
         }
     }
 
-    pub fn abs(&self) -> Self {
-        Self::new(self.x.abs(), self.y.abs(), self.z.abs())
-    }
-
     pub fn max(&self, other: Self) -> Self {
         Self::new(self.x.max(other.x), self.y.max(other.y), self.z.max(other.z))
     }
@@ -155,28 +273,8 @@ impl Vec3 {
         }
     }
 
-    pub fn sqrt(&self) -> Self {
-        Self {
-            x: self.x.sqrt(),
-            y: self.y.sqrt(),
-            z: self.z.sqrt(),
-        }
-    }
-
-    pub fn exp(&self) -> Self {
-        Self {
-            x: self.x.exp(),
-            y: self.y.exp(),
-            z: self.z.exp(),
-        }
-    }
-
-    pub fn fract(&self) -> Self {
-        Self {
-            x: self.x.fract(),
-            y: self.y.fract(),
-            z: self.z.fract(),
-        }
+    pub fn floor(&self) -> Self {
+        Self::new(self.x.floor(), self.y.floor(), self.z.floor())
     }
 
     pub fn clamp(&self, min: f32, max: f32) -> Self {
@@ -187,12 +285,14 @@ impl Vec3 {
         }
     }
 
-    pub fn powf(&self, n: f32) -> Self {
-        Self {
-            x: self.x.powf(n),
-            y: self.y.powf(n),
-            z: self.z.powf(n),
-        }
+    // Channels clamped to [0, 1] and sRGB encoded
+    pub fn linear_to_srgb(&self) -> Self {
+        let c = self.clamp(0.0, 1.0);
+        Self::new(linear_to_srgb(c.x), linear_to_srgb(c.y), linear_to_srgb(c.z))
+    }
+
+    pub fn srgb_to_linear(&self) -> Self {
+        Self::new(srgb_to_linear(self.x), srgb_to_linear(self.y), srgb_to_linear(self.z))
     }
 }
 
@@ -230,101 +330,9 @@ impl From<&Vec3> for Vec3 {
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct Vec4 {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
-    pub w: f32,
-}
-
-impl Vec4 {
-    pub fn new(x: f32, y: f32, z: f32, w: f32) -> Self {
-        Self { x, y, z, w }
-    }
-
-    pub fn splat(v: f32) -> Self {
-        Self::new(v, v, v, v)
-    }
-
-    pub fn min(&self, other: Self) -> Self {
-        Self::new(
-            self.x.min(other.x),
-            self.y.min(other.y),
-            self.z.min(other.z),
-            self.w.min(other.w),
-        )
-    }
-
-    pub fn lerp<T: Into<Vec4>>(&self, other: T, t: f32) -> Self {
-        let other = other.into();
-        Self {
-            x: self.x + (other.x - self.x) * t,
-            y: self.y + (other.y - self.y) * t,
-            z: self.z + (other.z - self.z) * t,
-            w: self.w + (other.w - self.w) * t,
-        }
-    }
-
-    pub fn sqrt(&self) -> Self {
-        Self {
-            x: self.x.sqrt(),
-            y: self.y.sqrt(),
-            z: self.z.sqrt(),
-            w: self.w.sqrt(),
-        }
-    }
-
-    pub fn length(&self) -> f32 {
-        (self.x * self.x + self.y * self.y + self.z * self.z + self.w * self.w).sqrt()
-    }
-
-    pub fn normalize(&self) -> Self {
-        let len = self.length();
-        if len != 0.0 {
-            *self * (1.0 / len)
-        } else {
-            *self
-        }
-    }
-}
-
-impl Mul<f32> for Vec4 {
-    type Output = Self;
-    fn mul(self, scalar: f32) -> Self {
-        Self::new(
-            self.x * scalar,
-            self.y * scalar,
-            self.z * scalar,
-            self.w * scalar
-        )
-    }
-}
-
-impl From<&Vec4> for Vec4 {
-    fn from(v: &Vec4) -> Self {
-        *v
-    }
-}
-
 pub struct Mat4(pub [[f32; 4]; 4]);
 
 impl Mat4 {
-
-    pub fn new(
-        m00: f32, m01: f32, m02: f32, m03: f32,
-        m10: f32, m11: f32, m12: f32, m13: f32,
-        m20: f32, m21: f32, m22: f32, m23: f32,
-        m30: f32, m31: f32, m32: f32, m33: f32
-    ) -> Self {
-        Mat4([
-            [m00, m01, m02, m03],
-            [m10, m11, m12, m13],
-            [m20, m21, m22, m23],
-            [m30, m31, m32, m33]
-        ])
-    }
-
     pub fn from_rotation_y(angle: f32) -> Self {
         let (sin, cos) = angle.sin_cos();
         Self([
@@ -364,6 +372,21 @@ impl Mat4 {
         rot_z * rot_y * rot_x // Order matters
     }
 
+    pub fn from_translation(t: Vec3) -> Self {
+        Self([
+            [1.0, 0.0, 0.0, t.x],
+            [0.0, 1.0, 0.0, t.y],
+            [0.0, 0.0, 1.0, t.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    // `rotation` applied about `pivot` instead of the origin: move the pivot to the
+    // origin, rotate, and move it back
+    pub fn from_rotation_about(pivot: Vec3, rotation: Mat4) -> Self {
+        Mat4::from_translation(pivot) * rotation * Mat4::from_translation(pivot * -1.0)
+    }
+
     pub fn transform_point3(&self, p: Vec3) -> Vec3 {
         let x = self.0[0][0] * p.x + self.0[0][1] * p.y + self.0[0][2] * p.z + self.0[0][3];
         let y = self.0[1][0] * p.x + self.0[1][1] * p.y + self.0[1][2] * p.z + self.0[1][3];
@@ -371,9 +394,10 @@ impl Mat4 {
         Vec3::new(x, y, z)
     }
 
+    // Inverse of an affine transform: the 3x3 part is inverted and the translation
+    // column undone. This is a simple implementation and might not be numerically
+    // stable for all matrices.
     pub fn inverse(&self) -> Self {
-        // This is a simple implementation and might not be numerically stable for all matrices
-        // For a more robust implementation, consider using a full matrix inversion algorithm
         let mut inv = [[0.0; 4]; 4];
         let mat = self.0;
         let det = mat[0][0] * (mat[1][1] * mat[2][2] - mat[2][1] * mat[1][2])
@@ -390,6 +414,10 @@ impl Mat4 {
         inv[2][0] = (mat[1][0] * mat[2][1] - mat[2][0] * mat[1][1]) * inv_det;
         inv[2][1] = (mat[2][0] * mat[0][1] - mat[0][0] * mat[2][1]) * inv_det;
         inv[2][2] = (mat[0][0] * mat[1][1] - mat[1][0] * mat[0][1]) * inv_det;
+        for row in inv.iter_mut().take(3) {
+            row[3] = -(row[0] * mat[0][3] + row[1] * mat[1][3] + row[2] * mat[2][3]);
+        }
+        inv[3][3] = 1.0;
 
         Mat4(inv)
     }    
@@ -399,13 +427,123 @@ impl Mul for Mat4 {
     type Output = Self;
     fn mul(self, other: Self) -> Self {
         let mut result = [[0.0; 4]; 4];
-        for i in 0..4 {
-            for j in 0..4 {
+        for (i, row) in result.iter_mut().enumerate() {
+            for (j, cell) in row.iter_mut().enumerate() {
                 for k in 0..4 {
-                    result[i][j] += self.0[i][k] * other.0[k][j];
+                    *cell += self.0[i][k] * other.0[k][j];
                 }
             }
         }
         Self(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points over a few cells either side of the origin, off the lattice
+    fn sample_points() -> impl Iterator<Item = Vec3> {
+        (0..500).map(|i| Vec3::new(hash_f32(i) * 8.0 - 4.0, hash_f32(i + 1000) * 8.0 - 4.0, hash_f32(i + 2000) * 8.0 - 4.0))
+    }
+
+    // F1 and F2 over every neighbor in a 5x5x5 block, with no shortcuts
+    fn brute_force_worley_3d(p: Vec3, seed: u32) -> Worley {
+        let cell = [p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32];
+        let mut distances: Vec<f32> = (0..125)
+            .map(|i| {
+                let c = [cell[0] + i % 5 - 2, cell[1] + i / 5 % 5 - 2, cell[2] + i / 25 - 2];
+                let hash = lattice_hash(seed, &c);
+                let point = Vec3::new(c[0] as f32 + hash_f32(hash), c[1] as f32 + hash_f32(hash_u32(hash)), c[2] as f32 + hash_f32(hash_u32(hash ^ 0x85ebca6b)));
+                (point - p).length()
+            })
+            .collect();
+        distances.sort_by(f32::total_cmp);
+        Worley { f1: distances[0], f2: distances[1] }
+    }
+
+    #[test]
+    fn worley_values_are_pinned() {
+        let close = |w: Worley, f1: f32, f2: f32| (w.f1 - f1).abs() < 1e-5 && (w.f2 - f2).abs() < 1e-5;
+        assert!(close(worley_3d(Vec3::new(0.5, 0.5, 0.5), 1), 0.20980406, 0.78688747));
+        assert!(close(worley_3d(Vec3::new(3.25, -1.75, 0.0), 7), 0.28975728, 0.74579215));
+        let warped = warp(Vec3::new(-10.1, 4.9, 2.3), 0.5, 42);
+        assert!((warped - Vec3::new(-9.759418, 5.126973, 2.3223739)).length() < 1e-5);
+    }
+
+    #[test]
+    fn worley_stays_in_range_and_matches_a_full_search() {
+        for p in sample_points() {
+            for seed in [0, 9] {
+                let solid = worley_3d(p, seed);
+                assert!(0.0 <= solid.f1 && solid.f1 <= solid.f2 && solid.f1 <= 3f32.sqrt(), "{:?}", solid);
+                assert!(solid.edge() >= 0.0);
+                // Skipping the far cells never changes the answer
+                let reference = brute_force_worley_3d(p, seed);
+                assert!((solid.f1 - reference.f1).abs() < 1e-5, "{:?} vs {:?}", solid, reference);
+                assert_eq!(worley_3d(p, seed), solid);
+            }
+        }
+        assert_ne!(worley_3d(Vec3::new(0.5, 0.5, 0.5), 1), worley_3d(Vec3::new(0.5, 0.5, 0.5), 2));
+    }
+
+    #[test]
+    fn warp_moves_points_at_most_its_strength() {
+        for p in sample_points() {
+            let warped = warp(p, 0.3, 5);
+            let offset = warped - p;
+            assert!(offset.x.abs() <= 0.3 && offset.y.abs() <= 0.3 && offset.z.abs() <= 0.3, "{:?}", offset);
+            assert_eq!((warped - warp(p, 0.3, 5)).length(), 0.0);
+            assert_eq!((warp(p, 0.0, 5) - p).length(), 0.0);
+        }
+    }
+
+    #[test]
+    fn srgb_curves_invert_each_other() {
+        for step in 0..=1000 {
+            let v = step as f32 / 1000.0;
+            assert!((srgb_to_linear(linear_to_srgb(v)) - v).abs() < 1e-5, "{}", v);
+            assert!((linear_to_srgb(srgb_to_linear(v)) - v).abs() < 1e-5, "{}", v);
+        }
+        assert_eq!(linear_to_srgb(0.0), 0.0);
+        assert!((linear_to_srgb(1.0) - 1.0).abs() < 1e-6);
+        // Mid gray is famously dark in linear light
+        assert!((srgb_to_linear(0.5) - 0.214).abs() < 1e-3);
+    }
+
+    #[test]
+    fn srgb_curves_switch_segments_at_their_breakpoints() {
+        // The linear toe below the breakpoint, the power curve above, meeting there
+        assert_eq!(linear_to_srgb(0.002), 0.002 * 12.92);
+        assert_eq!(srgb_to_linear(0.03), 0.03 / 12.92);
+        assert!((linear_to_srgb(0.003_130_8) - 0.040_45).abs() < 1e-5);
+        assert!((srgb_to_linear(0.040_45) - 0.003_130_8).abs() < 1e-6);
+        let above = 0.003_130_8f32 + 1e-4;
+        assert!((linear_to_srgb(above) - (1.055 * above.powf(1.0 / 2.4) - 0.055)).abs() < 1e-7);
+        // No jump across either seam
+        assert!((linear_to_srgb(0.003_130_8 + 1e-6) - linear_to_srgb(0.003_130_8 - 1e-6)).abs() < 1e-4);
+        assert!((srgb_to_linear(0.040_45 + 1e-6) - srgb_to_linear(0.040_45 - 1e-6)).abs() < 1e-5);
+        // Vec3 clamps before encoding
+        let encoded = Vec3::new(-0.5, 0.5, 2.0).linear_to_srgb();
+        assert_eq!(encoded.x, 0.0);
+        assert!((encoded.z - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn concentric_disc_samples_stay_on_the_unit_disc() {
+        let steps = 64;
+        let mut quadrants = [0; 4];
+        for i in 0..=steps {
+            for j in 0..=steps {
+                let (x, y) = concentric_disc(i as f32 / steps as f32, j as f32 / steps as f32);
+                assert!(x * x + y * y <= 1.0 + 1e-5, "{} {} -> {} {}", i, j, x, y);
+                quadrants[(x >= 0.0) as usize * 2 + (y >= 0.0) as usize] += 1;
+            }
+        }
+        // The square's edge lands on the circle and its middle on the center
+        let (x, y) = concentric_disc(1.0, 0.5);
+        assert!((x - 1.0).abs() < 1e-6 && y.abs() < 1e-6);
+        assert_eq!(concentric_disc(0.5, 0.5), (0.0, 0.0));
+        assert!(quadrants.iter().all(|&count| count > steps * steps / 8), "{:?}", quadrants);
+    }
+}
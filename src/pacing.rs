@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Weight of the newest present in the running average of their cost
+const COST_SMOOTHING: f32 = 0.2;
+// Rates count the events of the last second
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// Events per second over the last second
+pub struct RateCounter {
+    times: VecDeque<Instant>,
+}
+
+impl RateCounter {
+    pub fn new() -> Self {
+        RateCounter { times: VecDeque::new() }
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.times.push_back(now);
+        while self.times.front().is_some_and(|&time| now.duration_since(time) > RATE_WINDOW) {
+            self.times.pop_front();
+        }
+    }
+
+    pub fn rate(&self, now: Instant) -> f32 {
+        let recent = self.times.iter().filter(|&&time| now.duration_since(time) <= RATE_WINDOW).count();
+        recent as f32 / RATE_WINDOW.as_secs_f32()
+    }
+}
+
+// Decides which rendered frames get written to the terminal. Over a slow link
+// writing a frame can take longer than the frame budget, and writing every frame
+// anyway lets output queue up until the picture is seconds behind. Once writes
+// cost more than the budget, frames are still rendered every loop but only go
+// out after the last write has had as long again to drain, latest frame first.
+pub struct PresentPacer {
+    // None presents every frame
+    budget: Option<Duration>,
+    // Running average of how long a present takes, in seconds
+    cost: f32,
+    last_present_end: Option<Instant>,
+    rendered: RateCounter,
+    presented: RateCounter,
+}
+
+impl PresentPacer {
+    pub fn new(budget: Option<Duration>) -> Self {
+        PresentPacer { budget, cost: 0.0, last_present_end: None, rendered: RateCounter::new(), presented: RateCounter::new() }
+    }
+
+    // Counts a frame rendered at `now`, and tells whether it should be presented
+    pub fn should_present(&mut self, now: Instant) -> bool {
+        self.rendered.tick(now);
+        if !self.is_skipping() {
+            return true;
+        }
+        self.last_present_end.is_none_or(|end| now.duration_since(end).as_secs_f32() >= self.cost)
+    }
+
+    // A present that took `duration`, ending at `end`
+    pub fn presented(&mut self, duration: Duration, end: Instant) {
+        self.cost += (duration.as_secs_f32() - self.cost) * COST_SMOOTHING;
+        self.last_present_end = Some(end);
+        self.presented.tick(end);
+    }
+
+    // Whether presents cost more than the budget, so frames get dropped
+    pub fn is_skipping(&self) -> bool {
+        self.budget.is_some_and(|budget| self.cost > budget.as_secs_f32())
+    }
+
+    // Frames rendered and frames presented per second
+    pub fn rates(&self, now: Instant) -> (f32, f32) {
+        (self.rendered.rate(now), self.presented.rate(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(16);
+
+    // Renders `frames` frames one FRAME apart, each present taking `cost`. Returns
+    // the indices of the frames presented and the pacer afterwards.
+    fn run(budget: Option<Duration>, cost: Duration, frames: u32) -> (Vec<u32>, PresentPacer, Instant) {
+        let start = Instant::now();
+        let mut pacer = PresentPacer::new(budget);
+        let mut shown = Vec::new();
+        let mut now = start;
+        for frame in 0..frames {
+            now = now.max(start + FRAME * frame);
+            if pacer.should_present(now) {
+                // The write blocks the loop for as long as it takes
+                now += cost;
+                pacer.presented(cost, now);
+                shown.push(frame);
+            }
+        }
+        (shown, pacer, now)
+    }
+
+    #[test]
+    fn every_frame_presents_within_budget_or_without_one() {
+        for (budget, cost) in [(Some(FRAME), Duration::from_millis(5)), (None, Duration::from_millis(5)), (None, Duration::from_millis(50))] {
+            let (shown, pacer, _) = run(budget, cost, 120);
+            assert_eq!(shown, (0..120).collect::<Vec<_>>(), "{:?} {:?}", budget, cost);
+            assert!(!pacer.is_skipping());
+        }
+    }
+
+    #[test]
+    fn slow_presents_are_spaced_by_their_cost() {
+        let cost = Duration::from_millis(40);
+        let (shown, pacer, _) = run(Some(FRAME), cost, 300);
+        assert!(pacer.is_skipping());
+        assert!((pacer.cost - cost.as_secs_f32()).abs() < 1e-4);
+        // Once the average has settled, each present waits out another cost's worth
+        // of frames after the last one finished, then takes the frame rendered then
+        let settled: Vec<u32> = shown.iter().copied().filter(|&frame| frame > 60).collect();
+        assert!(settled.windows(2).all(|pair| (pair[1] - pair[0]) * FRAME >= cost * 2 && (pair[1] - pair[0] - 1) * FRAME < cost * 2), "{:?}", settled);
+        // The newest frame still goes out rather than the loop falling behind
+        assert!(*shown.last().unwrap() >= 300 - 6);
+    }
+
+    #[test]
+    fn rendered_and_presented_rates_diverge_when_skipping() {
+        let (_, pacer, end) = run(Some(FRAME), Duration::from_millis(40), 300);
+        let (rendered, presented) = pacer.rates(end);
+        // 16ms frames, and a present every 80ms or so
+        assert!((rendered - 62.0).abs() <= 2.0, "{}", rendered);
+        assert!((presented - 12.5).abs() <= 1.5, "{}", presented);
+
+        let (_, pacer, end) = run(None, Duration::from_millis(5), 300);
+        let (rendered, presented) = pacer.rates(end);
+        assert_eq!(rendered, presented);
+    }
+
+    #[test]
+    fn rates_forget_events_older_than_a_second() {
+        let start = Instant::now();
+        let mut counter = RateCounter::new();
+        for tick in 0..30 {
+            counter.tick(start + Duration::from_millis(100) * tick);
+        }
+        let last = start + Duration::from_millis(2900);
+        assert_eq!(counter.rate(last), 11.0);
+        assert_eq!(counter.rate(last + Duration::from_millis(950)), 1.0);
+        assert_eq!(counter.rate(last + Duration::from_secs(2)), 0.0);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+// A pair keeps its color against a newcomer unless the newcomer covers this much
+// more of the frame, so two colors trading places near the cutoff don't redefine
+// a pair every frame
+const RETAIN_BONUS: f32 = 1.25;
+
+// Color pairs for terminals with fewer of them than the palette has colors. Every
+// palette color has a virtual pair number as if there were enough; each frame the
+// most used ones get real pairs and the rest are drawn with the nearest color that
+// has one. Pairs keep their color from frame to frame until the least recently
+// used one is needed for another.
+pub struct PairBudget {
+    // Virtual pair shown by each real pair, real pairs numbered from 1
+    slots: Vec<Option<usize>>,
+    // Frame each slot was last drawn in
+    last_used: Vec<u64>,
+    frame: u64,
+}
+
+// How a frame's virtual pairs are drawn
+pub struct Allocation {
+    // Real pair of every virtual pair in the frame, 0 for one without a
+    // compatible color to snap to
+    pub pairs: HashMap<usize, i16>,
+    // Real pairs given a new color this frame, with the virtual pair they now show
+    pub defined: Vec<(i16, usize)>,
+}
+
+impl PairBudget {
+    pub fn new(capacity: usize) -> Self {
+        PairBudget { slots: vec![None; capacity], last_used: vec![0; capacity], frame: 0 }
+    }
+
+    // Forget every pair's color, for when what the virtual pairs mean changes
+    pub fn reset(&mut self) {
+        self.slots.fill(None);
+        self.last_used.fill(0);
+    }
+
+    // Real pairs for a frame from its histogram of (virtual pair, cells using it).
+    // `distance` compares two virtual pairs' colors, None when one can't stand in
+    // for the other.
+    pub fn allocate(&mut self, histogram: &[(usize, usize)], distance: impl Fn(usize, usize) -> Option<i32>) -> Allocation {
+        self.frame += 1;
+        let mut ranked: Vec<(usize, f32)> = histogram
+            .iter()
+            .map(|&(virtual_pair, cells)| {
+                let bonus = if self.slot_of(virtual_pair).is_some() { RETAIN_BONUS } else { 1.0 };
+                (virtual_pair, cells as f32 * bonus)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked.truncate(self.slots.len());
+
+        // Keep what is already defined first, so only newcomers can evict
+        let mut newcomers = Vec::new();
+        for &(virtual_pair, _) in &ranked {
+            match self.slot_of(virtual_pair) {
+                Some(slot) => self.last_used[slot] = self.frame,
+                None => newcomers.push(virtual_pair),
+            }
+        }
+        let mut defined = Vec::new();
+        for virtual_pair in newcomers {
+            // Free slots have never been used, so they come first
+            let slot = (0..self.slots.len()).filter(|&slot| self.last_used[slot] < self.frame).min_by_key(|&slot| self.last_used[slot]).expect("at most one newcomer per free or stale slot");
+            self.slots[slot] = Some(virtual_pair);
+            self.last_used[slot] = self.frame;
+            defined.push((slot as i16 + 1, virtual_pair));
+        }
+
+        let pairs = histogram.iter().map(|&(virtual_pair, _)| (virtual_pair, self.real_pair(virtual_pair, &distance))).collect();
+        Allocation { pairs, defined }
+    }
+
+    fn slot_of(&self, virtual_pair: usize) -> Option<usize> {
+        self.slots.iter().position(|&slot| slot == Some(virtual_pair))
+    }
+
+    // The pair showing `virtual_pair`, or the one showing the nearest color to it
+    fn real_pair(&self, virtual_pair: usize, distance: &impl Fn(usize, usize) -> Option<i32>) -> i16 {
+        if let Some(slot) = self.slot_of(virtual_pair) {
+            return slot as i16 + 1;
+        }
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, shown)| shown.and_then(|shown| distance(virtual_pair, shown)).map(|d| (slot, d)))
+            .min_by_key(|&(_, d)| d)
+            .map_or(0, |(slot, _)| slot as i16 + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Virtual pairs in banks of ten, a pair's color being its number: any two in a
+    // bank can stand in for each other, the nearer the better
+    fn distance(a: usize, b: usize) -> Option<i32> {
+        (a / 10 == b / 10).then(|| (a as i32 - b as i32).abs())
+    }
+
+    fn defined(allocation: &Allocation) -> Vec<usize> {
+        let mut pairs: Vec<usize> = allocation.defined.iter().map(|&(_, virtual_pair)| virtual_pair).collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn the_most_used_colors_get_pairs() {
+        let mut budget = PairBudget::new(2);
+        let allocation = budget.allocate(&[(1, 5), (4, 50), (7, 30), (8, 1)], distance);
+        assert_eq!(defined(&allocation), [4, 7]);
+        let (four, seven) = (allocation.pairs[&4], allocation.pairs[&7]);
+        assert!([four, seven] == [1, 2] || [four, seven] == [2, 1]);
+        // The rest are drawn with the nearest color that has a pair
+        assert_eq!(allocation.pairs[&1], four);
+        assert_eq!(allocation.pairs[&8], seven);
+    }
+
+    #[test]
+    fn colors_only_snap_within_their_bank() {
+        let mut budget = PairBudget::new(1);
+        let allocation = budget.allocate(&[(3, 10), (5, 2), (13, 1)], distance);
+        assert_eq!(allocation.pairs[&3], 1);
+        assert_eq!(allocation.pairs[&5], 1);
+        assert_eq!(allocation.pairs[&13], 0);
+    }
+
+    #[test]
+    fn a_steady_frame_redefines_nothing() {
+        let mut budget = PairBudget::new(3);
+        let histogram = [(1, 40), (2, 30), (3, 20), (4, 10)];
+        let first = budget.allocate(&histogram, distance);
+        assert_eq!(defined(&first), [1, 2, 3]);
+        for _ in 0..5 {
+            let again = budget.allocate(&histogram, distance);
+            assert!(again.defined.is_empty());
+            assert_eq!(again.pairs, first.pairs);
+        }
+    }
+
+    #[test]
+    fn a_newcomer_needs_a_clear_lead_to_take_a_pair() {
+        let mut budget = PairBudget::new(1);
+        budget.allocate(&[(1, 100)], distance);
+        // Ahead, but not by RETAIN_BONUS
+        let close = budget.allocate(&[(1, 100), (2, 120)], distance);
+        assert!(close.defined.is_empty());
+        assert_eq!(close.pairs[&2], 1);
+        // Far enough ahead
+        let clear = budget.allocate(&[(1, 100), (2, 130)], distance);
+        assert_eq!(clear.defined, [(1, 2)]);
+    }
+
+    #[test]
+    fn the_least_recently_used_pair_is_evicted() {
+        let mut budget = PairBudget::new(2);
+        let first = budget.allocate(&[(1, 10), (2, 10)], distance);
+        let (one, two) = (first.pairs[&1], first.pairs[&2]);
+        // 2 sits a frame out, so 3 takes its pair and 1 keeps its own
+        budget.allocate(&[(1, 10)], distance);
+        let third = budget.allocate(&[(1, 10), (3, 20)], distance);
+        assert_eq!(third.defined, [(two, 3)]);
+        assert_eq!(third.pairs[&1], one);
+        // Both are in use now, so a newcomer that ranks too low gets none
+        let fourth = budget.allocate(&[(1, 10), (3, 20), (5, 1)], distance);
+        assert!(fourth.defined.is_empty());
+        assert_eq!(fourth.pairs[&5], two);
+    }
+
+    #[test]
+    fn reset_forgets_every_pair() {
+        let mut budget = PairBudget::new(2);
+        let histogram = [(1, 10), (2, 10)];
+        budget.allocate(&histogram, distance);
+        budget.reset();
+        assert_eq!(defined(&budget.allocate(&histogram, distance)), [1, 2]);
+        // And with nothing in the frame, nothing has a pair to snap to
+        budget.reset();
+        assert_eq!(budget.allocate(&[], distance).pairs.len(), 0);
+        assert_eq!(budget.real_pair(1, &distance), 0);
+    }
+}
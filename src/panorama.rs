@@ -0,0 +1,138 @@
+use crate::camera::{pixel_to_ndc, Camera, Projection};
+use crate::math::Vec3;
+use crate::raymarch::{ray_march, SceneFrame};
+use crate::screenshot::write_rgb_png;
+use crate::shader::ShaderSettings;
+use rayon::prelude::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+const PANORAMA_ROOT: &str = "panoramas";
+// Width of an exported panorama in pixels; the height is always half of it
+pub const DEFAULT_PANORAMA_WIDTH: usize = 4096;
+pub const MAX_PANORAMA_WIDTH: usize = 16384;
+
+// The camera a panorama is taken with: the scene camera's eye, looking the way
+// it faces but level, so the horizon runs straight across the middle
+pub fn panorama_camera(camera: &Camera) -> Camera {
+    let forward = camera.target - camera.eye;
+    let level = Vec3::new(forward.x, 0.0, forward.z);
+    let level = if level.length() > 1e-6 { level } else { Vec3::new(0.0, 0.0, 1.0) };
+    Camera::new(camera.eye, camera.eye + level, Vec3::new(0.0, 1.0, 0.0), Projection::Equirectangular)
+}
+
+// Ray through the center of pixel (x, y) of a `width` x `height` panorama. Columns
+// span the full turn with the seam straight behind the camera, rows run from the
+// zenith down to the nadir.
+pub fn panorama_ray(camera: &Camera, x: usize, y: usize, width: usize, height: usize) -> (Vec3, Vec3) {
+    camera.ray(pixel_to_ndc(x as f32 + 0.5, y as f32 + 0.5, width, height))
+}
+
+// A 360x180 degree equirectangular render of one moment of the scene, made on a
+// thread of its own so the interactive loop keeps running. Rows are shared with
+// the render through rayon's pool.
+pub struct PanoramaExport {
+    width: usize,
+    height: usize,
+    rows_done: Arc<AtomicUsize>,
+    cancel: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<PathBuf>>,
+}
+
+impl PanoramaExport {
+    // `frame` fixes the scene time and `camera` the eye; written to
+    // panoramas/panorama-NNNNNN.png under `index`
+    pub fn start(frame: SceneFrame, camera: Camera, shader: ShaderSettings, width: usize, index: u32) -> Self {
+        let height = (width / 2).max(1);
+        let rows_done = Arc::new(AtomicUsize::new(0));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let path = Path::new(PANORAMA_ROOT).join(format!("panorama-{:06}.png", index));
+        let thread = {
+            let (rows_done, cancel) = (rows_done.clone(), cancel.clone());
+            thread::spawn(move || {
+                let camera = panorama_camera(&camera);
+                let shader = shader.shader();
+                let footprint = camera.pixel_footprint(width);
+                let mut image = vec![0u8; width * height * 3];
+                image.par_chunks_mut(width * 3).enumerate().for_each(|(y, row)| {
+                    if cancel.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    for (x, rgb) in row.chunks_exact_mut(3).enumerate() {
+                        let (origin, direction) = panorama_ray(&camera, x, y, width, height);
+                        let color = ray_march(origin, direction, &frame, shader.as_ref(), footprint, None).color;
+                        rgb.copy_from_slice(&[color.r, color.g, color.b]);
+                    }
+                    rows_done.fetch_add(1, Ordering::Relaxed);
+                });
+                if cancel.load(Ordering::Relaxed) {
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+                }
+                fs::create_dir_all(PANORAMA_ROOT)?;
+                write_rgb_png(&path, width, height, &image)?;
+                Ok(path)
+            })
+        };
+        PanoramaExport { width, height, rows_done, cancel, thread }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    // Share of the rows rendered, 0 to 1
+    pub fn progress(&self) -> f32 {
+        self.rows_done.load(Ordering::Relaxed) as f32 / self.height as f32
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    // Waits for the file to be written, returning its path
+    pub fn finish(self) -> io::Result<PathBuf> {
+        self.thread.join().unwrap_or_else(|_| Err(io::Error::other("panorama render panicked")))
+    }
+
+    // Stops rendering after the rows in flight, without writing anything
+    pub fn cancel(self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_meet_at_the_seam_behind_the_camera() {
+        // Looking down at an angle, which the panorama levels out
+        let scene_camera = Camera::new(Vec3::new(1.0, 2.0, -3.0), Vec3::new(3.0, 0.0, -2.0), Vec3::new(0.0, 1.0, 0.0), Projection::Equirectangular);
+        let camera = panorama_camera(&scene_camera);
+        let forward = Vec3::new(2.0, 0.0, 1.0).normalize();
+        let (width, height) = (64, 32);
+        let column_step = std::f32::consts::TAU / width as f32;
+
+        for y in 0..height {
+            let (origin, first) = panorama_ray(&camera, 0, y, width, height);
+            let (_, second) = panorama_ray(&camera, 1, y, width, height);
+            let (_, last) = panorama_ray(&camera, width - 1, y, width, height);
+            assert!((origin - scene_camera.eye).length() < 1e-5);
+            // Across the seam the step is the same as between any two columns
+            let across = first.dot(&last).clamp(-1.0, 1.0).acos();
+            let between = first.dot(&second).clamp(-1.0, 1.0).acos();
+            assert!((across - between).abs() < 1e-3, "row {}: {} vs {}", y, across, between);
+            let latitude = first.y.asin();
+            assert!((between - column_step * latitude.cos()).abs() < 1e-3, "row {}", y);
+            // The two edge columns mirror each other about the direction straight back
+            assert!((first.y - last.y).abs() < 1e-5);
+            let back = Vec3::new(first.x + last.x, 0.0, first.z + last.z).normalize();
+            assert!((back + forward).length() < 1e-3, "row {}", y);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use ncurses::{KEY_DOWN, KEY_LEFT, KEY_RIGHT, KEY_UP};
+use crate::math::Vec3;
+use crate::pixel::Pixel;
+use crate::raymarch::{Hit, ObjectId};
+
+// Cells the crosshair reaches out from the picked cell, which itself stays uncovered
+const CROSSHAIR_ARM: usize = 2;
+
+// The cell whose ray is inspected while picking
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PickCursor {
+    pub x: usize,
+    pub y: usize,
+}
+
+impl PickCursor {
+    pub fn centered(cells_w: usize, cells_h: usize) -> Self {
+        PickCursor { x: cells_w / 2, y: cells_h / 2 }
+    }
+
+    // One cell along an arrow key, staying on a cells_w x cells_h screen. None for
+    // any other key.
+    pub fn moved(self, key: i32, cells_w: usize, cells_h: usize) -> Option<Self> {
+        let PickCursor { x, y } = self;
+        let (x, y) = match key {
+            KEY_LEFT => (x.saturating_sub(1), y),
+            KEY_RIGHT => (x + 1, y),
+            KEY_UP => (x, y.saturating_sub(1)),
+            KEY_DOWN => (x, y + 1),
+            _ => return None,
+        };
+        Some(PickCursor { x, y }.clamped(cells_w, cells_h))
+    }
+
+    // Back on screen after it shrank
+    pub fn clamped(self, cells_w: usize, cells_h: usize) -> Self {
+        PickCursor { x: self.x.min(cells_w.saturating_sub(1)), y: self.y.min(cells_h.saturating_sub(1)) }
+    }
+}
+
+// The info panel's lines for a picked ray: what it hit, or a miss, and `color`,
+// the pixel it shaded before post-processing
+pub fn describe(hit: Option<&Hit>, color: Pixel) -> Vec<String> {
+    let color = format!("color {} {} {}", color.r, color.g, color.b);
+    let Some(hit) = hit else {
+        return vec!["miss".to_string(), color];
+    };
+    let object = match hit.object {
+        ObjectId::Cube(index) => format!("object cube {}", index),
+        object => format!("object {}", object.name()),
+    };
+    // The light has no albedo to speak of
+    let material = if hit.material.emissive.length() == 0.0 {
+        format!("albedo {}", vector(hit.material.albedo))
+    } else {
+        format!("emissive {}", vector(hit.material.emissive))
+    };
+    vec![
+        object,
+        material,
+        format!("position {}", vector(hit.position)),
+        format!("depth {:.3}", hit.depth),
+        format!("normal {}", vector(hit.normal)),
+        color,
+    ]
+}
+
+fn vector(v: Vec3) -> String {
+    format!("{:.2} {:.2} {:.2}", v.x, v.y, v.z)
+}
+
+// Text for TerminalBuffer::set_overlay: a crosshair around the cursor, and `lines`
+// in the top corner on the other side of the screen from it
+pub fn overlay(cursor: PickCursor, lines: &[String], cells_w: usize) -> Vec<(usize, usize, String)> {
+    let PickCursor { x, y } = cursor;
+    let mut overlay = vec![
+        (x.saturating_sub(CROSSHAIR_ARM), y, "-".repeat(x.min(CROSSHAIR_ARM))),
+        (x + 1, y, "-".repeat(CROSSHAIR_ARM)),
+    ];
+    for reach in 1..=CROSSHAIR_ARM {
+        if let Some(above) = y.checked_sub(reach) {
+            overlay.push((x, above, "|".to_string()));
+        }
+        overlay.push((x, y + reach, "|".to_string()));
+    }
+    let width = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+    let panel_x = if x < cells_w / 2 { cells_w.saturating_sub(width) } else { 0 };
+    overlay.extend(lines.iter().enumerate().map(|(row, line)| (panel_x, row, format!("{:<width$}", line))));
+    overlay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrows_move_the_cursor_without_leaving_the_screen() {
+        let (w, h) = (40, 16);
+        let corner = PickCursor { x: 0, y: 0 };
+        assert_eq!(corner.moved(KEY_LEFT, w, h), Some(corner));
+        assert_eq!(corner.moved(KEY_UP, w, h), Some(corner));
+        assert_eq!(corner.moved(KEY_RIGHT, w, h), Some(PickCursor { x: 1, y: 0 }));
+        assert_eq!(corner.moved(KEY_DOWN, w, h), Some(PickCursor { x: 0, y: 1 }));
+
+        let far = PickCursor { x: w - 1, y: h - 1 };
+        assert_eq!(far.moved(KEY_RIGHT, w, h), Some(far));
+        assert_eq!(far.moved(KEY_DOWN, w, h), Some(far));
+        assert_eq!(far.moved(KEY_LEFT, w, h), Some(PickCursor { x: w - 2, y: h - 1 }));
+        assert_eq!(far.moved('x' as i32, w, h), None);
+    }
+
+    #[test]
+    fn a_cursor_off_a_shrunk_screen_comes_back_on() {
+        let cursor = PickCursor::centered(80, 30);
+        assert_eq!(cursor, PickCursor { x: 40, y: 15 });
+        assert_eq!(cursor.clamped(80, 30), cursor);
+        assert_eq!(cursor.clamped(20, 10), PickCursor { x: 19, y: 9 });
+        assert_eq!(cursor.clamped(0, 0), PickCursor { x: 0, y: 0 });
+        // A move on the smaller screen clamps as well
+        assert_eq!(cursor.moved(KEY_UP, 20, 10), Some(PickCursor { x: 19, y: 9 }));
+    }
+
+    #[test]
+    fn the_panel_goes_on_the_side_away_from_the_cursor() {
+        let lines = vec!["miss".to_string(), "color 1 2 3".to_string()];
+        let panel = |cursor: PickCursor| {
+            let overlay = overlay(cursor, &lines, 40);
+            overlay.into_iter().filter(|(_, _, text)| text.starts_with("miss") || text.starts_with("color")).collect::<Vec<_>>()
+        };
+        // Padded to the widest line, flush with the right edge
+        let right = vec![(29, 0, "miss       ".to_string()), (29, 1, "color 1 2 3".to_string())];
+        assert_eq!(panel(PickCursor { x: 5, y: 8 }), right);
+        assert_eq!(panel(PickCursor { x: 0, y: 0 }), right);
+        let left = vec![(0, 0, "miss       ".to_string()), (0, 1, "color 1 2 3".to_string())];
+        assert_eq!(panel(PickCursor { x: 20, y: 8 }), left);
+        assert_eq!(panel(PickCursor { x: 39, y: 15 }), left);
+    }
+
+    #[test]
+    fn the_crosshair_leaves_the_picked_cell_clear() {
+        let overlay = overlay(PickCursor { x: 1, y: 1 }, &[], 40);
+        assert_eq!(overlay, vec![
+            (0, 1, "-".to_string()),
+            (2, 1, "--".to_string()),
+            (1, 0, "|".to_string()),
+            (1, 2, "|".to_string()),
+            (1, 3, "|".to_string()),
+        ]);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::font::GLYPH_HEIGHT;
+use crate::framebuffer::Framebuffer;
+use crate::pixel::Pixel;
+use ncurses::{KEY_DOWN, KEY_ENTER, KEY_LEFT, KEY_RIGHT, KEY_UP};
+
+// Size of a thumbnail in framebuffer pixels, wide enough for the longest name
+// under it, and the scene time it shows
+pub const THUMBNAIL_WIDTH: usize = 28;
+pub const THUMBNAIL_HEIGHT: usize = 12;
+pub const THUMBNAIL_TIME: f32 = 2.0;
+// Space around the grid and between tiles
+const TILE_GAP: usize = 2;
+// A tile is the thumbnail with its name underneath
+const TILE_HEIGHT: usize = THUMBNAIL_HEIGHT + 1 + GLYPH_HEIGHT;
+
+// What a key press in the picker asks for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PickerAction {
+    Stay,
+    // Back to the running scene as it was
+    Close,
+    // Switch to the scene with this --scene name
+    Start(&'static str),
+}
+
+// Grid of scene thumbnails to choose the next scene from. Thumbnails are rendered
+// the first time the picker is drawn and kept from then on; rows scroll to keep
+// the selection on screen when there are more scenes than fit.
+pub struct ScenePicker {
+    names: Vec<&'static str>,
+    thumbnails: Vec<Option<Framebuffer>>,
+    open: bool,
+    selected: usize,
+    // First tile row on screen
+    scroll: usize,
+    // Tiles per row at the last draw, for moving up and down
+    columns: usize,
+}
+
+impl ScenePicker {
+    pub fn new(names: Vec<&'static str>) -> Self {
+        let thumbnails = names.iter().map(|_| None).collect();
+        ScenePicker { names, thumbnails, open: false, selected: 0, scroll: 0, columns: 1 }
+    }
+
+    pub fn open(&mut self) {
+        self.open = true;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    // Scenes still without a thumbnail, by index and name
+    pub fn missing_thumbnails(&self) -> Vec<(usize, &'static str)> {
+        self.names.iter().enumerate().filter(|(index, _)| self.thumbnails[*index].is_none()).map(|(index, &name)| (index, name)).collect()
+    }
+
+    // Thumbnails go stale when the scenes change, as on a reseed
+    pub fn forget_thumbnails(&mut self) {
+        self.thumbnails.iter_mut().for_each(|thumbnail| *thumbnail = None);
+    }
+
+    pub fn set_thumbnail(&mut self, index: usize, thumbnail: Framebuffer) {
+        self.thumbnails[index] = Some(thumbnail);
+    }
+
+    // Arrows move the selection, Enter starts the selected scene and ESC leaves.
+    // Starting or leaving closes the picker.
+    pub fn handle_key(&mut self, key: i32) -> PickerAction {
+        let last = self.names.len().saturating_sub(1);
+        match key {
+            KEY_LEFT => self.selected = self.selected.saturating_sub(1),
+            KEY_RIGHT => self.selected = (self.selected + 1).min(last),
+            KEY_UP => self.selected = self.selected.checked_sub(self.columns).unwrap_or(self.selected),
+            KEY_DOWN => self.selected = (self.selected + self.columns).min(last),
+            // ncurses reports Enter as a newline unless the terminal is in raw mode
+            c if c == KEY_ENTER || c == '\n' as i32 || c == '\r' as i32 => {
+                self.open = false;
+                return self.names.get(self.selected).map_or(PickerAction::Close, |&name| PickerAction::Start(name));
+            }
+            27 => {
+                self.open = false;
+                return PickerAction::Close;
+            }
+            _ => {}
+        }
+        PickerAction::Stay
+    }
+
+    // Lay the tiles out over the cleared framebuffer, the selected one framed in
+    // `highlight`
+    pub fn draw(&mut self, fb: &mut Framebuffer, text_color: Pixel, highlight: Pixel) {
+        fb.clear();
+        self.columns = (fb.width.saturating_sub(TILE_GAP) / (THUMBNAIL_WIDTH + TILE_GAP)).max(1);
+        let visible_rows = (fb.height.saturating_sub(TILE_GAP) / (TILE_HEIGHT + TILE_GAP)).max(1);
+
+        // Scroll just far enough to bring the selected row on screen
+        let selected_row = self.selected / self.columns;
+        if selected_row < self.scroll {
+            self.scroll = selected_row;
+        } else if selected_row >= self.scroll + visible_rows {
+            self.scroll = selected_row + 1 - visible_rows;
+        }
+
+        let first = self.scroll * self.columns;
+        let end = ((self.scroll + visible_rows) * self.columns).min(self.names.len());
+        for index in first..end {
+            let (column, row) = (index % self.columns, index / self.columns - self.scroll);
+            let x = TILE_GAP + column * (THUMBNAIL_WIDTH + TILE_GAP);
+            let y = TILE_GAP + row * (TILE_HEIGHT + TILE_GAP);
+            if let Some(thumbnail) = &self.thumbnails[index] {
+                fb.blit(thumbnail, x, y);
+            }
+            if index == self.selected {
+                let (left, top) = (x as i32 - 1, y as i32 - 1);
+                let (right, bottom) = ((x + THUMBNAIL_WIDTH) as i32, (y + THUMBNAIL_HEIGHT) as i32);
+                fb.draw_line(left, top, right, top, highlight);
+                fb.draw_line(right, top, right, bottom, highlight);
+                fb.draw_line(right, bottom, left, bottom, highlight);
+                fb.draw_line(left, bottom, left, top, highlight);
+            }
+            let label = self.names[index].to_uppercase();
+            let (label_width, _) = Framebuffer::text_size(&label);
+            fb.draw_text(x + THUMBNAIL_WIDTH.saturating_sub(label_width) / 2, y + THUMBNAIL_HEIGHT + 1, &label, text_color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 5] = ["cubes", "terrain", "repetition", "plasma", "tunnel"];
+    const WHITE: Pixel = Pixel { r: 255, g: 255, b: 255, a: 255 };
+
+    // Open over a framebuffer with room for two tiles across and one row of them
+    fn picker() -> (ScenePicker, Framebuffer) {
+        let mut picker = ScenePicker::new(NAMES.to_vec());
+        picker.open();
+        let mut fb = Framebuffer::new(TILE_GAP + 2 * (THUMBNAIL_WIDTH + TILE_GAP), TILE_GAP + TILE_HEIGHT + TILE_GAP);
+        picker.draw(&mut fb, WHITE, WHITE);
+        assert_eq!(picker.columns, 2);
+        (picker, fb)
+    }
+
+    #[test]
+    fn arrows_stay_within_the_scenes() {
+        let (mut picker, _) = picker();
+        assert_eq!(picker.handle_key(KEY_LEFT), PickerAction::Stay);
+        assert_eq!(picker.handle_key(KEY_UP), PickerAction::Stay);
+        assert_eq!(picker.selected, 0);
+        picker.handle_key(KEY_RIGHT);
+        picker.handle_key(KEY_DOWN);
+        assert_eq!(picker.selected, 3);
+        // Down from the last full row lands on the last scene, not past it
+        picker.handle_key(KEY_DOWN);
+        assert_eq!(picker.selected, 4);
+        picker.handle_key(KEY_RIGHT);
+        assert_eq!(picker.selected, 4);
+        picker.handle_key(KEY_UP);
+        assert_eq!(picker.selected, 2);
+        assert!(picker.is_open());
+    }
+
+    #[test]
+    fn rows_scroll_to_keep_the_selection_in_view() {
+        let (mut picker, mut fb) = picker();
+        let mut draw = |picker: &mut ScenePicker| {
+            picker.draw(&mut fb, WHITE, WHITE);
+            picker.scroll
+        };
+        picker.handle_key(KEY_RIGHT);
+        assert_eq!(draw(&mut picker), 0);
+        picker.handle_key(KEY_DOWN);
+        assert_eq!(draw(&mut picker), 1);
+        picker.handle_key(KEY_DOWN);
+        assert_eq!(draw(&mut picker), 2);
+        // Back up only as far as the selected row
+        picker.handle_key(KEY_UP);
+        assert_eq!(draw(&mut picker), 1);
+        picker.handle_key(KEY_RIGHT);
+        assert_eq!(draw(&mut picker), 1);
+        picker.handle_key(KEY_UP);
+        assert_eq!(draw(&mut picker), 0);
+    }
+
+    #[test]
+    fn enter_starts_and_escape_closes() {
+        let (mut picker, _) = picker();
+        picker.handle_key(KEY_RIGHT);
+        picker.handle_key(KEY_DOWN);
+        assert_eq!(picker.handle_key('\n' as i32), PickerAction::Start("plasma"));
+        assert!(!picker.is_open());
+
+        for enter in [KEY_ENTER, '\r' as i32] {
+            picker.open();
+            assert_eq!(picker.handle_key(enter), PickerAction::Start("plasma"));
+        }
+        picker.open();
+        assert_eq!(picker.handle_key(27), PickerAction::Close);
+        assert!(!picker.is_open());
+        // With nothing to pick, Enter only closes
+        let mut empty = ScenePicker::new(Vec::new());
+        empty.open();
+        assert_eq!(empty.handle_key('\n' as i32), PickerAction::Close);
+    }
+}
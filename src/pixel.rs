@@ -7,7 +7,32 @@ pub struct Pixel {
 }
 
 impl Pixel {
-    pub fn to_rgb(&self) -> (u8, u8, u8) {
+    pub fn to_rgb(self) -> (u8, u8, u8) {
         (self.r, self.g, self.b)
     }
 }
+
+// An RRGGBB color as written on the command line and in config files, with or
+// without a leading '#'
+pub fn parse_hex_rgb(text: &str) -> Option<(u8, u8, u8)> {
+    let digits = text.strip_prefix('#').unwrap_or(text);
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_colors_parse_with_or_without_a_hash() {
+        assert_eq!(parse_hex_rgb("#1a00FF"), Some((26, 0, 255)));
+        assert_eq!(parse_hex_rgb("000000"), Some((0, 0, 0)));
+        for bad in ["", "#", "#12345", "#1234567", "12345g", "#+1+2+3", "#ééé"] {
+            assert_eq!(parse_hex_rgb(bad), None, "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::framebuffer::Framebuffer;
+use crate::pixel::Pixel;
+
+// Immediate-mode line graphs drawn straight into the framebuffer
+
+#[derive(Clone, Copy, Debug)]
+pub struct PlotStyle {
+    pub line_color: Pixel,
+    // Area between the line and the bottom of the plot, None to leave it empty
+    pub fill_color: Option<Pixel>,
+    // Drawn over the whole region first; use a low alpha for a translucent backdrop
+    pub background: Pixel,
+    pub axis_color: Pixel,
+    // Left and bottom axis lines, off for sparklines
+    pub axes: bool,
+}
+
+impl Default for PlotStyle {
+    fn default() -> Self {
+        PlotStyle {
+            line_color: Pixel { r: 255, g: 255, b: 255, a: 255 },
+            fill_color: Some(Pixel { r: 255, g: 255, b: 255, a: 64 }),
+            background: Pixel { r: 0, g: 0, b: 0, a: 160 },
+            axis_color: Pixel { r: 128, g: 128, b: 128, a: 255 },
+            axes: true,
+        }
+    }
+}
+
+impl PlotStyle {
+    // Compact style for small inline graphs
+    pub fn sparkline() -> Self {
+        PlotStyle {
+            fill_color: None,
+            axes: false,
+            ..Self::default()
+        }
+    }
+}
+
+// Line graph of `samples` autoscaled to the region (x, y, width, height). Non-finite
+// samples leave a gap in the line. The region is clipped to the framebuffer.
+pub fn draw_plot(fb: &mut Framebuffer, x: usize, y: usize, width: usize, height: usize, samples: &[f32], style: &PlotStyle) {
+    let width = width.min(fb.width.saturating_sub(x));
+    let height = height.min(fb.height.saturating_sub(y));
+    if width == 0 || height == 0 {
+        return;
+    }
+    fb.blit_rect(x, y, width, height, style.background);
+
+    // Axes take the left column and the bottom row, the graph gets the rest
+    let (left, bottom) = (x as i32, (y + height - 1) as i32);
+    let (plot_x, plot_width, plot_height) = if style.axes {
+        fb.draw_line(left, y as i32, left, bottom, style.axis_color);
+        fb.draw_line(left, bottom, (x + width - 1) as i32, bottom, style.axis_color);
+        (x + 1, width - 1, height - 1)
+    } else {
+        (x, width, height)
+    };
+    if plot_width == 0 || plot_height == 0 {
+        return;
+    }
+
+    let Some((min, max)) = value_range(samples) else {
+        return;
+    };
+    let plot_bottom = (y + plot_height - 1) as f32;
+    let to_point = |i: usize, value: f32| -> (f32, f32) {
+        let t = if samples.len() > 1 { i as f32 / (samples.len() - 1) as f32 } else { 0.0 };
+        // Flat data sits in the middle of the plot
+        let v = if max > min { (value - min) / (max - min) } else { 0.5 };
+        (plot_x as f32 + t * (plot_width - 1) as f32, plot_bottom - v * (plot_height - 1) as f32)
+    };
+
+    for i in 0..samples.len() {
+        if !samples[i].is_finite() {
+            continue;
+        }
+        let (x0, y0) = to_point(i, samples[i]);
+        let next = samples.get(i + 1).filter(|value| value.is_finite());
+        let (x1, y1) = match next {
+            Some(&value) => to_point(i + 1, value),
+            None => (x0, y0),
+        };
+
+        if let Some(fill) = style.fill_color {
+            // Columns up to, but not including, the next point's column
+            let (first, last) = (x0.round() as usize, x1.round() as usize);
+            for column in first..last.max(first + 1) {
+                let t = if last > first { (column - first) as f32 / (last - first) as f32 } else { 0.0 };
+                let top = (y0 + (y1 - y0) * t).round() as usize + 1;
+                let bottom = plot_bottom as usize + 1;
+                fb.blit_rect(column, top, 1, bottom.saturating_sub(top), fill);
+            }
+        }
+        fb.draw_line(x0.round() as i32, y0.round() as i32, x1.round() as i32, y1.round() as i32, style.line_color);
+    }
+}
+
+// The most recent samples, one per column, without axes
+pub fn draw_sparkline(fb: &mut Framebuffer, x: usize, y: usize, width: usize, height: usize, samples: &[f32], style: &PlotStyle) {
+    let recent = &samples[samples.len().saturating_sub(width)..];
+    draw_plot(fb, x, y, width, height, recent, style);
+}
+
+// Smallest and largest finite sample
+fn value_range(samples: &[f32]) -> Option<(f32, f32)> {
+    samples.iter().filter(|value| value.is_finite()).fold(None, |range, &value| match range {
+        None => Some((value, value)),
+        Some((min, max)) => Some((min.min(value), max.max(value))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: Pixel = Pixel { r: 255, g: 0, b: 0, a: 255 };
+
+    fn line_only() -> PlotStyle {
+        PlotStyle { line_color: LINE, background: Pixel { r: 0, g: 0, b: 0, a: 0 }, ..PlotStyle::sparkline() }
+    }
+
+    // Smallest and largest x and y of the pixels the line was drawn on
+    fn line_extents(fb: &Framebuffer) -> Option<(usize, usize, usize, usize)> {
+        (0..fb.width * fb.height)
+            .map(|i| (i % fb.width, i / fb.width))
+            .filter(|&(x, y)| fb.get_pixel(x, y).to_rgb() == LINE.to_rgb())
+            .fold(None, |extents, (x, y)| match extents {
+                None => Some((x, x, y, y)),
+                Some((x0, x1, y0, y1)) => Some((x0.min(x), x1.max(x), y0.min(y), y1.max(y))),
+            })
+    }
+
+    #[test]
+    fn ramp_spans_the_plot_region() {
+        let mut fb = Framebuffer::new(20, 12);
+        let ramp: Vec<f32> = (0..10).map(|i| i as f32 * 3.0).collect();
+        draw_plot(&mut fb, 2, 1, 10, 8, &ramp, &line_only());
+        assert_eq!(line_extents(&fb), Some((2, 11, 1, 8)));
+        // Rising to the right: lowest on the left, highest on the right
+        assert_eq!(fb.get_pixel(2, 8).to_rgb(), LINE.to_rgb());
+        assert_eq!(fb.get_pixel(11, 1).to_rgb(), LINE.to_rgb());
+
+        // Axes take the left column and bottom row from the graph
+        let mut fb = Framebuffer::new(20, 12);
+        draw_plot(&mut fb, 2, 1, 10, 8, &ramp, &PlotStyle { axes: true, fill_color: None, ..line_only() });
+        assert_eq!(line_extents(&fb), Some((3, 11, 1, 7)));
+    }
+
+    #[test]
+    fn nan_samples_leave_gaps() {
+        let mut fb = Framebuffer::new(12, 6);
+        let samples = [1.0, 2.0, 3.0, f32::NAN, f32::NAN, f32::NAN, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0];
+        draw_plot(&mut fb, 0, 0, 12, 6, &samples, &line_only());
+        let drawn = |x: usize| (0..6).any(|y| fb.get_pixel(x, y).to_rgb() == LINE.to_rgb());
+        assert!(drawn(2) && drawn(6));
+        assert!(!drawn(3) && !drawn(4) && !drawn(5));
+
+        let mut fb = Framebuffer::new(12, 6);
+        draw_plot(&mut fb, 0, 0, 12, 6, &[f32::NAN, f32::INFINITY], &line_only());
+        draw_sparkline(&mut fb, 0, 0, 12, 6, &[], &line_only());
+        assert_eq!(line_extents(&fb), None);
+    }
+}
@@ -0,0 +1,543 @@
+use crate::dither::DitherMatrix;
+use crate::framebuffer::Framebuffer;
+use crate::geometry::PixelFormat;
+use crate::pixel::Pixel;
+use crate::tonecurve::ToneCurve;
+use rayon::prelude::*;
+
+// Which buffer the unsharp mask works on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SharpenTarget {
+    // Brightness buffer only: crisper glyph choice, colors untouched
+    Brightness,
+    // RGB channels, for output modes where color carries most of the detail
+    Color,
+    Both,
+}
+
+impl SharpenTarget {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "brightness" => Some(SharpenTarget::Brightness),
+            "color" => Some(SharpenTarget::Color),
+            "both" => Some(SharpenTarget::Both),
+            _ => None,
+        }
+    }
+
+    // Modes without the ASCII ramp get nothing from brightness sharpening
+    pub fn default_for(format: PixelFormat) -> Self {
+        match format {
+            PixelFormat::Ascii | PixelFormat::Braille => SharpenTarget::Brightness,
+            PixelFormat::HalfBlock | PixelFormat::Sixel { .. } => SharpenTarget::Color,
+        }
+    }
+
+    pub fn brightness(&self) -> bool {
+        *self != SharpenTarget::Color
+    }
+
+    pub fn color(&self) -> bool {
+        *self != SharpenTarget::Brightness
+    }
+}
+
+// Where posterization sits in the brightness pass
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PosterizeOrder {
+    // Quantize the luminance, then apply brightness and contrast
+    BeforeAdjust,
+    // Quantize the final brightness, so contrast can't spread the levels unevenly
+    AfterAdjust,
+}
+
+impl PosterizeOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "before" => Some(PosterizeOrder::BeforeAdjust),
+            "after" => Some(PosterizeOrder::AfterAdjust),
+            _ => None,
+        }
+    }
+}
+
+// Gamma the glyph ramps assume the terminal shows brightness with
+pub const DEFAULT_DISPLAY_GAMMA: f32 = 2.2;
+
+// How colors travel from the raymarcher to the terminal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorPipeline {
+    // The raymarcher's linear values are written as if they were sRGB and
+    // brightness uses Rec. 601 weights on them. Kept to compare against.
+    Legacy,
+    // Shading in linear light, encoded to sRGB once when a sample is written, and
+    // brightness from Rec. 709 luminance of the decoded colors
+    Linear,
+}
+
+impl ColorPipeline {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "legacy" => Some(ColorPipeline::Legacy),
+            "linear" => Some(ColorPipeline::Linear),
+            _ => None,
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            ColorPipeline::Legacy => ColorPipeline::Linear,
+            ColorPipeline::Linear => ColorPipeline::Legacy,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorPipeline::Legacy => "legacy",
+            ColorPipeline::Linear => "linear",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PostProcessConfig {
+    pub color_pipeline: ColorPipeline,
+    // Applied to the raymarched radiance before it's encoded
+    pub tone_curve: ToneCurve,
+    // The brightness buffer holds linear luminance; glyphs are picked after raising
+    // it to 1 / display_gamma
+    pub display_gamma: f32,
+    // None skips posterization
+    pub posterize_levels: Option<u8>,
+    pub posterize_order: PosterizeOrder,
+    pub contrast: f32,
+    pub sharpening: f32,
+    pub sharpen_target: SharpenTarget,
+    pub color_sharpening: f32,
+    // Furthest color sharpening moves a channel, the strongest halo an edge gets
+    pub color_sharpen_overshoot: u8,
+    // Box blur radius of the color buffer ahead of sharpening, 0 for none. Softens
+    // noisy scenes and images before they are quantized to the palette.
+    pub color_blur: usize,
+    // Ordered dither before palette quantization, see apply_ordered_dithering
+    pub dither_strength: f32,
+    pub dither_matrix: DitherMatrix,
+    // Alternate between the two nearest palette colors across frames
+    pub temporal_dither: bool,
+    // Glyph brightness follows the frame's histogram, see AutoExposure
+    pub auto_exposure: bool,
+    // Ordered dither between neighbouring glyphs of the ramp, see GlyphRamp::glyph_at
+    pub ramp_dither: bool,
+    pub ramp_dither_matrix: DitherMatrix,
+
+    // Geometric outlines from screen-space normal divergence
+    pub outline: bool,
+    pub outline_threshold: f32,
+    pub outline_strength: f32,
+
+    // Samples per pixel smeared along the motion vectors, 1 for no blur
+    pub vector_blur_samples: u32,
+
+    // Screen-space effects applied to the color buffer after tone mapping
+    // White balance, see apply_white_balance; 0 for neutral
+    pub temperature: f32,
+    pub vignette: bool,
+    pub vignette_strength: f32,
+    pub vignette_radius: f32,
+    pub vignette_falloff: f32,
+    pub scanlines: bool,
+    pub scanline_factor: f32,
+    pub crt: bool,
+    pub crt_distortion: f32,
+    pub chromatic_aberration: bool,
+    pub chromatic_strength: f32,
+    pub glitch_intensity: f32,
+    // Glow trails: each frame adds the previous output scaled by the decay
+    pub trails: bool,
+    pub trail_decay: f32,
+    // Temporal smoothing: each frame is blended with the previous one, followed
+    // along the scene's motion. The blend is the weight of the new frame, and
+    // history further off in any channel than the tolerance is dropped.
+    pub temporal_smoothing: bool,
+    pub temporal_blend: f32,
+    pub temporal_color_tolerance: u8,
+}
+
+impl Default for PostProcessConfig {
+    fn default() -> Self {
+        PostProcessConfig {
+            color_pipeline: ColorPipeline::Linear,
+            tone_curve: ToneCurve::Linear,
+            display_gamma: DEFAULT_DISPLAY_GAMMA,
+            posterize_levels: Some(32),
+            posterize_order: PosterizeOrder::BeforeAdjust,
+            contrast: 1.25,
+            sharpening: 1.25,
+            sharpen_target: SharpenTarget::Brightness,
+            color_sharpening: 0.5,
+            color_sharpen_overshoot: 32,
+            color_blur: 0,
+            dither_strength: 0.1,
+            dither_matrix: DitherMatrix::Bayer2,
+            temporal_dither: false,
+            auto_exposure: false,
+            ramp_dither: false,
+            ramp_dither_matrix: DitherMatrix::Bayer4,
+            outline: false,
+            outline_threshold: 0.3,
+            outline_strength: 0.85,
+            vector_blur_samples: 1,
+            temperature: 0.0,
+            vignette: false,
+            vignette_strength: 0.6,
+            vignette_radius: 0.0,
+            vignette_falloff: 2.0,
+            scanlines: false,
+            scanline_factor: 0.6,
+            crt: false,
+            crt_distortion: 0.15,
+            chromatic_aberration: false,
+            chromatic_strength: 2.0,
+            glitch_intensity: 1.0,
+            trails: false,
+            trail_decay: 0.6,
+            temporal_smoothing: false,
+            temporal_blend: 0.25,
+            temporal_color_tolerance: 64,
+        }
+    }
+}
+
+// `after_pass` sees the buffer after each enabled pass, given the pass's name
+pub fn apply_screen_effects(fb: &mut Framebuffer, config: &PostProcessConfig, mut after_pass: impl FnMut(&str, &Framebuffer)) {
+    // Outlines first, while the normal buffer still lines up with the color buffer
+    if config.outline {
+        fb.apply_normal_outline(config.outline_threshold, config.outline_strength);
+        after_pass("outline", fb);
+    }
+    // While the motion vectors still line up with the color buffer too
+    if config.vector_blur_samples > 1 {
+        fb.apply_motion_blur(config.vector_blur_samples);
+        after_pass("motion-blur", fb);
+    }
+    // Grading, on the picture as rendered
+    if config.temperature != 0.0 {
+        fb.apply_white_balance(config.temperature);
+        after_pass("white-balance", fb);
+    }
+    if config.chromatic_aberration {
+        fb.apply_chromatic_aberration(config.chromatic_strength);
+        after_pass("chromatic-aberration", fb);
+    }
+    if config.crt {
+        fb.apply_barrel_distortion(config.crt_distortion);
+        after_pass("crt", fb);
+    }
+    if config.vignette {
+        fb.apply_vignette(config.vignette_strength, config.vignette_radius, config.vignette_falloff);
+        after_pass("vignette", fb);
+    }
+    if config.scanlines {
+        fb.apply_scanlines(config.scanline_factor);
+        after_pass("scanlines", fb);
+    }
+}
+
+// Short burst of slice displacement and channel swapping, re-randomized every frame
+pub struct GlitchEffect {
+    frames_remaining: u32,
+    frame: u32,
+}
+
+impl GlitchEffect {
+    const DURATION_FRAMES: u32 = 8;
+
+    pub fn new() -> Self {
+        GlitchEffect { frames_remaining: 0, frame: 0 }
+    }
+
+    pub fn trigger(&mut self) {
+        self.frames_remaining = Self::DURATION_FRAMES;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.frames_remaining > 0
+    }
+
+    pub fn apply(&mut self, fb: &mut Framebuffer, config: &PostProcessConfig) {
+        if self.frames_remaining == 0 {
+            return;
+        }
+        self.frames_remaining -= 1;
+        self.frame = self.frame.wrapping_add(1);
+        fb.apply_glitch(self.frame, config.glitch_intensity);
+    }
+}
+
+// Motion trails from feeding each output back into the next frame, new = current +
+// previous * decay. Kept in f32 so faint trails fade out smoothly instead of
+// sticking at a byte value.
+pub struct GlowTrails {
+    previous: Vec<[f32; 3]>,
+}
+
+impl GlowTrails {
+    pub fn new() -> Self {
+        GlowTrails { previous: Vec::new() }
+    }
+
+    pub fn apply(&mut self, fb: &mut Framebuffer, config: &PostProcessConfig) {
+        // Dropping the history when disabled or resized starts the trails afresh
+        if !config.trails {
+            self.previous.clear();
+            return;
+        }
+        if self.previous.len() != fb.data.len() {
+            self.previous = vec![[0.0; 3]; fb.data.len()];
+        }
+
+        let decay = config.trail_decay;
+        fb.data.par_iter_mut().zip(self.previous.par_iter_mut()).for_each(|(pixel, previous)| {
+            let blended = [
+                (pixel.r as f32 + previous[0] * decay).min(255.0),
+                (pixel.g as f32 + previous[1] * decay).min(255.0),
+                (pixel.b as f32 + previous[2] * decay).min(255.0),
+            ];
+            *previous = blended;
+            *pixel = Pixel { r: blended[0].round() as u8, g: blended[1].round() as u8, b: blended[2].round() as u8, a: pixel.a };
+        });
+    }
+}
+
+// Auto exposure scales the glyph brightness so that this share of the frame's pixels
+// sits at or below the target level, keeping highlights just short of white
+const AUTO_EXPOSURE_PERCENTILE: f32 = 0.9;
+const AUTO_EXPOSURE_TARGET: f32 = 235.0;
+// Gain limits, so a black frame isn't blown up into noise
+const AUTO_EXPOSURE_MIN_GAIN: f32 = 0.25;
+const AUTO_EXPOSURE_MAX_GAIN: f32 = 8.0;
+// How fast the gain follows the frame, per second: it covers about 63% of the way
+// to a new target in 1 / speed seconds, so a sudden change is eased in over a few
+// frames instead of flickering
+const AUTO_EXPOSURE_SPEED: f32 = 3.0;
+
+// Brightness gain from the frame's luminance histogram, adapted smoothly over time
+pub struct AutoExposure {
+    // Current gain in stops and the time it was reached, None before the first frame
+    state: Option<(f32, f32)>,
+}
+
+impl AutoExposure {
+    pub fn new() -> Self {
+        AutoExposure { state: None }
+    }
+
+    // Factor for this frame's brightness, 1 while disabled. The first frame after
+    // enabling takes its target gain right away. `time` is in seconds.
+    pub fn gain(&mut self, fb: &Framebuffer, config: &PostProcessConfig, time: f32) -> f32 {
+        if !config.auto_exposure {
+            self.state = None;
+            return 1.0;
+        }
+        self.adapt(&fb.luminance_histogram(config.color_pipeline), time)
+    }
+
+    fn adapt(&mut self, histogram: &[u32; 256], time: f32) -> f32 {
+        // An empty frame keeps the gain as it is
+        let Some(level) = histogram_percentile(histogram, AUTO_EXPOSURE_PERCENTILE) else {
+            return self.state.map_or(1.0, |(stops, _)| stops.exp2());
+        };
+        let level = level.max(1) as f32;
+        let target = (AUTO_EXPOSURE_TARGET / level).clamp(AUTO_EXPOSURE_MIN_GAIN, AUTO_EXPOSURE_MAX_GAIN).log2();
+        let stops = match self.state {
+            Some((stops, last_time)) => {
+                let elapsed = (time - last_time).max(0.0);
+                stops + (target - stops) * (1.0 - (-elapsed * AUTO_EXPOSURE_SPEED).exp())
+            }
+            None => target,
+        };
+        self.state = Some((stops, time));
+        stops.exp2()
+    }
+}
+
+// Lowest level with at least `fraction` of the counted pixels at or below it, None
+// when nothing was counted
+fn histogram_percentile(histogram: &[u32; 256], fraction: f32) -> Option<u8> {
+    let total: u64 = histogram.iter().map(|&count| count as u64).sum();
+    let wanted = ((fraction as f64 * total as f64).ceil() as u64).max(1);
+    let mut seen = 0;
+    histogram.iter().position(|&count| {
+        seen += count as u64;
+        seen >= wanted
+    }).map(|level| level as u8)
+}
+
+// History whose depth differs by more than this fraction belongs to another surface
+const TEMPORAL_DEPTH_TOLERANCE: f32 = 0.05;
+
+// Running average of past frames. Each pixel reads the history where its surface
+// was last frame, per the framebuffer's reprojection buffer, or at the same spot
+// where the renderer gave none. History from off screen, at another depth or too
+// far off in color is dropped, so moving objects and disocclusions don't ghost.
+pub struct TemporalSmoothing {
+    width: usize,
+    height: usize,
+    colors: Vec<[f32; 3]>,
+    depths: Vec<f32>,
+}
+
+impl TemporalSmoothing {
+    pub fn new() -> Self {
+        TemporalSmoothing { width: 0, height: 0, colors: Vec::new(), depths: Vec::new() }
+    }
+
+    pub fn apply(&mut self, fb: &mut Framebuffer, config: &PostProcessConfig) {
+        if !config.temporal_smoothing {
+            self.colors.clear();
+            return;
+        }
+        let (width, height) = (fb.width, fb.height);
+        let has_history = self.colors.len() == width * height && (self.width, self.height) == (width, height);
+
+        let frame: &Framebuffer = fb;
+        let blended: Vec<[f32; 3]> = (0..width * height).into_par_iter().map(|index| {
+            let (x, y) = (index % width, index / width);
+            let pixel = frame.data[index];
+            let current = [pixel.r as f32, pixel.g as f32, pixel.b as f32];
+            match has_history.then(|| self.history_at(frame, x, y, current, config)).flatten() {
+                Some(history) => std::array::from_fn(|c| history[c] + (current[c] - history[c]) * config.temporal_blend),
+                None => current,
+            }
+        }).collect();
+
+        self.depths = (0..width * height).map(|index| fb.get_depth(index % width, index / width)).collect();
+        for (pixel, color) in fb.data.iter_mut().zip(&blended) {
+            *pixel = Pixel { r: color[0].round() as u8, g: color[1].round() as u8, b: color[2].round() as u8, a: pixel.a };
+        }
+        self.colors = blended;
+        self.width = width;
+        self.height = height;
+    }
+
+    // History for pixel (x, y), None when it can't be trusted
+    fn history_at(&self, fb: &Framebuffer, x: usize, y: usize, current: [f32; 3], config: &PostProcessConfig) -> Option<[f32; 3]> {
+        let reprojection = fb.get_reprojection(x, y);
+        let (previous_x, previous_y) = reprojection.map_or((x as f32 + 0.5, y as f32 + 0.5), |r| (r.x, r.y));
+        if previous_x < 0.0 || previous_y < 0.0 || previous_x >= self.width as f32 || previous_y >= self.height as f32 {
+            return None;
+        }
+        let index = previous_y as usize * self.width + previous_x as usize;
+
+        if let Some(reprojection) = reprojection {
+            let (expected, found) = (reprojection.depth, self.depths[index]);
+            let same_surface = if expected.is_finite() && found.is_finite() {
+                (expected - found).abs() <= TEMPORAL_DEPTH_TOLERANCE * expected
+            } else {
+                expected.is_finite() == found.is_finite()
+            };
+            if !same_surface {
+                return None;
+            }
+        }
+        let history = self.colors[index];
+        let tolerance = config.temporal_color_tolerance as f32;
+        (0..3).all(|c| (history[c] - current[c]).abs() <= tolerance).then_some(history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dump::FrameDump;
+
+    // A one pixel high framebuffer of grays
+    fn gray_row(values: &[u8]) -> Framebuffer {
+        let mut fb = Framebuffer::new(values.len(), 1);
+        for (x, &v) in values.iter().enumerate() {
+            fb.set_pixel(x, 0, Pixel { r: v, g: v, b: v, a: 255 });
+        }
+        fb
+    }
+
+    fn grays(fb: &Framebuffer) -> Vec<u8> {
+        fb.data.iter().map(|pixel| pixel.r).collect()
+    }
+
+    #[test]
+    fn trails_without_decay_show_the_current_frame() {
+        let config = PostProcessConfig { trails: true, trail_decay: 0.0, ..PostProcessConfig::default() };
+        let mut trails = GlowTrails::new();
+        trails.apply(&mut gray_row(&[255, 200, 0]), &config);
+        let mut fb = gray_row(&[0, 100, 50]);
+        trails.apply(&mut fb, &config);
+        assert_eq!(grays(&fb), [0, 100, 50]);
+    }
+
+    #[test]
+    fn trails_leave_a_fading_residue() {
+        let config = PostProcessConfig { trails: true, trail_decay: 0.5, ..PostProcessConfig::default() };
+        let mut trails = GlowTrails::new();
+        trails.apply(&mut gray_row(&[200, 0]), &config);
+
+        // The pixel that went dark keeps half its light, then a quarter
+        let mut fb = gray_row(&[0, 0]);
+        trails.apply(&mut fb, &config);
+        assert_eq!(grays(&fb), [100, 0]);
+        let mut fb = gray_row(&[0, 0]);
+        trails.apply(&mut fb, &config);
+        assert_eq!(grays(&fb), [50, 0]);
+
+        // Turning trails off drops the history
+        trails.apply(&mut gray_row(&[0, 0]), &PostProcessConfig::default());
+        let mut fb = gray_row(&[0, 0]);
+        trails.apply(&mut fb, &config);
+        assert_eq!(grays(&fb), [0, 0]);
+    }
+
+    // Names of the files a dump of `config`'s screen effects writes
+    fn dumped_passes(config: &PostProcessConfig, name: &str) -> Vec<String> {
+        let root = std::env::temp_dir().join(format!("ascii_sobel-{}-{}", name, std::process::id()));
+        let mut dump = FrameDump::create_in(&root, 3).unwrap();
+        apply_screen_effects(&mut gray_row(&[10, 120, 250]), config, |pass, fb| dump.write(pass, "ppm", |path| fb.write_color_ppm(path)));
+        let dir = dump.finish().unwrap();
+        let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        files.sort();
+        let _ = std::fs::remove_dir_all(&root);
+        files
+    }
+
+    #[test]
+    fn stage_dump_writes_one_file_per_enabled_pass() {
+        assert!(dumped_passes(&PostProcessConfig::default(), "no-passes").is_empty());
+
+        let config = PostProcessConfig { vignette: true, crt: true, scanlines: true, temperature: 0.4, ..PostProcessConfig::default() };
+        assert_eq!(dumped_passes(&config, "passes"), ["00-white-balance.ppm", "01-crt.ppm", "02-vignette.ppm", "03-scanlines.ppm"]);
+    }
+
+    #[test]
+    fn auto_exposure_pulls_toward_the_target_smoothly() {
+        let config = PostProcessConfig { auto_exposure: true, color_pipeline: ColorPipeline::Legacy, ..PostProcessConfig::default() };
+        let dark = gray_row(&[50; 16]);
+        let bright = gray_row(&[250; 16]);
+
+        // A first frame takes its gain at once: up for dark, down for bright
+        let dark_gain = AutoExposure::new().gain(&dark, &config, 0.0);
+        assert!((dark_gain - AUTO_EXPOSURE_TARGET / 50.0).abs() < 1e-3, "{}", dark_gain);
+        let bright_gain = AutoExposure::new().gain(&bright, &config, 0.0);
+        assert!((bright_gain - AUTO_EXPOSURE_TARGET / 250.0).abs() < 1e-3, "{}", bright_gain);
+        assert_eq!(AutoExposure::new().gain(&gray_row(&[0; 16]), &config, 0.0), AUTO_EXPOSURE_MAX_GAIN);
+
+        // A sudden cut to a bright frame is eased in over several frames
+        let mut exposure = AutoExposure::new();
+        exposure.gain(&dark, &config, 0.0);
+        let next = exposure.gain(&bright, &config, 1.0 / 60.0);
+        assert!(next < dark_gain && next > dark_gain * 0.9, "{}", next);
+        let settled = exposure.gain(&bright, &config, 3.0);
+        assert!((settled - bright_gain).abs() < 0.05, "{}", settled);
+
+        // Turning it off gives a neutral gain and forgets the state
+        assert_eq!(exposure.gain(&bright, &PostProcessConfig { auto_exposure: false, ..config.clone() }, 4.0), 1.0);
+        assert!(exposure.state.is_none());
+    }
+}
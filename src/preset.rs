@@ -0,0 +1,388 @@
+use crate::ascii::DEFAULT_RAMP;
+use crate::catalog::Parameter;
+use crate::pixel::parse_hex_rgb;
+use crate::postprocess::PostProcessConfig;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// The built-in presets followed by the user's, once those are registered
+static REGISTRY: OnceLock<Vec<Preset>> = OnceLock::new();
+
+// A fixed set of colors the frame is quantized to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub colors: &'static [(u8, u8, u8)],
+}
+
+impl Palette {
+    pub fn all() -> &'static [Palette] {
+        PALETTES
+    }
+}
+
+// A complete look: which colors the frame is quantized to, the glyph ramp, the
+// background and the post-process settings that suit them
+#[derive(Clone, Copy, Debug)]
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    // None keeps the terminal's full palette
+    pub palette: Option<&'static Palette>,
+    // Glyphs from empty to dense
+    pub ramp: &'static str,
+    // None uses the theme background
+    pub background: Option<(u8, u8, u8)>,
+    pub posterize_levels: u8,
+    pub contrast: f32,
+    pub scanlines: bool,
+    pub vignette: bool,
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::all().iter().copied().find(|preset| preset.name == name)
+    }
+
+    // The next preset in the registry, wrapping around
+    pub fn next(&self) -> Self {
+        let all = Self::all();
+        let index = all.iter().position(|preset| preset.name == self.name).unwrap_or(0);
+        all[(index + 1) % all.len()]
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        Self::all().iter().map(|preset| preset.name).collect()
+    }
+
+    pub fn all() -> &'static [Preset] {
+        REGISTRY.get().map_or(PRESETS, Vec::as_slice)
+    }
+
+    // Add the user's presets after the built-in ones, before any preset is looked up.
+    // Only the first call registers anything.
+    pub fn register(user: Vec<Preset>) {
+        let _ = REGISTRY.set(PRESETS.iter().copied().chain(user).collect());
+    }
+
+    // Everything the preset sets, for --explain
+    pub fn parameters(&self) -> Vec<Parameter> {
+        let rgb = |(r, g, b): (u8, u8, u8)| format!("#{:02x}{:02x}{:02x}", r, g, b);
+        vec![
+            Parameter::new("palette", self.palette.map_or("terminal", |palette| palette.name), ""),
+            Parameter::new("ramp", format!("\"{}\"", self.ramp), ""),
+            Parameter::new("background", self.background.map_or_else(|| "theme".to_string(), rgb), ""),
+            Parameter::new("posterize-levels", self.posterize_levels, ""),
+            Parameter::new("contrast", self.contrast, ""),
+            Parameter::new("scanlines", self.scanlines, "key s"),
+            Parameter::new("vignette", self.vignette, "key v"),
+        ]
+    }
+
+    // Overwrite the settings the preset cares about, leaving the rest as they are
+    pub fn apply(&self, config: &mut PostProcessConfig) {
+        config.posterize_levels = Some(self.posterize_levels);
+        config.contrast = self.contrast;
+        config.scanlines = self.scanlines;
+        config.vignette = self.vignette;
+    }
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        PRESETS[0]
+    }
+}
+
+// User presets from a file given with --presets, a small subset of TOML: one
+// `[preset.NAME]` table per preset with any of the keys below. Keys left out take
+// the default preset's value.
+//
+//     [preset.neon]
+//     description = "Pink glyphs on near black"
+//     palette = "vaporwave"    # a built-in palette, or "terminal"
+//     ramp = " .:*#"
+//     background = "#100010"   # or "theme"
+//     posterize_levels = 8
+//     contrast = 1.5
+//     scanlines = true
+//     vignette = false
+//
+// Every bad entry is reported, one per line of the error.
+pub fn load_presets(path: &Path) -> io::Result<Vec<Preset>> {
+    let text = fs::read_to_string(path)?;
+    parse_presets(&text).map_err(|errors| io::Error::new(io::ErrorKind::InvalidData, errors.join("\n")))
+}
+
+fn parse_presets(text: &str) -> Result<Vec<Preset>, Vec<String>> {
+    let mut presets: Vec<Preset> = Vec::new();
+    let mut errors = Vec::new();
+    // Whether the keys that follow belong to a table that was rejected, and are
+    // skipped rather than reported again
+    let mut skipping = false;
+    for (number, line) in text.lines().enumerate() {
+        let mut report = |what: String| errors.push(format!("line {}: {}", number + 1, what));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            let table = line.split('#').next().unwrap_or_default().trim_end();
+            let name = table.strip_prefix('[').and_then(|table| table.strip_suffix(']')).and_then(|table| table.trim().strip_prefix("preset."));
+            skipping = true;
+            match name {
+                None => report(format!("expected a [preset.NAME] table, found {}", table)),
+                Some(name) if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+                    report(format!("bad preset name '{}', expected letters, digits, '-' and '_'", name))
+                }
+                Some(name) if PRESETS.iter().chain(&presets).any(|preset| preset.name == name) => report(format!("preset '{}' is already defined", name)),
+                Some(name) => {
+                    presets.push(Preset { name: leak(name.to_string()), description: "User preset", ..Preset::default() });
+                    skipping = false;
+                }
+            }
+            continue;
+        }
+        if skipping {
+            continue;
+        }
+        let Some(preset) = presets.last_mut() else {
+            report("key outside a [preset.NAME] table".to_string());
+            skipping = true;
+            continue;
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            report(format!("expected key = value, found {}", line));
+            continue;
+        };
+        let key = key.trim();
+        match parse_value(value) {
+            Ok(value) => {
+                if let Err(what) = set_key(preset, key, value) {
+                    report(what);
+                }
+            }
+            Err(what) => report(format!("{} for {}", what, key)),
+        }
+    }
+    if errors.is_empty() { Ok(presets) } else { Err(errors) }
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Text(String),
+    Number(f32),
+    Bool(bool),
+}
+
+// A quoted string, a number or a boolean, with an optional comment after it
+fn parse_value(text: &str) -> Result<Value, String> {
+    let text = text.trim();
+    if let Some(quoted) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next() {
+                None => return Err("unterminated string".to_string()),
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some(escaped @ ('"' | '\\')) => value.push(escaped),
+                    _ => return Err("unknown escape in string".to_string()),
+                },
+                Some(c) => value.push(c),
+            }
+        }
+        let rest = chars.as_str().trim_start();
+        return if rest.is_empty() || rest.starts_with('#') { Ok(Value::Text(value)) } else { Err(format!("unexpected '{}' after string", rest)) };
+    }
+    let bare = text.split('#').next().unwrap_or_default().trim();
+    match bare {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ => bare.parse::<f32>().ok().filter(|n| n.is_finite()).map(Value::Number).ok_or_else(|| format!("bad value '{}'", bare)),
+    }
+}
+
+fn set_key(preset: &mut Preset, key: &str, value: Value) -> Result<(), String> {
+    match (key, value) {
+        ("description", Value::Text(text)) => preset.description = leak(text),
+        ("palette", Value::Text(name)) if name == "terminal" => preset.palette = None,
+        ("palette", Value::Text(name)) => {
+            preset.palette = Some(Palette::all().iter().find(|palette| palette.name == name).ok_or_else(|| {
+                let names: Vec<&str> = Palette::all().iter().map(|palette| palette.name).collect();
+                format!("unknown palette '{}', expected terminal or one of {}", name, names.join(", "))
+            })?)
+        }
+        ("ramp", Value::Text(ramp)) if !ramp.is_empty() => preset.ramp = leak(ramp),
+        ("background", Value::Text(color)) if color == "theme" => preset.background = None,
+        ("background", Value::Text(color)) => {
+            preset.background = Some(parse_hex_rgb(&color).ok_or_else(|| format!("bad background '{}', expected \"#RRGGBB\" or \"theme\"", color))?)
+        }
+        ("posterize_levels", Value::Number(levels)) if levels.fract() == 0.0 && (1.0..=255.0).contains(&levels) => preset.posterize_levels = levels as u8,
+        ("contrast", Value::Number(contrast)) if contrast > 0.0 => preset.contrast = contrast,
+        ("scanlines", Value::Bool(on)) => preset.scanlines = on,
+        ("vignette", Value::Bool(on)) => preset.vignette = on,
+        ("description" | "palette" | "background", _) => return Err(format!("{} expects a string", key)),
+        ("ramp", _) => return Err("ramp expects a string of at least one glyph".to_string()),
+        ("posterize_levels", _) => return Err("posterize_levels expects a whole number from 1 to 255".to_string()),
+        ("contrast", _) => return Err("contrast expects a number above 0".to_string()),
+        ("scanlines" | "vignette", _) => return Err(format!("{} expects true or false", key)),
+        _ => return Err(format!("unknown key '{}'", key)),
+    }
+    Ok(())
+}
+
+// Presets are read once at startup and live for the rest of the run
+fn leak(text: String) -> &'static str {
+    Box::leak(text.into_boxed_str())
+}
+
+const PALETTES: &[Palette] = &[
+    Palette { name: "matrix", description: "Phosphor greens on black", colors: MATRIX_PALETTE },
+    Palette { name: "amber", description: "Amber monochrome monitor", colors: AMBER_PALETTE },
+    Palette { name: "cga", description: "CGA mode 4, palette 1 in high intensity", colors: CGA_PALETTE },
+    Palette { name: "vaporwave", description: "Purples, pinks and cyans", colors: VAPORWAVE_PALETTE },
+];
+
+const MATRIX_PALETTE: &[(u8, u8, u8)] = &[
+    (0, 0, 0), (0, 48, 0), (0, 96, 16), (0, 144, 32), (0, 192, 48), (0, 255, 65), (170, 255, 170),
+];
+
+const AMBER_PALETTE: &[(u8, u8, u8)] = &[
+    (0, 0, 0), (64, 32, 0), (128, 72, 0), (192, 112, 0), (255, 176, 0), (255, 214, 128),
+];
+
+// CGA mode 4, palette 1 in high intensity
+const CGA_PALETTE: &[(u8, u8, u8)] = &[
+    (0, 0, 0), (85, 255, 255), (255, 85, 255), (255, 255, 255),
+];
+
+const VAPORWAVE_PALETTE: &[(u8, u8, u8)] = &[
+    (26, 0, 51), (117, 0, 160), (185, 103, 255), (255, 113, 206), (1, 205, 254), (5, 255, 161), (255, 251, 150),
+];
+
+const PRESETS: &[Preset] = &[
+    Preset {
+        name: "default",
+        description: "Full terminal colors and the standard glyph ramp",
+        palette: None,
+        ramp: DEFAULT_RAMP,
+        background: None,
+        posterize_levels: 32,
+        contrast: 1.25,
+        scanlines: false,
+        vignette: false,
+    },
+    Preset {
+        name: "matrix",
+        description: "Green digital rain on black",
+        palette: Some(&PALETTES[0]),
+        ramp: " .:-=+*01",
+        background: Some((0, 0, 0)),
+        posterize_levels: 8,
+        contrast: 1.4,
+        scanlines: false,
+        vignette: true,
+    },
+    Preset {
+        name: "amber",
+        description: "Amber monochrome monitor with scanlines",
+        palette: Some(&PALETTES[1]),
+        ramp: DEFAULT_RAMP,
+        background: Some((0, 0, 0)),
+        posterize_levels: 16,
+        contrast: 1.3,
+        scanlines: true,
+        vignette: true,
+    },
+    Preset {
+        name: "cga",
+        description: "Four color CGA graphics",
+        palette: Some(&PALETTES[2]),
+        ramp: " .:*#@",
+        background: Some((0, 0, 0)),
+        posterize_levels: 4,
+        contrast: 1.5,
+        scanlines: false,
+        vignette: false,
+    },
+    Preset {
+        name: "vaporwave",
+        description: "Neon pastels on deep purple with scanlines",
+        palette: Some(&PALETTES[3]),
+        ramp: " .~=+x*%@",
+        background: Some((26, 0, 51)),
+        posterize_levels: 16,
+        contrast: 1.6,
+        scanlines: true,
+        vignette: false,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_presets_parse_with_defaults_for_missing_keys() {
+        let text = r##"
+# Two looks of my own
+[preset.neon]
+description = "Pink on purple"   # trailing comment
+palette = "vaporwave"
+ramp = " .#\"@"
+background = "#1a0033"
+posterize_levels = 6
+contrast = 1.75
+scanlines = true
+vignette = false
+
+[preset.plain]
+palette = "terminal"
+background = "theme"
+"##;
+        let presets = parse_presets(text).unwrap();
+        assert_eq!(presets.len(), 2);
+        let neon = presets[0];
+        assert_eq!((neon.name, neon.description, neon.ramp), ("neon", "Pink on purple", " .#\"@"));
+        assert_eq!(neon.palette.map(|palette| palette.name), Some("vaporwave"));
+        assert_eq!(neon.background, Some((26, 0, 51)));
+        assert_eq!((neon.posterize_levels, neon.contrast, neon.scanlines, neon.vignette), (6, 1.75, true, false));
+
+        let (plain, default) = (presets[1], Preset::default());
+        assert_eq!((plain.name, plain.palette, plain.background), ("plain", None, None));
+        assert_eq!((plain.ramp, plain.posterize_levels, plain.contrast), (default.ramp, default.posterize_levels, default.contrast));
+    }
+
+    #[test]
+    fn every_bad_entry_is_reported() {
+        let text = "\
+ramp = \"stray\"
+[preset.ok]
+palette = \"sepia\"
+background = \"#12345\"
+posterize_levels = 0
+contrast = -1
+scanlines = \"yes\"
+colour = 1
+ramp = \"unterminated
+[preset.matrix]
+ramp = \"skipped along with its table\"
+[preset.bad name]
+[looks.other]
+vignette
+";
+        let errors = parse_presets(text).unwrap_err();
+        let lines: Vec<&str> = errors.iter().map(|error| error.split(':').next().unwrap()).collect();
+        assert_eq!(lines, ["line 1", "line 3", "line 4", "line 5", "line 6", "line 7", "line 8", "line 9", "line 10", "line 12", "line 13"], "{:#?}", errors);
+        assert!(errors[1].contains("unknown palette 'sepia'"), "{}", errors[1]);
+        assert!(errors[6].contains("unknown key 'colour'"), "{}", errors[6]);
+        assert!(errors[7].contains("unterminated string"), "{}", errors[7]);
+        assert!(errors[8].contains("'matrix' is already defined"), "{}", errors[8]);
+        // The key after a rejected table is skipped rather than reported again
+        assert!(!errors.iter().any(|error| error.starts_with("line 14")));
+    }
+}
@@ -1,69 +1,979 @@
 // raymarch.rs
 
-use crate::math::{Vec2, Vec3, Mat4};
+use crate::camera::PixelFootprint;
+use crate::catalog::{Cost, Parameter, SceneInfo};
+use crate::envmap::EnvironmentMap;
+use crate::math::{hash_f32, hash_u32, smin, value_noise_3d, warp, worley_3d, Smoothstep, Vec2, Vec3, Mat4};
+use crate::framebuffer::Framebuffer;
 use crate::pixel::Pixel;
+use crate::postprocess::ColorPipeline;
+use crate::shader::{Material, Shader};
+use crate::tonecurve::ToneCurve;
+use std::cell::Cell;
+use std::f32::consts::TAU;
 use std::sync::LazyLock;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
 
 struct ShaderGlobals {
     resolution: Vec2,
     time: f32,
+    exposure: f32,
+    pipeline: ColorPipeline,
+    tone_curve: ToneCurve,
+    shadows: ShadowSettings,
+    hit_epsilon: HitEpsilon,
+    ambient: AmbientLight,
+    lighting: LightingRig,
+    scene: Scene,
+    cubes: CubeLayout,
+    floor: FloorTexture,
+    // Radius of the rounded seam where the cubes meet the floor, 0 for a hard edge
+    floor_blend: f32,
+    // This run's seed for the per-run variation: cube colors and spins, terrain
+    seed: u32,
+    // The previous finished frame, sampled by the scene's screen face
+    feedback: Option<Arc<Framebuffer>>,
+    // Replaces the sky gradient and the hemisphere ambient light when loaded
+    envmap: Option<Arc<EnvironmentMap>>,
 }
 
 static GLOBALS: LazyLock<Mutex<ShaderGlobals>> = LazyLock::new(|| {
     Mutex::new(ShaderGlobals {
         resolution: Vec2::new(0.0, 0.0),
         time: 0.0,
+        exposure: 0.0,
+        pipeline: ColorPipeline::Linear,
+        tone_curve: ToneCurve::Linear,
+        shadows: ShadowSettings::default(),
+        hit_epsilon: HitEpsilon::default(),
+        ambient: AmbientLight::Hemisphere,
+        lighting: LightingRig::Single,
+        scene: Scene::Cubes,
+        cubes: CubeLayout::Classic,
+        floor: FloorTexture::Checker,
+        floor_blend: DEFAULT_FLOOR_BLEND,
+        seed: 0,
+        feedback: None,
+        envmap: None,
     })
 });
 
-pub fn update_globals(resolution: Vec2, time: f32) {
-    if let Ok(mut globals) = GLOBALS.lock() {
-        globals.resolution = resolution;
-        globals.time = time;
+// Half extent of the three cubes
+const CUBE_SIZE: f32 = 0.5;
+// Blend radius between the cubes and the floor, small enough to only round off
+// corners that dip near it
+const DEFAULT_FLOOR_BLEND: f32 = 0.25;
+// Centers of the cubes, in an equilateral triangle. The first one carries the
+// feedback screen.
+const CUBE_POSITIONS: [Vec3; 3] = [
+    Vec3 { x: -1.5, y: CUBE_SIZE, z: 0.0 },
+    Vec3 { x: 1.5, y: CUBE_SIZE, z: 0.0 },
+    Vec3 { x: 0.0, y: CUBE_SIZE, z: 1.732 },
+];
+// Rotation speed of each cube around x, y and z in radians per second
+const CUBE_SPINS: [Vec3; 3] = [
+    Vec3 { x: 0.5, y: 0.8, z: 0.3 },
+    Vec3 { x: 0.3, y: 0.6, z: 0.9 },
+    Vec3 { x: 0.7, y: 0.4, z: 0.5 },
+];
+// The third cube circles this point around the vertical axis while it spins
+const ORBITING_CUBE: usize = 2;
+const ORBIT_PIVOT: Vec3 = Vec3 { x: 0.0, y: CUBE_SIZE, z: 2.4 };
+const ORBIT_SPEED: f32 = 0.5; // Radians per second
+// A ring of cubes is centered under the classic three and spaced so neighbors
+// can't touch however they turn
+const RING_CENTER: Vec3 = Vec3 { x: 0.0, y: CUBE_SIZE, z: 0.577 };
+const RING_MIN_RADIUS: f32 = 1.732;
+const RING_SPACING: f32 = 2.0;
+// Range of the seeded spin speeds around each axis, radians per second
+const RING_SPIN_MIN: f32 = 0.3;
+const RING_SPIN_MAX: f32 = 0.9;
+// The classic cubes' spins are scaled per axis by a seeded factor in this range
+const CLASSIC_SPIN_MIN_SCALE: f32 = 0.6;
+const CLASSIC_SPIN_MAX_SCALE: f32 = 1.4;
+// Side of a checkerboard tile and its two colors
+const CHECKER_TILE: f32 = 2.0;
+const CHECKER_DARK: Vec3 = Vec3 { x: 0.12, y: 0.14, z: 0.16 };
+const CHECKER_LIGHT: Vec3 = Vec3 { x: 0.9, y: 0.95, z: 0.99 };
+// Pixels across a tile at which the checkerboard starts fading to its average
+// color, and below which it is fully faded, before it can alias
+const CHECKER_FADE_START: f32 = 4.0;
+const CHECKER_FADE_END: f32 = 1.0;
+// World units per marble band and how far the veins meander
+const MARBLE_SCALE: f32 = 0.8;
+const MARBLE_WARP: f32 = 1.2;
+// Caustic cells per world unit, and how fast the pattern shifts, in cells per second
+const CAUSTICS_SCALE: f32 = 1.5;
+const CAUSTICS_SPEED: f32 = 0.3;
+// Color of the sphere drawn at the light, before exposure
+const LIGHT_EMISSION: Vec3 = Vec3 { x: 1.0, y: 0.88, z: 0.55 };
+// Brightness of the ambient light on a surface facing the sky
+const AMBIENT_LEVEL: f32 = 0.1;
+// The three-point rig's fill light, relative to the key light. Being nearer, it
+// ends up about a third as bright as the key on the cubes.
+const FILL_STRENGTH: f32 = 0.2;
+// Ambient occlusion probes this many points along the normal, this far apart, and
+// darkens by how much closer the scene is than the probe distance
+const AO_SAMPLES: u32 = 5;
+const AO_STEP: f32 = 0.06;
+const AO_STRENGTH: f32 = 3.0;
+
+pub fn update_globals(resolution: Vec2, time: f32, exposure: f32, pipeline: ColorPipeline, tone_curve: &ToneCurve) {
+    let mut globals = GLOBALS.lock().unwrap_or_else(PoisonError::into_inner);
+    globals.resolution = resolution;
+    globals.time = time;
+    globals.exposure = exposure;
+    globals.pipeline = pipeline;
+    globals.tone_curve = tone_curve.clone();
+}
+
+// None turns the screen face back into a plain cube face
+pub fn set_feedback_texture(texture: Option<Arc<Framebuffer>>) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).feedback = texture;
+}
+
+pub fn set_environment_map(envmap: Option<Arc<EnvironmentMap>>) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).envmap = envmap;
+}
+
+pub fn set_shadow_settings(shadows: ShadowSettings) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).shadows = shadows;
+}
+
+pub fn set_hit_epsilon(hit_epsilon: HitEpsilon) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).hit_epsilon = hit_epsilon;
+}
+
+pub fn set_ambient_light(ambient: AmbientLight) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).ambient = ambient;
+}
+
+pub fn set_lighting_rig(lighting: LightingRig) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).lighting = lighting;
+}
+
+// A scene that varies by seed takes the run's seed, whatever it was made with
+pub fn set_scene(scene: Scene) {
+    let mut globals = GLOBALS.lock().unwrap_or_else(PoisonError::into_inner);
+    globals.scene = scene.reseed(globals.seed);
+}
+
+pub fn set_cube_layout(cubes: CubeLayout) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).cubes = cubes;
+}
+
+pub fn set_floor_texture(floor: FloorTexture) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).floor = floor;
+}
+
+pub fn set_floor_blend(radius: f32) {
+    GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).floor_blend = radius;
+}
+
+// Reseeds the current scene and cube layout along with everything seeded later
+pub fn set_seed(seed: u32) {
+    let mut globals = GLOBALS.lock().unwrap_or_else(PoisonError::into_inner);
+    globals.seed = seed;
+    globals.scene = globals.scene.reseed(seed);
+    globals.cubes = globals.cubes.reseed(seed);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scene {
+    // Rotating cubes over a checkerboard floor, laid out as set_cube_layout says
+    Cubes,
+    // Rolling fractal hills, the same for every run with the same seed
+    Terrain { seed: u32 },
+    // A sphere in every cell of an endless grid, sized by the seed cell by cell
+    Repetition { seed: u32 },
+}
+
+impl Scene {
+    // Every raymarched scene, as --list-scenes and the picker show them
+    pub const INFO: [SceneInfo; 3] = [
+        SceneInfo { name: "cubes", description: "Spinning cubes over a textured floor under a circling light", cost: Cost::Medium },
+        SceneInfo { name: "terrain", description: "Rolling fractal hills out to the horizon, shaped by the seed", cost: Cost::High },
+        SceneInfo { name: "repetition", description: "Rows of spheres to the horizon, each sized by the seed", cost: Cost::High },
+    ];
+
+    pub fn from_name(name: &str, seed: u32) -> Option<Self> {
+        match name {
+            "cubes" => Some(Scene::Cubes),
+            "terrain" => Some(Scene::Terrain { seed }),
+            "repetition" => Some(Scene::Repetition { seed }),
+            _ => None,
+        }
+    }
+
+    // The same scene drawn from `seed`
+    pub fn reseed(self, seed: u32) -> Self {
+        match self {
+            Scene::Cubes => Scene::Cubes,
+            Scene::Terrain { .. } => Scene::Terrain { seed },
+            Scene::Repetition { .. } => Scene::Repetition { seed },
+        }
+    }
+
+    // Everything that only depends on `time`, worked out once instead of in every
+    // distance evaluation
+    fn prepare(self, time: f32, light_radius: f32, cubes: CubeLayout, floor_blend: f32, seed: u32, rays: Arc<RayGlobals>) -> SceneFrame {
+        let cube_count = match self {
+            Scene::Cubes => cubes.count(),
+            Scene::Terrain { .. } | Scene::Repetition { .. } => 0,
+        };
+        SceneFrame {
+            scene: self,
+            time,
+            light: Light::orbiting(time, light_radius),
+            cubes,
+            floor_blend,
+            seed,
+            cube_transforms: (0..cube_count).map(|cube| cubes.transform(cube, time, seed)).collect(),
+            cube_inverses: OnceLock::new(),
+            rays,
+        }
+    }
+}
+
+// Where the cubes of the cube scene sit and how they turn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CubeLayout {
+    // Three cubes in a triangle, one of them orbiting a point as it spins at
+    // speeds varied by the run's seed
+    Classic,
+    // `count` cubes evenly spaced on a circle, each spinning at speeds drawn from
+    // `seed`
+    Ring { count: usize, seed: u32 },
+}
+
+impl CubeLayout {
+    pub fn count(&self) -> usize {
+        match *self {
+            CubeLayout::Classic => CUBE_POSITIONS.len(),
+            CubeLayout::Ring { count, .. } => count,
+        }
+    }
+
+    // The same layout spinning at speeds drawn from `seed`
+    pub fn reseed(self, seed: u32) -> Self {
+        match self {
+            CubeLayout::Classic => CubeLayout::Classic,
+            CubeLayout::Ring { count, .. } => CubeLayout::Ring { count, seed },
+        }
+    }
+
+    // Center of cube number `cube` while it isn't orbiting
+    fn position(&self, cube: usize) -> Vec3 {
+        match *self {
+            CubeLayout::Classic => CUBE_POSITIONS[cube],
+            CubeLayout::Ring { count, .. } => {
+                let radius = RING_MIN_RADIUS.max(RING_SPACING * count as f32 / TAU);
+                let angle = TAU * cube as f32 / count as f32;
+                RING_CENTER + Vec3::new(angle.sin(), 0.0, -angle.cos()) * radius
+            }
+        }
+    }
+
+    // Rotation speed of cube number `cube` around x, y and z in radians per second.
+    // The classic layout takes the run's seed, a ring its own.
+    fn spin(&self, cube: usize, run_seed: u32) -> Vec3 {
+        let random = |seed: u32, axis: u32| hash_f32(hash_u32(hash_u32(seed) ^ (cube as u32).wrapping_mul(3).wrapping_add(axis)));
+        match *self {
+            CubeLayout::Classic => {
+                let scale = |axis: u32| CLASSIC_SPIN_MIN_SCALE + (CLASSIC_SPIN_MAX_SCALE - CLASSIC_SPIN_MIN_SCALE) * random(run_seed, axis);
+                let spin = CUBE_SPINS[cube];
+                Vec3::new(spin.x * scale(0), spin.y * scale(1), spin.z * scale(2))
+            }
+            CubeLayout::Ring { seed, .. } => {
+                let speed = |axis: u32| RING_SPIN_MIN + (RING_SPIN_MAX - RING_SPIN_MIN) * random(seed, axis);
+                Vec3::new(speed(0), speed(1), speed(2))
+            }
+        }
+    }
+
+    // World to local frame of cube number `cube` at `time`
+    fn transform(&self, cube: usize, time: f32, run_seed: u32) -> Mat4 {
+        let spin = self.spin(cube, run_seed) * time;
+        let local = Mat4::from_euler_angles(spin.x, spin.y, spin.z) * Mat4::from_translation(self.position(cube) * -1.0);
+        if *self == CubeLayout::Classic && cube == ORBITING_CUBE {
+            // Turning p back along the orbit puts it where it was relative to the cube
+            // at its starting position
+            local * Mat4::from_rotation_about(ORBIT_PIVOT, Mat4::from_rotation_y(-ORBIT_SPEED * time))
+        } else {
+            local
+        }
+    }
+}
+
+// The scene frozen at one moment, shared by every ray of a frame
+pub struct SceneFrame {
+    scene: Scene,
+    time: f32,
+    light: Light,
+    cubes: CubeLayout,
+    floor_blend: f32,
+    seed: u32,
+    // Takes a world position into each cube's local frame, empty without cubes
+    cube_transforms: Vec<Mat4>,
+    // Their inverses, worked out when a later frame first reprojects into this one
+    cube_inverses: OnceLock<Vec<Mat4>>,
+    rays: Arc<RayGlobals>,
+}
+
+// The globals every ray reads, copied once per frame so the rays of a frame don't
+// all take turns on the lock
+struct RayGlobals {
+    exposure: f32,
+    pipeline: ColorPipeline,
+    tone_curve: ToneCurve,
+    shadows: ShadowSettings,
+    hit_epsilon: HitEpsilon,
+    ambient: AmbientLight,
+    lighting: LightingRig,
+    floor: FloorTexture,
+    feedback: Option<Arc<Framebuffer>>,
+    envmap: Option<Arc<EnvironmentMap>>,
+}
+
+impl SceneFrame {
+    // The same scene at another moment, for motion blur
+    fn at(&self, time: f32) -> SceneFrame {
+        self.scene.prepare(time, self.light.radius, self.cubes, self.floor_blend, self.seed, self.rays.clone())
+    }
+
+    pub fn scene(&self) -> Scene {
+        self.scene
+    }
+
+    // Where the point `p` on `object`'s surface was in `previous`: carried back
+    // through the object's own motion. None for the sky, which has no position, and
+    // for a cube `previous` doesn't have.
+    pub fn previous_position(&self, p: Vec3, object: ObjectId, previous: &SceneFrame) -> Option<Vec3> {
+        match object {
+            ObjectId::Sky => None,
+            ObjectId::Floor | ObjectId::Terrain | ObjectId::Sphere => Some(p),
+            ObjectId::Cube(cube) => {
+                let local = self.cube_transforms.get(cube)?.transform_point3(p);
+                let inverse = previous.cube_inverses.get_or_init(|| previous.cube_transforms.iter().map(Mat4::inverse).collect());
+                Some(inverse.get(cube)?.transform_point3(local))
+            }
+            ObjectId::Light => Some(p - self.light.position + previous.light.position),
+        }
+    }
+}
+
+// The scene picked with set_scene at `time`
+pub fn prepare_frame(time: f32) -> SceneFrame {
+    let scene = GLOBALS.lock().unwrap_or_else(PoisonError::into_inner).scene;
+    prepare_scene_frame(scene, time)
+}
+
+// What `scene` is rendered with as the globals stand, for --explain. Before the
+// command line is applied these are the defaults.
+pub fn explain_scene(scene: Scene) -> Vec<Parameter> {
+    let globals = GLOBALS.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut parameters = Vec::new();
+    if let Scene::Repetition { .. } = scene {
+        parameters.push(Parameter::new("floor", globals.floor.name(), "--floor"));
+    }
+    if scene == Scene::Cubes {
+        let layout = match globals.cubes {
+            CubeLayout::Classic => "classic".to_string(),
+            CubeLayout::Ring { count, .. } => format!("ring of {}", count),
+        };
+        parameters.push(Parameter::new("layout", layout, "--objects"));
+        parameters.push(Parameter::new("floor", globals.floor.name(), "--floor"));
+        parameters.push(Parameter::new("floor-blend", globals.floor_blend, "--floor-blend"));
+    }
+    let shadows = &globals.shadows;
+    parameters.extend([
+        Parameter::new("light-radius", shadows.light_radius, "--light-radius"),
+        Parameter::new("shadows", shadows.quality.name(), "--shadows"),
+        Parameter::new("shadow-steps", shadows.max_steps, "--shadow-steps"),
+        Parameter::new("shadow-bias", shadows.bias.constant, "--shadow-bias"),
+        Parameter::new("shadow-slope-bias", shadows.bias.slope, "--shadow-slope-bias"),
+        Parameter::new("ambient", globals.ambient.name(), "--ambient"),
+        Parameter::new("lighting", globals.lighting.name(), "--lighting"),
+        Parameter::new("envmap", if globals.envmap.is_some() { "loaded" } else { "none" }, "--envmap"),
+        Parameter::new("hit-epsilon", globals.hit_epsilon.base, "--hit-epsilon"),
+        Parameter::new("epsilon-growth", globals.hit_epsilon.growth, "--epsilon-growth"),
+    ]);
+    parameters
+}
+
+// Any scene at `time`, with the light, layout and shading the globals say now
+pub fn prepare_scene_frame(scene: Scene, time: f32) -> SceneFrame {
+    let globals = GLOBALS.lock().unwrap_or_else(PoisonError::into_inner);
+    let rays = RayGlobals {
+        exposure: globals.exposure,
+        pipeline: globals.pipeline,
+        tone_curve: globals.tone_curve.clone(),
+        shadows: globals.shadows,
+        hit_epsilon: globals.hit_epsilon,
+        ambient: globals.ambient,
+        lighting: globals.lighting,
+        floor: globals.floor,
+        feedback: globals.feedback.clone(),
+        envmap: globals.envmap.clone(),
+    };
+    scene.prepare(time, globals.shadows.light_radius, globals.cubes, globals.floor_blend, globals.seed, Arc::new(rays))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShadowQuality {
+    Off,
+    // Binary visibility test toward the light's center
+    Hard,
+    // Penumbra from the closest miss along the shadow ray
+    Soft,
+}
+
+impl ShadowQuality {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(ShadowQuality::Off),
+            "hard" => Some(ShadowQuality::Hard),
+            "soft" => Some(ShadowQuality::Soft),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShadowQuality::Off => "off",
+            ShadowQuality::Hard => "hard",
+            ShadowQuality::Soft => "soft",
+        }
+    }
+}
+
+// Pattern on the cubes scene's floor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloorTexture {
+    Checker,
+    // White stone with dark veins, bent by domain warping
+    Marble,
+    // Bright wavering lines over blue tiles, as at the bottom of a pool
+    Caustics,
+}
+
+impl FloorTexture {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "checker" => Some(FloorTexture::Checker),
+            "marble" => Some(FloorTexture::Marble),
+            "caustics" => Some(FloorTexture::Caustics),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FloorTexture::Checker => "checker",
+            FloorTexture::Marble => "marble",
+            FloorTexture::Caustics => "caustics",
+        }
+    }
+
+    // Floor color at `p` on the plane, as sRGB. `pixel_width` is how much of the
+    // floor one pixel covers there, in world units.
+    fn color(&self, p: Vec3, time: f32, pixel_width: f32) -> Vec3 {
+        match self {
+            FloorTexture::Checker => {
+                let cell = (p * (1.0 / CHECKER_TILE)).floor();
+                let pattern = (cell.x as i32 + cell.z as i32) & 1;
+                let tile = if pattern == 0 { CHECKER_DARK } else { CHECKER_LIGHT };
+                // Tiles a few pixels wide or less would alias, so they give way to
+                // the average of the two colors as they shrink
+                let contrast = (CHECKER_TILE / pixel_width).smoothstep(CHECKER_FADE_END, CHECKER_FADE_START);
+                self.albedo().lerp(tile, contrast)
+            }
+            FloorTexture::Marble => {
+                let q = warp(Vec3::new(p.x, 0.0, p.z) * (1.0 / MARBLE_SCALE), MARBLE_WARP, 7);
+                // Bands along a diagonal, thinned into veins
+                let band = 0.5 + 0.5 * (3.0 * (q.x + q.z) + 2.0 * value_noise_3d(q * 2.0, 11)).sin();
+                let vein = 1.0 - band.powf(0.15);
+                Vec3::new(0.92, 0.9, 0.86).lerp(Vec3::new(0.2, 0.22, 0.26), vein)
+            }
+            FloorTexture::Caustics => {
+                // Time as the third axis, so the cells drift and reshape
+                let q = warp(Vec3::new(p.x, p.z, time * CAUSTICS_SPEED) * CAUSTICS_SCALE, 0.4, 3);
+                let line = 1.0 - worley_3d(q, 5).edge().smoothstep(0.0, 0.12);
+                Vec3::new(0.1, 0.3, 0.42).lerp(Vec3::new(0.85, 0.97, 1.0), line)
+            }
+        }
+    }
+
+    // Average color, for the light bounced off the floor
+    fn albedo(&self) -> Vec3 {
+        match self {
+            // The two tiles, half and half
+            FloorTexture::Checker => CHECKER_DARK.lerp(CHECKER_LIGHT, 0.5),
+            FloorTexture::Marble => Vec3::new(0.8, 0.79, 0.76),
+            FloorTexture::Caustics => Vec3::new(0.25, 0.42, 0.53),
+        }
+    }
+}
+
+// Where the light that doesn't come straight from the light source comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AmbientLight {
+    // The same amount from every direction
+    Flat,
+    // Sky from above and light bounced off the ground from below, blended by how
+    // much the surface faces up. With an environment map, its blurred light from
+    // all around instead.
+    Hemisphere,
+}
+
+impl AmbientLight {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flat" => Some(AmbientLight::Flat),
+            "hemisphere" => Some(AmbientLight::Hemisphere),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AmbientLight::Flat => "flat",
+            AmbientLight::Hemisphere => "hemisphere",
+        }
+    }
+}
+
+// The lights a scene is lit with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightingRig {
+    // The circling light and the ambient light
+    Single,
+    // The circling light as the key, a dim unshadowed fill light circling opposite
+    // it lower down, and the ambient light darkened by ambient occlusion
+    ThreePoint,
+}
+
+impl LightingRig {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "single" => Some(LightingRig::Single),
+            "three-point" => Some(LightingRig::ThreePoint),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LightingRig::Single => "single",
+            LightingRig::ThreePoint => "three-point",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    pub quality: ShadowQuality,
+    // Iteration budget of a shadow ray
+    pub max_steps: u32,
+    // Radius of the spherical light; larger lights cast wider penumbrae
+    pub light_radius: f32,
+    pub bias: ShadowBias,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            quality: ShadowQuality::Soft,
+            max_steps: 100,
+            light_radius: 2.0,
+            bias: ShadowBias::default(),
+        }
+    }
+}
+
+// Spherical point light circling above the cubes
+struct Light {
+    position: Vec3,
+    radius: f32,
+}
+
+impl Light {
+    fn orbiting(time: f32, radius: f32) -> Self {
+        let orbit_radius = 15.0; // Radius of the circular path
+        let height = 15.0; // Height above the cubes
+        let speed = 0.5; // Radians per second
+
+        let angle = time * speed;
+        Light {
+            position: Vec3::new(orbit_radius * angle.cos(), height, orbit_radius * angle.sin()),
+            radius,
+        }
+    }
+
+    // The fill light of a three-point rig: across the orbit from the key light and
+    // lower, so it reaches the sides the key leaves dark
+    fn fill(time: f32) -> Self {
+        let key = Light::orbiting(time, 0.0);
+        Light {
+            position: Vec3::new(-key.position.x, key.position.y * 0.4, -key.position.z),
+            radius: 0.0,
+        }
+    }
+}
+
+// Distance a shadow ray skips before it starts looking for occluders. A fixed offset
+// either leaves acne on surfaces lit at a grazing angle or detaches the contact shadows
+// under the cubes, so the offset grows with the tangent of the light's incidence angle.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowBias {
+    // Offset for a surface facing the light head on
+    pub constant: f32,
+    // Extra offset per unit of tan(incidence angle)
+    pub slope: f32,
+    // Upper limit, reached as the light approaches the surface plane
+    pub max: f32,
+}
+
+impl Default for ShadowBias {
+    fn default() -> Self {
+        ShadowBias {
+            constant: 0.01,
+            slope: 0.01,
+            max: 0.1,
+        }
+    }
+}
+
+impl ShadowBias {
+    pub fn for_surface(&self, normal: Vec3, light_dir: Vec3) -> f32 {
+        let cos = normal.dot(&light_dir).clamp(0.0, 1.0);
+        let sin = (1.0 - cos * cos).sqrt();
+        // Clamp so a light exactly in the surface plane stays finite
+        let tan = sin / cos.max(1e-3);
+        (self.constant + self.slope * tan).min(self.max.max(self.constant))
+    }
+}
+
+// Distance from the scene at which a ray counts as having hit it. A fixed value is
+// needlessly tight far away, where a pixel covers much more than it and the last
+// steps crawl toward the surface, so it grows with the distance along the ray.
+#[derive(Clone, Copy, Debug)]
+pub struct HitEpsilon {
+    // Epsilon at the camera
+    pub base: f32,
+    // Growth per unit of distance, relative to the base; 0 keeps it fixed
+    pub growth: f32,
+}
+
+impl Default for HitEpsilon {
+    fn default() -> Self {
+        HitEpsilon {
+            base: 0.001,
+            growth: 0.02,
+        }
+    }
+}
+
+impl HitEpsilon {
+    // Epsilon for a ray `t` along
+    pub fn at(&self, t: f32) -> f32 {
+        self.base * (1.0 + t * self.growth)
+    }
+}
+
+pub struct MarchResult {
+    pub color: Pixel,
+    pub normal: Vec3, // Zero when the ray escapes to the sky
+    pub depth: f32, // Distance along the ray, infinite for the sky
+    pub object: ObjectId,
+}
+
+// Work done by the marcher. Each tile counts its own while rendering, and the tiles
+// are summed per frame; a benchmark sums whole frames, so the per-frame figures
+// below are averages over `frames`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RayStats {
+    pub frames: u32,
+    // Rays from the camera, including every depth of field and motion blur sample
+    pub primary_rays: u64,
+    pub march_steps: u64,
+    // Most steps any one primary ray took
+    pub max_march_steps: u32,
+    pub shadow_rays: u64,
+    // Distance evaluations of the whole scene, for marching, normals, shadows and
+    // ambient occlusion
+    pub sdf_evaluations: u64,
+    // Points probed for ambient occlusion, AO_SAMPLES for each shaded point
+    pub ao_probes: u64,
+}
+
+impl RayStats {
+    fn add_ray(&mut self, steps: u32, sdf_evaluations: u32, shadow_rays: u32, ao_probes: u32) {
+        self.primary_rays += 1;
+        self.march_steps += steps as u64;
+        self.max_march_steps = self.max_march_steps.max(steps);
+        self.shadow_rays += shadow_rays as u64;
+        self.sdf_evaluations += sdf_evaluations as u64;
+        self.ao_probes += ao_probes as u64;
+    }
+
+    pub fn merge(&mut self, other: &RayStats) {
+        self.frames += other.frames;
+        self.primary_rays += other.primary_rays;
+        self.march_steps += other.march_steps;
+        self.max_march_steps = self.max_march_steps.max(other.max_march_steps);
+        self.shadow_rays += other.shadow_rays;
+        self.sdf_evaluations += other.sdf_evaluations;
+        self.ao_probes += other.ao_probes;
+    }
+
+    // Label and value of each figure, per frame
+    pub fn figures(&self) -> [(&'static str, String); 6] {
+        let frames = self.frames.max(1) as f64;
+        let per_frame = |count: u64| format_count(count as f64 / frames);
+        let average_steps = self.march_steps as f64 / self.primary_rays.max(1) as f64;
+        [
+            ("rays", per_frame(self.primary_rays)),
+            ("steps avg", format!("{:.1}", average_steps)),
+            ("steps max", self.max_march_steps.to_string()),
+            ("shadow rays", per_frame(self.shadow_rays)),
+            ("sdf evals", per_frame(self.sdf_evaluations)),
+            ("ao probes", per_frame(self.ao_probes)),
+        ]
+    }
+
+    pub fn summary(&self) -> String {
+        let figures: Vec<String> = self.figures().iter().map(|(label, value)| format!("{} {}", label, value)).collect();
+        format!("per frame: {}", figures.join(", "))
+    }
+}
+
+// Counts with a k or M suffix past a thousand
+fn format_count(count: f64) -> String {
+    if count >= 1.0e6 {
+        format!("{:.2}M", count / 1.0e6)
+    } else if count >= 1.0e3 {
+        format!("{:.1}k", count / 1.0e3)
     } else {
-        eprintln!("Failed to lock GLOBALS mutex.");
+        format!("{:.0}", count)
+    }
+}
+
+// What a ray hit, so its surface point can be followed from frame to frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObjectId {
+    Sky,
+    Floor,
+    // Index into the frame's cubes
+    Cube(usize),
+    Terrain,
+    // One of the repetition scene's spheres
+    Sphere,
+    Light,
+}
+
+impl ObjectId {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectId::Sky => "sky",
+            ObjectId::Floor => "floor",
+            ObjectId::Cube(_) => "cube",
+            ObjectId::Terrain => "terrain",
+            ObjectId::Sphere => "sphere",
+            ObjectId::Light => "light",
+        }
+    }
+}
+
+// Everything known about the surface a ray stopped at, for inspecting one ray
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub object: ObjectId,
+    pub material: Material,
+    pub position: Vec3,
+    pub normal: Vec3,
+    // Distance along the ray
+    pub depth: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MotionBlur {
+    // Rays per pixel spread across the shutter interval; 1 disables the blur
+    pub samples: u32,
+    // Length of the shutter interval in seconds, centered on the frame time
+    pub shutter: f32,
+}
+
+impl Default for MotionBlur {
+    fn default() -> Self {
+        MotionBlur {
+            samples: 1,
+            shutter: 1.0 / 60.0,
+        }
+    }
+}
+
+// Average of several rays at stratified, jittered times within the shutter interval.
+// `pixel_key` seeds the jitter so each pixel gets the same offsets every frame.
+// Normal and depth come from the sample nearest the middle of the interval.
+#[allow(clippy::too_many_arguments)]
+pub fn ray_march_blurred(origin: Vec3, direction: Vec3, frame: &SceneFrame, shader: &dyn Shader, motion: &MotionBlur, pixel_key: u32, footprint: PixelFootprint, mut stats: Option<&mut RayStats>) -> MarchResult {
+    if motion.samples <= 1 {
+        return ray_march(origin, direction, frame, shader, footprint, stats);
     }
+
+    let samples = motion.samples;
+    let mut sample = |i: u32| {
+        let jitter = hash_f32(hash_u32(pixel_key) ^ i.wrapping_mul(0x9e3779b9));
+        let offset = ((i as f32 + jitter) / samples as f32 - 0.5) * motion.shutter;
+        ray_march(origin, direction, &frame.at(frame.time + offset), shader, footprint, stats.as_deref_mut())
+    };
+
+    average_samples(sample(samples / 2), (0..samples).filter(|&i| i != samples / 2).map(sample))
 }
 
-pub fn ray_march(origin: Vec3, direction: Vec3, time: f32) -> Pixel {
-    let globals = GLOBALS.lock().unwrap();
-    let resolution = globals.resolution;
-    drop(globals); // Release the lock early
+// Color averaged over `first` and `rest`, with the normal and depth of `first`
+pub fn average_samples(first: MarchResult, rest: impl Iterator<Item = MarchResult>) -> MarchResult {
+    let mut sum = [first.color.r as u32, first.color.g as u32, first.color.b as u32];
+    let mut samples = 1;
+    for result in rest {
+        sum[0] += result.color.r as u32;
+        sum[1] += result.color.g as u32;
+        sum[2] += result.color.b as u32;
+        samples += 1;
+    }
+
+    let average = |channel: u32| ((channel + samples / 2) / samples) as u8;
+    MarchResult {
+        color: Pixel { r: average(sum[0]), g: average(sum[1]), b: average(sum[2]), a: 255 },
+        ..first
+    }
+}
 
-    // Define light parameters
-    let light_radius = 15.0; // Radius of the circular path
-    let light_height = 15.0; // Height above the cubes
-    let light_speed = 0.5; // Radians per second
+// `footprint` is the width of the pixel the ray belongs to, used to soften silhouettes.
+// The ray's work is added to `stats` when given.
+pub fn ray_march(origin: Vec3, direction: Vec3, frame: &SceneFrame, shader: &dyn Shader, footprint: PixelFootprint, stats: Option<&mut RayStats>) -> MarchResult {
+    ray_march_hit(origin, direction, frame, shader, footprint, stats).0
+}
 
-    // Calculate light position moving in a circle
-    let light_angle = time * light_speed;
-    let light_pos = Vec3::new(
-        light_radius * light_angle.cos(),
-        light_height,
-        light_radius * light_angle.sin(),
-    );
+// ray_march along with the full record of what the ray hit, None for the sky
+pub fn ray_march_hit(origin: Vec3, direction: Vec3, frame: &SceneFrame, shader: &dyn Shader, footprint: PixelFootprint, stats: Option<&mut RayStats>) -> (MarchResult, Option<Hit>) {
+    let RayGlobals { exposure, pipeline, ref tone_curve, shadows, hit_epsilon, ambient, lighting, floor, ref feedback, ref envmap } = *frame.rays;
+
+    let light = &frame.light;
+    let fill_light = (lighting == LightingRig::ThreePoint).then(|| Light::fill(frame.time));
 
     // Raymarching setup
     let max_steps = 500;
     let max_dist = 1500.0;
-    let epsilon = 0.001;
 
+    // Counted into plain locals, and only when someone reads the stats
+    let counting = stats.is_some();
+    let sdf_evaluations = Cell::new(0);
+    let shadow_rays = Cell::new(0);
+    let ao_probes = Cell::new(0);
+
+    // Everything that casts shadows
+    let sdf = |p: Vec3| {
+        if counting {
+            sdf_evaluations.set(sdf_evaluations.get() + 1);
+        }
+        scene_sdf(p, frame)
+    };
+    // The light itself shows as a glowing sphere the size of the area light, left out
+    // of the shadow rays since they all end inside it
+    let light_sphere = |p: Vec3| (p - light.position).length() - light.radius;
+    let visible_sdf = |p: Vec3| sdf(p).min(light_sphere(p));
+    // Scene colors and the feedback texture are authored as sRGB; shading needs
+    // them as linear light
+    let to_linear = |color: Vec3| match pipeline {
+        ColorPipeline::Legacy => color,
+        ColorPipeline::Linear => color.srgb_to_linear(),
+    };
+    let hemisphere = match (ambient, envmap) {
+        (AmbientLight::Hemisphere, None) => Some(HemisphereLight::new(frame.scene, floor, to_linear)),
+        _ => None,
+    };
+    // The environment's irradiance, scaled to the same average brightness as the
+    // other ambient lights so a map's exposure only sets the background's
+    let envmap_ambient = envmap.as_ref().filter(|_| ambient == AmbientLight::Hemisphere).map(|map| (map, AMBIENT_LEVEL / map.mean_irradiance_luminance().max(1e-6)));
+    // Ambient light reaching a surface facing `normal`
+    let ambient_at = |normal: Vec3| match (&envmap_ambient, &hemisphere) {
+        (Some((map, scale)), _) => map.irradiance(normal) * *scale,
+        (None, Some(light)) => light.at(normal),
+        (None, None) => Vec3::splat(AMBIENT_LEVEL),
+    };
+
+    // Lit surface color, normal and material at `p`, `t` along the ray
+    let shade_point = |p: Vec3, t: f32| {
+        let normal = calculate_normal(p, &visible_sdf);
+        if light_sphere(p) < sdf(p) {
+            let material = Material::emissive(to_linear(LIGHT_EMISSION));
+            return (material.shade(shader, normal, direction, normal, 1.0, 0.0, Vec3::zero()), normal, material);
+        }
+        // Compute light direction from p to light_pos
+        let to_light = (light.position - p).normalize();
+        let distance_to_light = (light.position - p).length();
+        // Compute shadow factor
+        let bias = shadows.bias.for_surface(normal, to_light);
+        if counting && shadows.quality != ShadowQuality::Off {
+            shadow_rays.set(shadow_rays.get() + 1);
+        }
+        let shadow = shadow_factor(p, to_light, distance_to_light, bias, light, &shadows, &sdf);
+        // Shade the point
+        let albedo = feedback
+            .as_ref()
+            .filter(|_| frame.scene == Scene::Cubes)
+            .and_then(|texture| screen_face_uv(p, frame).map(|(u, v)| texture.sample_texture(u, v)))
+            .unwrap_or_else(|| {
+                // A pixel's footprint stretches along the floor as the ray grazes it
+                let floor_pixel = footprint.width_at(t) / direction.y.abs().max(1e-3);
+                surface_color(p, frame, floor, floor_pixel)
+            });
+        let material = Material::diffuse(to_linear(albedo));
+        let mut ambient = ambient_at(normal);
+        if lighting == LightingRig::ThreePoint {
+            if counting {
+                ao_probes.set(ao_probes.get() + AO_SAMPLES);
+            }
+            ambient = ambient * ambient_occlusion(p, normal, &sdf);
+        }
+        let mut color = material.shade(shader, normal, direction, to_light, shadow, distance_to_light, ambient);
+        if let Some(fill) = &fill_light {
+            // Only the key light's ambient term, and no shadows for the fill
+            let to_fill = (fill.position - p).normalize();
+            let distance_to_fill = (fill.position - p).length();
+            color = color + shader.shade(material.albedo, normal, direction, to_fill, 1.0, distance_to_fill, Vec3::zero()) * FILL_STRENGTH;
+        }
+        (color, normal, material)
+    };
+
+    // Closest approach to the scene in pixel widths, and where along the ray it was
+    let mut closest = (f32::INFINITY, 0.0);
     let mut t = 0.0;
-    for _ in 0..max_steps {
+    let mut steps = 0;
+    while steps < max_steps {
+        steps += 1;
         let p = origin + direction * t;
-        let d = scene_sdf(p, time);
-        if d < epsilon {
+        let d = visible_sdf(p);
+        if d < hit_epsilon.at(t) {
             // Hit detected
-            let normal = calculate_normal(p, time);
-            // Compute light direction from p to light_pos
-            let to_light = (light_pos - p).normalize();
-            let distance_to_light = (light_pos - p).length();
-            // Compute shadow factor
-            let shadow = soft_shadow(p, to_light, distance_to_light, time);
-            // Shade the point
-            let color = shade(p, normal, direction, to_light, shadow, distance_to_light);
-            return vec3_to_pixel(color);
+            let (color, normal, material) = shade_point(p, t);
+            let object = if light_sphere(p) < sdf(p) { ObjectId::Light } else { hit_object(p, frame) };
+            if let Some(stats) = stats {
+                stats.add_ray(steps, sdf_evaluations.get(), shadow_rays.get(), ao_probes.get());
+            }
+            let result = MarchResult {
+                color: tone_map(apply_exposure(color, exposure), pipeline, tone_curve),
+                normal,
+                depth: t,
+                object,
+            };
+            return (result, Some(Hit { object, material, position: p, normal, depth: t }));
+        }
+        let pixels = d / footprint.width_at(t);
+        if pixels < closest.0 {
+            closest = (pixels, t);
         }
         t += d;
         if t > max_dist {
@@ -71,101 +981,286 @@ pub fn ray_march(origin: Vec3, direction: Vec3, time: f32) -> Pixel {
         }
     }
 
-    // Background color (sky)
+    let mut sky_color = match envmap {
+        // The map is linear already; the legacy pipeline works on display values
+        Some(map) => match pipeline {
+            ColorPipeline::Legacy => map.sample(direction).linear_to_srgb(),
+            ColorPipeline::Linear => map.sample(direction),
+        },
+        None => to_linear(background(direction)),
+    };
+
+    // A ray passing within a pixel of a silhouette still has part of that surface in
+    // its pixel: half at a graze, none a full pixel away. Blending it in smooths the
+    // stair steps along edges against the sky without casting more rays.
+    let (pixels, closest_t) = closest;
+    if pixels < 1.0 {
+        let coverage = 0.5 * (1.0 - pixels);
+        let (surface, _, _) = shade_point(origin + direction * closest_t, closest_t);
+        sky_color = sky_color.lerp(surface, coverage);
+    }
+    if let Some(stats) = stats {
+        stats.add_ray(steps, sdf_evaluations.get(), shadow_rays.get(), ao_probes.get());
+    }
+    let result = MarchResult {
+        color: tone_map(apply_exposure(sky_color, exposure), pipeline, tone_curve),
+        normal: Vec3::zero(),
+        depth: f32::INFINITY,
+        object: ObjectId::Sky,
+    };
+    (result, None)
+}
+
+// Sky color seen along `direction`, as sRGB
+fn background(direction: Vec3) -> Vec3 {
     let t = 0.5 * (direction.y + 1.0);
-    let sky_color = Vec3::new(0.25, 0.37, 0.5).lerp(&Vec3::new(1.0, 1.0, 1.0), t);
-    vec3_to_pixel(sky_color)
+    Vec3::new(0.25, 0.37, 0.5).lerp(Vec3::new(1.0, 1.0, 1.0), t)
 }
 
-fn scene_sdf(p: Vec3, time: f32) -> f32 {
-    let plane_sdf = p.y + 1.0;
+// Ambient light as a sky above and a ground below, the ground lit by the sky and
+// tinted by its albedo. Both are scaled so a vertical face, which sees half of each,
+// gets AMBIENT_LEVEL's brightness: tops come out brighter and bluer than the flat
+// level, undersides darker and tinted like the ground.
+struct HemisphereLight {
+    sky: Vec3,
+    ground: Vec3,
+}
 
-    let cube_size = 0.5;
+impl HemisphereLight {
+    fn new(scene: Scene, floor: FloorTexture, to_linear: impl Fn(Vec3) -> Vec3) -> Self {
+        // The sky gradient is linear in direction.y, and the cosine weighted mean
+        // of direction.y over the upper hemisphere is 2/3, so this is what a surface
+        // facing straight up sees on average
+        let sky = to_linear(background(Vec3::new(0.0, 2.0 / 3.0, 0.0)));
+        let ground = sky * to_linear(ground_albedo(scene, floor));
+        let scale = AMBIENT_LEVEL / luminance((sky + ground) * 0.5);
+        HemisphereLight { sky: sky * scale, ground: ground * scale }
+    }
 
-    // Define rotation speeds for each axis (radians per second)
-    // Each cube has its own rotation speed
-    // Cube 1
-    let rot_speed1_x = 0.5;
-    let rot_speed1_y = 0.8;
-    let rot_speed1_z = 0.3;
+    fn at(&self, normal: Vec3) -> Vec3 {
+        self.ground.lerp(self.sky, 0.5 * (normal.y + 1.0))
+    }
+}
 
-    // Cube 2
-    let rot_speed2_x = 0.3;
-    let rot_speed2_y = 0.6;
-    let rot_speed2_z = 0.9;
+// Average color of the ground, what light bounced up from below takes on
+fn ground_albedo(scene: Scene, floor: FloorTexture) -> Vec3 {
+    match scene {
+        // Mostly grass
+        Scene::Terrain { .. } => Vec3::new(0.18, 0.42, 0.16),
+        Scene::Cubes | Scene::Repetition { .. } => floor.albedo(),
+    }
+}
 
-    // Cube 3
-    let rot_speed3_x = 0.7;
-    let rot_speed3_y = 0.4;
-    let rot_speed3_z = 0.5;
+fn luminance(color: Vec3) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
 
-    // Rotation angles based on time and speeds
-    let angle1_x = time * rot_speed1_x;
-    let angle1_y = time * rot_speed1_y;
-    let angle1_z = time * rot_speed1_z;
+// The scene object nearest to `p`, which lies on the scene's surface
+fn hit_object(p: Vec3, frame: &SceneFrame) -> ObjectId {
+    match frame.scene {
+        Scene::Terrain { .. } => return ObjectId::Terrain,
+        Scene::Repetition { seed } => {
+            return if repetition_sphere_sdf(p, seed) < p.y + 1.0 { ObjectId::Sphere } else { ObjectId::Floor };
+        }
+        Scene::Cubes => {}
+    }
+    let half = Vec3::new(CUBE_SIZE, CUBE_SIZE, CUBE_SIZE);
+    let floor = (ObjectId::Floor, p.y + 1.0);
+    let (object, _) = (0..frame.cube_transforms.len())
+        .map(|cube| (ObjectId::Cube(cube), box_sdf(cube_local(p, frame, cube), half)))
+        .fold(floor, |closest, candidate| if candidate.1 < closest.1 { candidate } else { closest });
+    object
+}
+
+fn scene_sdf(p: Vec3, frame: &SceneFrame) -> f32 {
+    match frame.scene {
+        Scene::Cubes => cubes_sdf(p, frame),
+        Scene::Terrain { seed } => terrain_sdf(p, seed),
+        Scene::Repetition { seed } => repetition_sphere_sdf(p, seed).min(p.y + 1.0),
+    }
+}
+
+// The cubes among themselves keep hard edges; only the floor seam is blended
+fn cubes_sdf(p: Vec3, frame: &SceneFrame) -> f32 {
+    let plane_sdf = p.y + 1.0;
+    let half = Vec3::new(CUBE_SIZE, CUBE_SIZE, CUBE_SIZE);
+    let objects = (0..frame.cube_transforms.len()).fold(f32::INFINITY, |d, cube| d.min(box_sdf(cube_local(p, frame, cube), half)));
+    smin(plane_sdf, objects, frame.floor_blend)
+}
+
+// Height of the terrain floor and how far the hills rise above it
+const TERRAIN_BASE: f32 = -1.0;
+const TERRAIN_HEIGHT: f32 = 1.4;
+// Lattice cells per world unit of the broadest octave
+const TERRAIN_FREQUENCY: f32 = 0.6;
+const TERRAIN_OCTAVES: u32 = 5;
 
-    let angle2_x = time * rot_speed2_x;
-    let angle2_y = time * rot_speed2_y;
-    let angle2_z = time * rot_speed2_z;
+fn terrain_height(x: f32, z: f32, seed: u32) -> f32 {
+    TERRAIN_BASE + TERRAIN_HEIGHT * noise::fbm(x * TERRAIN_FREQUENCY, z * TERRAIN_FREQUENCY, seed, TERRAIN_OCTAVES)
+}
 
-    let angle3_x = time * rot_speed3_x;
-    let angle3_y = time * rot_speed3_y;
-    let angle3_z = time * rot_speed3_z;
+// Vertical distance to a heightfield overestimates the true distance where the
+// slope is steep, so it is scaled down to keep the march from stepping through hills.
+// Slopes stay below 2, and 1 / sqrt(1 + 2^2) is about 0.45.
+fn terrain_sdf(p: Vec3, seed: u32) -> f32 {
+    (p.y - terrain_height(p.x, p.z, seed)) * 0.45
+}
 
-    // Define fixed positions for the cubes
-    let cube1_pos = Vec3::new(-1.5, cube_size, 0.0);
-    let cube2_pos = Vec3::new(1.5, cube_size, 0.0);
-    let cube3_pos = Vec3::new(0.0, cube_size, 1.732); // Positioned to form an equilateral triangle
+// Side of the repetition scene's square cells, and the range the seed scales each
+// cell's sphere radius over. The largest sphere leaves a gap to the cell border.
+const REPETITION_CELL: f32 = 1.5;
+const REPETITION_MIN_RADIUS: f32 = 0.15;
+const REPETITION_MAX_RADIUS: f32 = 0.6;
 
-    // Apply rotations to each cube
-    let rotated_p1 = rotate_all_axes(p - cube1_pos, angle1_x, angle1_y, angle1_z);
-    let rotated_p2 = rotate_all_axes(p - cube2_pos, angle2_x, angle2_y, angle2_z);
-    let rotated_p3 = rotate_all_axes(p - cube3_pos, angle3_x, angle3_y, angle3_z);
+// The repetition scene's cell holding `p`, counted along x and z
+fn repetition_cell(p: Vec3) -> (i32, i32) {
+    ((p.x / REPETITION_CELL).floor() as i32, (p.z / REPETITION_CELL).floor() as i32)
+}
 
-    // Compute SDFs for each cube
-    let cube1_sdf = box_sdf(rotated_p1, Vec3::new(cube_size, cube_size, cube_size));
-    let cube2_sdf = box_sdf(rotated_p2, Vec3::new(cube_size, cube_size, cube_size));
-    let cube3_sdf = box_sdf(rotated_p3, Vec3::new(cube_size, cube_size, cube_size));
+// Random value in [0, 1) for a cell, a different one for each `salt`
+fn repetition_random(cell: (i32, i32), seed: u32, salt: u32) -> f32 {
+    hash_f32(hash_u32(hash_u32(seed ^ salt) ^ cell.0 as u32) ^ (cell.1 as u32).wrapping_mul(0x9e3779b9))
+}
 
-    // Combine SDFs: plane and cubes
-    plane_sdf.min(cube1_sdf).min(cube2_sdf).min(cube3_sdf)
+// Distance to the sphere resting on the floor in `p`'s cell. Only that one sphere
+// is looked at, so the distance is capped at the border of the cell plus the gap no
+// sphere reaches into; a step can't pass through a bigger neighbor that way.
+fn repetition_sphere_sdf(p: Vec3, seed: u32) -> f32 {
+    let cell = repetition_cell(p);
+    let radius = REPETITION_MIN_RADIUS + (REPETITION_MAX_RADIUS - REPETITION_MIN_RADIUS) * repetition_random(cell, seed, 0);
+    let center = Vec3::new((cell.0 as f32 + 0.5) * REPETITION_CELL, -1.0 + radius, (cell.1 as f32 + 0.5) * REPETITION_CELL);
+    let to_border = 0.5 * REPETITION_CELL - (p.x - center.x).abs().max((p.z - center.z).abs());
+    ((p - center).length() - radius).min(to_border + 0.5 * REPETITION_CELL - REPETITION_MAX_RADIUS)
 }
 
-// Function to rotate a point around all three axes
-fn rotate_all_axes(p: Vec3, angle_x: f32, angle_y: f32, angle_z: f32) -> Vec3 {
-    let rot_matrix = Mat4::from_euler_angles(angle_x, angle_y, angle_z);
-    rot_matrix.transform_point3(p)
+// `p` in the local frame of cube number `cube`
+fn cube_local(p: Vec3, frame: &SceneFrame, cube: usize) -> Vec3 {
+    frame.cube_transforms[cube].transform_point3(p)
 }
 
+// Texture coordinates on cube 1's local -z face, the one facing the camera at the
+// start, which displays the feedback texture
+fn screen_face_uv(p: Vec3, frame: &SceneFrame) -> Option<(f32, f32)> {
+    let local = cube_local(p, frame, 0);
+    let half = Vec3::new(CUBE_SIZE, CUBE_SIZE, CUBE_SIZE);
+    // On the cube, with z the dominant axis of the local position
+    let on_face = box_sdf(local, half) < 0.01 && -local.z >= local.x.abs().max(local.y.abs());
+    if !on_face {
+        return None;
+    }
+    let u = (0.5 + local.x / (2.0 * CUBE_SIZE)).clamp(0.0, 1.0);
+    let v = (0.5 - local.y / (2.0 * CUBE_SIZE)).clamp(0.0, 1.0);
+    Some((u, v))
+}
+
+// Textured floor and position-tinted cubes, terrain banded by height, or a color per
+// sphere
+fn surface_color(p: Vec3, frame: &SceneFrame, floor: FloorTexture, floor_pixel: f32) -> Vec3 {
+    if let Scene::Repetition { seed } = frame.scene {
+        if hit_object(p, frame) == ObjectId::Floor {
+            return floor.color(p, frame.time, floor_pixel);
+        }
+        let cell = repetition_cell(p);
+        return Vec3::new(repetition_random(cell, seed, 1), repetition_random(cell, seed, 2), repetition_random(cell, seed, 3)) * 0.7 + Vec3::splat(0.3);
+    }
+    if let Scene::Terrain { .. } = frame.scene {
+        let height = ((p.y - TERRAIN_BASE) / TERRAIN_HEIGHT).clamp(0.0, 1.0);
+        let grass = Vec3::new(0.18, 0.42, 0.16);
+        let rock = Vec3::new(0.45, 0.38, 0.3);
+        let snow = Vec3::new(0.95, 0.96, 0.98);
+        return if height < 0.55 {
+            grass.lerp(rock, height / 0.55)
+        } else {
+            rock.lerp(snow, ((height - 0.55) / 0.2).min(1.0))
+        };
+    }
+    if p.y < -0.99 {
+        floor.color(p, frame.time, floor_pixel)
+    } else {
+        // Cube color based on position, the waves shifted by the seed so each run
+        // gets its own palette
+        let phase = |axis: u32| TAU * hash_f32(hash_u32(frame.seed) ^ axis);
+        Vec3::new(
+            (p.x + phase(0)).sin() * 0.5 + 0.5,
+            (p.y + phase(1)).sin() * 0.5 + 0.5,
+            (p.z + phase(2)).sin() * 0.5 + 0.5
+        )
+    }
+}
+
+
 fn box_sdf(p: Vec3, b: Vec3) -> f32 {
     let q = Vec3::new(p.x.abs(), p.y.abs(), p.z.abs()) - b;
     q.max(Vec3::new(0.0, 0.0, 0.0)).length() + q.x.max(q.y.max(q.z)).min(0.0)
 }
 
-fn calculate_normal(p: Vec3, time: f32) -> Vec3 {
+fn calculate_normal(p: Vec3, sdf: &impl Fn(Vec3) -> f32) -> Vec3 {
     let epsilon = 0.001;
     Vec3::new(
-        scene_sdf(Vec3::new(p.x + epsilon, p.y, p.z), time) - scene_sdf(Vec3::new(p.x - epsilon, p.y, p.z), time),
-        scene_sdf(Vec3::new(p.x, p.y + epsilon, p.z), time) - scene_sdf(Vec3::new(p.x, p.y - epsilon, p.z), time),
-        scene_sdf(Vec3::new(p.x, p.y, p.z + epsilon), time) - scene_sdf(Vec3::new(p.x, p.y, p.z - epsilon), time)
+        sdf(Vec3::new(p.x + epsilon, p.y, p.z)) - sdf(Vec3::new(p.x - epsilon, p.y, p.z)),
+        sdf(Vec3::new(p.x, p.y + epsilon, p.z)) - sdf(Vec3::new(p.x, p.y - epsilon, p.z)),
+        sdf(Vec3::new(p.x, p.y, p.z + epsilon)) - sdf(Vec3::new(p.x, p.y, p.z - epsilon))
     ).normalize()
 }
 
-// Soft shadow function adjusted for point light
-fn soft_shadow(p: Vec3, light_dir: Vec3, distance_to_light: f32, time: f32) -> f32 {
-    let mut t = 0.01; // Start slightly offset to avoid self-shadowing
-    let max_dist = distance_to_light; // Only check up to the light source
-    let mut shadow = 1.0;
-    let k = 2.0; // Reduced softness factor for smoother shadows
+// Fraction of the light visible from `p`, from 0 (fully shadowed) to 1
+fn shadow_factor(p: Vec3, light_dir: Vec3, distance_to_light: f32, bias: f32, light: &Light, settings: &ShadowSettings, sdf: &impl Fn(Vec3) -> f32) -> f32 {
+    // Stop short of the light so its own surroundings don't count as occluders
+    let max_dist = (distance_to_light - light.radius).max(bias);
+    match settings.quality {
+        ShadowQuality::Off => 1.0,
+        ShadowQuality::Hard => hard_shadow(p, light_dir, bias, max_dist, settings.max_steps, sdf),
+        ShadowQuality::Soft => {
+            // The light subtends radius / distance; a miss by less than that at distance t
+            // leaves part of the light covered
+            let k = distance_to_light / light.radius.max(1e-3);
+            soft_shadow(p, light_dir, bias, max_dist, k, settings.max_steps, sdf)
+        }
+    }
+}
 
-    for _ in 0..100 {
-        let current_p = p + light_dir * t;
-        let dist = scene_sdf(current_p, time);
+// How open the surroundings of `p` are around its normal, 1 out in the open and
+// toward 0 in creases and corners, from how close the scene comes to points probed
+// along the normal
+fn ambient_occlusion(p: Vec3, normal: Vec3, sdf: &impl Fn(Vec3) -> f32) -> f32 {
+    let mut occlusion = 0.0;
+    let mut weight = 1.0;
+    for i in 1..=AO_SAMPLES {
+        let probe = AO_STEP * i as f32;
+        occlusion += (probe - sdf(p + normal * probe)).max(0.0) * weight;
+        weight *= 0.5;
+    }
+    (1.0 - AO_STRENGTH * occlusion).clamp(0.0, 1.0)
+}
+
+fn hard_shadow(p: Vec3, light_dir: Vec3, bias: f32, max_dist: f32, max_steps: u32, sdf: &impl Fn(Vec3) -> f32) -> f32 {
+    let mut t = bias; // Start offset to avoid self-shadowing
+    for _ in 0..max_steps {
+        let dist = sdf(p + light_dir * t);
         if dist < 0.001 {
-            // Occluder found
-            shadow *= 1.0 - (t / max_dist).powf(k);
+            return 0.0;
+        }
+        t += dist;
+        if t > max_dist {
             break;
         }
+    }
+    1.0
+}
+
+// Every near miss along the ray narrows the penumbra, tracked as the minimum of
+// k * d / t; `k` is the ray length over which one unit of clearance unshadows the light
+fn soft_shadow(p: Vec3, light_dir: Vec3, bias: f32, max_dist: f32, k: f32, max_steps: u32, sdf: &impl Fn(Vec3) -> f32) -> f32 {
+    let mut t = bias; // Start offset to avoid self-shadowing
+    let mut shadow: f32 = 1.0;
+
+    for _ in 0..max_steps {
+        let dist = sdf(p + light_dir * t);
+        shadow = shadow.min(k * dist / t);
+        if dist < 0.001 || shadow < 0.001 {
+            // Fully occluded, nothing further along can change that
+            return 0.0;
+        }
         t += dist;
         if t > max_dist {
             break;
@@ -176,43 +1271,19 @@ fn soft_shadow(p: Vec3, light_dir: Vec3, distance_to_light: f32, time: f32) -> f
     shadow.clamp(0.0, 1.0)
 }
 
-fn shade(
-    p: Vec3,
-    normal: Vec3,
-    view_dir: Vec3,
-    light_dir: Vec3,
-    shadow: f32,
-    distance_to_light: f32
-) -> Vec3 {
-    let light_color = Vec3::new(1.0, 1.0, 1.0);
-    let object_color = if p.y < -0.99 {
-        // Checkerboard floor
-        let pattern = ((p.x * 0.5).floor() as i32 + (p.z * 0.5).floor() as i32) & 1;
-        if pattern == 0 {
-            Vec3::new(0.12, 0.14, 0.16)
-        } else {
-            Vec3::new(0.9, 0.95, 0.99)
-        }
-    } else {
-        // Cube color based on position
-        Vec3::new(
-            p.x.sin() * 0.5 + 0.5,
-            p.y.sin() * 0.5 + 0.5,
-            p.z.sin() * 0.5 + 0.5
-        )
-    };
-
-    let ambient = 0.1;
-
-    // Diffuse lighting
-    let diffuse = normal.dot(&light_dir).max(0.0) * shadow;
-
-    // Light attenuation with scaling factor
-    let light_intensity = 500.0; 
-    let attenuation = light_intensity / (distance_to_light * distance_to_light + 1.0);
+// Scale linear radiance by 2^exposure (photographic stops)
+fn apply_exposure(color: Vec3, exposure: f32) -> Vec3 {
+    color * exposure.exp2()
+}
 
-    // Final color with attenuation
-    object_color * light_color * (ambient + diffuse) * attenuation
+// Map linear radiance to a displayable pixel through the tone curve. This is the
+// only place the raymarcher encodes to sRGB.
+pub fn tone_map(color: Vec3, pipeline: ColorPipeline, curve: &ToneCurve) -> Pixel {
+    let color = Vec3::new(curve.apply(color.x), curve.apply(color.y), curve.apply(color.z));
+    match pipeline {
+        ColorPipeline::Legacy => vec3_to_pixel(color),
+        ColorPipeline::Linear => vec3_to_pixel(color.linear_to_srgb()),
+    }
 }
 
 fn vec3_to_pixel(v: Vec3) -> Pixel {
@@ -223,3 +1294,452 @@ fn vec3_to_pixel(v: Vec3) -> Pixel {
         a: 255,
     }
 }
+
+// Seeded lattice noise over the xz plane
+mod noise {
+    use crate::math::{hash_f32, hash_u32};
+
+    // Random value in [0, 1) at an integer lattice point
+    fn lattice(x: i32, z: i32, seed: u32) -> f32 {
+        hash_f32(hash_u32(hash_u32(seed) ^ x as u32) ^ (z as u32).wrapping_mul(0x9e3779b9))
+    }
+
+    // Quintic fade, so the interpolated surface has continuous normals
+    fn fade(t: f32) -> f32 {
+        t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+    }
+
+    // Lattice values blended across each cell, in [0, 1)
+    pub fn value_noise(x: f32, z: f32, seed: u32) -> f32 {
+        let (cell_x, cell_z) = (x.floor(), z.floor());
+        let (ix, iz) = (cell_x as i32, cell_z as i32);
+        let (u, v) = (fade(x - cell_x), fade(z - cell_z));
+        let near = lattice(ix, iz, seed) + (lattice(ix + 1, iz, seed) - lattice(ix, iz, seed)) * u;
+        let far = lattice(ix, iz + 1, seed) + (lattice(ix + 1, iz + 1, seed) - lattice(ix, iz + 1, seed)) * u;
+        near + (far - near) * v
+    }
+
+    // Octaves of value noise at doubling frequency and halving amplitude, each with
+    // its own seed, normalized back to [0, 1)
+    pub fn fbm(x: f32, z: f32, seed: u32, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total = 0.0;
+        let (mut x, mut z) = (x, z);
+        for octave in 0..octaves {
+            sum += amplitude * value_noise(x, z, seed.wrapping_add(octave.wrapping_mul(0x632be5ab)));
+            total += amplitude;
+            amplitude *= 0.5;
+            // Rotate between octaves so the lattice axes don't line up
+            (x, z) = (1.6 * x - 1.2 * z, 1.2 * x + 1.6 * z);
+        }
+        sum / total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{pixel_to_ndc, Camera, Projection};
+    use crate::shader::PhongShader;
+
+    // `scene` at `time` as the defaults would draw it, built without the globals so
+    // tests running side by side can't disturb each other
+    fn test_frame(scene: Scene, time: f32, seed: u32) -> SceneFrame {
+        test_frame_with_cubes(scene, CubeLayout::Classic, time, seed)
+    }
+
+    fn test_frame_with_cubes(scene: Scene, cubes: CubeLayout, time: f32, seed: u32) -> SceneFrame {
+        let rays = test_rays();
+        scene.prepare(time, rays.shadows.light_radius, cubes.reseed(seed), DEFAULT_FLOOR_BLEND, seed, Arc::new(rays))
+    }
+
+    fn test_rays() -> RayGlobals {
+        RayGlobals {
+            exposure: 0.0,
+            pipeline: ColorPipeline::Linear,
+            tone_curve: ToneCurve::Linear,
+            shadows: ShadowSettings::default(),
+            hit_epsilon: HitEpsilon::default(),
+            ambient: AmbientLight::Hemisphere,
+            lighting: LightingRig::Single,
+            floor: FloorTexture::Checker,
+            feedback: None,
+            envmap: None,
+        }
+    }
+
+    // A `width` x `height` picture of `frame` from the default camera, one ray per pixel
+    fn render(frame: &SceneFrame, width: usize, height: usize) -> Vec<(u8, u8, u8)> {
+        let mut camera = Camera::new(Vec3::new(0.0, 1.25, -1.75), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Projection::from_name("perspective").unwrap());
+        camera.aspect_ratio = width as f32 / height as f32;
+        let footprint = camera.pixel_footprint(width);
+        (0..width * height)
+            .map(|index| {
+                let ndc = pixel_to_ndc((index % width) as f32 + 0.5, (index / width) as f32 + 0.5, width, height);
+                let (origin, direction) = camera.ray(ndc);
+                ray_march(origin, direction, frame, &PhongShader, footprint, None).color.to_rgb()
+            })
+            .collect()
+    }
+
+    // FNV-1a over the pixels, for comparing a picture against a stored one
+    fn checksum(pixels: &[(u8, u8, u8)]) -> u64 {
+        pixels.iter().flat_map(|&(r, g, b)| [r, g, b]).fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+    }
+
+    const SEEDED_SCENES: [fn(u32) -> Scene; 3] = [|_| Scene::Cubes, |seed| Scene::Terrain { seed }, |seed| Scene::Repetition { seed }];
+
+    #[test]
+    fn same_seed_same_image() {
+        for scene in SEEDED_SCENES {
+            let first = render(&test_frame(scene(7), 1.0, 7), 24, 12);
+            let second = render(&test_frame(scene(7), 1.0, 7), 24, 12);
+            assert_eq!(first, second, "{:?}", scene(7));
+        }
+    }
+
+    // Seed 7 as it rendered when the scenes were last changed on purpose. Update the
+    // values along with a change meant to alter what the scenes look like.
+    #[test]
+    fn seeded_golden_images() {
+        for (scene, expected) in SEEDED_SCENES.iter().zip([6039854708267209069u64, 355079642290994973, 5940572694205029297]) {
+            let pixels = render(&test_frame(scene(7), 1.0, 7), 24, 12);
+            assert_eq!(checksum(&pixels), expected, "{:?}", scene(7));
+        }
+    }
+
+    #[test]
+    fn another_seed_another_image() {
+        for scene in SEEDED_SCENES {
+            let first = render(&test_frame(scene(7), 1.0, 7), 24, 12);
+            let second = render(&test_frame(scene(8), 1.0, 8), 24, 12);
+            assert_ne!(first, second, "{:?}", scene(7));
+        }
+    }
+
+    #[test]
+    fn reseed_keeps_the_scene() {
+        assert_eq!(Scene::Cubes.reseed(3), Scene::Cubes);
+        assert_eq!(Scene::Terrain { seed: 1 }.reseed(3), Scene::Terrain { seed: 3 });
+        assert_eq!(Scene::Repetition { seed: 1 }.reseed(3), Scene::Repetition { seed: 3 });
+        for info in Scene::INFO {
+            assert!(Scene::from_name(info.name, 0).is_some(), "{}", info.name);
+        }
+    }
+
+    #[test]
+    fn repetition_spheres_vary_with_the_seed() {
+        let radius = |seed: u32, cell: (i32, i32)| {
+            // Straight down onto the middle of the cell, where the top of its sphere
+            // is, from low enough that the cap at the cell border doesn't apply
+            let x = (cell.0 as f32 + 0.5) * REPETITION_CELL;
+            let z = (cell.1 as f32 + 0.5) * REPETITION_CELL;
+            let top = 0.1 - repetition_sphere_sdf(Vec3::new(x, 0.1, z), seed);
+            (top + 1.0) * 0.5
+        };
+        let sizes: Vec<f32> = (0..8).map(|cell| radius(7, (cell, 2))).collect();
+        assert!(sizes.iter().all(|&r| (REPETITION_MIN_RADIUS - 1e-4..=REPETITION_MAX_RADIUS + 1e-4).contains(&r)), "{:?}", sizes);
+        assert!(sizes.iter().any(|&r| (r - sizes[0]).abs() > 0.05), "{:?}", sizes);
+        assert_eq!(radius(7, (3, -4)), radius(7, (3, -4)));
+        assert_ne!(radius(7, (3, -4)), radius(8, (3, -4)));
+    }
+
+    #[test]
+    fn a_stop_of_exposure_doubles_radiance() {
+        let color = Vec3::new(0.1, 0.25, 0.4);
+        for (stops, scale) in [(0.0, 1.0), (1.0, 2.0), (2.0, 4.0), (-1.0, 0.5)] {
+            let exposed = apply_exposure(color, stops);
+            assert_eq!((exposed.x, exposed.y, exposed.z), (color.x * scale, color.y * scale, color.z * scale), "{} stops", stops);
+        }
+    }
+
+    #[test]
+    fn grazing_light_gets_a_larger_shadow_bias() {
+        let bias = ShadowBias::default();
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let facing = bias.for_surface(normal, normal);
+        let angled = bias.for_surface(normal, Vec3::new(1.0, 1.0, 0.0).normalize());
+        let grazing = bias.for_surface(normal, Vec3::new(1.0, 0.05, 0.0).normalize());
+        assert_eq!(facing, bias.constant);
+        assert!(facing < angled && angled < grazing, "{} {} {}", facing, angled, grazing);
+        // Light in or behind the surface plane stays at the limit
+        assert_eq!(bias.for_surface(normal, Vec3::new(1.0, 0.0, 0.0)), bias.max);
+        assert_eq!(bias.for_surface(normal, Vec3::new(0.0, -1.0, 0.0)), bias.max);
+    }
+
+    #[test]
+    fn terrain_sdf_follows_the_seed() {
+        let points: Vec<Vec3> = (0..32).map(|i| Vec3::new(i as f32 * 0.73 - 11.0, (i % 5) as f32 * 0.4 - 1.0, i as f32 * -1.31 + 7.0)).collect();
+        let distances = |seed: u32| points.iter().map(|&p| terrain_sdf(p, seed)).collect::<Vec<f32>>();
+        assert_eq!(distances(7), distances(7));
+        assert_ne!(distances(7), distances(8));
+
+        // Noise stays in range for any seed
+        for seed in [0, 1, 7, u32::MAX] {
+            for p in &points {
+                let n = noise::fbm(p.x, p.z, seed, TERRAIN_OCTAVES);
+                assert!((0.0..1.0).contains(&n), "{} at seed {}", n, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn orbiting_cube_circles_its_pivot() {
+        let layout = CubeLayout::Classic;
+        let center = |cube: usize, time: f32| layout.transform(cube, time, 7).inverse().transform_point3(Vec3::zero());
+        let start = CUBE_POSITIONS[ORBITING_CUBE] - ORBIT_PIVOT;
+        for step in 0..40 {
+            let time = step as f32 * 0.15;
+            let offset = center(ORBITING_CUBE, time) - ORBIT_PIVOT;
+            // Same height and distance from the pivot, turned by the orbit's angle so far
+            assert!(offset.y.abs() < 1e-4, "{} at {}", offset.y, time);
+            assert!((offset.length() - start.length()).abs() < 1e-4, "{} at {}", offset.length(), time);
+            let cos = (offset.dot(&start) / (offset.length() * start.length())).clamp(-1.0, 1.0);
+            assert!((cos.acos() - ORBIT_SPEED * time).abs() < 1e-3, "{} at {}", cos.acos(), time);
+
+            // The others only spin in place
+            let still = center(0, time) - CUBE_POSITIONS[0];
+            assert!(still.length() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn ring_cubes_are_minima_around_the_ring() {
+        const SAMPLES: usize = 3600;
+        for count in [1, 3, 7, 12] {
+            let frame = test_frame_with_cubes(Scene::Cubes, CubeLayout::Ring { count, seed: 5 }, 2.5, 5);
+            // Around the circle through the cube centers, at their height
+            let radius = RING_MIN_RADIUS.max(RING_SPACING * count as f32 / TAU);
+            let angle = |i: usize| TAU * i as f32 / SAMPLES as f32;
+            let distances: Vec<f32> = (0..SAMPLES).map(|i| scene_sdf(RING_CENTER + Vec3::new(angle(i).sin(), 0.0, -angle(i).cos()) * radius, &frame)).collect();
+            let minima: Vec<usize> = (0..SAMPLES)
+                .filter(|&i| distances[i] < distances[(i + SAMPLES - 1) % SAMPLES] && distances[i] <= distances[(i + 1) % SAMPLES])
+                .collect();
+            assert_eq!(minima.len(), count, "{} cubes", count);
+            for (cube, &i) in minima.iter().enumerate() {
+                // At the cube's center, half its size deep inside it
+                let expected = TAU * cube as f32 / count as f32;
+                assert!((angle(i) - expected).abs() < 2e-3, "cube {} of {} at {}", cube, count, angle(i));
+                assert!((distances[i] + CUBE_SIZE).abs() < 1e-2, "cube {} of {}: {}", cube, count, distances[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn floor_blend_rounds_the_seam_under_a_cube() {
+        // At time zero the cubes sit unrotated, their bottoms a cube's height over the floor
+        let mut frame = test_frame(Scene::Cubes, 0.0, 1);
+        let below = CUBE_POSITIONS[0] - Vec3::new(0.0, 2.0 * CUBE_SIZE, 0.0);
+        let above = CUBE_POSITIONS[0] + Vec3::new(0.0, 2.0 * CUBE_SIZE, 0.0);
+        let blended = (cubes_sdf(below, &frame), cubes_sdf(above, &frame));
+        frame.floor_blend = 0.0;
+        let hard = (cubes_sdf(below, &frame), cubes_sdf(above, &frame));
+
+        // Halfway between the cube and the floor both are 0.5 away, and the blend
+        // pulls the surface in by a quarter of its radius
+        assert!((hard.0 - CUBE_SIZE).abs() < 1e-5, "{}", hard.0);
+        assert!((blended.0 - (CUBE_SIZE - DEFAULT_FLOOR_BLEND * 0.25)).abs() < 1e-5, "{}", blended.0);
+        // Away from the seam nothing changes
+        assert_eq!(blended.1, hard.1);
+    }
+
+    #[test]
+    fn checker_tiles_fade_to_their_average_when_small() {
+        let floor = FloorTexture::Checker;
+        let (dark_spot, light_spot) = (Vec3::new(0.5, 0.0, 0.5), Vec3::new(0.5 + CHECKER_TILE, 0.0, 0.5));
+        let near = |v: Vec3, expected: Vec3| (v - expected).length() < 1e-5;
+        let contrast = |pixel_width: f32| (floor.color(light_spot, 0.0, pixel_width) - floor.color(dark_spot, 0.0, pixel_width)).length();
+
+        // Hundreds of pixels to a tile: the two colors as they are
+        assert!(near(floor.color(dark_spot, 0.0, 0.01), CHECKER_DARK));
+        assert!(near(floor.color(light_spot, 0.0, 0.01), CHECKER_LIGHT));
+        // Under a pixel to a tile: both the mid color
+        let average = CHECKER_DARK.lerp(CHECKER_LIGHT, 0.5);
+        assert!(near(floor.color(dark_spot, 0.0, CHECKER_TILE * 2.0), average));
+        assert!(near(floor.color(light_spot, 0.0, CHECKER_TILE * 2.0), average));
+        // And the contrast drops steadily in between
+        let widths: Vec<f32> = (0..=20).map(|i| CHECKER_TILE / CHECKER_FADE_START * (CHECKER_FADE_START / CHECKER_FADE_END).powf(i as f32 / 20.0)).collect();
+        assert!(widths.windows(2).all(|pair| contrast(pair[1]) <= contrast(pair[0])));
+        assert!(contrast(widths[0]) > 0.99 * contrast(0.01) && contrast(widths[20]) < 1e-5);
+    }
+
+    #[test]
+    fn hit_epsilon_grows_with_distance() {
+        let epsilon = HitEpsilon::default();
+        assert_eq!(epsilon.at(0.0), epsilon.base);
+        let distances = [0.0, 0.5, 1.0, 5.0, 20.0, 100.0];
+        assert!(distances.windows(2).all(|pair| epsilon.at(pair[1]) > epsilon.at(pair[0])));
+        assert!((epsilon.at(100.0) / epsilon.base - (1.0 + 100.0 * epsilon.growth)).abs() < 1e-4);
+        let fixed = HitEpsilon { growth: 0.0, ..epsilon };
+        assert!(distances.iter().all(|&t| fixed.at(t) == fixed.base));
+
+        // Rays that end on the floor well clear of the cubes, one straight down from
+        // just above it and one skimming along it from far away
+        let march = |hit_epsilon: HitEpsilon, origin: Vec3, direction: Vec3| {
+            let mut frame = test_frame(Scene::Cubes, 0.0, 1);
+            frame.rays = Arc::new(RayGlobals { hit_epsilon, ..test_rays() });
+            let footprint = PixelFootprint { base: 0.0, spread: 0.001 };
+            let mut stats = RayStats::default();
+            let depth = ray_march(origin, direction, &frame, &PhongShader, footprint, Some(&mut stats)).depth;
+            (depth, stats.march_steps)
+        };
+        let (near_depth, _) = march(epsilon, Vec3::new(30.0, -0.5, -20.0), Vec3::new(0.0, -1.0, 0.0));
+        // A near hit still lands within the tight base epsilon
+        assert!((near_depth - 0.5).abs() <= epsilon.base * 1.05, "{}", near_depth);
+
+        let (origin, direction) = (Vec3::new(30.0, 1.0, -20.0), Vec3::new(0.0, -0.05, 1.0).normalize());
+        let (far_fixed, fixed_steps) = march(fixed, origin, direction);
+        let (far_grown, grown_steps) = march(epsilon, origin, direction);
+        assert!(far_fixed > 15.0 && far_fixed.is_finite());
+        // Far away it stops sooner, but no further off than the grown epsilon allows
+        assert!(grown_steps < fixed_steps, "{} vs {}", grown_steps, fixed_steps);
+        assert!((far_grown - far_fixed).abs() * direction.y.abs() <= epsilon.at(far_fixed), "{} vs {}", far_grown, far_fixed);
+    }
+
+    #[test]
+    fn three_point_rig_adds_a_fill_and_occludes_the_ambient() {
+        // The fill circles opposite the key and lower, still above the floor
+        for step in 0..12 {
+            let time = step as f32 * 1.1;
+            let (key, fill) = (Light::orbiting(time, 1.0).position, Light::fill(time).position);
+            assert!(key.x * fill.x + key.z * fill.z < 0.0, "at {}", time);
+            assert!(fill.y < key.y && fill.y > 0.0, "at {}", time);
+        }
+
+        // A light tile of the open floor well away from the cubes, lit by the key, the
+        // fill and the ambient light in turn. The key mirrored below the floor lights
+        // nothing but keeps its falloff, which the ambient term shares.
+        let brightness = |lighting: LightingRig, key: bool| {
+            let mut frame = test_frame(Scene::Cubes, 0.0, 1);
+            frame.rays = Arc::new(RayGlobals { lighting, ..test_rays() });
+            if !key {
+                let above = frame.light.position;
+                frame.light.position = Vec3::new(above.x, -2.0 - above.y, above.z);
+            }
+            let footprint = PixelFootprint { base: 0.0, spread: 0.001 };
+            let (r, g, b) = ray_march(Vec3::new(3.0, -0.5, -8.0), Vec3::new(0.0, -1.0, 0.0), &frame, &PhongShader, footprint, None).color.to_rgb();
+            r as u32 + g as u32 + b as u32
+        };
+        let ambient = brightness(LightingRig::Single, false);
+        let with_key = brightness(LightingRig::Single, true);
+        let with_fill = brightness(LightingRig::ThreePoint, false);
+        let all = brightness(LightingRig::ThreePoint, true);
+        assert!(ambient > 0, "ambient");
+        assert!(with_key > ambient && with_fill > ambient, "{} {} {}", ambient, with_key, with_fill);
+        // Nothing there to occlude, so the fill is the only thing the rig adds
+        assert!(all > with_key && all > with_fill, "{} {} {}", all, with_key, with_fill);
+        assert_ne!(with_key, with_fill);
+
+        // Ambient occlusion darkens the crease between a floor and a wall, the more
+        // the deeper into it, and leaves the open floor alone
+        let crease = |p: Vec3| p.y.min(p.x);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let occlusion: Vec<f32> = [5.0, 0.2, 0.1, 0.02].iter().map(|&x| ambient_occlusion(Vec3::new(x, 0.0, 0.0), up, &crease)).collect();
+        assert_eq!(occlusion[0], 1.0);
+        assert!(occlusion.windows(2).all(|pair| pair[1] < pair[0]) && occlusion[3] < 0.5, "{:?}", occlusion);
+        // The open floor of the scene isn't occluded at all
+        let frame = test_frame(Scene::Cubes, 0.0, 1);
+        assert_eq!(ambient_occlusion(Vec3::new(3.0, -1.0, -8.0), up, &|p: Vec3| scene_sdf(p, &frame)), 1.0);
+    }
+
+    // Cubes against the sky as the default camera sees them, with and without the
+    // near-miss blend. Sky pixels away from the silhouettes must stay plain sky, and
+    // the surfaces themselves must come out as they did before the blend existed.
+    #[test]
+    fn near_misses_only_soften_the_sky_beside_edges() {
+        let (width, height) = (48, 24);
+        let frame = test_frame(Scene::Cubes, 1.0, 7);
+        let mut camera = Camera::new(Vec3::new(0.0, 1.25, -1.75), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Projection::from_name("perspective").unwrap());
+        camera.aspect_ratio = width as f32 / height as f32;
+        let footprint = camera.pixel_footprint(width);
+        let rays = test_rays();
+        let plain_sky = |direction: Vec3| tone_map(apply_exposure(background(direction).srgb_to_linear(), rays.exposure), rays.pipeline, &rays.tone_curve).to_rgb();
+        // Each pixel's color, whether it hit, and the sky behind it
+        let pixels: Vec<_> = (0..width * height)
+            .map(|index| {
+                let ndc = pixel_to_ndc((index % width) as f32 + 0.5, (index / width) as f32 + 0.5, width, height);
+                let (origin, direction) = camera.ray(ndc);
+                let (result, hit) = ray_march_hit(origin, direction, &frame, &PhongShader, footprint, None);
+                (result.color.to_rgb(), hit.is_some(), plain_sky(direction))
+            })
+            .collect();
+        let is_hit = |x: i32, y: i32| x >= 0 && y >= 0 && x < width as i32 && y < height as i32 && pixels[y as usize * width + x as usize].1;
+        let beside_a_hit = |index: usize| {
+            let (x, y) = ((index % width) as i32, (index / width) as i32);
+            (-1..=1).any(|dy| (-1..=1).any(|dx| is_hit(x + dx, y + dy)))
+        };
+
+        let mut softened = 0;
+        for (index, &(color, hit, sky)) in pixels.iter().enumerate() {
+            if !hit && color != sky {
+                assert!(beside_a_hit(index), "pixel {} changed away from any edge", index);
+                softened += 1;
+            }
+        }
+        assert!(softened > 0);
+        assert!(pixels.iter().any(|&(_, hit, _)| hit) && pixels.iter().any(|&(_, hit, _)| !hit));
+
+        // The hit pixels as they rendered before the blend went in, and the whole
+        // picture with it. Update along with a change meant to alter either.
+        let hits: Vec<(u8, u8, u8)> = pixels.iter().filter(|&&(_, hit, _)| hit).map(|&(color, _, _)| color).collect();
+        let all: Vec<(u8, u8, u8)> = pixels.iter().map(|&(color, _, _)| color).collect();
+        assert_eq!((checksum(&hits), checksum(&all)), (4470197390885735306, 12568151431829240507));
+    }
+
+    #[test]
+    fn ray_stats_count_each_kind_of_work() {
+        // One ray straight down onto the open floor
+        let count = |lighting: LightingRig| {
+            let mut frame = test_frame(Scene::Cubes, 0.0, 1);
+            frame.rays = Arc::new(RayGlobals { lighting, ..test_rays() });
+            let footprint = PixelFootprint { base: 0.0, spread: 0.001 };
+            let mut stats = RayStats::default();
+            let counted = ray_march(Vec3::new(3.0, -0.5, -8.0), Vec3::new(0.0, -1.0, 0.0), &frame, &PhongShader, footprint, Some(&mut stats));
+            let uncounted = ray_march(Vec3::new(3.0, -0.5, -8.0), Vec3::new(0.0, -1.0, 0.0), &frame, &PhongShader, footprint, None);
+            assert_eq!(counted.color.to_rgb(), uncounted.color.to_rgb());
+            stats
+        };
+        let single = count(LightingRig::Single);
+        assert_eq!((single.primary_rays, single.shadow_rays, single.ao_probes), (1, 1, 0));
+        assert!(single.march_steps > 0 && single.sdf_evaluations > single.march_steps);
+        // The three-point rig probes for occlusion, each probe one more evaluation
+        let three_point = count(LightingRig::ThreePoint);
+        assert_eq!(three_point.ao_probes, AO_SAMPLES as u64);
+        assert_eq!(three_point.sdf_evaluations, single.sdf_evaluations + AO_SAMPLES as u64);
+
+        let mut total = single;
+        total.merge(&three_point);
+        total.frames = 1;
+        assert_eq!(total.ao_probes, AO_SAMPLES as u64);
+        assert_eq!(total.figures()[5], ("ao probes", AO_SAMPLES.to_string()));
+    }
+
+    #[test]
+    fn motion_blur_is_exact_at_one_sample_and_repeats_per_pixel() {
+        let frame = test_frame(Scene::Cubes, 1.5, 0);
+        let mut camera = Camera::new(Vec3::new(0.0, 1.25, -1.75), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Projection::from_name("perspective").unwrap());
+        let (width, height) = (24, 12);
+        camera.aspect_ratio = width as f32 / height as f32;
+        let footprint = camera.pixel_footprint(width);
+        let fields = |result: MarchResult| (result.color.to_rgb(), [result.normal.x, result.normal.y, result.normal.z].map(f32::to_bits), result.depth.to_bits());
+        let single = MotionBlur { samples: 1, ..MotionBlur::default() };
+        let blurred = MotionBlur { samples: 4, shutter: 0.5 };
+        let mut differs = false;
+        for index in 0..width * height {
+            let ndc = pixel_to_ndc((index % width) as f32 + 0.5, (index / width) as f32 + 0.5, width, height);
+            let (origin, direction) = camera.ray(ndc);
+            let key = index as u32;
+            let plain = fields(ray_march(origin, direction, &frame, &PhongShader, footprint, None));
+            assert_eq!(fields(ray_march_blurred(origin, direction, &frame, &PhongShader, &single, key, footprint, None)), plain);
+
+            let first = fields(ray_march_blurred(origin, direction, &frame, &PhongShader, &blurred, key, footprint, None));
+            for _ in 0..3 {
+                assert_eq!(fields(ray_march_blurred(origin, direction, &frame, &PhongShader, &blurred, key, footprint, None)), first);
+            }
+            differs |= first.0 != plain.0;
+        }
+        // The moving cubes do smear, so the repeats above compared real blur
+        assert!(differs);
+    }
+}
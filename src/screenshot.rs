@@ -0,0 +1,144 @@
+use crate::font::{glyph, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::terminal::{displayed_background, displayed_cell_colors, Cell, CellSink, PaletteKind};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+// Size of a terminal cell in the image, and how much the bitmap font is scaled up
+// inside it
+const CELL_WIDTH: usize = 8;
+const CELL_HEIGHT: usize = 16;
+const GLYPH_SCALE: usize = 2;
+
+// A cell as the terminal shows it
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ScreenCell {
+    ch: char,
+    foreground: (u8, u8, u8),
+    background: (u8, u8, u8),
+}
+
+// The frame as it appears in the terminal, rasterized to an RGB image: every cell's
+// glyph in the colors its color pair shows, quantized exactly like the terminal's
+// palette. Fed through capture_cells from the final, dithered frame.
+pub struct TerminalScreenshot {
+    width: usize,
+    height: usize,
+    palette: PaletteKind,
+    cells: Vec<Option<ScreenCell>>,
+    // What empty cells show
+    background: (u8, u8, u8),
+}
+
+impl CellSink for TerminalScreenshot {
+    fn clear(&mut self) {
+        self.cells.fill(None);
+    }
+
+    fn set_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        if x < self.width && y < self.height {
+            let (foreground, background) = displayed_cell_colors(self.palette, &cell);
+            self.cells[y * self.width + x] = Some(ScreenCell { ch: cell.ch, foreground, background });
+        }
+    }
+}
+
+impl TerminalScreenshot {
+    pub fn new(width: usize, height: usize, palette: PaletteKind) -> Self {
+        TerminalScreenshot { width, height, palette, cells: vec![None; width * height], background: displayed_background(palette) }
+    }
+
+    // Image size in pixels
+    pub fn size(&self) -> (usize, usize) {
+        (self.width * CELL_WIDTH, self.height * CELL_HEIGHT)
+    }
+
+    // RGB rows, top to bottom. Glyphs the font lacks are drawn as '?'.
+    pub fn rasterize(&self) -> Vec<u8> {
+        let (image_width, image_height) = self.size();
+        let mut image = vec![0; image_width * image_height * 3];
+        let glyph_x = (CELL_WIDTH - GLYPH_WIDTH * GLYPH_SCALE) / 2;
+        let glyph_y = (CELL_HEIGHT - GLYPH_HEIGHT * GLYPH_SCALE) / 2;
+
+        for (index, cell) in self.cells.iter().enumerate() {
+            let (cell_x, cell_y) = ((index % self.width) * CELL_WIDTH, (index / self.width) * CELL_HEIGHT);
+            let (ch, foreground, background) = cell.map_or((' ', self.background, self.background), |cell| (cell.ch, cell.foreground, cell.background));
+            let rows = glyph(ch).or_else(|| glyph('?')).unwrap_or_default();
+            for y in 0..CELL_HEIGHT {
+                for x in 0..CELL_WIDTH {
+                    // Position within the glyph's bitmap, if inside it
+                    let bit = (x.checked_sub(glyph_x).map(|x| x / GLYPH_SCALE), y.checked_sub(glyph_y).map(|y| y / GLYPH_SCALE));
+                    let ink = match bit {
+                        (Some(column), Some(row)) if column < GLYPH_WIDTH && row < GLYPH_HEIGHT => rows[row] & (1 << (GLYPH_WIDTH - 1 - column)) != 0,
+                        _ => false,
+                    };
+                    let (r, g, b) = if ink { foreground } else { background };
+                    let offset = ((cell_y + y) * image_width + cell_x + x) * 3;
+                    image[offset..offset + 3].copy_from_slice(&[r, g, b]);
+                }
+            }
+        }
+        image
+    }
+
+    pub fn write_png(&self, path: &Path) -> io::Result<()> {
+        let (width, height) = self.size();
+        write_rgb_png(path, width, height, &self.rasterize())
+    }
+}
+
+// 8-bit RGB image, rows top to bottom with no padding
+pub fn write_rgb_png(path: &Path, width: usize, height: usize, data: &[u8]) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(data).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: (u8, u8, u8) = (255, 0, 0);
+    const BLUE: (u8, u8, u8) = (0, 0, 255);
+
+    fn pixel(image: &[u8], image_width: usize, x: usize, y: usize) -> (u8, u8, u8) {
+        let offset = (y * image_width + x) * 3;
+        (image[offset], image[offset + 1], image[offset + 2])
+    }
+
+    #[test]
+    fn glyphs_land_in_their_cells_in_their_colors() {
+        let mut screenshot = TerminalScreenshot::new(3, 1, PaletteKind::Monochrome);
+        screenshot.background = (10, 10, 10);
+        screenshot.cells[0] = Some(ScreenCell { ch: 'T', foreground: RED, background: BLUE });
+        screenshot.cells[2] = Some(ScreenCell { ch: '\u{2603}', foreground: BLUE, background: RED });
+        assert_eq!(screenshot.size(), (3 * CELL_WIDTH, CELL_HEIGHT));
+        let image = screenshot.rasterize();
+        let (width, _) = screenshot.size();
+        let at = |x, y| pixel(&image, width, x, y);
+
+        // The glyph is centered, each bit of the font a GLYPH_SCALE square
+        let (left, top) = ((CELL_WIDTH - GLYPH_WIDTH * GLYPH_SCALE) / 2, (CELL_HEIGHT - GLYPH_HEIGHT * GLYPH_SCALE) / 2);
+        assert_eq!(at(0, 0), BLUE);
+        // T: a full top row, then only the middle column
+        for x in 0..GLYPH_WIDTH * GLYPH_SCALE {
+            assert_eq!(at(left + x, top), RED);
+            assert_eq!(at(left + x, top + GLYPH_SCALE), if x / GLYPH_SCALE == 1 { RED } else { BLUE });
+        }
+        assert_eq!(at(left + GLYPH_SCALE, top + GLYPH_HEIGHT * GLYPH_SCALE), BLUE);
+
+        // An empty cell shows the background, a glyph the font lacks a '?'
+        assert!((CELL_WIDTH..2 * CELL_WIDTH).all(|x| (0..CELL_HEIGHT).all(|y| at(x, y) == (10, 10, 10))));
+        let question = glyph('?').unwrap();
+        for (row, bits) in question.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                let ink = bits & (1 << (GLYPH_WIDTH - 1 - column)) != 0;
+                let color = at(2 * CELL_WIDTH + left + column * GLYPH_SCALE, top + row * GLYPH_SCALE);
+                assert_eq!(color, if ink { BLUE } else { RED });
+            }
+        }
+    }
+}
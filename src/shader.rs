@@ -0,0 +1,200 @@
+use crate::math::Vec3;
+
+// Surface shading used by the raymarcher. `albedo` is the material color the scene
+// picked for the hit point and `ambient` the indirect light reaching it. Returns
+// linear radiance before exposure and tone mapping.
+pub trait Shader: Sync {
+    #[allow(clippy::too_many_arguments)]
+    fn shade(
+        &self,
+        albedo: Vec3,
+        normal: Vec3,
+        view_dir: Vec3,
+        light_dir: Vec3,
+        shadow: f32,
+        distance_to_light: f32,
+        ambient: Vec3,
+    ) -> Vec3;
+}
+
+// What the scene assigns to a surface point
+#[derive(Clone, Copy, Debug)]
+pub struct Material {
+    pub albedo: Vec3,
+    // Light the surface gives off by itself, added after shading so neither the
+    // light's direction and falloff nor shadows change it
+    pub emissive: Vec3,
+}
+
+impl Material {
+    pub fn diffuse(albedo: Vec3) -> Self {
+        Material { albedo, emissive: Vec3::zero() }
+    }
+
+    pub fn emissive(color: Vec3) -> Self {
+        Material { albedo: Vec3::zero(), emissive: color }
+    }
+
+    // Linear radiance leaving the surface, the arguments as for Shader::shade
+    #[allow(clippy::too_many_arguments)]
+    pub fn shade(&self, shader: &dyn Shader, normal: Vec3, view_dir: Vec3, light_dir: Vec3, shadow: f32, distance_to_light: f32, ambient: Vec3) -> Vec3 {
+        shader.shade(self.albedo, normal, view_dir, light_dir, shadow, distance_to_light, ambient) + self.emissive
+    }
+}
+
+const LIGHT_INTENSITY: f32 = 500.0;
+
+// Ambient plus Lambert diffuse with inverse square falloff
+pub struct PhongShader;
+
+impl Shader for PhongShader {
+    fn shade(&self, albedo: Vec3, normal: Vec3, _view_dir: Vec3, light_dir: Vec3, shadow: f32, distance_to_light: f32, ambient: Vec3) -> Vec3 {
+        let light_color = Vec3::new(1.0, 1.0, 1.0);
+
+        // Diffuse lighting
+        let diffuse = normal.dot(&light_dir).max(0.0) * shadow;
+
+        albedo * (ambient + light_color * diffuse) * attenuation(distance_to_light)
+    }
+}
+
+// Cel shading: diffuse light snapped to a few flat bands. The ink outline comes from
+// the normal outline pass, see ShaderKind::outlined.
+pub struct ToonShader {
+    pub bands: u32,
+}
+
+impl Shader for ToonShader {
+    fn shade(&self, albedo: Vec3, normal: Vec3, _view_dir: Vec3, light_dir: Vec3, shadow: f32, distance_to_light: f32, ambient: Vec3) -> Vec3 {
+        let diffuse = normal.dot(&light_dir).max(0.0) * shadow;
+        let bands = self.bands.max(2) as f32;
+        let banded = ((diffuse * bands).floor() / (bands - 1.0)).min(1.0);
+
+        albedo * (ambient + Vec3::splat(banded)) * attenuation(distance_to_light)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShaderKind {
+    Phong,
+    Toon,
+}
+
+impl ShaderKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "phong" => Some(ShaderKind::Phong),
+            "toon" => Some(ShaderKind::Toon),
+            _ => None,
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            ShaderKind::Phong => ShaderKind::Toon,
+            ShaderKind::Toon => ShaderKind::Phong,
+        }
+    }
+
+    // Whether the look includes a dark outline around shapes
+    pub fn outlined(&self) -> bool {
+        *self == ShaderKind::Toon
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShaderSettings {
+    pub kind: ShaderKind,
+    // Number of diffuse bands in toon shading
+    pub toon_bands: u32,
+}
+
+impl Default for ShaderSettings {
+    fn default() -> Self {
+        ShaderSettings {
+            kind: ShaderKind::Phong,
+            toon_bands: 4,
+        }
+    }
+}
+
+impl ShaderSettings {
+    pub fn shader(&self) -> Box<dyn Shader> {
+        match self.kind {
+            ShaderKind::Phong => Box::new(PhongShader),
+            ShaderKind::Toon => Box::new(ToonShader { bands: self.toon_bands }),
+        }
+    }
+}
+
+// Light attenuation with scaling factor
+fn attenuation(distance_to_light: f32) -> f32 {
+    LIGHT_INTENSITY / (distance_to_light * distance_to_light + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Radiance with light arriving at `diffuse` = cos(angle) to the normal, no
+    // ambient, and the light at distance zero
+    fn shade_at(shader: &dyn Shader, diffuse: f32) -> f32 {
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let light_dir = Vec3::new((1.0 - diffuse * diffuse).sqrt(), diffuse, 0.0);
+        shader.shade(Vec3::splat(1.0), normal, normal, light_dir, 1.0, 0.0, Vec3::zero()).x / LIGHT_INTENSITY
+    }
+
+    // Distinct outputs over a sweep of the light from grazing to overhead
+    fn levels(shader: &dyn Shader) -> Vec<f32> {
+        let mut levels: Vec<f32> = (0..=200).map(|i| shade_at(shader, i as f32 / 200.0)).collect();
+        levels.dedup_by(|a, b| (*a - *b).abs() < 1e-4);
+        levels
+    }
+
+    #[test]
+    fn toon_shading_has_one_level_per_band() {
+        for bands in [2, 3, 4, 6] {
+            let levels = levels(&ToonShader { bands });
+            assert_eq!(levels.len(), bands as usize, "{:?}", levels);
+            assert!(levels.windows(2).all(|pair| pair[1] > pair[0]), "{:?}", levels);
+        }
+        assert!(levels(&PhongShader).len() > 100);
+    }
+
+    #[test]
+    fn toon_bands_step_at_their_boundaries() {
+        let toon = ToonShader { bands: 4 };
+        // 0.3 and 0.45 share the second band, 0.55 is in the third
+        assert_eq!(shade_at(&toon, 0.3), shade_at(&toon, 0.45));
+        assert!(shade_at(&toon, 0.55) > shade_at(&toon, 0.45));
+        assert_eq!(shade_at(&toon, 0.1), 0.0);
+        assert!((shade_at(&toon, 0.4) - 1.0 / 3.0).abs() < 1e-6);
+        assert!((shade_at(&toon, 0.6) - 2.0 / 3.0).abs() < 1e-6);
+        assert_eq!(shade_at(&toon, 0.8), 1.0);
+        assert_eq!(shade_at(&toon, 1.0), 1.0);
+    }
+
+    #[test]
+    fn emissive_material_ignores_light_and_shadow() {
+        let glow = Vec3::new(2.0, 1.5, 0.25);
+        let material = Material::emissive(glow);
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let shaders: [&dyn Shader; 2] = [&PhongShader, &ToonShader { bands: 3 }];
+        for shader in shaders {
+            for light_dir in [normal, Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.6, 0.8, 0.0)] {
+                for shadow in [0.0, 0.5, 1.0] {
+                    for distance in [0.0, 3.0, 40.0] {
+                        let radiance = material.shade(shader, normal, normal, light_dir, shadow, distance, Vec3::splat(0.3));
+                        assert_eq!((radiance.x, radiance.y, radiance.z), (glow.x, glow.y, glow.z));
+                    }
+                }
+            }
+        }
+
+        // On a lit surface the glow adds to the shading
+        let lit = Material { albedo: Vec3::splat(1.0), emissive: glow };
+        let shaded = PhongShader.shade(Vec3::splat(1.0), normal, normal, normal, 1.0, 0.0, Vec3::zero());
+        let radiance = lit.shade(&PhongShader, normal, normal, normal, 1.0, 0.0, Vec3::zero());
+        assert_eq!((radiance.x, radiance.y, radiance.z), (shaded.x + glow.x, shaded.y + glow.y, shaded.z + glow.z));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::camera::pixel_to_ndc;
+use crate::catalog::{Cost, SceneInfo};
+use crate::framebuffer::Framebuffer;
+use crate::math::{hash_f32, Vec2, Vec3};
+use crate::postprocess::ColorPipeline;
+use crate::raymarch::tone_map;
+use crate::tonecurve::ToneCurve;
+use rayon::prelude::*;
+use std::f32::consts::TAU;
+
+// Shadertoy style scenes: the picture is a function of screen position and time,
+// and the adapter takes care of resolution, aspect and writing the framebuffer.
+// A new scene is a function like `plasma` below plus a line in `from_name`.
+
+// Per-frame values a scene can read, like Shadertoy's iTime. Scenes see the
+// picture through `uv` alone, so they needn't know the framebuffer size.
+#[derive(Clone, Copy)]
+pub struct FrameContext {
+    // Scene time in seconds
+    pub time: f32,
+    // Width over height of the picture as it appears on screen
+    pub aspect: f32,
+    // The run's --seed, for scenes that vary from run to run
+    pub seed: u32,
+}
+
+// `uv` runs over [-aspect, aspect] x [-1, 1] with y up, corrected for the cell shape
+// so circles stay round. Returns linear color. Called from many threads at once.
+pub trait MainImage: Sync {
+    fn main_image(&self, uv: Vec2, frame: &FrameContext) -> Vec3;
+}
+
+impl<F> MainImage for F
+where
+    F: Fn(Vec2, &FrameContext) -> Vec3 + Sync,
+{
+    fn main_image(&self, uv: Vec2, frame: &FrameContext) -> Vec3 {
+        self(uv, frame)
+    }
+}
+
+// Shown in place of the raymarched scene
+pub struct ShaderScene {
+    image: Box<dyn MainImage>,
+}
+
+impl ShaderScene {
+    pub fn new(image: impl MainImage + 'static) -> Self {
+        ShaderScene { image: Box::new(image) }
+    }
+
+    // Every built-in scene, as --list-scenes and the picker show them
+    pub const INFO: [SceneInfo; 1] = [
+        SceneInfo { name: "plasma", description: "Sine wave plasma cycling through a rainbow", cost: Cost::Low },
+    ];
+
+    // Built-in scenes by their --scene name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "plasma" => Some(ShaderScene::new(plasma)),
+            _ => None,
+        }
+    }
+
+    // `pixel_aspect` is the height / width of a framebuffer pixel on screen
+    pub fn render(&self, fb: &mut Framebuffer, time: f32, pixel_aspect: f32, pipeline: ColorPipeline, tone_curve: &ToneCurve, seed: u32) {
+        // A flat picture: no normals for the outline pass, no depth
+        fb.clear();
+        let (width, height) = (fb.width, fb.height);
+        if width == 0 || height == 0 {
+            return;
+        }
+        let frame = FrameContext {
+            time,
+            aspect: width as f32 / (height as f32 * pixel_aspect),
+            seed,
+        };
+
+        fb.data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let ndc = pixel_to_ndc(x as f32 + 0.5, y as f32 + 0.5, width, height);
+                let uv = Vec2::new(ndc.x * frame.aspect, ndc.y);
+                *pixel = tone_map(self.image.main_image(uv, &frame), pipeline, tone_curve);
+            }
+        });
+    }
+}
+
+// Old school plasma: a few sine waves summed and cycled through a rainbow, starting
+// from a different point of the cycle and rainbow in every run
+fn plasma(uv: Vec2, frame: &FrameContext) -> Vec3 {
+    let t = frame.time + 100.0 * hash_f32(frame.seed);
+    let radius = (uv.x * uv.x + uv.y * uv.y).sqrt();
+    let wave = (uv.x * 3.0 + t).sin()
+        + (uv.y * 4.0 - t * 1.3).sin()
+        + ((uv.x + uv.y) * 2.5 + t * 0.7).sin()
+        + (radius * 5.0 - t * 2.0).sin();
+
+    // The wave spans [-4, 4]; one full turn of the rainbow over that range
+    let phase = wave * TAU / 8.0 + TAU * hash_f32(frame.seed ^ 0x9e3779b9);
+    let channel = |offset: f32| 0.5 + 0.5 * (phase + offset * TAU).sin();
+    Vec3::new(channel(0.0), channel(1.0 / 3.0), channel(2.0 / 3.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Red right of center, green above it, and blue inside a circle of radius 0.5
+    fn quadrants(uv: Vec2, _: &FrameContext) -> Vec3 {
+        let on = |inside: bool| if inside { 1.0 } else { 0.0 };
+        Vec3::new(on(uv.x > 0.0), on(uv.y > 0.0), on(uv.x * uv.x + uv.y * uv.y < 0.25))
+    }
+
+    #[test]
+    fn uv_is_centered_y_up_and_round_on_screen() {
+        let scene = ShaderScene::new(quadrants);
+        for (width, height, pixel_aspect) in [(80, 40, 1.0), (120, 40, 2.0), (60, 90, 0.5)] {
+            let mut fb = Framebuffer::new(width, height);
+            scene.render(&mut fb, 0.0, pixel_aspect, ColorPipeline::Linear, &ToneCurve::Linear, 0);
+            let at = |x: usize, y: usize| {
+                let (r, g, b) = fb.get_pixel(x, y).to_rgb();
+                (r > 128, g > 128, b > 128)
+            };
+            // Top left is up and to the left, bottom right down and to the right
+            assert_eq!(at(0, 0), (false, true, false));
+            assert_eq!(at(width - 1, height - 1), (true, false, false));
+
+            // The circle spans as far across as it does up and down, once the pixels
+            // are drawn `pixel_aspect` times as tall as wide
+            let across = (0..width).filter(|&x| at(x, height / 2).2).count() as f32;
+            let down = (0..height).filter(|&y| at(width / 2, y).2).count() as f32;
+            assert!((across - down * pixel_aspect).abs() <= 2.0 * pixel_aspect.max(1.0), "{}x{} at {}: {} vs {}", width, height, pixel_aspect, across, down);
+            // A radius of 0.5 is a quarter of the height
+            assert!((down - height as f32 / 2.0).abs() <= 1.0, "{}", down);
+        }
+    }
+}
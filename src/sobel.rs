@@ -1,76 +1,235 @@
 use rayon::prelude::*;
+use crate::dump::write_pgm;
 use crate::framebuffer::Framebuffer;
+use std::f32::consts::PI;
+use std::io;
+use std::path::Path;
 
-const CHUNK_SIZE: usize = 4;
-
-pub fn compute_gradients(fb: &Framebuffer) -> Vec<(f32, f32)> {
-    let width = fb.width;
-    let height = fb.height;
-
-    let gx: [[i32; 3]; 3] = [[-1, 0, 1], [-2, 0, 2], [-1, 0, 1]];
-    let gy: [[i32; 3]; 3] = [[-1, -2, -1], [0, 0, 0], [1, 2, 1]];
-
-    // Compute gradients in parallel
-    let gradients: Vec<(f32, f32)> = (0..height)
-        .into_par_iter()
-        .flat_map(|y| {
-            (0..width).into_par_iter().map(move |x| {
-                if x == 0 || x == width - 1 || y == 0 || y == height - 1 {
-                    (0.0, 0.0)
-                } else {
-                    let mut grad_x = 0;
-                    let mut grad_y = 0;
-                    for dy in 0..3 {
-                        for dx in 0..3 {
-                            let px = fb.get_brightness(x + dx - 1, y + dy - 1) as i32;
-                            grad_x += px * gx[dy][dx];
-                            grad_y += px * gy[dy][dx];
-                        }
-                    }
-                    let mag = ((grad_x * grad_x + grad_y * grad_y) as f32).sqrt();
-                    let angle = (grad_y as f32).atan2(grad_x as f32);
-                    (mag, angle)
-                }
-            })
-        })
-        .collect();
+// Gradient magnitude and angle per pixel after non-maximum suppression. Both passes
+// write into buffers kept from the previous frame, so a steady-size frame allocates
+// nothing.
+#[derive(Default)]
+pub struct GradientBuffer {
+    raw: Vec<(f32, f32)>,
+    suppressed: Vec<(f32, f32)>,
+}
 
-    // Apply non-maximum suppression
-    apply_non_maximum_suppression(&gradients, width, height)
+impl GradientBuffer {
+    // Angles are corrected for `pixel_aspect` (height / width) so they match the slope
+    // on screen
+    pub fn compute(&mut self, fb: &Framebuffer, pixel_aspect: f32) -> &[(f32, f32)] {
+        let width = fb.width;
+        let height = fb.height;
+        self.raw.clear();
+        self.raw.resize(width * height, (0.0, 0.0));
+        self.suppressed.clear();
+        self.suppressed.resize(width * height, (0.0, 0.0));
+
+        // Every pixel of a buffer this small is on the border
+        if width < 3 || height < 3 {
+            return &self.suppressed;
+        }
+
+        // Compute gradients in parallel, border pixels stay zero
+        self.raw.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            if y == 0 || y == height - 1 {
+                return;
+            }
+            for (x, gradient) in row.iter_mut().enumerate().take(width - 1).skip(1) {
+                *gradient = sobel_at(fb, x, y);
+            }
+        });
+
+        // Suppression walks the pixel grid, so it runs on the uncorrected angles
+        let raw = &self.raw;
+        self.suppressed.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            if y == 0 || y == height - 1 {
+                return;
+            }
+            for (x, gradient) in row.iter_mut().enumerate().take(width - 1).skip(1) {
+                *gradient = suppress_at(raw, x, y, width, height);
+            }
+        });
+        if pixel_aspect != 1.0 {
+            self.suppressed.par_iter_mut().for_each(|(_, angle)| {
+                *angle = (angle.sin() / pixel_aspect).atan2(angle.cos());
+            });
+        }
+        &self.suppressed
+    }
 }
 
-fn apply_non_maximum_suppression(gradients: &[(f32, f32)], width: usize, height: usize) -> Vec<(f32, f32)> {
-    (0..height)
-        .into_par_iter()
-        .flat_map(|y| {
-            (0..width).into_par_iter().map(move |x| {
-                if x == 0 || x == width - 1 || y == 0 || y == height - 1 {
-                    (0.0, 0.0)
-                } else {
-                    let (mag, angle) = gradients[y * width + x];
-                    let angle_deg = angle.to_degrees();
-                    let (nx, ny): (i32, i32) = if (-22.5..22.5).contains(&angle_deg) || (157.5..202.5).contains(&angle_deg) {
-                        (1, 0)
-                    } else if (22.5..67.5).contains(&angle_deg) || (-157.5..-112.5).contains(&angle_deg) {
-                        (1, -1)
-                    } else if (67.5..112.5).contains(&angle_deg) || (-112.5..-67.5).contains(&angle_deg) {
-                        (0, -1)
-                    } else {
-                        (1, 1)
-                    };
-                    let prev_y = (y as i32 - ny).max(0) as usize;
-                    let prev_x = (x as i32 - nx).max(0) as usize;
-                    let next_y = (y as i32 + ny).min(height as i32 - 1) as usize;
-                    let next_x = (x as i32 + nx).min(width as i32 - 1) as usize;
-                    let prev = gradients[prev_y * width + prev_x].0;
-                    let next = gradients[next_y * width + next_x].0;
-                    if mag >= prev && mag >= next {
-                        (mag, angle)
-                    } else {
-                        (0.0, angle)
-                    }
+const GX: [[i32; 3]; 3] = [[-1, 0, 1], [-2, 0, 2], [-1, 0, 1]];
+const GY: [[i32; 3]; 3] = [[-1, -2, -1], [0, 0, 0], [1, 2, 1]];
+
+// Sobel gradient at an interior pixel
+fn sobel_at(fb: &Framebuffer, x: usize, y: usize) -> (f32, f32) {
+    let mut grad_x = 0;
+    let mut grad_y = 0;
+    for dy in 0..3 {
+        for dx in 0..3 {
+            let px = fb.get_brightness(x + dx - 1, y + dy - 1) as i32;
+            grad_x += px * GX[dy][dx];
+            grad_y += px * GY[dy][dx];
+        }
+    }
+    let mag = ((grad_x * grad_x + grad_y * grad_y) as f32).sqrt();
+    let angle = (grad_y as f32).atan2(grad_x as f32);
+    (mag, angle)
+}
+
+// Keeps an interior pixel's magnitude only if it peaks along the gradient direction
+fn suppress_at(gradients: &[(f32, f32)], x: usize, y: usize, width: usize, height: usize) -> (f32, f32) {
+    let (mag, angle) = gradients[y * width + x];
+    let angle_deg = angle.to_degrees();
+    let (nx, ny): (i32, i32) = if (-22.5..22.5).contains(&angle_deg) || (157.5..202.5).contains(&angle_deg) {
+        (1, 0)
+    } else if (22.5..67.5).contains(&angle_deg) || (-157.5..-112.5).contains(&angle_deg) {
+        (1, -1)
+    } else if (67.5..112.5).contains(&angle_deg) || (-112.5..-67.5).contains(&angle_deg) {
+        (0, -1)
+    } else {
+        (1, 1)
+    };
+    let prev_y = (y as i32 - ny).max(0) as usize;
+    let prev_x = (x as i32 - nx).max(0) as usize;
+    let next_y = (y as i32 + ny).min(height as i32 - 1) as usize;
+    let next_x = (x as i32 + nx).min(width as i32 - 1) as usize;
+    let prev = gradients[prev_y * width + prev_x].0;
+    let next = gradients[next_y * width + next_x].0;
+    if mag >= prev && mag >= next {
+        (mag, angle)
+    } else {
+        (0.0, angle)
+    }
+}
+
+// Widest --edge-width accepted; the cost per cell grows with its square
+pub const MAX_EDGE_WIDTH: usize = 8;
+
+// Thickens a per-cell edge mask, holding each edge cell's gradient angle, by
+// `radius` cells in every direction. A cell off the edges takes the angle of the
+// nearest edge cell within the square around it, so a widened line keeps the
+// glyphs of the line it grew from. Radius 0 returns the mask as it is.
+pub fn dilate_edges(edges: &[Option<f32>], width: usize, height: usize, radius: usize) -> Vec<Option<f32>> {
+    if radius == 0 {
+        return edges.to_vec();
+    }
+    let r = radius as i32;
+    // Offsets nearest first, so the first edge found is the one to copy
+    let mut offsets: Vec<(i32, i32)> = (-r..=r).flat_map(|dy| (-r..=r).map(move |dx| (dx, dy))).collect();
+    offsets.sort_by_key(|&(dx, dy)| (dx * dx + dy * dy, dy, dx));
+    (0..width * height)
+        .map(|index| {
+            let (x, y) = ((index % width) as i32, (index / width) as i32);
+            offsets.iter().find_map(|&(dx, dy)| {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 {
+                    return None;
                 }
+                edges[ny as usize * width + nx as usize]
             })
         })
         .collect()
-}
\ No newline at end of file
+}
+
+// Debug dumps of a gradient buffer
+
+// Magnitude clamped to [0, 255]
+pub fn write_gradient_magnitude_pgm(gradients: &[(f32, f32)], width: usize, height: usize, path: &Path) -> io::Result<()> {
+    write_pgm(path, width, height, gradients.iter().map(|&(mag, _)| mag.clamp(0.0, 255.0) as u8))
+}
+
+// Angle mapped from [-pi, pi] to [0, 255]
+pub fn write_gradient_angle_pgm(gradients: &[(f32, f32)], width: usize, height: usize, path: &Path) -> io::Result<()> {
+    write_pgm(path, width, height, gradients.iter().map(|&(_, angle)| ((angle + PI) / (2.0 * PI) * 255.0) as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ascii::angle_to_ascii;
+    use crate::pixel::Pixel;
+    use crate::postprocess::ColorPipeline;
+
+    // Brightness rising by `slope_x` per column and `slope_y` per row, which the
+    // Sobel kernels measure exactly
+    fn ramp(width: usize, height: usize, slope_x: i32, slope_y: i32) -> Framebuffer {
+        let mut fb = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let level = (128 + slope_x * x as i32 + slope_y * y as i32) as u8;
+                fb.set_pixel(x, y, Pixel { r: level, g: level, b: level, a: 255 });
+            }
+        }
+        fb.compute_brightness_buffer(None, ColorPipeline::Legacy);
+        fb
+    }
+
+    #[test]
+    fn diagonal_edge_on_tall_pixels_is_diagonal() {
+        // Pixels three times as tall as wide: brightness constant along x = 3y,
+        // which runs at 45 degrees on screen
+        let (width, height, aspect) = (9, 5, 3.0);
+        let fb = ramp(width, height, 4, -12);
+        let mut gradients = GradientBuffer::default();
+
+        let (_, corrected) = gradients.compute(&fb, aspect)[2 * width + 4];
+        assert!((corrected.to_degrees() + 45.0).abs() < 1.0, "angle {}", corrected.to_degrees());
+        assert_eq!(angle_to_ascii(corrected), '\\');
+
+        // Taken as square the same edge leans toward horizontal
+        let (_, uncorrected) = gradients.compute(&fb, 1.0)[2 * width + 4];
+        assert_eq!(angle_to_ascii(uncorrected), '-');
+    }
+
+    #[test]
+    fn degenerate_sizes_have_no_edges() {
+        let mut gradients = GradientBuffer::default();
+        for (width, height) in [(0, 0), (1, 1), (2, 2), (3, 1)] {
+            let fb = ramp(width, height, 40, 40);
+            let result = gradients.compute(&fb, 2.0);
+            assert_eq!(result.len(), width * height);
+            assert!(result.iter().all(|&(mag, _)| mag == 0.0));
+        }
+        assert!(dilate_edges(&[], 0, 0, 2).is_empty());
+        assert_eq!(dilate_edges(&[Some(0.5)], 1, 1, 2), [Some(0.5)]);
+    }
+
+    #[test]
+    fn repeated_frames_reuse_the_buffers() {
+        let mut gradients = GradientBuffer::default();
+        gradients.compute(&ramp(40, 20, 2, 3), 2.0);
+        let buffers = |gradients: &GradientBuffer| (gradients.raw.as_ptr(), gradients.raw.capacity(), gradients.suppressed.as_ptr(), gradients.suppressed.capacity());
+        let first = buffers(&gradients);
+        for (width, height) in [(40, 20), (30, 10), (40, 20)] {
+            gradients.compute(&ramp(width, height, 2, 3), 2.0);
+            assert_eq!(buffers(&gradients), first);
+        }
+    }
+
+    #[test]
+    fn dilation_spreads_edges_by_the_radius() {
+        // One edge in the middle of a 5x5 mask, and one in a corner
+        let (width, height) = (5, 5);
+        let mut edges = vec![None; width * height];
+        edges[2 * width + 2] = Some(0.5);
+        assert_eq!(dilate_edges(&edges, width, height, 0), edges);
+
+        let grown = dilate_edges(&edges, width, height, 1);
+        for y in 0..height {
+            for x in 0..width {
+                let expected = (x.abs_diff(2) <= 1 && y.abs_diff(2) <= 1).then_some(0.5);
+                assert_eq!(grown[y * width + x], expected, "({}, {})", x, y);
+            }
+        }
+        assert_eq!(dilate_edges(&edges, width, height, 2), vec![Some(0.5); width * height]);
+
+        // Clipped at the border, and each cell takes the angle of the nearer edge
+        edges[0] = Some(-1.0);
+        let grown = dilate_edges(&edges, width, height, 1);
+        assert_eq!(grown.iter().filter(|edge| edge.is_some()).count(), 4 + 9 - 1);
+        assert_eq!((grown[0], grown[1], grown[width + 1], grown[2 * width + 2]), (Some(-1.0), Some(-1.0), Some(-1.0), Some(0.5)));
+        assert_eq!(grown[width + 2], Some(0.5));
+    }
+}
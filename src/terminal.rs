@@ -1,108 +1,939 @@
 use ncurses::*;
 use crate::framebuffer::Framebuffer;
+use crate::pixel::Pixel;
 use crate::terminalbuffer::TerminalBuffer;
-use crate::ascii::{angle_to_ascii, brightness_to_ascii};
+use crate::geometry::OutputGeometry;
+use crate::ascii::{angle_to_ascii, brightness_to_fill_ascii, GlyphRamp, RenderMode};
+use crate::pairbudget::PairBudget;
+use crate::theme::Theme;
 // use std::env;
-use lazy_static::lazy_static;
-use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::panic;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard};
+use crate::sobel::dilate_edges;
+use rayon::prelude::*;
 
-const COLOR_PAIRS: usize = 216; // 6 levels for each R, G, B (6^3 = 216)
+const CUBE_COLORS: usize = 216; // 6 levels for each R, G, B (6^3 = 216)
 const ANGLE_TO_ASCII_THRESHOLD: f32 = 280.0;
 
-lazy_static! {
-    static ref COLOR_PAIRS_INITIALIZED: Once = Once::new();
+// Channel levels of the built-in xterm 6x6x6 cube
+const XTERM_CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// Standard xterm RGB values of the 16 ANSI colors
+pub const ANSI_COLORS: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+// Palette selected once the terminal's color capabilities are known
+static PALETTE: OnceLock<PaletteSetup> = OnceLock::new();
+// Background the color pairs are defined against, fixed before the first pair is
+static THEME: OnceLock<Theme> = OnceLock::new();
+// ncurses isn't thread safe; held around every call into it once the input thread
+// runs alongside the render loop
+static TERMINAL: Mutex<()> = Mutex::new(());
+// Colors and background of the active preset, mapped onto the terminal palette
+static PRESET_PALETTE: RwLock<PresetPalette> = RwLock::new(PresetPalette { colors: Vec::new(), background: None });
+// Hands out the pairs when COLOR_PAIRS can't hold one per palette color. Taken
+// after TERMINAL, never before, by whoever needs both.
+static PAIR_BUDGET: Mutex<Option<PairBudget>> = Mutex::new(None);
+
+struct PresetPalette {
+    // Empty for the terminal's full palette
+    colors: Vec<(u8, u8, u8)>,
+    // None for the theme background
+    background: Option<(u8, u8, u8)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaletteKind {
+    // Redefine the first 216 colors as an evenly spaced RGB cube
+    Custom216,
+    // Use the built-in xterm 6x6x6 cube at color indices 16..=231
+    Xterm256,
+    // Nearest of the 16 standard ANSI colors. With only 8 colors, the bright half
+    // is drawn as bold, which most such terminals show brighter.
+    Ansi16,
+    Ansi8,
+    // No color support, characters only
+    Monochrome,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ColorCapabilities {
+    pub has_colors: bool,
+    pub colors: i32,
+    pub color_pairs: i32,
+    pub can_change_color: bool,
 }
 
-fn supports_true_color() -> bool {
-    false
-    // env::var("COLORTERM").map_or(false, |val| val == "truecolor" || val == "24bit")
+impl ColorCapabilities {
+    // Query ncurses; only meaningful after start_color()
+    pub fn query() -> Self {
+        ColorCapabilities {
+            has_colors: has_colors(),
+            colors: COLORS(),
+            color_pairs: COLOR_PAIRS(),
+            can_change_color: can_change_color(),
+        }
+    }
+}
+
+// Height / width of a terminal cell from the window size in pixels. Terminals that
+// don't report a pixel size (ws_xpixel/ws_ypixel left at zero) give None.
+pub fn detect_cell_aspect() -> Option<f32> {
+    let mut size = libc::winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result != 0 || size.ws_row == 0 || size.ws_col == 0 || size.ws_xpixel == 0 || size.ws_ypixel == 0 {
+        return None;
+    }
+    let cell_width = size.ws_xpixel as f32 / size.ws_col as f32;
+    let cell_height = size.ws_ypixel as f32 / size.ws_row as f32;
+    Some(cell_height / cell_width)
+}
+
+// Pick the theme before anything is drawn; later calls are ignored
+pub fn set_theme(theme: Theme) {
+    let _ = THEME.set(theme);
+}
+
+pub fn active_theme() -> Theme {
+    *THEME.get_or_init(|| Theme::Dark)
+}
+
+// Restrict output to a preset's colors, or the full palette with None, and redefine
+// the color pairs to match. Call between frames; cells already on screen change
+// color at the next refresh. A palette larger than the terminal's falls back to the
+// full palette.
+pub fn set_preset_palette(colors: Option<&[(u8, u8, u8)]>, background: Option<(u8, u8, u8)>) {
+    let setup = init_color_pairs();
+    let _terminal = lock_terminal();
+    {
+        let mut preset = PRESET_PALETTE.write().unwrap_or_else(PoisonError::into_inner);
+        preset.colors = colors
+            .filter(|colors| colors.len() <= palette_size(setup.kind))
+            .map(<[_]>::to_vec)
+            .unwrap_or_default();
+        preset.background = background;
+    }
+
+    if setup.budgeted {
+        // What the virtual pairs show has changed
+        if let Some(budget) = pair_budget().as_mut() {
+            budget.reset();
+        }
+    } else if setup.shared_banks {
+        init_pair_bank(setup.kind, FILL_BANK_ACTIVE.load(Ordering::Relaxed), 0);
+    } else {
+        init_pair_bank(setup.kind, false, 0);
+        init_pair_bank(setup.kind, true, palette_size(setup.kind));
+    }
+}
+
+// Puts the terminal back the way the shell left it when dropped, which includes
+// unwinding out of a panic, so a crash doesn't leave it without echo or a cursor
+pub struct TerminalGuard {
+    teardown: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl TerminalGuard {
+    pub fn new(teardown: impl Fn() + Send + Sync + 'static) -> Self {
+        TerminalGuard { teardown: Arc::new(teardown) }
+    }
+
+    // For the session initscr started; ends it unless it already has been
+    pub fn curses() -> Self {
+        TerminalGuard::new(|| {
+            if !isendwin() {
+                endwin();
+            }
+        })
+    }
+
+    // Tear down from the panic hook as well, before the message is printed. Left
+    // to the unwinding, the message would go to the curses screen and vanish with
+    // it, and a panic on another thread would never unwind through the guard.
+    pub fn install_panic_hook(&self) {
+        let teardown = self.teardown.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            teardown();
+            previous(info);
+        }));
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        (self.teardown)();
+    }
+}
+
+// Exclusive use of ncurses until the guard drops. Not reentrant.
+pub fn lock_terminal() -> MutexGuard<'static, ()> {
+    TERMINAL.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn preset_palette() -> RwLockReadGuard<'static, PresetPalette> {
+    PRESET_PALETTE.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn pair_budget() -> MutexGuard<'static, Option<PairBudget>> {
+    PAIR_BUDGET.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Palette in use, initializing the color pairs on first use
+pub fn active_palette() -> PaletteKind {
+    init_color_pairs().kind
+}
+
+// Whether glyph and fill cells can't be on screen together, the two banks of
+// pairs taking turns in the same pair numbers
+pub fn shared_pair_banks() -> bool {
+    let setup = init_color_pairs();
+    setup.shared_banks && !setup.budgeted
+}
+
+// Color pairs and boldness for cells drawn outside of a frame, like the test
+// pattern's swatches, from each cell's `color` and `fill`. They are styled all at
+// once the way a frame's are, so under a pair budget the most used colors get
+// pairs rather than whichever came first.
+pub fn style_cells(cells: &mut [(usize, usize, Cell)]) {
+    let setup = init_color_pairs();
+    let _terminal = lock_terminal();
+    let preset = preset_palette();
+    // Shared banks hold one bank at a time, and switching redefines the pairs of the
+    // cells styled before, so there every cell comes from the fill bank
+    let fill_only = setup.shared_banks && !setup.budgeted && cells.iter().any(|(_, _, cell)| cell.fill);
+    for (_, _, cell) in cells.iter_mut() {
+        cell.fill = (cell.fill || fill_only) && setup.kind != PaletteKind::Monochrome;
+        let (r, g, b) = cell.color;
+        let entry = closest_entry(setup.kind, &preset.colors, r, g, b);
+        cell.color_pair = entry.map_or(0, |entry| entry.slot as i16 + 1 + pair_bank_offset(&setup, cell.fill));
+        cell.bold = !cell.fill && entry.is_some_and(|entry| entry.bold);
+    }
+    if setup.budgeted {
+        allocate_pairs(setup.kind, &preset, cells);
+    }
 }
 
-fn init_color_pairs() {
-    COLOR_PAIRS_INITIALIZED.call_once(|| {
+pub fn select_palette(caps: &ColorCapabilities) -> PaletteKind {
+    // Pair 0 is reserved and the rest can be shared out, so one more is enough
+    if !caps.has_colors || caps.colors < 8 || caps.color_pairs < 2 {
+        PaletteKind::Monochrome
+    } else if caps.colors >= CUBE_COLORS as i32 && caps.can_change_color {
+        PaletteKind::Custom216
+    } else if caps.colors >= 256 {
+        PaletteKind::Xterm256
+    } else if caps.colors >= 16 {
+        PaletteKind::Ansi16
+    } else {
+        PaletteKind::Ansi8
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PaletteSetup {
+    kind: PaletteKind,
+    // When COLOR_PAIRS can't hold both the foreground-only and the fill pairs, the
+    // two banks share pair numbers and are redefined whenever fill mode toggles
+    shared_banks: bool,
+    // When it can't even hold one bank, pairs are numbered as if it could and
+    // PAIR_BUDGET maps those onto the real ones frame by frame
+    budgeted: bool,
+}
+
+// Which bank currently occupies the shared pair numbers
+static FILL_BANK_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn init_color_pairs() -> PaletteSetup {
+    *PALETTE.get_or_init(|| {
+        let _terminal = lock_terminal();
         start_color();
         use_default_colors();
-        for i in 0..COLOR_PAIRS {
-            let r = (i / 36) as i16 * 200;
-            let g = ((i / 6) % 6) as i16 * 200;
-            let b = (i % 6) as i16 * 200;
-            init_color(i as i16, r, g, b);
-            init_pair(i as i16 + 1, i as i16, -1); // -1 for default background
+
+        let caps = ColorCapabilities::query();
+        let kind = select_palette(&caps);
+        let size = palette_size(kind);
+        // Pair 0 is reserved, and pair numbers are i16
+        let pairs = (caps.color_pairs.clamp(1, i16::MAX as i32) - 1) as usize;
+        let budgeted = pairs < size;
+        let shared_banks = !budgeted && pairs < 2 * size;
+
+        if kind == PaletteKind::Custom216 {
+            for i in 0..CUBE_COLORS {
+                let r = (i / 36) as i16 * 200;
+                let g = ((i / 6) % 6) as i16 * 200;
+                let b = (i % 6) as i16 * 200;
+                init_color(i as i16, r, g, b);
+            }
+        }
+
+        if budgeted {
+            *pair_budget() = Some(PairBudget::new(pairs));
+        } else {
+            init_pair_bank(kind, false, 0);
+            if !shared_banks {
+                init_pair_bank(kind, true, size);
+            }
+        }
+        PaletteSetup { kind, shared_banks, budgeted }
+    })
+}
+
+// Define one pair per palette entry starting after `offset`. Foreground pairs draw the
+// palette color on the background; fill pairs use it as the background under a
+// contrasting foreground. With a preset palette the entries are the preset's
+// colors, each shown as the nearest terminal color.
+fn init_pair_bank(kind: PaletteKind, fill: bool, offset: usize) {
+    let preset = preset_palette();
+    let slots = if preset.colors.is_empty() { palette_size(kind) } else { preset.colors.len() };
+    for slot in 0..slots {
+        let (foreground, background) = pair_colors(kind, &preset, slot, fill);
+        init_pair((offset + slot) as i16 + 1, foreground, background);
+    }
+}
+
+// Foreground and background color numbers of the pair for bank slot `slot`
+fn pair_colors(kind: PaletteKind, preset: &PresetPalette, slot: usize, fill: bool) -> (i16, i16) {
+    let index = slot_palette_index(kind, preset, slot);
+    if fill {
+        (contrast_color_number(kind, index), color_number(kind, index))
+    } else {
+        (color_number(kind, index), background_color_number(kind, preset.background))
+    }
+}
+
+// Palette entry a bank slot shows: the slot itself, or the preset color's nearest
+fn slot_palette_index(kind: PaletteKind, preset: &PresetPalette, slot: usize) -> usize {
+    match preset.colors.get(slot) {
+        Some(&(r, g, b)) => palette_index(kind, r, g, b).unwrap_or(0),
+        None => slot,
+    }
+}
+
+// Point real pair `pair` at what virtual pair `virtual_pair` would show. Virtual
+// pairs are numbered like two unshared banks, foreground first.
+fn define_virtual_pair(kind: PaletteKind, preset: &PresetPalette, pair: i16, virtual_pair: usize) {
+    let (slot, fill) = virtual_pair_slot(kind, preset, virtual_pair);
+    let (foreground, background) = pair_colors(kind, preset, slot, fill);
+    init_pair(pair, foreground, background);
+}
+
+fn virtual_pair_slot(kind: PaletteKind, preset: &PresetPalette, virtual_pair: usize) -> (usize, bool) {
+    let bank = if preset.colors.is_empty() { palette_size(kind) } else { preset.colors.len() }.max(1);
+    ((virtual_pair - 1) % bank, virtual_pair > bank)
+}
+
+// How far apart two virtual pairs' colors are, None across banks, where a glyph
+// color can't stand in for a background
+fn virtual_pair_distance(kind: PaletteKind, preset: &PresetPalette, a: usize, b: usize) -> Option<i32> {
+    let ((slot_a, fill_a), (slot_b, fill_b)) = (virtual_pair_slot(kind, preset, a), virtual_pair_slot(kind, preset, b));
+    if fill_a != fill_b {
+        return None;
+    }
+    let rgb = |slot: usize| terminal_color_rgb(kind, color_number(kind, slot_palette_index(kind, preset, slot)));
+    let ((ar, ag, ab), (br, bg, bb)) = (rgb(slot_a), rgb(slot_b));
+    let (dr, dg, db) = (ar as i32 - br as i32, ag as i32 - bg as i32, ab as i32 - bb as i32);
+    Some(dr * dr + dg * dg + db * db)
+}
+
+// Real pairs for a frame of cells numbered with virtual pairs, most used colors
+// first, defining the ones that changed. Expects the terminal lock to be held.
+fn allocate_pairs(kind: PaletteKind, preset: &PresetPalette, cells: &mut [(usize, usize, Cell)]) {
+    let mut budget = pair_budget();
+    let Some(budget) = budget.as_mut() else {
+        return;
+    };
+    let mut counts = std::collections::HashMap::new();
+    for (_, _, cell) in cells.iter().filter(|(_, _, cell)| cell.color_pair > 0) {
+        *counts.entry(cell.color_pair as usize).or_insert(0) += 1;
+    }
+    let histogram: Vec<(usize, usize)> = counts.into_iter().collect();
+    let allocation = budget.allocate(&histogram, |a, b| virtual_pair_distance(kind, preset, a, b));
+    for &(pair, virtual_pair) in &allocation.defined {
+        define_virtual_pair(kind, preset, pair, virtual_pair);
+    }
+    for (_, _, cell) in cells.iter_mut().filter(|(_, _, cell)| cell.color_pair > 0) {
+        cell.color_pair = allocation.pairs[&(cell.color_pair as usize)];
+    }
+}
+
+// Palette color closest to the preset or theme background, quantized the same way
+// as the frame so the background and the pixels drawn over it can't disagree
+fn background_color_number(kind: PaletteKind, background: Option<(u8, u8, u8)>) -> i16 {
+    let (r, g, b) = background.unwrap_or_else(|| active_theme().background());
+    match palette_index(kind, r, g, b) {
+        Some(index) => color_number(kind, index),
+        None => -1, // Default background
+    }
+}
+
+// Pair number offset for the requested bank, switching shared banks if needed.
+// Budgeted pairs are virtual, numbered as two unshared banks.
+fn pair_bank_offset(setup: &PaletteSetup, fill: bool) -> i16 {
+    if setup.shared_banks && !setup.budgeted {
+        if FILL_BANK_ACTIVE.swap(fill, Ordering::Relaxed) != fill {
+            init_pair_bank(setup.kind, fill, 0);
         }
+        0
+    } else if fill {
+        palette_size(setup.kind) as i16
+    } else {
+        0
+    }
+}
+
+fn palette_size(palette: PaletteKind) -> usize {
+    match palette {
+        PaletteKind::Custom216 | PaletteKind::Xterm256 => CUBE_COLORS,
+        PaletteKind::Ansi16 => 16,
+        PaletteKind::Ansi8 => 8,
+        PaletteKind::Monochrome => 0,
+    }
+}
+
+// Terminal color number of palette entry `index`, without the bold an 8-color
+// terminal needs for the bright half
+fn color_number(palette: PaletteKind, index: usize) -> i16 {
+    match palette {
+        PaletteKind::Xterm256 => index as i16 + 16,
+        PaletteKind::Ansi8 => (index % 8) as i16,
+        _ => index as i16,
+    }
+}
+
+// Whether palette entry `index` is drawn bold, to reach the bright ANSI colors
+// on a terminal with only the first 8
+fn is_bright(palette: PaletteKind, index: usize) -> bool {
+    palette == PaletteKind::Ansi8 && index >= 8
+}
+
+// Where an RGB color lands: its slot in a pair bank, counted from 0, and whether
+// its glyph is drawn bold
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PaletteEntry {
+    slot: usize,
+    bold: bool,
+}
+
+// `preset` is the preset palette, empty for the terminal's full palette. None
+// without colors.
+fn closest_entry(palette: PaletteKind, preset: &[(u8, u8, u8)], r: u8, g: u8, b: u8) -> Option<PaletteEntry> {
+    if preset.is_empty() || palette == PaletteKind::Monochrome {
+        let index = palette_index(palette, r, g, b)?;
+        let slot = if palette == PaletteKind::Ansi8 { index % 8 } else { index };
+        Some(PaletteEntry { slot, bold: is_bright(palette, index) })
+    } else {
+        let slot = nearest_color_index(preset, r, g, b);
+        let (r, g, b) = preset[slot];
+        Some(PaletteEntry { slot, bold: palette_index(palette, r, g, b).is_some_and(|index| is_bright(palette, index)) })
+    }
+}
+
+// Every color a frame can come out in, for the quantizer: the preset's when the
+// terminal took it, else every color of the full palette. None without colors.
+pub fn effective_colors() -> Option<Vec<(u8, u8, u8)>> {
+    let palette = active_palette();
+    let preset = preset_palette();
+    if !preset.colors.is_empty() {
+        return Some(preset.colors.clone());
+    }
+    let count = match palette {
+        PaletteKind::Monochrome => return None,
+        PaletteKind::Ansi8 => ANSI_COLORS.len(),
+        _ => palette_size(palette),
+    };
+    Some((0..count).map(|index| entry_rgb(palette, index)).collect())
+}
+
+// RGB of palette entry `index` drawn as a glyph, bold included
+fn entry_rgb(palette: PaletteKind, index: usize) -> (u8, u8, u8) {
+    match palette {
+        PaletteKind::Ansi8 => ANSI_COLORS[index],
+        _ => terminal_color_rgb(palette, color_number(palette, index)),
+    }
+}
+
+// Terminal color number of a shade that stays readable on top of palette entry `index`
+fn contrast_color_number(palette: PaletteKind, index: usize) -> i16 {
+    match palette {
+        PaletteKind::Custom216 | PaletteKind::Xterm256 => {
+            let levels = [index / 36, (index / 6) % 6, index % 6];
+            let luminance = 0.299 * levels[0] as f32 + 0.587 * levels[1] as f32 + 0.114 * levels[2] as f32;
+            // Shift every component three levels away from the background
+            let shifted = levels.map(|l| if luminance >= 2.5 { l.saturating_sub(3) } else { (l + 3).min(5) });
+            color_number(palette, shifted[0] * 36 + shifted[1] * 6 + shifted[2])
+        }
+        _ => {
+            let (r, g, b) = ANSI_COLORS[color_number(palette, index) as usize];
+            let luminance = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+            let white = if palette == PaletteKind::Ansi16 { 15 } else { 7 };
+            if luminance >= 128.0 { 0 } else { white }
+        }
+    }
+}
+
+fn nearest_color_index(colors: &[(u8, u8, u8)], r: u8, g: u8, b: u8) -> usize {
+    colors
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, &(cr, cg, cb))| {
+            let dr = r as i32 - cr as i32;
+            let dg = g as i32 - cg as i32;
+            let db = b as i32 - cb as i32;
+            dr * dr + dg * dg + db * db
+        })
+        .map_or(0, |(i, _)| i)
+}
+
+// Index of the palette entry closest to an RGB color, None without colors
+fn palette_index(palette: PaletteKind, r: u8, g: u8, b: u8) -> Option<usize> {
+    match palette {
+        PaletteKind::Custom216 | PaletteKind::Xterm256 => {
+            let r_index = (r as usize * 5) / 255;
+            let g_index = (g as usize * 5) / 255;
+            let b_index = (b as usize * 5) / 255;
+            let index = r_index * 36 + g_index * 6 + b_index;
+            Some(index.min(CUBE_COLORS - 1))
+        }
+        PaletteKind::Ansi16 | PaletteKind::Ansi8 => Some(nearest_color_index(&ANSI_COLORS, r, g, b)),
+        PaletteKind::Monochrome => None,
+    }
+}
+
+// RGB the terminal actually shows for an input color under the given palette
+pub fn displayed_color(palette: PaletteKind, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = preset_entry(r, g, b);
+    let Some(index) = palette_index(palette, r, g, b) else {
+        // Characters only, drawn in the default foreground
+        return match active_theme() {
+            Theme::Dark => ANSI_COLORS[7],
+            Theme::Light => ANSI_COLORS[0],
+        };
+    };
+    entry_rgb(palette, index)
+}
+
+// Glyph and background colors the terminal shows for a cell
+pub fn displayed_cell_colors(palette: PaletteKind, cell: &Cell) -> ((u8, u8, u8), (u8, u8, u8)) {
+    let (r, g, b) = cell.color;
+    let color = displayed_color(palette, r, g, b);
+    if !cell.fill {
+        return (color, displayed_background(palette));
+    }
+    // Fill pairs show the color behind a contrasting shade of it, and a background
+    // can't be bold
+    let (r, g, b) = preset_entry(r, g, b);
+    let Some(index) = palette_index(palette, r, g, b) else {
+        return (color, color);
+    };
+    let contrast = terminal_color_rgb(palette, contrast_color_number(palette, index));
+    (contrast, terminal_color_rgb(palette, color_number(palette, index)))
+}
+
+// RGB of the background the frame is drawn over, quantized like the pairs' background
+pub fn displayed_background(palette: PaletteKind) -> (u8, u8, u8) {
+    let (r, g, b) = frame_background();
+    match palette_index(palette, r, g, b) {
+        Some(index) => terminal_color_rgb(palette, color_number(palette, index)),
+        // The terminal's default background, assumed to match the theme
+        None => (r, g, b),
+    }
+}
+
+// A preset palette picks the entry first, which then shows as its nearest terminal color
+fn preset_entry(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let preset = preset_palette();
+    if preset.colors.is_empty() { (r, g, b) } else { preset.colors[nearest_color_index(&preset.colors, r, g, b)] }
+}
+
+// RGB of terminal color `number` as the palette sets it up
+fn terminal_color_rgb(palette: PaletteKind, number: i16) -> (u8, u8, u8) {
+    let cube_levels = |index: usize| [index / 36, (index / 6) % 6, index % 6];
+    match palette {
+        PaletteKind::Custom216 => {
+            let [r, g, b] = cube_levels(number as usize);
+            (r as u8 * 51, g as u8 * 51, b as u8 * 51)
+        }
+        PaletteKind::Xterm256 => {
+            let [r, g, b] = cube_levels(number as usize - 16);
+            (XTERM_CUBE_LEVELS[r], XTERM_CUBE_LEVELS[g], XTERM_CUBE_LEVELS[b])
+        }
+        _ => ANSI_COLORS[number as usize],
+    }
+}
+
+// Copy of the framebuffer with every pixel replaced by its displayed terminal color
+pub fn quantize_framebuffer(fb: &Framebuffer, palette: PaletteKind) -> Framebuffer {
+    let mut quantized = fb.clone();
+    quantized.data.par_iter_mut().for_each(|pixel| {
+        let (r, g, b) = displayed_color(palette, pixel.r, pixel.g, pixel.b);
+        *pixel = Pixel { r, g, b, a: pixel.a };
     });
+    quantized
 }
 
-fn get_closest_color_pair(r: u8, g: u8, b: u8) -> i16 {
-    let r_index = (r as usize * 5) / 255;
-    let g_index = (g as usize * 5) / 255;
-    let b_index = (b as usize * 5) / 255;
-    let index = r_index * 36 + g_index * 6 + b_index;
-    (index.min(COLOR_PAIRS - 1) + 1) as i16
-}
-
-fn average_neighbor_colors(fb: &Framebuffer, x: usize, y: usize) -> (u8, u8, u8) {
-    let mut r_sum = 0;
-    let mut g_sum = 0;
-    let mut b_sum = 0;
-    let mut count = 0;
-
-    for dy in -1..=1 {
-        for dx in -1..=1 {
-            let nx = x as i32 + dx;
-            let ny = y as i32 + dy;
-            if nx >= 0 && nx < fb.width as i32 && ny >= 0 && ny < fb.height as i32 {
-                let pixel = fb.get_pixel(nx as usize, ny as usize);
-                let (r, g, b) = pixel.to_rgb();
-                r_sum += r as u32;
-                g_sum += g as u32;
-                b_sum += b as u32;
-                count += 1;
-            }
+// With `fill` set, each cell's background carries the pixel color and the glyph is
+// drawn in a contrasting shade on top of it
+#[allow(clippy::too_many_arguments)]
+pub fn draw_colored_frame(fb: &Framebuffer, gradients: &[(f32, f32)], geometry: &OutputGeometry, mode: RenderMode, edge_width: usize, fill: bool, ramp: &GlyphRamp, display_gamma: f32, buffer: &mut TerminalBuffer) {
+    let setup = init_color_pairs();
+    let _terminal = lock_terminal();
+    if setup.budgeted {
+        // Which colors get pairs is only known once the whole frame's are
+        let mut cells = Vec::new();
+        fill_styled_cells(setup, fb, gradients, geometry, mode, edge_width, fill, ramp, display_gamma, &mut cells);
+        allocate_pairs(setup.kind, &preset_palette(), &mut cells);
+        buffer.clear();
+        for (x, y, cell) in cells {
+            buffer.set_cell(x, y, cell);
         }
+    } else {
+        fill_styled_cells(setup, fb, gradients, geometry, mode, edge_width, fill, ramp, display_gamma, buffer);
     }
-    count /=2;
-    (
-        (r_sum / count) as u8,
-        (g_sum / count) as u8,
-        (b_sum / count) as u8,
-    )
+    buffer.swap_buffers();
+    buffer.render();
+}
+
+// The cells draw_colored_frame would show for `fb`, handed to `sink` instead of the
+// terminal. With a pair budget, cells keep the colors of the full palette rather
+// than the ones they snap to.
+#[allow(clippy::too_many_arguments)]
+pub fn capture_cells(fb: &Framebuffer, gradients: &[(f32, f32)], geometry: &OutputGeometry, mode: RenderMode, edge_width: usize, fill: bool, ramp: &GlyphRamp, display_gamma: f32, sink: &mut impl CellSink) {
+    let setup = init_color_pairs();
+    let _terminal = lock_terminal();
+    fill_styled_cells(setup, fb, gradients, geometry, mode, edge_width, fill, ramp, display_gamma, sink);
+}
+
+// Expects the terminal lock to be held, since switching pair banks talks to ncurses
+#[allow(clippy::too_many_arguments)]
+fn fill_styled_cells(setup: PaletteSetup, fb: &Framebuffer, gradients: &[(f32, f32)], geometry: &OutputGeometry, mode: RenderMode, edge_width: usize, fill: bool, ramp: &GlyphRamp, display_gamma: f32, sink: &mut impl CellSink) {
+    let fill = fill && setup.kind != PaletteKind::Monochrome;
+    let preset = preset_palette();
+    let style = CellStyle {
+        mode,
+        edge_width,
+        palette: setup.kind,
+        fill,
+        pair_offset: pair_bank_offset(&setup, fill),
+        theme: active_theme(),
+        preset_colors: &preset.colors,
+        ramp,
+        display_gamma,
+    };
+    fill_cells(fb, gradients, geometry, &style, sink);
+}
+
+// Color every cell is drawn over: the preset's background, or the theme's
+pub fn frame_background() -> (u8, u8, u8) {
+    preset_palette().background.unwrap_or_else(|| active_theme().background())
 }
 
-pub fn draw_colored_frame(fb: &Framebuffer, gradients: &[(f32, f32)], buffer: &mut TerminalBuffer) {
-    let is_true_color = supports_true_color();
-    if !is_true_color {
-        init_color_pairs();
+// A cell as fill_cells decided it
+#[derive(Clone, Copy, Debug)]
+pub struct Cell {
+    pub ch: char,
+    pub color_pair: i16,
+    // Color the pair was picked for: the glyph's, or the background's with `fill`
+    pub color: (u8, u8, u8),
+    pub fill: bool,
+    // Drawn bold for a bright color on an 8-color terminal
+    pub bold: bool,
+}
+
+// Where fill_cells puts a frame's cells: the terminal buffer, or an export
+pub trait CellSink {
+    // Forget the previous frame; cells not set afterwards stay empty
+    fn clear(&mut self);
+
+    fn set_cell(&mut self, x: usize, y: usize, cell: Cell);
+}
+
+// Cells with their positions, for a frame that needs another look before drawing
+impl CellSink for Vec<(usize, usize, Cell)> {
+    fn clear(&mut self) {
+        Vec::clear(self);
+    }
+
+    fn set_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        self.push((x, y, cell));
     }
+}
+
+// Everything besides the frame that decides a cell's glyph and color pair
+pub struct CellStyle<'a> {
+    pub mode: RenderMode,
+    // Cells added on each side of an edge, see dilate_edges
+    pub edge_width: usize,
+    pub palette: PaletteKind,
+    // Colored backgrounds with block glyphs; never set for monochrome
+    pub fill: bool,
+    // Added to every color pair to select the foreground or the fill bank
+    pub pair_offset: i16,
+    pub theme: Theme,
+    // Empty for the terminal's full palette
+    pub preset_colors: &'a [(u8, u8, u8)],
+    pub ramp: &'a GlyphRamp,
+    // For the fill glyphs; the ramp has it built in
+    pub display_gamma: f32,
+}
 
-    buffer.clear();
+// Choose a glyph and color pair for every cell into the buffer's pending frame.
+// Touches no terminal state, so it runs without ncurses set up.
+pub fn fill_cells(fb: &Framebuffer, gradients: &[(f32, f32)], geometry: &OutputGeometry, style: &CellStyle, sink: &mut impl CellSink) {
+    sink.clear();
 
-    for y in 0..fb.height {
-        for x in 0..fb.width {
+    // Thresholded first, so the edges can be widened before any glyph is picked
+    let edges: Vec<Option<f32>> = (0..geometry.cells_w * geometry.cells_h)
+        .map(|index| {
+            let (x, y) = geometry.cell_origin(index % geometry.cells_w, index / geometry.cells_w);
+            if style.mode == RenderMode::Brightness || x >= fb.width || y >= fb.height {
+                return None;
+            }
             let (magnitude, angle) = gradients[y * fb.width + x];
+            (magnitude > ANGLE_TO_ASCII_THRESHOLD).then_some(angle)
+        })
+        .collect();
+    let edges = dilate_edges(&edges, geometry.cells_w, geometry.cells_h, style.edge_width);
+
+    for cell_y in 0..geometry.cells_h {
+        for cell_x in 0..geometry.cells_w {
+            // Each cell is represented by the first framebuffer pixel it covers
+            let (x, y) = geometry.cell_origin(cell_x, cell_y);
+            if x >= fb.width || y >= fb.height {
+                continue;
+            }
+
             let brightness = fb.get_brightness(x, y);
-            let ch = if magnitude > ANGLE_TO_ASCII_THRESHOLD {
-                angle_to_ascii(angle)
+            let angle = edges[cell_y * geometry.cells_w + cell_x];
+            let edge = angle.is_some();
+            let shade = || if style.fill {
+                brightness_to_fill_ascii(brightness, style.display_gamma)
             } else {
-                brightness_to_ascii(brightness, false)
+                style.ramp.glyph_at(brightness, cell_x, cell_y)
+            };
+            let ch = match style.mode {
+                RenderMode::Brightness => shade(),
+                // A fill background still shows the color behind the blanks
+                RenderMode::Edges => angle.map_or(' ', angle_to_ascii),
+                RenderMode::Combined => angle.map_or_else(shade, angle_to_ascii),
             };
 
-            let (r, g, b) = if magnitude > ANGLE_TO_ASCII_THRESHOLD {
-//                average_neighbor_colors(fb, x, y)
-		(164,172,192)
+            let (r, g, b) = if edge && !style.fill {
+                style.theme.edge_color()
             } else {
-              fb.get_pixel(x, y).to_rgb()
+                fb.get_pixel(x, y).to_rgb()
             };
 
-//            if is_true_color {
-//                // Note: True color support might need to be handled differently with double buffering
-//                buffer.set_char(x, y, ch, 0);
-//            } else {
-                let color_pair = get_closest_color_pair(r, g, b);
-                buffer.set_char(x, y, ch, color_pair);
- //           }
+            let entry = closest_entry(style.palette, style.preset_colors, r, g, b);
+            let color_pair = entry.map_or(0, |entry| entry.slot as i16 + 1 + style.pair_offset);
+            let bold = !style.fill && entry.is_some_and(|entry| entry.bold);
+            sink.set_cell(cell_x, cell_y, Cell { ch, color_pair, color: (r, g, b), fill: style.fill, bold });
         }
     }
+}
 
-    buffer.swap_buffers();
-    buffer.render();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps(has_colors: bool, colors: i32, color_pairs: i32, can_change_color: bool) -> ColorCapabilities {
+        ColorCapabilities { has_colors, colors, color_pairs, can_change_color }
+    }
+
+    #[test]
+    fn palette_follows_the_capabilities() {
+        assert_eq!(select_palette(&caps(true, 256, 32767, true)), PaletteKind::Custom216);
+        assert_eq!(select_palette(&caps(true, 256, 32767, false)), PaletteKind::Xterm256);
+        assert_eq!(select_palette(&caps(true, 88, 7744, false)), PaletteKind::Ansi16);
+        assert_eq!(select_palette(&caps(true, 16, 256, true)), PaletteKind::Ansi16);
+        assert_eq!(select_palette(&caps(true, 8, 64, false)), PaletteKind::Ansi8);
+        // Too few pairs shrink the bank rather than switching the palette
+        assert_eq!(select_palette(&caps(true, 256, 16, false)), PaletteKind::Xterm256);
+        assert_eq!(select_palette(&caps(false, 256, 256, true)), PaletteKind::Monochrome);
+        assert_eq!(select_palette(&caps(true, 2, 2, false)), PaletteKind::Monochrome);
+        assert_eq!(select_palette(&caps(true, 256, 1, false)), PaletteKind::Monochrome);
+    }
+
+    #[test]
+    fn quantized_frames_only_hold_palette_colors() {
+        let mut fb = Framebuffer::new(16, 16);
+        for i in 0..256 {
+            let [r, g, b, _] = crate::math::hash_u32(i).to_le_bytes();
+            fb.set_pixel(i as usize % 16, i as usize / 16, Pixel { r, g, b, a: 200 });
+        }
+        let on_cube = |levels: &[u8], (r, g, b): (u8, u8, u8)| [r, g, b].iter().all(|c| levels.contains(c));
+        let custom_levels = [0, 51, 102, 153, 204, 255];
+        for palette in [PaletteKind::Custom216, PaletteKind::Xterm256, PaletteKind::Ansi16, PaletteKind::Ansi8] {
+            let quantized = quantize_framebuffer(&fb, palette);
+            for pixel in &quantized.data {
+                let rgb = pixel.to_rgb();
+                let allowed = match palette {
+                    PaletteKind::Custom216 => on_cube(&custom_levels, rgb),
+                    PaletteKind::Xterm256 => on_cube(&XTERM_CUBE_LEVELS, rgb),
+                    _ => ANSI_COLORS.contains(&rgb),
+                };
+                assert!(allowed, "{:?} under {:?}", rgb, palette);
+                assert_eq!(pixel.a, 200);
+            }
+            // Quantizing again changes nothing
+            let again = quantize_framebuffer(&quantized, palette);
+            assert!(again.data.iter().zip(&quantized.data).all(|(a, b)| a.to_rgb() == b.to_rgb()), "{:?}", palette);
+        }
+    }
+
+    #[test]
+    fn cells_get_their_glyphs_and_pairs() {
+        // White, black and red above a dark edge, white and blue
+        let colors = [(255, 255, 255), (0, 0, 0), (255, 0, 0), (40, 40, 40), (255, 255, 255), (0, 0, 255)];
+        let mut fb = Framebuffer::new(3, 2);
+        for (i, &(r, g, b)) in colors.iter().enumerate() {
+            fb.set_pixel(i % 3, i / 3, Pixel { r, g, b, a: 255 });
+        }
+        fb.compute_brightness_buffer(None, crate::postprocess::ColorPipeline::Legacy);
+        let mut gradients = vec![(0.0, 0.0); 6];
+        gradients[3] = (ANGLE_TO_ASCII_THRESHOLD + 1.0, 0.0);
+        let geometry = OutputGeometry::new(3, 2, crate::geometry::PixelFormat::Ascii, 2.0);
+        // Two glyphs, so anything short of mid-gray is the first
+        let ramp = GlyphRamp::new("ab", false, 1.0);
+        let mut style = CellStyle {
+            mode: RenderMode::Combined,
+            edge_width: 0,
+            palette: PaletteKind::Xterm256,
+            fill: false,
+            pair_offset: 0,
+            theme: Theme::Dark,
+            preset_colors: &[],
+            ramp: &ramp,
+            display_gamma: 1.0,
+        };
+        let cells = |style: &CellStyle| {
+            let mut cells = Vec::new();
+            fill_cells(&fb, &gradients, &geometry, style, &mut cells);
+            cells.into_iter().map(|(x, y, cell)| (x, y, cell.ch, cell.color_pair)).collect::<Vec<_>>()
+        };
+
+        // Pairs are one past the color's cube index; the edge takes the theme's color
+        let (r, g, b) = Theme::Dark.edge_color();
+        let edge_pair = (r as i16 * 5 / 255) * 36 + (g as i16 * 5 / 255) * 6 + b as i16 * 5 / 255 + 1;
+        assert_eq!(cells(&style), [(0, 0, 'b', 216), (1, 0, 'a', 1), (2, 0, 'a', 181), (0, 1, '|', edge_pair), (1, 1, 'b', 216), (2, 1, 'a', 6)]);
+
+        style.mode = RenderMode::Edges;
+        style.pair_offset = 100;
+        let edges = cells(&style);
+        assert_eq!(edges.iter().map(|&(_, _, ch, _)| ch).collect::<String>(), "   |  ");
+        assert_eq!(edges[2].3, 281);
+
+        style.palette = PaletteKind::Monochrome;
+        style.pair_offset = 0;
+        assert!(cells(&style).iter().all(|&(_, _, _, pair)| pair == 0));
+    }
+
+    #[test]
+    fn modes_keep_to_their_own_glyphs() {
+        // Every brightness, with strong gradients pointing every way on half the pixels
+        let (width, height) = (32, 16);
+        let mut fb = Framebuffer::new(width, height);
+        let mut gradients = Vec::new();
+        for i in 0..width * height {
+            let [r, g, b, turn] = crate::math::hash_u32(i as u32).to_le_bytes();
+            fb.set_pixel(i % width, i / width, Pixel { r, g, b, a: 255 });
+            let magnitude = if turn & 1 == 0 { ANGLE_TO_ASCII_THRESHOLD * 4.0 } else { 0.0 };
+            gradients.push((magnitude, (turn as f32 / 255.0 * 2.0 - 1.0) * std::f32::consts::PI));
+        }
+        fb.compute_brightness_buffer(None, crate::postprocess::ColorPipeline::Legacy);
+        let geometry = OutputGeometry::new(width, height, crate::geometry::PixelFormat::Ascii, 1.0);
+        // No glyph in common with the angle glyphs
+        let ramp_text = " .:oO@";
+        let ramp = GlyphRamp::new(ramp_text, false, 1.0);
+        let angle_glyphs = ['|', '/', '-', '\\', '+'];
+        let glyphs = |mode: RenderMode, edge_width: usize| {
+            let style = CellStyle {
+                mode,
+                edge_width,
+                palette: PaletteKind::Xterm256,
+                fill: false,
+                pair_offset: 0,
+                theme: Theme::Dark,
+                preset_colors: &[],
+                ramp: &ramp,
+                display_gamma: 1.0,
+            };
+            let mut cells = Vec::new();
+            fill_cells(&fb, &gradients, &geometry, &style, &mut cells);
+            cells.into_iter().map(|(_, _, cell)| cell.ch).collect::<Vec<_>>()
+        };
+
+        for edge_width in [0, 2] {
+            let brightness = glyphs(RenderMode::Brightness, edge_width);
+            assert!(brightness.iter().all(|ch| ramp_text.contains(*ch)), "{:?}", brightness);
+            // Blanks are the only thing edges mode draws besides edges
+            let edges = glyphs(RenderMode::Edges, edge_width);
+            assert!(edges.iter().all(|ch| *ch == ' ' || angle_glyphs.contains(ch)), "{:?}", edges);
+            assert!(edges.iter().any(|ch| *ch != ' '));
+        }
+        // Combined uses both
+        let combined = glyphs(RenderMode::Combined, 0);
+        assert!(combined.iter().any(|ch| angle_glyphs.contains(ch)) && combined.iter().any(|ch| ".:oO@".contains(*ch)));
+    }
+
+    #[test]
+    fn guard_tears_down_once() {
+        use std::sync::atomic::AtomicUsize;
+        let counting = || {
+            let count = Arc::new(AtomicUsize::new(0));
+            let guard = {
+                let count = count.clone();
+                TerminalGuard::new(move || {
+                    count.fetch_add(1, Ordering::SeqCst);
+                })
+            };
+            (count, guard)
+        };
+
+        // Moving the guard doesn't run it, dropping it does
+        let (count, guard) = counting();
+        let moved = guard;
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        drop(moved);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // As does unwinding through it
+        let (count, guard) = counting();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+            let _guard = guard;
+            panic!("render loop failed");
+        }));
+        assert!(result.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    // The matrix preset's greens, not the frame's colors, are what the pairs get
+    // set up with
+    #[test]
+    fn a_preset_palette_reaches_the_color_pairs() {
+        let matrix = crate::preset::Preset::from_name("matrix").unwrap();
+        let preset = PresetPalette { colors: matrix.palette.unwrap().colors.to_vec(), background: matrix.background };
+        let green = |(r, g, b): (u8, u8, u8)| g >= r && g >= b && (r, g, b) != (255, 255, 255);
+        for kind in [PaletteKind::Custom216, PaletteKind::Xterm256] {
+            for i in 0..200 {
+                let [r, g, b, _] = crate::math::hash_u32(i).to_le_bytes();
+                let entry = closest_entry(kind, &preset.colors, r, g, b).unwrap();
+                assert!(entry.slot < preset.colors.len());
+                let (foreground, background) = pair_colors(kind, &preset, entry.slot, false);
+                assert!(green(terminal_color_rgb(kind, foreground)), "{:?} {:?}", kind, (r, g, b));
+                assert_eq!(terminal_color_rgb(kind, background), (0, 0, 0));
+                // A filled cell shows the green behind its glyph
+                let (_, fill) = pair_colors(kind, &preset, entry.slot, true);
+                assert_eq!(fill, foreground);
+            }
+            // Without the preset, red stays red
+            let entry = closest_entry(kind, &[], 255, 0, 0).unwrap();
+            let (foreground, _) = pair_colors(kind, &PresetPalette { colors: Vec::new(), background: None }, entry.slot, false);
+            assert_eq!(terminal_color_rgb(kind, foreground), (255, 0, 0));
+        }
+    }
 }
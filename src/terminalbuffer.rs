@@ -1,10 +1,24 @@
+use crate::terminal::{Cell, CellSink};
 use ncurses::*;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Glyph, color pair and boldness of a cell
+type CellContent = (char, i16, bool);
+
+const EMPTY_CELL: CellContent = (' ', 0, false);
 
 pub struct TerminalBuffer {
     width: usize,
     height: usize,
-    front_buffer: Vec<chtype>,
-    back_buffer: Vec<chtype>,
+    front_buffer: Vec<CellContent>,
+    back_buffer: Vec<CellContent>,
+    // Set by a resize: the next render repaints the whole screen, since the terminal
+    // may have reflowed what was on it
+    repaint: bool,
+    // Text laid over every frame at the swap, in the default colors
+    overlay: Vec<(usize, usize, String)>,
 }
 
 impl TerminalBuffer {
@@ -12,34 +26,75 @@ impl TerminalBuffer {
         TerminalBuffer {
             width,
             height,
-            front_buffer: vec![0; width * height],
-            back_buffer: vec![0; width * height],
+            front_buffer: vec![EMPTY_CELL; width * height],
+            back_buffer: vec![EMPTY_CELL; width * height],
+            repaint: false,
+            overlay: Vec::new(),
         }
     }
 
     pub fn clear(&mut self) {
-        self.back_buffer.fill(0);
+        self.back_buffer.fill(EMPTY_CELL);
     }
 
     pub fn set_char(&mut self, x: usize, y: usize, ch: char, color_pair: i16) {
+        self.set_styled_char(x, y, ch, color_pair, false);
+    }
+
+    // Bold reaches the bright half of the palette on 8-color terminals
+    pub fn set_styled_char(&mut self, x: usize, y: usize, ch: char, color_pair: i16, bold: bool) {
         if x < self.width && y < self.height {
             let index = y * self.width + x;
-            self.back_buffer[index] = ch as chtype | COLOR_PAIR(color_pair);
+            self.back_buffer[index] = (ch, color_pair, bold);
         }
     }
 
+    // Glyph and color pair of a cell of the frame being built, before the swap
+    #[cfg(test)]
+    pub fn pending_cell(&self, x: usize, y: usize) -> Option<(char, i16)> {
+        (x < self.width && y < self.height).then(|| {
+            let (ch, color_pair, _) = self.back_buffer[y * self.width + x];
+            (ch, color_pair)
+        })
+    }
+
+    // Text to show over the frames from the next swap on, each line starting at its
+    // cell; anything past the edge of the buffer is dropped
+    pub fn set_overlay(&mut self, overlay: Vec<(usize, usize, String)>) {
+        self.overlay = overlay;
+    }
+
     pub fn swap_buffers(&mut self) {
+        let overlay = std::mem::take(&mut self.overlay);
+        for (x, y, text) in &overlay {
+            for (i, ch) in text.chars().enumerate() {
+                self.set_char(x + i, *y, ch, 0);
+            }
+        }
+        self.overlay = overlay;
         std::mem::swap(&mut self.front_buffer, &mut self.back_buffer);
     }
 
-    pub fn render(&self) {
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let index = y * self.width + x;
-                mv(y as i32, x as i32);
-                addch(self.front_buffer[index]);
+    pub fn render(&mut self) {
+        let mut utf8 = [0; 4];
+        for (y, row) in self.front_buffer.chunks(self.width.max(1)).take(self.height).enumerate() {
+            for (x, ch, color_pair, bold) in layout_row(row) {
+                let attributes = COLOR_PAIR(color_pair) | if bold { A_BOLD() } else { 0 };
+                if ch.is_ascii() {
+                    mv(y as i32, x as i32);
+                    addch(ch as chtype | attributes);
+                } else {
+                    attron(attributes);
+                    mvaddstr(y as i32, x as i32, ch.encode_utf8(&mut utf8));
+                    attroff(attributes);
+                }
             }
         }
+        // Clearing here rather than at the resize puts the clear and the new frame in
+        // the same refresh, so the screen never shows up blank in between
+        if std::mem::take(&mut self.repaint) {
+            clearok(stdscr(), true);
+        }
         refresh();
     }
 
@@ -51,13 +106,141 @@ impl TerminalBuffer {
         self.width = new_width;
         self.height = new_height;
         let new_size = new_width * new_height;
-        
-        self.front_buffer.resize(new_size, 0);
-        self.back_buffer.resize(new_size, 0);
+
+        // Vec::resize keeps the allocation when shrinking
+        self.front_buffer.resize(new_size, EMPTY_CELL);
+        self.back_buffer.resize(new_size, EMPTY_CELL);
         self.clear();
+        self.repaint = true;
+    }
+
+    // Debug dumps of the displayed frame, one line per row
+
+    // Characters as UTF-8 text
+    pub fn write_characters(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        for row in self.front_buffer.chunks(self.width.max(1)).take(self.height) {
+            text.extend(layout_row(row).map(|(_, ch, _, _)| ch));
+            text.push('\n');
+        }
+        fs::write(path, text)
     }
 
+    // Color pair number of every cell, separated by spaces
+    pub fn write_color_pairs(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        for row in self.front_buffer.chunks(self.width.max(1)).take(self.height) {
+            let pairs: Vec<String> = row.iter().map(|&(_, color_pair, _)| color_pair.to_string()).collect();
+            text.push_str(&pairs.join(" "));
+            text.push('\n');
+        }
+        fs::write(path, text)
+    }
+
+    #[allow(dead_code)]
     pub fn get_size(&self) -> (usize, usize) {
         (self.width, self.height)
     }
-}
\ No newline at end of file
+}
+
+impl CellSink for TerminalBuffer {
+    fn clear(&mut self) {
+        TerminalBuffer::clear(self);
+    }
+
+    fn set_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        self.set_styled_char(x, y, cell.ch, cell.color_pair, cell.bold);
+    }
+}
+
+// What to draw for a row of cells: the column, glyph, color pair and boldness of each glyph in
+// order. A wide glyph takes its own cell and the one after it, whose content is
+// dropped. One that would hang off the end of the row, and anything without a
+// width of its own, is drawn as a space so later columns stay in place.
+fn layout_row(row: &[CellContent]) -> impl Iterator<Item = (usize, char, i16, bool)> + '_ {
+    let mut column = 0;
+    std::iter::from_fn(move || {
+        let &(ch, color_pair, bold) = row.get(column)?;
+        let x = column;
+        let (ch, width) = match char_width(ch) {
+            2 if x + 1 < row.len() => (ch, 2),
+            1 => (ch, 1),
+            _ => (' ', 1),
+        };
+        column += width;
+        Some((x, ch, color_pair, bold))
+    })
+}
+
+// Terminal columns a glyph occupies: 0 for control and combining characters, 2 for
+// East Asian wide and fullwidth characters and emoji, 1 for the rest
+pub fn char_width(ch: char) -> usize {
+    const ZERO_WIDTH: &[(u32, u32)] = &[
+        (0x0300, 0x036f), (0x200b, 0x200f), (0x20d0, 0x20ff), (0xfe00, 0xfe0f), (0xfe20, 0xfe2f),
+    ];
+    const WIDE: &[(u32, u32)] = &[
+        (0x1100, 0x115f), (0x2e80, 0x303e), (0x3041, 0x33ff), (0x3400, 0x4dbf), (0x4e00, 0x9fff),
+        (0xa000, 0xa4cf), (0xac00, 0xd7a3), (0xf900, 0xfaff), (0xfe30, 0xfe4f), (0xff00, 0xff60),
+        (0xffe0, 0xffe6), (0x1f300, 0x1f64f), (0x1f900, 0x1f9ff), (0x20000, 0x2fffd), (0x30000, 0x3fffd),
+    ];
+    let code = ch as u32;
+    let within = |ranges: &[(u32, u32)]| ranges.iter().any(|&(start, end)| (start..=end).contains(&code));
+    if ch.is_control() || within(ZERO_WIDTH) {
+        0
+    } else if within(WIDE) {
+        2
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glyph_widths() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('█'), 1);
+        assert_eq!(char_width('⣿'), 1);
+        assert_eq!(char_width('漢'), 2);
+        assert_eq!(char_width('한'), 2);
+        assert_eq!(char_width('😀'), 2);
+        assert_eq!(char_width('\u{301}'), 0);
+        assert_eq!(char_width('\t'), 0);
+    }
+
+    #[test]
+    fn wide_glyphs_cover_the_next_column() {
+        let row: Vec<CellContent> = "a漢xb😀?\u{301}c漢".chars().enumerate().map(|(i, ch)| (ch, i as i16, false)).collect();
+        let laid_out: Vec<(usize, char, i16)> = layout_row(&row).map(|(x, ch, color_pair, _)| (x, ch, color_pair)).collect();
+        // The cells behind a wide glyph are skipped, a combining mark and a wide glyph
+        // in the last column become spaces
+        assert_eq!(laid_out, [(0, 'a', 0), (1, '漢', 1), (3, 'b', 3), (4, '😀', 4), (6, ' ', 6), (7, 'c', 7), (8, ' ', 8)]);
+        // Every column of the row is drawn exactly once
+        let columns: usize = laid_out.iter().map(|&(_, ch, _)| char_width(ch)).sum();
+        assert_eq!(columns, row.len());
+    }
+
+    #[test]
+    fn resized_buffer_takes_cells_everywhere_inside_it() {
+        let mut buffer = TerminalBuffer::new(12, 5);
+        for (width, height) in [(4, 2), (12, 5), (20, 1), (0, 0), (3, 9)] {
+            buffer.resize(width, height);
+            assert_eq!(buffer.get_size(), (width, height));
+            for y in 0..height {
+                for x in 0..width {
+                    assert_eq!(buffer.pending_cell(x, y), Some((' ', 0)));
+                    buffer.set_char(x, y, '#', 2);
+                }
+            }
+            // Outside the new size, writes are dropped
+            buffer.set_char(width, 0, '#', 2);
+            buffer.set_char(0, height, '#', 2);
+            assert_eq!(buffer.pending_cell(width, 0), None);
+            buffer.swap_buffers();
+            assert!(buffer.front_buffer.iter().all(|&(ch, _, _)| ch == '#'));
+            assert_eq!(buffer.front_buffer.len(), width * height);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use ncurses::*;
+use crate::ascii::{angle_to_ascii, brightness_to_fill_ascii, GlyphRamp, DEFAULT_RAMP};
+use crate::geometry::DEFAULT_CELL_ASPECT;
+use crate::postprocess::DEFAULT_DISPLAY_GAMMA;
+use crate::terminal::{active_palette, active_theme, detect_cell_aspect, shared_pair_banks, style_cells, Cell, CellSink, ColorCapabilities, ANSI_COLORS};
+use crate::theme::Theme;
+use crate::terminalbuffer::TerminalBuffer;
+
+// Static diagnostic screen for checking palettes and glyph ramps on a terminal.
+// Everything goes through the same palette and TerminalBuffer code as the demo.
+
+const LABEL_WIDTH: usize = 10;
+const GRAY_STEPS: usize = 32;
+const RAMP_WIDTH: usize = 64;
+const EDGE_SAMPLES: usize = 16;
+// Width of each theme's panel in the side by side comparison
+const THEME_PANEL_WIDTH: usize = 32;
+// Circle for checking the cell aspect correction, center and radius in cells
+const CIRCLE_X: f32 = 68.0;
+const CIRCLE_Y: f32 = 19.0;
+const CIRCLE_RADIUS: f32 = 8.0;
+
+// Show the pattern until a key is pressed, redrawing on resize
+pub fn run(cell_aspect_override: Option<f32>, theme_source: &str) {
+    nodelay(stdscr(), false);
+    keypad(stdscr(), true);
+
+    let mut buffer = TerminalBuffer::new(0, 0);
+    loop {
+        let mut width = 0;
+        let mut height = 0;
+        getmaxyx(stdscr(), &mut height, &mut width);
+        buffer.resize(width.max(0) as usize, height.max(0) as usize);
+        clear();
+
+        let (cell_aspect, source) = match cell_aspect_override {
+            Some(aspect) => (aspect, "override"),
+            None => match detect_cell_aspect() {
+                Some(aspect) => (aspect, "detected"),
+                None => (DEFAULT_CELL_ASPECT, "default"),
+            },
+        };
+        draw_pattern(&mut buffer, cell_aspect, source, theme_source);
+        buffer.swap_buffers();
+        buffer.render();
+
+        if getch() != KEY_RESIZE {
+            break;
+        }
+    }
+}
+
+fn draw_pattern(buffer: &mut TerminalBuffer, cell_aspect: f32, aspect_source: &str, theme_source: &str) {
+    buffer.clear();
+    put_str(buffer, 0, 0, "terminal_gfx test pattern - press any key to exit");
+
+    // Colored cells are collected and given their pairs together, like a frame's
+    let mut swatches = Vec::new();
+
+    // 6x6x6 cube: one 6x6 block per red level, green down and blue across, two cells per swatch
+    put_str(buffer, 0, 1, "cube");
+    for r in 0..6 {
+        for g in 0..6 {
+            for b in 0..6 {
+                let color = (r as u8 * 51, g as u8 * 51, b as u8 * 51);
+                let x = r * 13 + b * 2;
+                swatches.push(swatch(x, 2 + g, ' ', color, true));
+                swatches.push(swatch(x + 1, 2 + g, ' ', color, true));
+            }
+        }
+    }
+
+    put_str(buffer, 0, 9, "gray");
+    for i in 0..GRAY_STEPS {
+        let level = (i * 255 / (GRAY_STEPS - 1)) as u8;
+        swatches.push(swatch(LABEL_WIDTH + i * 2, 9, ' ', (level, level, level), true));
+        swatches.push(swatch(LABEL_WIDTH + i * 2 + 1, 9, ' ', (level, level, level), true));
+    }
+
+    // The ANSI colors as glyphs; an 8-color terminal should show the second half
+    // brighter through bold. With too few pairs for both banks the glyphs can't
+    // share the screen with the backgrounds above, so the row is left out.
+    put_str(buffer, 0, 10, "ansi");
+    if shared_pair_banks() {
+        put_str(buffer, LABEL_WIDTH, 10, "(too few color pairs to show glyphs with backgrounds)");
+    } else {
+        for (i, &color) in ANSI_COLORS.iter().enumerate() {
+            swatches.push(swatch(LABEL_WIDTH + i * 2, 10, '#', color, false));
+            swatches.push(swatch(LABEL_WIDTH + i * 2 + 1, 10, '#', color, false));
+        }
+    }
+
+    // Glyph ramps over the full brightness range
+    let ramp = GlyphRamp::new(DEFAULT_RAMP, false, DEFAULT_DISPLAY_GAMMA);
+    let inverted = GlyphRamp::new(DEFAULT_RAMP, true, DEFAULT_DISPLAY_GAMMA);
+    let ramps: [(&str, &dyn Fn(u8) -> char); 3] = [
+        ("ramp", &|b| ramp.glyph(b)),
+        ("inverted", &|b| inverted.glyph(b)),
+        ("fill", &|b| brightness_to_fill_ascii(b, DEFAULT_DISPLAY_GAMMA)),
+    ];
+    for (row, (label, ramp)) in ramps.iter().enumerate() {
+        put_str(buffer, 0, 11 + row, label);
+        for i in 0..RAMP_WIDTH {
+            let brightness = (i * 255 / (RAMP_WIDTH - 1)) as u8;
+            buffer.set_char(LABEL_WIDTH + i, 11 + row, ramp(brightness), 0);
+        }
+    }
+
+    // The glyph ramp as each theme draws it, on that theme's background. Both panels
+    // should read as a gradient from dark on the left to bright on the right.
+    put_str(buffer, 0, 14, "themes");
+    for (panel, theme) in [Theme::Dark, Theme::Light].into_iter().enumerate() {
+        let ramp = GlyphRamp::new(DEFAULT_RAMP, theme.inverts_ramp(), DEFAULT_DISPLAY_GAMMA);
+        for i in 0..THEME_PANEL_WIDTH {
+            let brightness = (i * 255 / (THEME_PANEL_WIDTH - 1)) as u8;
+            let x = LABEL_WIDTH + panel * THEME_PANEL_WIDTH + i;
+            swatches.push(swatch(x, 14, ramp.glyph(brightness), theme.background(), true));
+        }
+    }
+
+    style_cells(&mut swatches);
+    for (x, y, cell) in swatches {
+        buffer.set_cell(x, y, cell);
+    }
+
+    put_str(buffer, 0, 15, "edges");
+    for i in 0..EDGE_SAMPLES {
+        let angle = (i as f32 / EDGE_SAMPLES as f32 * 2.0 - 1.0) * std::f32::consts::PI;
+        buffer.set_char(LABEL_WIDTH + i * 2, 15, angle_to_ascii(angle), 0);
+    }
+
+    let caps = ColorCapabilities::query();
+    let colorterm = std::env::var("COLORTERM").unwrap_or_else(|_| "unset".to_string());
+    let lines = [
+        format!("COLORS {}  COLOR_PAIRS {}", caps.colors, caps.color_pairs),
+        format!("has_colors {}  can_change_color {}", caps.has_colors, caps.can_change_color),
+        format!("palette {:?}  COLORTERM {}", active_palette(), colorterm),
+        format!("cell aspect {:.2} ({})  theme {:?} ({})", cell_aspect, aspect_source, active_theme(), theme_source),
+        "the circle should look round".to_string(),
+    ];
+    for (row, line) in lines.iter().enumerate() {
+        put_str(buffer, 0, 17 + row, line);
+    }
+
+    draw_circle(buffer, cell_aspect);
+}
+
+// Outline of a circle that is only round on screen if the cell aspect is right
+fn draw_circle(buffer: &mut TerminalBuffer, cell_aspect: f32) {
+    let radius_y = CIRCLE_RADIUS / cell_aspect;
+    let top = (CIRCLE_Y - radius_y).floor().max(0.0) as usize;
+    let bottom = (CIRCLE_Y + radius_y).ceil() as usize;
+    let left = (CIRCLE_X - CIRCLE_RADIUS).floor().max(0.0) as usize;
+    let right = (CIRCLE_X + CIRCLE_RADIUS).ceil() as usize;
+
+    for y in top..=bottom {
+        for x in left..=right {
+            // Distance in cell widths, with rows stretched by the cell aspect
+            let dx = x as f32 - CIRCLE_X;
+            let dy = (y as f32 - CIRCLE_Y) * cell_aspect;
+            let distance = (dx * dx + dy * dy).sqrt();
+            if (distance - CIRCLE_RADIUS).abs() < 0.5 * cell_aspect.max(1.0) {
+                buffer.set_char(x, y, '*', 0);
+            }
+        }
+    }
+}
+
+// A colored cell for style_cells to give a pair, `fill` for the color as background
+fn swatch(x: usize, y: usize, ch: char, color: (u8, u8, u8), fill: bool) -> (usize, usize, Cell) {
+    (x, y, Cell { ch, color_pair: 0, color, fill, bold: false })
+}
+
+// Text in the default colors; anything past the edge of the buffer is dropped
+fn put_str(buffer: &mut TerminalBuffer, x: usize, y: usize, text: &str) {
+    for (i, ch) in text.chars().enumerate() {
+        buffer.set_char(x + i, y, ch, 0);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::pixel::Pixel;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+// Light or dark terminal background. Everything drawn assumes one of the two, so the
+// glyph ramp, color pairs and overlays agree on which way is "bright".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Theme::Dark),
+            "light" => Some(Theme::Light),
+            _ => None,
+        }
+    }
+
+    // Light for backgrounds brighter than mid gray
+    pub fn for_background((r, g, b): (u8, u8, u8)) -> Self {
+        let luminance = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+        if luminance >= 128.0 { Theme::Light } else { Theme::Dark }
+    }
+
+    // Color every cell is drawn over, set explicitly in the color pairs rather than
+    // left to the terminal's default background
+    pub fn background(self) -> (u8, u8, u8) {
+        match self {
+            Theme::Dark => (0, 0, 0),
+            Theme::Light => (255, 255, 255),
+        }
+    }
+
+    // Dense glyphs read as bright on a dark background and as dark on a light one
+    pub fn inverts_ramp(self) -> bool {
+        self == Theme::Light
+    }
+
+    // Color of Sobel edge glyphs, which don't carry the pixel color
+    pub fn edge_color(self) -> (u8, u8, u8) {
+        match self {
+            Theme::Dark => (164, 172, 192),
+            Theme::Light => (64, 72, 92),
+        }
+    }
+
+    // Text color and translucent backdrop for titles, the HUD and notices
+    pub fn overlay_colors(self) -> (Pixel, Pixel) {
+        match self {
+            Theme::Dark => (Pixel { r: 255, g: 255, b: 255, a: 255 }, Pixel { r: 0, g: 0, b: 0, a: 160 }),
+            Theme::Light => (Pixel { r: 0, g: 0, b: 0, a: 255 }, Pixel { r: 255, g: 255, b: 255, a: 160 }),
+        }
+    }
+}
+
+// Ask the terminal for its background color with OSC 11. Must run before ncurses
+// takes over the terminal. A device attributes request (DA1) goes out right behind
+// the query; every terminal answers that one, so its reply arriving means the OSC 11
+// query was ignored and there's no need to sit out the timeout. Terminals that
+// answer neither give None after `timeout`.
+pub fn query_background(timeout: Duration) -> Option<(u8, u8, u8)> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").ok()?;
+    let fd = tty.as_raw_fd();
+
+    // Raw, non-blocking reads so the reply is neither echoed nor held for a newline
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+        return None;
+    }
+    let mut raw = saved;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+    raw.c_cc[libc::VMIN] = 0;
+    raw.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+        return None;
+    }
+
+    let mut reply = Vec::new();
+    if tty.write_all(b"\x1b]11;?\x1b\\\x1b[c").and_then(|_| tty.flush()).is_ok() {
+        let deadline = Instant::now() + timeout;
+        while !has_device_attributes(&reply) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let mut poll_fd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll_fd, 1, remaining.as_millis().max(1) as i32) } <= 0 {
+                break;
+            }
+            let mut chunk = [0u8; 64];
+            match tty.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => reply.extend_from_slice(&chunk[..count]),
+            }
+        }
+    }
+
+    // Flushing drops anything that arrived after we stopped reading, so a slow
+    // reply can't show up later as key presses
+    unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &saved) };
+    parse_background_reply(&reply)
+}
+
+// Whether the reply so far holds a complete DA1 answer, ESC [ ? ... c
+fn has_device_attributes(reply: &[u8]) -> bool {
+    reply
+        .windows(3)
+        .position(|w| w == b"\x1b[?")
+        .is_some_and(|start| reply[start..].contains(&b'c'))
+}
+
+// Color from an OSC 11 reply, ESC ] 11 ; rgb:RRRR/GGGG/BBBB terminated by BEL or
+// ESC \. Terminals send one to four hex digits per channel.
+fn parse_background_reply(reply: &[u8]) -> Option<(u8, u8, u8)> {
+    let text = String::from_utf8_lossy(reply);
+    let start = text.find("]11;rgb:")? + "]11;rgb:".len();
+    let end = text[start..].find(['\x07', '\x1b'])? + start;
+    let channels: Vec<u8> = text[start..end].split('/').map(scale_hex_channel).collect::<Option<_>>()?;
+    match *channels.as_slice() {
+        [r, g, b] => Some((r, g, b)),
+        _ => None,
+    }
+}
+
+fn scale_hex_channel(digits: &str) -> Option<u8> {
+    if digits.is_empty() || digits.len() > 4 {
+        return None;
+    }
+    let value = u32::from_str_radix(digits, 16).ok()?;
+    let max = (1u32 << (4 * digits.len())) - 1;
+    Some(((value * 255 + max / 2) / max) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_channels_scale_from_any_width() {
+        assert_eq!(scale_hex_channel("f"), Some(255));
+        assert_eq!(scale_hex_channel("8"), Some(136));
+        assert_eq!(scale_hex_channel("0"), Some(0));
+        assert_eq!(scale_hex_channel("80"), Some(128));
+        assert_eq!(scale_hex_channel("fF"), Some(255));
+        assert_eq!(scale_hex_channel("800"), Some(128));
+        assert_eq!(scale_hex_channel("ffff"), Some(255));
+        assert_eq!(scale_hex_channel("8080"), Some(128));
+        assert_eq!(scale_hex_channel("1a1a"), Some(0x1a));
+        assert_eq!(scale_hex_channel(""), None);
+        assert_eq!(scale_hex_channel("fffff"), None);
+        assert_eq!(scale_hex_channel("0g"), None);
+    }
+
+    #[test]
+    fn background_replies_parse_with_either_terminator() {
+        assert_eq!(parse_background_reply(b"\x1b]11;rgb:1a1a/2b2b/3c3c\x07"), Some((0x1a, 0x2b, 0x3c)));
+        assert_eq!(parse_background_reply(b"\x1b]11;rgb:ff/80/00\x1b\\"), Some((255, 128, 0)));
+        assert_eq!(parse_background_reply(b"\x1b]11;rgb:f/8/0\x07"), Some((255, 136, 0)));
+        // The device attributes reply that follows it doesn't get in the way
+        assert_eq!(parse_background_reply(b"\x1b]11;rgb:0000/0000/0000\x1b\\\x1b[?62;22c"), Some((0, 0, 0)));
+    }
+
+    #[test]
+    fn malformed_background_replies_give_nothing() {
+        for reply in [
+            &b""[..],
+            b"\x1b[?62;22c",
+            b"\x1b]11;rgb:ffff/ffff/ffff",
+            b"\x1b]10;rgb:ffff/ffff/ffff\x07",
+            b"\x1b]11;rgb:ffff/ffff\x07",
+            b"\x1b]11;rgb:ffff/ffff/ffff/ffff\x07",
+            b"\x1b]11;rgb:ffff//ffff\x07",
+            b"\x1b]11;rgb:fffff/ffff/ffff\x07",
+            b"\x1b]11;rgb:zz/00/00\x07",
+            b"\x1b]11;rgba:ff/ff/ff/ff\x07",
+        ] {
+            assert_eq!(parse_background_reply(reply), None, "{:?}", String::from_utf8_lossy(reply));
+        }
+    }
+}
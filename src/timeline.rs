@@ -0,0 +1,175 @@
+use crate::raymarch::Scene;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Cues at fixed points of the scene clock. Text file, one event per line:
+// `<seconds> <action> [argument]`, with `#` starting a comment. Actions are
+// flash, bell, blink, glitch and `scene <name>`.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventAction {
+    // One frame drawn brighter
+    Flash,
+    // The terminal bell
+    Bell,
+    // The terminal's visual bell
+    Blink,
+    Glitch,
+    SwitchScene(Scene),
+}
+
+impl EventAction {
+    fn parse(action: &str, argument: Option<&str>, seed: u32) -> Option<Self> {
+        match (action, argument) {
+            ("flash", None) => Some(EventAction::Flash),
+            ("bell", None) => Some(EventAction::Bell),
+            ("blink", None) => Some(EventAction::Blink),
+            ("glitch", None) => Some(EventAction::Glitch),
+            ("scene", Some(name)) => Scene::from_name(name, seed).map(EventAction::SwitchScene),
+            _ => None,
+        }
+    }
+}
+
+// An action repeated on every beat, starting at time zero
+#[derive(Clone, Copy, Debug)]
+struct Metronome {
+    period: f32,
+    action: EventAction,
+}
+
+// Each event fires exactly once as the clock passes it, however far the clock
+// moves between two frames. Moving the clock backwards re-arms the events after
+// the new time without firing anything.
+#[derive(Default)]
+pub struct Timeline {
+    // Sorted by time
+    events: Vec<(f32, EventAction)>,
+    metronome: Option<Metronome>,
+    // Index of the first event that hasn't fired
+    next: usize,
+    // Clock at the last advance, None before the first
+    last_time: Option<f32>,
+}
+
+impl Timeline {
+    pub fn new(mut events: Vec<(f32, EventAction)>) -> Self {
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Timeline { events, ..Timeline::default() }
+    }
+
+    // `seed` is given to the terrain scene when an event switches to it
+    pub fn load(path: &Path, seed: u32) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut events = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let Some(time) = words.next() else {
+                continue;
+            };
+            let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{} on line {} of {}", what, number + 1, path.display()));
+            let time = time.parse::<f32>().ok().filter(|t| t.is_finite()).ok_or_else(|| invalid("bad timestamp"))?;
+            let action = words.next().ok_or_else(|| invalid("missing action"))?;
+            let action = EventAction::parse(action, words.next(), seed).ok_or_else(|| invalid("unknown action"))?;
+            if words.next().is_some() {
+                return Err(invalid("trailing text"));
+            }
+            events.push((time, action));
+        }
+        Ok(Timeline::new(events))
+    }
+
+    // Repeat `action` every `period` seconds from time zero
+    pub fn set_metronome(&mut self, period: f32, action: EventAction) {
+        self.metronome = Some(Metronome { period, action });
+    }
+
+    // Whether nothing is left to fire as the clock moves forward
+    pub fn is_finished(&self) -> bool {
+        self.metronome.is_none() && self.next == self.events.len()
+    }
+
+    // Actions of every event after the previous call's `time` up to and including
+    // this one, in order. The first call fires everything up to `time`.
+    pub fn advance(&mut self, time: f32) -> Vec<EventAction> {
+        let last_time = self.last_time.replace(time);
+        if last_time.is_some_and(|last| time < last) {
+            // Scrubbed back: whatever lies ahead fires again
+            self.next = self.events.partition_point(|&(event_time, _)| event_time <= time);
+            return Vec::new();
+        }
+
+        let end = self.events.partition_point(|&(event_time, _)| event_time <= time).max(self.next);
+        let mut fired: Vec<(f32, EventAction)> = self.events[self.next..end].to_vec();
+        self.next = end;
+
+        if let Some(metronome) = self.metronome {
+            // Beats k * period with last_time < k * period <= time, counting from beat 0
+            let first = last_time.map_or(0.0, |last| (last / metronome.period).floor() + 1.0).max(0.0);
+            let last = (time / metronome.period).floor();
+            let mut beat = first;
+            while beat <= last {
+                fired.push((beat * metronome.period, metronome.action));
+                beat += 1.0;
+            }
+            fired.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+        fired.into_iter().map(|(_, action)| action).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frame times that speed up, stall, repeat and jump, as a struggling renderer's do
+    fn irregular_clock(end: f32) -> Vec<f32> {
+        let mut times = Vec::new();
+        let mut time = 0.0;
+        let steps = [0.016, 0.016, 0.0, 0.033, 0.25, 0.001, 0.016, 1.7, 0.0, 0.05];
+        for i in 0.. {
+            time += steps[i % steps.len()];
+            if time > end {
+                break;
+            }
+            times.push(time);
+        }
+        times.push(end);
+        times
+    }
+
+    #[test]
+    fn events_fire_once_however_the_frames_fall() {
+        let events: Vec<(f32, EventAction)> = (0..40).map(|i| (i as f32 * 0.37, if i % 2 == 0 { EventAction::Flash } else { EventAction::Bell })).collect();
+        let mut timeline = Timeline::new(events.clone());
+        let fired: Vec<EventAction> = irregular_clock(20.0).into_iter().flat_map(|time| timeline.advance(time)).collect();
+        assert_eq!(fired, events.iter().map(|&(_, action)| action).collect::<Vec<_>>());
+        assert!(timeline.is_finished());
+        assert!(timeline.advance(25.0).is_empty());
+    }
+
+    #[test]
+    fn scrubbing_back_rearms_the_events_ahead() {
+        let mut timeline = Timeline::new(vec![(1.0, EventAction::Flash), (2.0, EventAction::Bell), (3.0, EventAction::Glitch)]);
+        assert_eq!(timeline.advance(2.5), [EventAction::Flash, EventAction::Bell]);
+        // Nothing fires on the way back, the events after the new time fire again
+        assert!(timeline.advance(1.5).is_empty());
+        assert!(!timeline.is_finished());
+        assert_eq!(timeline.advance(3.5), [EventAction::Bell, EventAction::Glitch]);
+        assert!(timeline.advance(0.0).is_empty());
+        assert_eq!(timeline.advance(10.0), [EventAction::Flash, EventAction::Bell, EventAction::Glitch]);
+    }
+
+    #[test]
+    fn metronome_beats_once_each() {
+        let mut timeline = Timeline::new(vec![(0.75, EventAction::Bell)]);
+        timeline.set_metronome(0.5, EventAction::Flash);
+        let fired: Vec<EventAction> = irregular_clock(10.0).into_iter().flat_map(|time| timeline.advance(time)).collect();
+        // Beats 0, 0.5, ..., 10 and the bell between the second and third
+        assert_eq!(fired.iter().filter(|&&action| action == EventAction::Flash).count(), 21);
+        assert_eq!(&fired[..4], [EventAction::Flash, EventAction::Flash, EventAction::Bell, EventAction::Flash]);
+        assert!(!timeline.is_finished());
+    }
+}
@@ -0,0 +1,163 @@
+// Simulation steps per second, whatever rate frames are drawn at
+pub const UPDATE_RATE: f32 = 120.0;
+// Most steps one frame catches up on, a quarter second at UPDATE_RATE. Whatever a
+// stall (a resize, the process being suspended) adds beyond them is dropped, so
+// the scene carries on from where it stopped instead of jumping ahead.
+const MAX_STEPS: u32 = 30;
+
+// Runs updates in fixed steps. Each frame's elapsed time goes into an accumulator
+// that is spent a whole step at a time; what is left over tells how far the frame
+// is from the last step to the next.
+pub struct FixedTimestep {
+    step: f32,
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    // `rate` steps per second
+    pub fn new(rate: f32) -> Self {
+        FixedTimestep { step: 1.0 / rate, accumulator: 0.0 }
+    }
+
+    // Length of a step in seconds
+    pub fn step(&self) -> f32 {
+        self.step
+    }
+
+    // Adds `elapsed` seconds and returns how many steps are due
+    pub fn advance(&mut self, elapsed: f32) -> u32 {
+        self.accumulator += elapsed.max(0.0);
+        let due = (self.accumulator / self.step) as u32;
+        let steps = due.min(MAX_STEPS);
+        self.accumulator -= steps as f32 * self.step;
+        if steps < due {
+            self.accumulator %= self.step;
+        }
+        steps
+    }
+
+    // Fraction of a step since the last one, for drawing between it and the one
+    // before
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.step).clamp(0.0, 1.0)
+    }
+}
+
+// Scene time as the updates step it, at the last step and the one before
+pub struct SceneClock {
+    previous: f32,
+    current: f32,
+}
+
+impl SceneClock {
+    pub fn new() -> Self {
+        SceneClock { previous: 0.0, current: 0.0 }
+    }
+
+    // One update of `step` seconds; a stopped clock holds still
+    pub fn tick(&mut self, step: f32, running: bool) {
+        self.previous = self.current;
+        if running {
+            self.current += step;
+        }
+    }
+
+    // Jump by `delta` seconds, never before zero
+    pub fn scrub(&mut self, delta: f32) {
+        self.previous = (self.previous + delta).max(0.0);
+        self.current = (self.current + delta).max(0.0);
+    }
+
+    pub fn restart(&mut self) {
+        *self = SceneClock::new();
+    }
+
+    // Scene time at the last step
+    pub fn now(&self) -> f32 {
+        self.current
+    }
+
+    // Scene time `alpha` of the way from the step before the last to the last
+    pub fn at(&self, alpha: f32) -> f32 {
+        self.previous + (self.current - self.previous) * alpha
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrubbing_adds_up_and_stops_at_zero() {
+        let mut clock = SceneClock::new();
+        for _ in 0..4 {
+            clock.tick(0.5, true);
+        }
+        clock.scrub(1.5);
+        clock.scrub(-0.5);
+        assert_eq!(clock.now(), 3.0);
+        clock.scrub(-10.0);
+        assert_eq!(clock.now(), 0.0);
+        assert_eq!(clock.at(0.5), 0.0);
+
+        // A stopped clock keeps wherever it was scrubbed to
+        clock.scrub(2.0);
+        clock.tick(0.5, false);
+        assert_eq!(clock.now(), 2.0);
+        assert_eq!(clock.at(0.3), 2.0);
+        clock.restart();
+        assert_eq!(clock.now(), 0.0);
+    }
+
+    #[test]
+    fn steps_keep_pace_with_the_frames() {
+        // Sixty frames a second is two steps each, give or take the rounding
+        let mut timestep = FixedTimestep::new(UPDATE_RATE);
+        let mut steps = 0;
+        for _ in 0..600 {
+            let due = timestep.advance(1.0 / 60.0);
+            assert!((1..=3).contains(&due), "{}", due);
+            steps += due;
+        }
+        assert!((1199..=1200).contains(&steps), "{}", steps);
+
+        // Faster than the updates, most frames have none due and draw in between
+        let mut timestep = FixedTimestep::new(UPDATE_RATE);
+        let mut steps = 0;
+        for _ in 0..144 {
+            let due = timestep.advance(1.0 / 144.0);
+            assert!(due <= 1);
+            steps += due;
+            assert!((0.0..=1.0).contains(&timestep.alpha()));
+        }
+        assert!((119..=120).contains(&steps), "{}", steps);
+    }
+
+    #[test]
+    fn a_stall_catches_up_at_most_max_steps() {
+        let mut timestep = FixedTimestep::new(UPDATE_RATE);
+        assert_eq!(timestep.advance(5.0), MAX_STEPS);
+        // The rest of the stall is dropped, bar what is left of a step
+        assert!(timestep.alpha() < 1.0);
+        assert_eq!(timestep.advance(0.0), 0);
+        // Time running backwards adds nothing
+        assert_eq!(timestep.advance(-1.0), 0);
+        // And it carries on at the usual pace
+        let steps: u32 = (0..60).map(|_| timestep.advance(1.0 / 60.0)).sum();
+        assert!((119..=121).contains(&steps), "{}", steps);
+    }
+
+    #[test]
+    fn frames_draw_between_the_last_two_steps() {
+        let mut clock = SceneClock::new();
+        clock.tick(0.5, true);
+        clock.tick(0.5, true);
+        assert_eq!((clock.at(0.0), clock.at(0.5), clock.at(1.0)), (0.5, 0.75, 1.0));
+        // Stopped, every alpha is the same moment
+        clock.tick(0.5, false);
+        assert_eq!((clock.at(0.0), clock.at(0.3)), (1.0, 1.0));
+        clock.tick(0.5, true);
+        clock.restart();
+        assert_eq!(clock.at(1.0), 0.0);
+    }
+}
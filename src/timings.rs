@@ -0,0 +1,228 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+// Rows written between flushes, so a crash loses at most this many frames
+const FLUSH_EVERY: usize = 30;
+
+pub const CSV_HEADER: &str = "timestamp_s,frame,simulate_ms,raymarch_ms,post_ms,edges_ms,present_ms,total_ms,resolution_scale,dropped,late";
+
+// The parts a frame's time is split into
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    // Input, the clock and timeline events
+    Simulate,
+    Raymarch,
+    // Screen effects, overlays and palette quantization
+    Post,
+    // Sobel gradients
+    Edges,
+    // Picking the cells and drawing them to the terminal
+    Present,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [Stage::Simulate, Stage::Raymarch, Stage::Post, Stage::Edges, Stage::Present];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Simulate => "simulate",
+            Stage::Raymarch => "raymarch",
+            Stage::Post => "post",
+            Stage::Edges => "edges",
+            Stage::Present => "present",
+        }
+    }
+}
+
+// Milliseconds spent in each stage
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StageTimes {
+    milliseconds: [f32; Stage::ALL.len()],
+}
+
+impl StageTimes {
+    pub fn get(&self, stage: Stage) -> f32 {
+        self.milliseconds[stage as usize]
+    }
+
+    pub fn total(&self) -> f32 {
+        self.milliseconds.iter().sum()
+    }
+}
+
+// Charges the time since the previous lap to a stage, so the stages add up to the
+// whole frame
+pub struct StageTimer {
+    last: Instant,
+    pub times: StageTimes,
+}
+
+impl StageTimer {
+    pub fn new() -> Self {
+        StageTimer { last: Instant::now(), times: StageTimes::default() }
+    }
+
+    pub fn lap(&mut self, stage: Stage) {
+        let now = Instant::now();
+        self.times.milliseconds[stage as usize] += now.duration_since(self.last).as_secs_f32() * 1000.0;
+        self.last = now;
+    }
+}
+
+// One row of the timing log
+#[derive(Clone, Copy, Debug)]
+pub struct FrameTiming {
+    // Seconds since the run started
+    pub timestamp: f32,
+    pub frame_index: u32,
+    pub stages: StageTimes,
+    // Rays per pixel along each axis, below 1 while the frame budget lowers the resolution
+    pub resolution_scale: f32,
+    // The watchdog cut the frame short, leaving tiles from the previous one
+    pub dropped: bool,
+    // Took longer than the frame interval
+    pub late: bool,
+}
+
+impl FrameTiming {
+    pub fn csv_row(&self) -> String {
+        let mut row = format!("{:.4},{}", self.timestamp, self.frame_index);
+        for stage in Stage::ALL {
+            row.push_str(&format!(",{:.3}", self.stages.get(stage)));
+        }
+        row.push_str(&format!(",{:.3},{},{},{}", self.stages.total(), self.resolution_scale, self.dropped as u8, self.late as u8));
+        row
+    }
+}
+
+// --log-timings: rows go over a channel to a thread that does the writing, so the
+// render loop never waits on the disk. The thread also keeps every frame's times
+// for the summary at exit.
+pub struct TimingLog {
+    sender: Sender<FrameTiming>,
+    writer: JoinHandle<io::Result<String>>,
+}
+
+impl TimingLog {
+    // Appends to `path`, writing the header first if the file is new or empty
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut writer = BufWriter::new(file);
+        if writer.get_ref().metadata()?.len() == 0 {
+            writeln!(writer, "{}", CSV_HEADER)?;
+            writer.flush()?;
+        }
+
+        let (sender, receiver) = mpsc::channel::<FrameTiming>();
+        let writer = thread::spawn(move || {
+            let mut frames = Vec::new();
+            for timing in receiver {
+                writeln!(writer, "{}", timing.csv_row())?;
+                frames.push(timing.stages);
+                if frames.len() % FLUSH_EVERY == 0 {
+                    writer.flush()?;
+                }
+            }
+            writer.flush()?;
+            Ok(summary(&frames))
+        });
+        Ok(TimingLog { sender, writer })
+    }
+
+    pub fn record(&self, timing: FrameTiming) {
+        // Fails only once the writer gave up on an error, which finish reports
+        let _ = self.sender.send(timing);
+    }
+
+    // Write out what's left and return the per-stage summary
+    pub fn finish(self) -> io::Result<String> {
+        drop(self.sender);
+        self.writer.join().unwrap_or_else(|_| Err(io::Error::other("timing log writer panicked")))
+    }
+}
+
+// Mean, 95th and 99th percentile of every stage and the whole frame
+fn summary(frames: &[StageTimes]) -> String {
+    if frames.is_empty() {
+        return "No frames timed".to_string();
+    }
+    let mut text = format!("Frame timings over {} frames, mean / p95 / p99 in ms:", frames.len());
+    let columns = Stage::ALL.iter().map(|stage| (stage.name(), frames.iter().map(|times| times.get(*stage)).collect::<Vec<_>>()));
+    for (name, mut samples) in columns.chain(std::iter::once(("total", frames.iter().map(StageTimes::total).collect()))) {
+        samples.sort_by(f32::total_cmp);
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        text.push_str(&format!("\n  {:<9} {:.2} / {:.2} / {:.2}", name, mean, percentile(&samples, 0.95), percentile(&samples, 0.99)));
+    }
+    text
+}
+
+// Nearest-rank percentile of sorted, non-empty samples
+fn percentile(sorted: &[f32], fraction: f32) -> f32 {
+    let rank = (fraction * sorted.len() as f32).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(frame_index: u32) -> FrameTiming {
+        let mut stages = StageTimes::default();
+        for (i, milliseconds) in stages.milliseconds.iter_mut().enumerate() {
+            *milliseconds = (frame_index as usize * 5 + i) as f32 * 0.25;
+        }
+        FrameTiming { timestamp: frame_index as f32 / 60.0, frame_index, stages, resolution_scale: 0.5, dropped: frame_index.is_multiple_of(7), late: frame_index.is_multiple_of(3) }
+    }
+
+    #[test]
+    fn log_rows_follow_the_header() {
+        let path = std::env::temp_dir().join(format!("ascii_sobel-timings-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // Two runs appending to the same file, the header written once
+        for run in 0..2 {
+            let log = TimingLog::create(&path).unwrap();
+            for frame in 0..40 {
+                log.record(timing(run * 40 + frame));
+            }
+            assert!(log.finish().unwrap().starts_with("Frame timings over 40 frames"));
+        }
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut lines = text.lines();
+        let columns: Vec<&str> = lines.next().unwrap().split(',').collect();
+        // Time and frame, the stages, then the total, scale and the two flags
+        assert_eq!(columns.len(), 2 + Stage::ALL.len() + 4);
+        assert!(Stage::ALL.iter().all(|stage| columns.contains(&format!("{}_ms", stage.name()).as_str())));
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        assert_eq!(rows.len(), 80);
+        for (frame, row) in rows.iter().enumerate() {
+            assert_eq!(row.len(), columns.len(), "{:?}", row);
+            let field = |name: &str| row[columns.iter().position(|&column| column == name).unwrap()];
+            assert_eq!(field("frame").parse::<u32>().unwrap(), frame as u32);
+            for (column, value) in columns.iter().zip(row) {
+                if column.ends_with("_ms") || column.ends_with("_s") || *column == "resolution_scale" {
+                    assert!(value.parse::<f32>().unwrap().is_finite(), "{} = {}", column, value);
+                }
+            }
+            let total: f32 = Stage::ALL.iter().map(|stage| field(&format!("{}_ms", stage.name())).parse::<f32>().unwrap()).sum();
+            assert!((field("total_ms").parse::<f32>().unwrap() - total).abs() < 1e-2);
+            assert_eq!(field("dropped"), if frame.is_multiple_of(7) { "1" } else { "0" });
+            assert_eq!(field("late"), if frame.is_multiple_of(3) { "1" } else { "0" });
+        }
+    }
+
+    #[test]
+    fn percentiles_take_the_nearest_rank() {
+        let samples: Vec<f32> = (1..=100).map(|i| i as f32).collect();
+        assert_eq!(percentile(&samples, 0.95), 95.0);
+        assert_eq!(percentile(&samples, 0.99), 99.0);
+        assert_eq!(percentile(&[3.0], 0.99), 3.0);
+        assert_eq!(percentile(&samples, 0.0), 1.0);
+        assert_eq!(summary(&[]), "No frames timed");
+    }
+}
@@ -0,0 +1,303 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// Entries in a custom curve's lookup table, spread evenly from 0 to its last point
+const LUT_SIZE: usize = 1024;
+// Radiance the built-in curves are drawn up to in the HUD
+const BUILTIN_RANGE: f32 = 4.0;
+
+// How linear radiance is squeezed into the displayable [0, 1] before encoding
+#[derive(Clone, Debug)]
+pub enum ToneCurve {
+    // Clipped at 1
+    Linear,
+    // x / (1 + x): never clips, but flattens highlights early
+    Reinhard,
+    // Narkowicz's fit of the ACES filmic curve, with a toe and a soft shoulder
+    Aces,
+    // Spline through points loaded with --tone-curve
+    Custom(Arc<CustomCurve>),
+}
+
+impl ToneCurve {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear" => Some(ToneCurve::Linear),
+            "reinhard" => Some(ToneCurve::Reinhard),
+            "aces" => Some(ToneCurve::Aces),
+            _ => None,
+        }
+    }
+
+    // The next curve, taking in the custom one when there is one
+    pub fn next(&self, custom: Option<&Arc<CustomCurve>>) -> Self {
+        match (self, custom) {
+            (ToneCurve::Linear, _) => ToneCurve::Reinhard,
+            (ToneCurve::Reinhard, _) => ToneCurve::Aces,
+            (ToneCurve::Aces, Some(curve)) => ToneCurve::Custom(curve.clone()),
+            (ToneCurve::Aces, None) | (ToneCurve::Custom(_), _) => ToneCurve::Linear,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneCurve::Linear => "linear",
+            ToneCurve::Reinhard => "reinhard",
+            ToneCurve::Aces => "aces",
+            ToneCurve::Custom(_) => "custom",
+        }
+    }
+
+    // One channel of linear radiance, mapped. Linear leaves the clipping to the
+    // pixel conversion.
+    pub fn apply(&self, x: f32) -> f32 {
+        match self {
+            ToneCurve::Linear => x,
+            ToneCurve::Reinhard => x.max(0.0) / (1.0 + x.max(0.0)),
+            ToneCurve::Aces => {
+                let x = x.max(0.0);
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }
+            ToneCurve::Custom(curve) => curve.lookup(x),
+        }
+    }
+
+    // `count` outputs evenly spaced over the curve's interesting inputs, clipped
+    // like the display clips them, for plotting
+    pub fn samples(&self, count: usize) -> Vec<f32> {
+        let range = match self {
+            ToneCurve::Custom(curve) => curve.range(),
+            _ => BUILTIN_RANGE,
+        };
+        (0..count)
+            .map(|i| self.apply(range * i as f32 / count.saturating_sub(1).max(1) as f32).clamp(0.0, 1.0))
+            .collect()
+    }
+}
+
+// Why a set of points can't make a tone curve. Points are numbered from 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CurveError {
+    TooFewPoints,
+    NotFinite { point: usize },
+    NegativeInput { point: usize },
+    // Its input isn't above the previous point's
+    UnsortedInput { point: usize },
+    // Its output is below the previous point's
+    Decreasing { point: usize },
+}
+
+impl CurveError {
+    fn point(&self) -> Option<usize> {
+        match *self {
+            CurveError::TooFewPoints => None,
+            CurveError::NotFinite { point } | CurveError::NegativeInput { point } | CurveError::UnsortedInput { point } | CurveError::Decreasing { point } => Some(point),
+        }
+    }
+}
+
+impl fmt::Display for CurveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CurveError::TooFewPoints => write!(f, "a tone curve needs at least two points"),
+            CurveError::NotFinite { point } => write!(f, "point {} is not a finite number", point),
+            CurveError::NegativeInput { point } => write!(f, "point {} has a negative input", point),
+            CurveError::UnsortedInput { point } => write!(f, "point {} has an input no larger than the point before it; inputs must increase", point),
+            CurveError::Decreasing { point } => write!(f, "the curve falls from point {} to point {}; outputs must never decrease", point - 1, point),
+        }
+    }
+}
+
+impl std::error::Error for CurveError {}
+
+// Monotone cubic spline through (input, output) points. Fritsch-Carlson limits
+// the slopes so the spline never overshoots between points, so a curve through
+// non-decreasing outputs never decreases either. Flat before the first point and
+// after the last.
+#[derive(Debug)]
+pub struct CustomCurve {
+    points: Vec<(f32, f32)>,
+    tangents: Vec<f32>,
+    // evaluate() sampled from 0 to the last point's input
+    lut: Vec<f32>,
+}
+
+impl CustomCurve {
+    pub fn new(points: Vec<(f32, f32)>) -> Result<Self, CurveError> {
+        if points.len() < 2 {
+            return Err(CurveError::TooFewPoints);
+        }
+        for (index, &(x, y)) in points.iter().enumerate() {
+            let point = index + 1;
+            if !x.is_finite() || !y.is_finite() {
+                return Err(CurveError::NotFinite { point });
+            }
+            if x < 0.0 {
+                return Err(CurveError::NegativeInput { point });
+            }
+            if let Some(&(previous_x, previous_y)) = index.checked_sub(1).map(|i| &points[i]) {
+                if x <= previous_x {
+                    return Err(CurveError::UnsortedInput { point });
+                }
+                if y < previous_y {
+                    return Err(CurveError::Decreasing { point });
+                }
+            }
+        }
+
+        let secants: Vec<f32> = points.windows(2).map(|pair| (pair[1].1 - pair[0].1) / (pair[1].0 - pair[0].0)).collect();
+        let last = secants.len() - 1;
+        let mut tangents: Vec<f32> = (0..points.len())
+            .map(|i| match i {
+                0 => secants[0],
+                i if i > last => secants[last],
+                // Flat at a local extreme, which outputs that never decrease only
+                // have on flat stretches
+                i if secants[i - 1] * secants[i] <= 0.0 => 0.0,
+                i => (secants[i - 1] + secants[i]) / 2.0,
+            })
+            .collect();
+        for (i, &secant) in secants.iter().enumerate() {
+            if secant == 0.0 {
+                tangents[i] = 0.0;
+                tangents[i + 1] = 0.0;
+                continue;
+            }
+            let (a, b) = (tangents[i] / secant, tangents[i + 1] / secant);
+            let length = (a * a + b * b).sqrt();
+            if length > 3.0 {
+                tangents[i] = 3.0 * a / length * secant;
+                tangents[i + 1] = 3.0 * b / length * secant;
+            }
+        }
+
+        let mut curve = CustomCurve { points, tangents, lut: Vec::new() };
+        let range = curve.range();
+        curve.lut = (0..LUT_SIZE).map(|i| curve.evaluate(range * i as f32 / (LUT_SIZE - 1) as f32)).collect();
+        Ok(curve)
+    }
+
+    // Text file, one `<input> <output>` pair per line, with `#` starting a comment
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+        let mut points = Vec::new();
+        let mut lines = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let values: Vec<&str> = line.split_whitespace().collect();
+            if values.is_empty() {
+                continue;
+            }
+            let parsed: Option<Vec<f32>> = values.iter().map(|value| value.parse().ok()).collect();
+            let Some(&[x, y]) = parsed.as_deref() else {
+                return Err(invalid(format!("expected an input and an output on line {} of {}", number + 1, path.display())));
+            };
+            points.push((x, y));
+            lines.push(number + 1);
+        }
+        CustomCurve::new(points).map_err(|e| match e.point() {
+            Some(point) => invalid(format!("{} (line {} of {})", e, lines[point - 1], path.display())),
+            None => invalid(format!("{} in {}", e, path.display())),
+        })
+    }
+
+    // The last point's input, past which the curve stays flat
+    pub fn range(&self) -> f32 {
+        self.points[self.points.len() - 1].0
+    }
+
+    // The spline at `x`, computed directly
+    pub fn evaluate(&self, x: f32) -> f32 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if x <= first.0 {
+            return first.1;
+        }
+        if x >= last.0 {
+            return last.1;
+        }
+        // Segment from point i to point i + 1 holding x
+        let i = self.points.partition_point(|&(point_x, _)| point_x <= x) - 1;
+        let ((x0, y0), (x1, y1)) = (self.points[i], self.points[i + 1]);
+        let h = x1 - x0;
+        let t = (x - x0) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        (2.0 * t3 - 3.0 * t2 + 1.0) * y0 + (t3 - 2.0 * t2 + t) * h * self.tangents[i] + (-2.0 * t3 + 3.0 * t2) * y1 + (t3 - t2) * h * self.tangents[i + 1]
+    }
+
+    // The spline at `x` from the lookup table, linearly interpolated
+    pub fn lookup(&self, x: f32) -> f32 {
+        if x.is_nan() || x <= 0.0 {
+            return self.lut[0];
+        }
+        let position = x / self.range() * (LUT_SIZE - 1) as f32;
+        if position >= (LUT_SIZE - 1) as f32 {
+            return self.lut[LUT_SIZE - 1];
+        }
+        let index = position as usize;
+        let t = position - index as f32;
+        self.lut[index] + (self.lut[index + 1] - self.lut[index]) * t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s_curve() -> CustomCurve {
+        CustomCurve::new(vec![(0.0, 0.0), (0.25, 0.1), (0.5, 0.5), (1.0, 0.85), (2.0, 0.95), (3.0, 1.0)]).unwrap()
+    }
+
+    #[test]
+    fn lookup_table_matches_direct_evaluation() {
+        let curve = s_curve();
+        for i in 0..=3000 {
+            let x = i as f32 / 1000.0;
+            assert!((curve.lookup(x) - curve.evaluate(x)).abs() < 1e-3, "at {}: {} vs {}", x, curve.lookup(x), curve.evaluate(x));
+        }
+        // Through its points, and flat outside them
+        for (x, y) in [(0.25, 0.1), (0.5, 0.5), (2.0, 0.95)] {
+            assert!((curve.evaluate(x) - y).abs() < 1e-6);
+        }
+        assert_eq!((curve.lookup(-1.0), curve.lookup(f32::NAN), curve.lookup(10.0)), (0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn custom_curves_never_decrease() {
+        let curve = s_curve();
+        let samples: Vec<f32> = (0..=4000).map(|i| curve.evaluate(i as f32 / 1000.0)).collect();
+        assert!(samples.windows(2).all(|pair| pair[1] >= pair[0]));
+        // Including across a flat stretch between rising ones
+        let plateau = CustomCurve::new(vec![(0.0, 0.0), (1.0, 0.5), (2.0, 0.5), (3.0, 1.0)]).unwrap();
+        let samples: Vec<f32> = (0..=3000).map(|i| plateau.lookup(i as f32 / 1000.0)).collect();
+        assert!(samples.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert!(samples[1000..=2000].iter().all(|&y| (y - 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn bad_points_are_rejected() {
+        let error = |points: &[(f32, f32)]| CustomCurve::new(points.to_vec()).err();
+        assert_eq!(error(&[(0.0, 0.0), (1.0, 0.8), (2.0, 0.6)]), Some(CurveError::Decreasing { point: 3 }));
+        assert_eq!(error(&[(0.0, 0.0), (1.0, 0.5), (1.0, 0.6)]), Some(CurveError::UnsortedInput { point: 3 }));
+        assert_eq!(error(&[(0.0, 0.0), (2.0, 0.5), (1.0, 0.6)]), Some(CurveError::UnsortedInput { point: 3 }));
+        assert_eq!(error(&[(-1.0, 0.0), (1.0, 1.0)]), Some(CurveError::NegativeInput { point: 1 }));
+        assert_eq!(error(&[(0.0, f32::NAN), (1.0, 1.0)]), Some(CurveError::NotFinite { point: 1 }));
+        assert_eq!(error(&[(0.0, 0.0)]), Some(CurveError::TooFewPoints));
+        assert_eq!(CurveError::Decreasing { point: 3 }.to_string(), "the curve falls from point 2 to point 3; outputs must never decrease");
+    }
+
+    #[test]
+    fn loading_names_the_offending_line() {
+        let path = std::env::temp_dir().join(format!("ascii_sobel-curve-{}.txt", std::process::id()));
+        fs::write(&path, "# input output\n0 0\n\n1 0.7  # knee\n2 0.6\n").unwrap();
+        let error = CustomCurve::load(&path).unwrap_err();
+        fs::write(&path, "0 0\n1 0.7\n3 1\n").unwrap();
+        let loaded = CustomCurve::load(&path);
+        let _ = fs::remove_file(&path);
+        assert!(error.to_string().contains("(line 5 of"), "{}", error);
+        assert_eq!(loaded.unwrap().range(), 3.0);
+    }
+}
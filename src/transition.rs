@@ -0,0 +1,141 @@
+use crate::math::Smoothstep;
+use std::time::Instant;
+
+// Width of the wipe's soft edge as a fraction of the frame width
+const WIPE_SOFTNESS: f32 = 0.15;
+
+// How a switch between two looks is blended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransitionKind {
+    Cut,
+    Crossfade,
+    // The new look sweeps in from the left behind a soft edge
+    Wipe,
+}
+
+impl TransitionKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cut" => Some(TransitionKind::Cut),
+            "crossfade" => Some(TransitionKind::Crossfade),
+            "wipe" => Some(TransitionKind::Wipe),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransitionSettings {
+    pub kind: TransitionKind,
+    // Seconds of wall-clock time, so a transition also plays out while paused
+    pub duration: f32,
+}
+
+impl Default for TransitionSettings {
+    fn default() -> Self {
+        TransitionSettings {
+            kind: TransitionKind::Crossfade,
+            duration: 1.0,
+        }
+    }
+}
+
+pub struct Transition {
+    kind: TransitionKind,
+    duration: f32,
+    started: Instant,
+}
+
+impl Transition {
+    // None when the settings ask for a hard cut
+    pub fn start(settings: &TransitionSettings) -> Option<Self> {
+        if settings.kind == TransitionKind::Cut || settings.duration <= 0.0 {
+            return None;
+        }
+        Some(Transition {
+            kind: settings.kind,
+            duration: settings.duration,
+            started: Instant::now(),
+        })
+    }
+
+    // 0 when the transition starts, 1 once it is over
+    pub fn progress(&self) -> f32 {
+        (self.started.elapsed().as_secs_f32() / self.duration).min(1.0)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.progress() >= 1.0
+    }
+
+    // How much of the outgoing frame shows in column `x` of a frame `width` pixels wide
+    pub fn outgoing_weight(&self, progress: f32, x: usize, width: f32) -> f32 {
+        match self.kind {
+            TransitionKind::Cut => 0.0,
+            TransitionKind::Crossfade => 1.0 - progress,
+            TransitionKind::Wipe => {
+                // The edge travels far enough that both frames are whole at the ends
+                let edge = progress * (1.0 + 2.0 * WIPE_SOFTNESS) - WIPE_SOFTNESS;
+                let u = (x as f32 + 0.5) / width;
+                u.smoothstep(edge - WIPE_SOFTNESS, edge + WIPE_SOFTNESS)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A transition that began `elapsed` seconds ago
+    fn started_ago(kind: TransitionKind, duration: f32, elapsed: f32) -> Transition {
+        let started = Instant::now().checked_sub(Duration::from_secs_f32(elapsed)).unwrap();
+        Transition { kind, duration, started }
+    }
+
+    #[test]
+    fn progress_runs_from_zero_to_one_and_stops() {
+        let fresh = started_ago(TransitionKind::Crossfade, 1000.0, 0.0);
+        assert!(fresh.progress() < 1e-3);
+        assert!(!fresh.finished());
+
+        let halfway = started_ago(TransitionKind::Crossfade, 1000.0, 500.0);
+        assert!((halfway.progress() - 0.5).abs() < 1e-3);
+        assert!(!halfway.finished());
+
+        let over = started_ago(TransitionKind::Crossfade, 1.0, 5.0);
+        assert_eq!(over.progress(), 1.0);
+        assert!(over.finished());
+    }
+
+    #[test]
+    fn cuts_and_empty_durations_start_nothing() {
+        assert!(Transition::start(&TransitionSettings { kind: TransitionKind::Cut, duration: 1.0 }).is_none());
+        assert!(Transition::start(&TransitionSettings { kind: TransitionKind::Crossfade, duration: 0.0 }).is_none());
+        let started = Transition::start(&TransitionSettings::default()).unwrap();
+        assert!(!started.finished());
+    }
+
+    #[test]
+    fn crossfade_weighs_every_column_alike() {
+        let fade = started_ago(TransitionKind::Crossfade, 1.0, 0.0);
+        for x in [0, 40, 79] {
+            assert_eq!(fade.outgoing_weight(0.0, x, 80.0), 1.0);
+            assert_eq!(fade.outgoing_weight(0.5, x, 80.0), 0.5);
+            assert_eq!(fade.outgoing_weight(1.0, x, 80.0), 0.0);
+        }
+    }
+
+    #[test]
+    fn wipe_shows_whole_frames_at_its_ends() {
+        let wipe = started_ago(TransitionKind::Wipe, 1.0, 0.0);
+        for x in 0..80 {
+            assert_eq!(wipe.outgoing_weight(0.0, x, 80.0), 1.0);
+            assert_eq!(wipe.outgoing_weight(1.0, x, 80.0), 0.0);
+        }
+        // Midway the new look has reached the left side but not the right
+        assert_eq!(wipe.outgoing_weight(0.5, 0, 80.0), 0.0);
+        assert_eq!(wipe.outgoing_weight(0.5, 79, 80.0), 1.0);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::input::quit_queued;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often the watcher looks at the clock and the keyboard. Keeps ESC under
+// ~10ms on slow frames.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interruption {
+    // The frame ran past its budget
+    OverBudget,
+    // The quit key is waiting to be read; other keys wait for the frame
+    Quit,
+}
+
+// Input is only read between frames, so a frame that takes seconds would leave the
+// app frozen. The watchdog raises a shared flag that the render loop checks between
+// chunks, which then gives up on the rest of the frame.
+#[derive(Clone)]
+pub struct Watchdog {
+    budget: Duration,
+    cancel: Arc<AtomicBool>,
+    // Whether the user asked to quit, so the frame needn't finish
+    quit_requested: fn() -> bool,
+}
+
+impl Watchdog {
+    pub fn new(budget: Duration, cancel: Arc<AtomicBool>) -> Self {
+        Watchdog { budget, cancel, quit_requested: quit_queued }
+    }
+
+    // Run `frame` on this thread while a helper thread watches it. Returns the
+    // frame's result and why it was cut short, if it was.
+    pub fn run<R>(&self, frame: impl FnOnce() -> R) -> (R, Option<Interruption>) {
+        self.cancel.store(false, Ordering::Relaxed);
+        let start = Instant::now();
+        let done = AtomicBool::new(false);
+        let interruption = Mutex::new(None);
+
+        let result = thread::scope(|scope| {
+            let watcher = scope.spawn(|| {
+                while !done.load(Ordering::Acquire) {
+                    let elapsed = start.elapsed();
+                    let reason = if elapsed >= self.budget {
+                        Some(Interruption::OverBudget)
+                    } else if (self.quit_requested)() {
+                        Some(Interruption::Quit)
+                    } else {
+                        None
+                    };
+                    if reason.is_some() {
+                        *interruption.lock().unwrap_or_else(PoisonError::into_inner) = reason;
+                        self.cancel.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    thread::park_timeout(POLL_INTERVAL);
+                }
+            });
+            let result = frame();
+            // Wake the watcher so the frame doesn't wait out its poll interval
+            done.store(true, Ordering::Release);
+            watcher.thread().unpark();
+            result
+        });
+        (result, interruption.into_inner().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    const BUDGET: Duration = Duration::from_millis(40);
+
+    fn watchdog(quit_requested: fn() -> bool) -> (Watchdog, Arc<AtomicBool>) {
+        let cancel = Arc::new(AtomicBool::new(false));
+        (Watchdog { budget: BUDGET, cancel: cancel.clone(), quit_requested }, cancel)
+    }
+
+    fn never() -> bool {
+        false
+    }
+
+    // A frame that would take seconds, checking the flag between chunks the way
+    // the render loop does. Returns how many chunks it got through.
+    fn slow_frame(cancel: &AtomicBool) -> usize {
+        let mut chunks = 0;
+        while chunks < 1000 && !cancel.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(2));
+            chunks += 1;
+        }
+        chunks
+    }
+
+    #[test]
+    fn a_slow_frame_is_cut_short_at_its_budget() {
+        let (watchdog, cancel) = watchdog(never);
+        let start = Instant::now();
+        let (chunks, interruption) = watchdog.run(|| slow_frame(&cancel));
+        assert_eq!(interruption, Some(Interruption::OverBudget));
+        assert!(chunks < 1000);
+        assert!(start.elapsed() >= BUDGET);
+        assert!(start.elapsed() < BUDGET * 10, "took {:?}", start.elapsed());
+
+        // The next frame starts with the flag down
+        let (result, interruption) = watchdog.run(|| cancel.load(Ordering::Relaxed));
+        assert!(!result);
+        assert_eq!(interruption, None);
+    }
+
+    #[test]
+    fn fast_frames_run_undisturbed() {
+        let (watchdog, cancel) = watchdog(never);
+        for frame in 0..20 {
+            let (result, interruption) = watchdog.run(|| {
+                thread::sleep(Duration::from_millis(1));
+                frame * 2
+            });
+            assert_eq!(result, frame * 2);
+            assert_eq!(interruption, None);
+            assert!(!cancel.load(Ordering::Relaxed));
+        }
+    }
+
+    #[test]
+    fn a_quit_cuts_the_frame_short_before_its_budget() {
+        // Asks to quit after the frame has been running a little while
+        static POLLS: AtomicUsize = AtomicUsize::new(0);
+        fn quit_after_a_few_polls() -> bool {
+            POLLS.fetch_add(1, Ordering::Relaxed) >= 2
+        }
+        let (mut watchdog, cancel) = watchdog(quit_after_a_few_polls);
+        watchdog.budget = Duration::from_secs(10);
+        let start = Instant::now();
+        let (chunks, interruption) = watchdog.run(|| slow_frame(&cancel));
+        assert_eq!(interruption, Some(Interruption::Quit));
+        assert!(chunks < 1000);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}